@@ -1,13 +1,77 @@
 ---@class TircConfig
 ---@field servers TircConfigServer[]
+---@field logging? TircConfigLogging
+---@field highlight? TircConfigHighlight
+---@field notification? TircConfigNotification
+---@field paste? TircConfigPaste
+---@field reload? TircConfigReload
 
 ---@class TircConfigServer
+---@field name? string defaults to `host`, must be unique across servers
 ---@field host string
 ---@field port number
 ---@field use_tls boolean
 ---@field accept_invalid_cert boolean
 ---@field nickname string[]
+---@field realname? string defaults to the first nickname
 ---@field autojoin string[]
+---@field reconnect? TircConfigReconnect
+---@field flood? TircConfigFlood
+---@field ping_interval? integer seconds of inactivity before pinging the server
+---@field ping_timeout? integer seconds to wait for a ping reply before reconnecting
+---@field sasl? TircConfigSasl
+
+---@class TircConfigFlood
+---@field enabled? boolean defaults to `true`
+---@field burst? integer messages sent at once before pacing, defaults to 5
+---@field interval? integer milliseconds between paced messages, defaults to 2000
+
+---@class TircConfigReconnect
+---@field enabled? boolean defaults to `true`
+---@field delay? integer seconds before the first attempt, doubled on every failure
+---@field max_delay? integer upper bound for `delay`
+
+---@class TircConfigSasl
+---@field mechanism? 'PLAIN' | 'EXTERNAL' defaults to 'PLAIN'
+---@field account? string account for PLAIN, defaults to the first nickname
+---@field password? string password for PLAIN
+---@field cert_path? string PKCS #12 client certificate for EXTERNAL
+---@field cert_password? string password of the client certificate
+
+---@class TircConfigLogging
+---@field enabled? boolean log every buffer, defaults to `false`
+---@field format? 'text' | 'raw' readable lines or replayable IRC lines, defaults to 'text'
+---@field directory? string defaults to `$XDG_DATA_HOME/tirc/logs`
+---@field buffers? table<string, boolean> per buffer overrides of `enabled`, keyed by 'server/buffer' or 'buffer'
+---@field restore? integer messages restored from 'raw' logs when a buffer is opened, defaults to 100, 0 disables
+
+---@class TircConfigHighlight
+---@field nickname? boolean highlight mentions of your nickname, defaults to `true`
+---@field keywords? string[] words highlighted like mentions of your nickname, ignoring case
+---@field patterns? string[] regular expressions highlighting the messages they match
+---@field exclude_nicks? string[] nicknames whose messages are never highlighted, e.g. bots
+---@field exclude_patterns? string[] regular expressions of messages never highlighted
+---@field buffers? table<string, TircConfigHighlightBuffer> per buffer rules, keyed by 'server/buffer' or 'buffer'
+
+---@class TircConfigHighlightBuffer
+---@field enabled? boolean whether messages in the buffer are highlighted at all, defaults to `true`
+---@field nickname? boolean overrides `nickname`
+---@field keywords? string[] words highlighted in addition to the global ones
+---@field patterns? string[] patterns highlighted in addition to the global ones
+
+---@class TircConfigNotification
+---@field highlights? boolean notify of highlights, defaults to `true`
+---@field queries? boolean notify of the first message of a new private conversation, defaults to `true`
+---@field bell? boolean ring the terminal bell, defaults to `true`
+---@field desktop? 'osc9' | 'osc777' escape sequence asking the terminal for a desktop notification, inside tmux it requires `allow-passthrough`
+---@field command? string[] command run with the title and text of the notification appended, e.g. `{ 'notify-send' }`
+---@field rate_limit? integer minimum seconds between notifications about the same buffer, defaults to 10
+
+---@class TircConfigPaste
+---@field delay? integer milliseconds between the lines of a paste sent as separate messages, on top of the server's `flood` limit, defaults to 500
+
+---@class TircConfigReload
+---@field watch? boolean reload when a Lua file in the config directory changes, like `:reload` does, defaults to `false`
 
 local M = {}
 
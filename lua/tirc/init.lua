@@ -1,19 +1,35 @@
----@alias EventName 'message'
----@alias FormatterName 'buffer_title' | 'message_time' | 'message_text' | 'user'
+---@alias EventName 'message' | 'highlight' | 'connect' | 'registered' | 'disconnect' | 'join' | 'part' | 'nick_change' | 'mode_change' | 'buffer_open' | 'buffer_close' | 'buffer_switch' | 'input_submit' | 'key' | 'tick'
+---@alias FormatterName 'buffer_title' | 'message_time' | 'message_text' | 'user' | 'buffer_bar'
 
 --- Styled span tree consumed by the renderer: a string, a `{ content, style }`
 --- pair, or a (possibly nested) list of either. Returning `nil` skips the line.
 ---@alias TircSpans string | table
 
+--- Sends commands to one server, raising an error when it is not connected.
 ---@class TircSender
+---@field server string name of the server
 ---@field send_privmsg fun(target: string, message: string)
 ---@field send_notice fun(target: string, message: string)
+---@field join fun(channel: string, key?: string)
+---@field part fun(channel: string, reason?: string)
+---@field kick fun(channel: string, nickname: string, reason?: string)
+---@field mode fun(target: string, modes?: string, ...: string) asks for the current modes without `modes`, e.g. `irc.mode('#tirc', '+o', 'alice')`
+---@field topic fun(channel: string, topic?: string) asks for the topic without one
+---@field nick fun(nickname: string)
+---@field whois fun(nickname: string)
+---@field ctcp fun(target: string, request: string, params?: string) e.g. `irc.ctcp('alice', 'VERSION')`
+---@field action fun(target: string, text: string) like `:me`
+---@field raw fun(line: string) sends an IRC line as is, e.g. `'MODE #tirc +m'`
+---@field for_server fun(name: string): TircSender? sender of another server, nil if it has not connected yet
+---@field nickname fun(): string? own nickname, nil when not connected
+---@field channels fun(): string[] joined channels
+---@field users fun(channel: string): TircUser[]? nil when `channel` is not joined
 
 --- A single IRCv3 message tag as a `{ name, value }` pair.
 ---@alias TircMessageTag [string, string]
 
 ---@class TircMessage
----@field command string IRC verb, or symbolic name for numeric replies (e.g. 'RPL_WELCOME')
+---@field command string IRC verb, symbolic name for numeric replies (e.g. 'RPL_WELCOME'), or 'TIRC' for lines generated by tirc itself
 ---@field params string[]
 ---@field nick? string set when the message carries a user prefix
 ---@field user? string
@@ -21,12 +37,87 @@
 ---@field server? string set instead of nick/user/host for server prefixes
 ---@field tags TircMessageTag[]
 ---@field raw string the raw IRC line, also returned by `tostring(msg)`
+---@field level? 'info' | 'warning' | 'error' severity of a 'TIRC' line
+---@field batch? TircBatch set when the message was received in a batch, or summarizes one
+---@field nicks? string[] nicknames involved in a netsplit or netjoin summarized by a 'TIRC' line
+---@field result? integer number of the `:grep` result listed by a 'TIRC' line
+---@field highlight? boolean set when the message matches the highlight rules of the config
+---@field queued? boolean set on our own message while it waits in the outgoing queue
+
+---@class TircBatch
+---@field type string lowercased batch type, e.g. 'netsplit', 'netjoin', 'chathistory' or 'labeled-response'
+---@field params string[]
+---@field parent? TircBatch the batch this one is nested in
+
+---@class TircServer
+---@field name string
+---@field host string
+---@field nickname string
+---@field capabilities table<string, boolean> IRCv3 capabilities enabled on the connection, e.g. `server.capabilities['labeled-response']`
+
+---@class TircBuffer
+---@field server string
+---@field name string
+---@field label string name shown in the buffer bar, `(server)` for status buffers of several servers
+---@field current boolean
+---@field unread integer messages from others since the buffer was last viewed
+---@field highlights integer unread messages mentioning you
 
 ---@class TircUser
 ---@field nickname string
 ---@field access_levels string[] e.g. `{ 'Owner', 'Voice' }`
 ---@field highest_access_level string
 
+---@class TircServerEvent
+---@field server string
+
+---@class TircRegisteredEvent: TircServerEvent
+---@field nickname string own nickname the server accepted
+
+---@class TircDisconnectEvent: TircServerEvent
+---@field error? string why the connection was lost
+
+--- Event about a user, whose `nickname` is missing for modes set by the server.
+---@class TircUserEvent: TircServerEvent
+---@field nickname? string user the event is about
+---@field is_self? boolean set when the user is you
+
+---@class TircJoinEvent: TircUserEvent
+---@field channel string
+
+---@class TircPartEvent: TircUserEvent
+---@field channel string
+---@field reason? string
+
+---@class TircNickChangeEvent: TircUserEvent
+---@field old_nickname string
+---@field new_nickname string
+
+---@class TircModeChange
+---@field mode string e.g. `'+o'`
+---@field arg? string e.g. the nickname given `+o`
+
+---@class TircModeChangeEvent: TircUserEvent
+---@field target string channel or nickname whose modes changed
+---@field changes TircModeChange[]
+
+---@class TircBufferEvent: TircServerEvent
+---@field buffer string name of the buffer, `(status)` for status buffers
+---@field previous_server? string buffer left, for `buffer_switch`
+---@field previous_buffer? string
+
+---@class TircInputSubmitEvent: TircServerEvent
+---@field buffer string
+---@field text string message typed, as rewritten by earlier handlers
+
+---@class TircKeyEvent: TircServerEvent
+---@field buffer string current buffer
+---@field key string e.g. `'a'`, `'Ctrl-r'`, `'Alt-Enter'` or `'Shift-Left'`
+---@field mode 'normal' | 'insert' | 'command' | 'search'
+
+---@class TircTickEvent
+---@field time integer seconds since the Unix epoch
+
 ---@class TircDateTime
 ---@field year integer
 ---@field month integer
@@ -35,11 +126,28 @@
 ---@field minute integer
 ---@field second integer
 
+---@class TircCompletionContext
+---@field mode 'insert' | 'command'
+---@field line string the whole input line
+---@field server string server of the current buffer
+---@field buffer string name of the current buffer
+
+---@class TircCommandContext
+---@field server string server of the current buffer
+---@field buffer string name of the current buffer
+---@field irc? TircSender sender of the server
+
+---@class TircCommandOptions
+---@field aliases? string[] other names the command is run with
+---@field complete? fun(word: string, ctx: TircCompletionContext): string[]? candidates for completing the arguments
+---@field help? string shown by `:help <name>`
+
 ---@class TircUiFormat
 ---@field buffer_title? fun(server: string, nickname: string, buffer: string): TircSpans
 ---@field message_time? fun(date_time: TircDateTime, msg: TircMessage): TircSpans
----@field message_text? fun(msg: TircMessage, nickname: string): TircSpans?
+---@field message_text? fun(msg: TircMessage, nickname: string, server: TircServer): TircSpans?
 ---@field user? fun(user: TircUser): TircSpans
+---@field buffer_bar? fun(buffers: TircBuffer[]): TircSpans
 
 ---@class TircUi
 ---@field format? TircUiFormat
@@ -47,7 +155,19 @@
 ---@class TircModule
 ---@field version string
 ---@field ui TircUi
----@field on fun(event_name: EventName, callback: fun(msg: table, irc: TircSender))
+---@field on fun(event_name: 'message' | 'highlight', callback: fun(msg: TircMessage, irc: TircSender)) IRC messages received, not those fetched from the history
+---@field on fun(event_name: 'connect' | 'disconnect', callback: fun(ev: TircServerEvent | TircDisconnectEvent, irc: TircSender))
+---@field on fun(event_name: 'registered', callback: fun(ev: TircRegisteredEvent, irc: TircSender)) the server welcomed you, commands can be sent
+---@field on fun(event_name: 'join', callback: fun(ev: TircJoinEvent, irc: TircSender))
+---@field on fun(event_name: 'part', callback: fun(ev: TircPartEvent, irc: TircSender))
+---@field on fun(event_name: 'nick_change', callback: fun(ev: TircNickChangeEvent, irc: TircSender))
+---@field on fun(event_name: 'mode_change', callback: fun(ev: TircModeChangeEvent, irc: TircSender))
+---@field on fun(event_name: 'buffer_open' | 'buffer_close' | 'buffer_switch', callback: fun(ev: TircBufferEvent, irc?: TircSender))
+---@field on fun(event_name: 'input_submit', callback: fun(ev: TircInputSubmitEvent, irc?: TircSender): string | false | nil) return `false` to cancel the message or a string to send instead
+---@field on fun(event_name: 'key', callback: fun(ev: TircKeyEvent, irc?: TircSender): boolean?) return `true` to keep tirc from handling the key
+---@field on fun(event_name: 'tick', callback: fun(ev: TircTickEvent, irc?: TircSender)) every second, `irc` is the sender of the current buffer's server
+---@field add_completion_source fun(source: fun(word: string, ctx: TircCompletionContext): string[]?) candidates starting with `word` are offered after the built-in ones
+---@field command fun(name: string, run: fun(args: string, ctx: TircCommandContext): string?, opts?: TircCommandOptions) registers `:name`, overriding a built-in command of that name; a returned string is run as a built-in command, e.g. `'join ' .. args`
 local M = {}
 
 local _tirc = require('_tirc')
@@ -10,6 +10,8 @@ local twhite = theme.style { fg = 'white' } -- this is darker than gray..
 local blue = theme.style { fg = 'blue' }
 local green = theme.style { fg = 'green' }
 local red = theme.style { fg = 'red' }
+local yellow = theme.style { fg = 'yellow' }
+local bold = theme.style { bold = true }
 local gray = theme.style { fg = 'gray' }
 local darkgray = theme.style { fg = 'darkgray' }
 
@@ -18,6 +20,11 @@ local server_notice_icon = {
   ' ',
 }
 
+local client_notice_icon = {
+  { { '-', { '*', white }, '-' }, blue },
+  ' ',
+}
+
 ---@param msg TircMessage
 local function format_join(msg)
   local realname = msg.params[3]
@@ -79,16 +86,24 @@ local function format_privmsg_message(message)
   return spans
 end
 
-local function message_is_draft(msg)
-  return utils.list_find(msg.tags, function(tag)
-    return tag[1] == 'time'
-  end) == nil
+--- Messages we sent are only echoed back with a timestamp when the server
+--- supports labeled responses, until then they are drafts. So are messages
+--- waiting in the outgoing queue.
+---@param msg TircMessage
+---@param server TircServer
+local function message_is_draft(msg, server)
+  return msg.queued
+    or server.capabilities['labeled-response']
+    and utils.list_find(msg.tags, function(tag)
+      return tag[1] == 'time'
+    end) == nil
 end
 
 ---@param msg TircMessage
 ---@param nickname string
-local function format_privmsg(msg, nickname)
-  local is_draft = message_is_draft(msg)
+---@param server TircServer
+local function format_privmsg(msg, nickname, server)
+  local is_draft = message_is_draft(msg, server)
 
   ---@type string
   local message_str = msg.params[2]
@@ -107,9 +122,18 @@ local function format_privmsg(msg, nickname)
     }
   end
 
+  if msg.highlight then
+    return {
+      is_action and format_privmsg_action_nickname(msg.nick or nickname, red)
+        or format_privmsg_nickname(msg.nick or nickname, red),
+      ' ',
+      { format_privmsg_message(message_str), bold },
+    }
+  end
+
   return {
-    is_action and format_privmsg_action_nickname(msg.nick, white)
-      or format_privmsg_nickname(msg.nick, blue),
+    is_action and format_privmsg_action_nickname(msg.nick or nickname, white)
+      or format_privmsg_nickname(msg.nick or nickname, blue),
     ' ',
     format_privmsg_message(message_str),
   }
@@ -211,6 +235,27 @@ local function format_access_level(level)
   return access_level_styles[level]
 end
 
+---@param buffer TircBuffer
+local function format_buffer(buffer)
+  local style = twhite
+
+  if buffer.highlights > 0 then
+    style = red
+  elseif buffer.unread > 0 then
+    style = yellow
+  end
+
+  local spans = { { buffer.label, style } }
+
+  if buffer.highlights > 0 then
+    spans[#spans + 1] = { string.format('(%d!)', buffer.highlights), red }
+  elseif buffer.unread > 0 then
+    spans[#spans + 1] = { string.format('(%d)', buffer.unread), yellow }
+  end
+
+  return { buffer.current and { spans, bold } or spans, ' ' }
+end
+
 ---@type TircUi
 M.ui = {
   format = {
@@ -242,7 +287,7 @@ M.ui = {
       }
     end,
 
-    message_text = function(msg, nickname)
+    message_text = function(msg, nickname, server)
       local command = msg.command
 
       if command == 'JOIN' then
@@ -250,7 +295,7 @@ M.ui = {
       elseif command == 'PART' then
         return format_part(msg)
       elseif command == 'PRIVMSG' then
-        return format_privmsg(msg, nickname)
+        return format_privmsg(msg, nickname, server)
       elseif command == 'NOTICE' then
         return format_notice(msg)
       elseif command == 'MODE' then
@@ -265,6 +310,15 @@ M.ui = {
         })
       elseif command == 'PING' or command == 'PONG' then
         return nil
+      elseif command == 'TIRC' then
+        return utils.list_concat(client_notice_icon, {
+          {
+            msg.params[1],
+            msg.level == 'error' and red
+              or msg.level == 'warning' and yellow
+              or twhite,
+          },
+        })
       elseif command == 'CAP' then
         return utils.list_concat(server_notice_icon, {
           'Capabilities ' .. table.concat(msg.params, ' '),
@@ -274,6 +328,10 @@ M.ui = {
       return tostring(msg)
     end,
 
+    buffer_bar = function(buffers)
+      return utils.list_map(buffers, format_buffer)
+    end,
+
     user = function(user)
       return {
         utils.list_map(user.access_levels, format_access_level),
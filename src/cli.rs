@@ -0,0 +1,349 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use indoc::indoc;
+
+use crate::config::{FloodConfig, ReconnectConfig, ServerConfig, TircConfig};
+
+pub const USAGE: &str = indoc! {"
+    Usage: tirc [OPTIONS] [URL]
+
+    Connects to the servers configured in init.lua, or only to the server of
+    an irc:// or ircs:// URL, e.g. ircs://irc.libera.chat:6697/#tirc.
+
+    Options:
+      -c, --config <PATH>  Read the config from PATH instead of
+                           $XDG_CONFIG_HOME/tirc/init.lua
+      -s, --server <NAME>  Only connect to the configured server NAME
+      -n, --nick <NICK>    Prefer NICK over the configured nicknames
+          --check-config   Check the config and exit
+      -V, --version        Print the version and exit
+      -h, --help           Print this help and exit
+"};
+
+/// Port of `irc://` URLs without one.
+const IRC_PORT: u16 = 6667;
+
+/// Port of `ircs://` URLs without one.
+const IRCS_PORT: u16 = 6697;
+
+/// Command-line arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub server: Option<String>,
+    pub nickname: Option<String>,
+    pub url: Option<IrcUrl>,
+    pub check_config: bool,
+    pub version: bool,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parses the arguments following the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            // Values may follow as the next argument or after `=`.
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .map(str::to_owned)
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("{} requires a value", flag))
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => parsed.config = Some(value()?.into()),
+                "-s" | "--server" => parsed.server = Some(value()?),
+                "-n" | "--nick" => parsed.nickname = Some(value()?),
+                "--check-config" => parsed.check_config = true,
+                "-V" | "--version" => parsed.version = true,
+                "-h" | "--help" => parsed.help = true,
+                flag if flag.starts_with('-') => bail!("Unknown option {}, see --help", flag),
+                _ if parsed.url.is_some() => bail!("Unexpected argument {}, see --help", arg),
+                _ => parsed.url = Some(IrcUrl::parse(&arg)?),
+            }
+        }
+
+        if parsed.server.is_some() && parsed.url.is_some() {
+            bail!("--server cannot be combined with a URL");
+        }
+
+        Ok(parsed)
+    }
+
+    /// Restricts the servers of `config` to the one selected by `--server`
+    /// or the URL, and prefers the nickname given by `--nick`.
+    pub fn apply(&self, config: &mut TircConfig) -> anyhow::Result<()> {
+        if let Some(url) = &self.url {
+            config.servers = Box::new([url.server_config(&config.servers)]);
+        } else if let Some(name) = &self.server {
+            let server = config
+                .servers
+                .iter()
+                .find(|server| server.name() == name)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = config.servers.iter().map(ServerConfig::name).collect();
+
+                    anyhow!(
+                        "No server named '{}' in init.lua, configured are: {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?;
+
+            config.servers = Box::new([server.clone()]);
+        }
+
+        if let Some(nickname) = &self.nickname {
+            for server in config.servers.iter_mut() {
+                server.nickname.retain(|known| known != nickname);
+                server.nickname.insert(0, nickname.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Server to connect to given as `irc://[nick@]host[:port][/channels]`,
+/// or `ircs://` for TLS, where channels are separated by commas.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrcUrl {
+    pub host: String,
+    pub port: u16,
+    pub use_tls: bool,
+    pub nickname: Option<String>,
+    pub channels: Vec<String>,
+}
+
+impl IrcUrl {
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let (use_tls, rest) = match url.split_once("://") {
+            Some(("irc", rest)) => (false, rest),
+            Some(("ircs", rest)) => (true, rest),
+            _ => bail!("Invalid URL {}, expected irc:// or ircs://", url),
+        };
+        // Options like `?key=` are not supported.
+        let rest = rest.split('?').next().unwrap_or_default();
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (nickname, address) = match authority.rsplit_once('@') {
+            Some((nickname, address)) => (Some(nickname.to_owned()), address),
+            None => (None, authority),
+        };
+        // IPv6 addresses are enclosed in brackets, e.g. `[::1]:6697`.
+        let (host, port) = match address.strip_prefix('[') {
+            Some(address) => match address.split_once(']') {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => bail!("Invalid URL {}, unclosed [", url),
+            },
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+
+        if host.is_empty() {
+            bail!("Invalid URL {}, the host is missing", url);
+        }
+
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow!("Invalid URL {}, bad port {}", url, port))?,
+            None if use_tls => IRCS_PORT,
+            None => IRC_PORT,
+        };
+        let channels = path
+            .replace("%23", "#")
+            .split(',')
+            .filter(|channel| !channel.is_empty())
+            .map(|channel| {
+                if channel.starts_with(['#', '&', '+', '!']) {
+                    channel.to_owned()
+                } else {
+                    format!("#{}", channel)
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            use_tls,
+            nickname: nickname.filter(|nickname| !nickname.is_empty()),
+            channels,
+        })
+    }
+
+    /// The config of the server, based on the configured server with the
+    /// same host or name, if any, so its nicknames and SASL still apply.
+    fn server_config(&self, servers: &[ServerConfig]) -> ServerConfig {
+        let configured = servers.iter().find(|server| {
+            server.host.eq_ignore_ascii_case(&self.host) || server.name() == self.host
+        });
+        let mut server = match configured {
+            Some(server) => server.clone(),
+            None => ServerConfig {
+                name: None,
+                host: self.host.clone(),
+                port: self.port,
+                use_tls: self.use_tls,
+                accept_invalid_cert: false,
+                nickname: Self::default_nicknames(servers),
+                realname: None,
+                autojoin: Vec::new(),
+                reconnect: ReconnectConfig::default(),
+                flood: FloodConfig::default(),
+                ping_interval: None,
+                ping_timeout: None,
+                sasl: None,
+            },
+        };
+
+        server.port = self.port;
+        server.use_tls = self.use_tls;
+
+        for channel in &self.channels {
+            if !server.autojoin.contains(channel) {
+                server.autojoin.push(channel.clone());
+            }
+        }
+
+        if let Some(nickname) = &self.nickname {
+            server.nickname.retain(|known| known != nickname);
+            server.nickname.insert(0, nickname.clone());
+        }
+
+        server
+    }
+
+    /// Nicknames of a server missing from the config: those of the first
+    /// configured server, or the login name.
+    fn default_nicknames(servers: &[ServerConfig]) -> Vec<String> {
+        match servers.first() {
+            Some(server) if !server.nickname.is_empty() => server.nickname.clone(),
+            _ => vec![std::env::var("USER").unwrap_or_else(|_| "tirc".to_owned())],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::LuaSerdeExt;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn config(lua_config: &str) -> TircConfig {
+        let lua = mlua::Lua::new();
+
+        lua.from_value(lua.load(lua_config).eval().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(
+            parse(&["--config=/tmp/init.lua", "-n", "me", "--check-config"]).unwrap(),
+            Args {
+                config: Some("/tmp/init.lua".into()),
+                nickname: Some("me".to_owned()),
+                check_config: true,
+                ..Default::default()
+            }
+        );
+        assert!(parse(&["--version"]).unwrap().version);
+        assert!(parse(&["--nick"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert!(parse(&["-s", "libera", "ircs://irc.libera.chat"]).is_err());
+    }
+
+    #[test]
+    fn urls_are_parsed() {
+        assert_eq!(
+            IrcUrl::parse("ircs://irc.libera.chat/#tirc,rust").unwrap(),
+            IrcUrl {
+                host: "irc.libera.chat".to_owned(),
+                port: 6697,
+                use_tls: true,
+                nickname: None,
+                channels: vec!["#tirc".to_owned(), "#rust".to_owned()],
+            }
+        );
+        assert_eq!(
+            IrcUrl::parse("irc://me@[::1]:7000/%23tirc").unwrap(),
+            IrcUrl {
+                host: "::1".to_owned(),
+                port: 7000,
+                use_tls: false,
+                nickname: Some("me".to_owned()),
+                channels: vec!["#tirc".to_owned()],
+            }
+        );
+        assert!(IrcUrl::parse("https://example.com").is_err());
+        assert!(IrcUrl::parse("irc://example.com:port").is_err());
+        assert!(IrcUrl::parse("irc:///#tirc").is_err());
+    }
+
+    #[test]
+    fn server_option_selects_a_configured_server() {
+        let mut tirc_config = config(
+            "{ servers = { { name = 'libera', host = 'irc.libera.chat', nickname = { 'a' } },
+                           { host = 'irc.oftc.net', nickname = { 'b' } } } }",
+        );
+
+        parse(&["--server", "irc.oftc.net", "--nick", "c"])
+            .unwrap()
+            .apply(&mut tirc_config)
+            .unwrap();
+        assert_eq!(tirc_config.servers.len(), 1);
+        assert_eq!(tirc_config.servers[0].host, "irc.oftc.net");
+        assert_eq!(tirc_config.servers[0].nickname, ["c", "b"]);
+
+        let error = parse(&["-s", "efnet"])
+            .unwrap()
+            .apply(&mut tirc_config)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No server named 'efnet' in init.lua, configured are: irc.oftc.net"
+        );
+    }
+
+    #[test]
+    fn urls_connect_to_configured_or_ad_hoc_servers() {
+        let lua_config = "{ servers = { { name = 'libera', host = 'irc.libera.chat',
+                                          nickname = { 'a' }, autojoin = { '#tirc' } } } }";
+
+        let mut tirc_config = config(lua_config);
+        parse(&["irc://irc.libera.chat:6667/rust"])
+            .unwrap()
+            .apply(&mut tirc_config)
+            .unwrap();
+        let server = &tirc_config.servers[0];
+        assert_eq!(server.name(), "libera");
+        assert_eq!((server.port, server.use_tls), (6667, false));
+        assert_eq!(server.autojoin, ["#tirc", "#rust"]);
+
+        let mut tirc_config = config(lua_config);
+        parse(&["ircs://irc.oftc.net/#tirc"])
+            .unwrap()
+            .apply(&mut tirc_config)
+            .unwrap();
+        let server = &tirc_config.servers[0];
+        assert_eq!(tirc_config.servers.len(), 1);
+        assert_eq!(server.name(), "irc.oftc.net");
+        assert_eq!(server.nickname, ["a"]);
+        assert_eq!(server.autojoin, ["#tirc"]);
+    }
+}
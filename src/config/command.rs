@@ -0,0 +1,172 @@
+use anyhow::anyhow;
+use mlua::{Lua, Table, Value};
+
+/// A command registered via `tirc.command(name, run, opts)`, run as `:name`
+/// or one of its aliases.
+#[derive(Debug)]
+pub struct LuaCommand {
+    /// Name the command was registered with, also for its aliases.
+    pub name: String,
+    pub help: Option<String>,
+    run: mlua::Function,
+    complete: Option<mlua::Function>,
+}
+
+impl LuaCommand {
+    /// The command registered as `name` or with `name` as an alias.
+    pub fn get(lua: &Lua, name: &str) -> mlua::Result<Option<Self>> {
+        let Value::Table(commands) = lua.named_registry_value("tirc-commands")? else {
+            return Ok(None);
+        };
+        let Some(command) = commands.get::<Option<Table>>(name)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            name: command.get("name")?,
+            help: command.get("help")?,
+            run: command.get("run")?,
+            complete: command.get("complete")?,
+        }))
+    }
+
+    /// Runs the command with the rest of the command line. Returns the
+    /// command line it asks to run as a built-in command instead, which lets
+    /// commands wrap the built-in ones.
+    pub fn run(&self, args: &str, context: Table) -> mlua::Result<Option<String>> {
+        self.run.call((args, context))
+    }
+
+    /// Candidates for completing `word` in the arguments of the command.
+    pub fn complete(&self, word: &str, context: Table) -> mlua::Result<Vec<String>> {
+        match &self.complete {
+            Some(complete) => Ok(complete
+                .call::<Option<Vec<String>>>((word, context))?
+                .unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Names and aliases of the commands registered via `tirc.command`, sorted.
+pub fn command_names(lua: &Lua) -> mlua::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    if let Value::Table(commands) = lua.named_registry_value("tirc-commands")? {
+        for pair in commands.pairs::<String, Value>() {
+            names.push(pair?.0);
+        }
+    }
+
+    names.sort();
+
+    Ok(names)
+}
+
+/// Backs `tirc.command(name, run, opts)`, where `opts` may list `aliases`,
+/// a `complete` function for the arguments and a `help` text. Registering a
+/// name again replaces its command.
+pub(super) fn register_command(
+    lua: &Lua,
+    (name, run, opts): (String, mlua::Function, Option<Table>),
+) -> mlua::Result<()> {
+    let commands = match lua.named_registry_value::<Value>("tirc-commands")? {
+        Value::Table(commands) => commands,
+        _ => {
+            let commands = lua.create_table()?;
+            lua.set_named_registry_value("tirc-commands", &commands)?;
+            commands
+        }
+    };
+    let command = lua.create_table()?;
+    let mut names = vec![name.clone()];
+
+    command.set("name", name)?;
+    command.set("run", run)?;
+
+    if let Some(opts) = opts {
+        command.set("help", opts.get::<Option<String>>("help")?)?;
+        command.set("complete", opts.get::<Option<mlua::Function>>("complete")?)?;
+        names.extend(
+            opts.get::<Option<Vec<String>>>("aliases")?
+                .unwrap_or_default(),
+        );
+    }
+
+    for name in names {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(mlua::Error::external(anyhow!(
+                "invalid command name '{}'",
+                name
+            )));
+        }
+
+        commands.set(name, &command)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::config::register_builtin_modules;
+
+    fn context(lua: &Lua) -> Table {
+        let context = lua.create_table().unwrap();
+        context.set("buffer", "#tirc").unwrap();
+        context
+    }
+
+    #[test]
+    fn commands_are_registered_with_aliases() {
+        let lua = Lua::new();
+        register_builtin_modules(&lua).unwrap();
+
+        lua.load(indoc! {"
+            local tirc = require('tirc')
+
+            tirc.command('shrug', function(args, ctx)
+              return 'me ' .. args .. ' in ' .. ctx.buffer .. ' ¯\\\\_(ツ)_/¯'
+            end, {
+              aliases = { 'sh' },
+              help = 'Shrugs',
+              complete = function(word) return { word .. 'ly' } end,
+            })
+            tirc.command('nothing', function() end)
+        "})
+            .exec()
+            .unwrap();
+
+        assert_eq!(command_names(&lua).unwrap(), ["nothing", "sh", "shrug"]);
+
+        let command = LuaCommand::get(&lua, "sh").unwrap().unwrap();
+        assert_eq!(command.name, "shrug");
+        assert_eq!(command.help.as_deref(), Some("Shrugs"));
+        assert_eq!(
+            command.run("sadly", context(&lua)).unwrap().as_deref(),
+            Some("me sadly in #tirc ¯\\_(ツ)_/¯")
+        );
+        assert_eq!(command.complete("sad", context(&lua)).unwrap(), ["sadly"]);
+
+        let command = LuaCommand::get(&lua, "nothing").unwrap().unwrap();
+        assert_eq!(command.run("", context(&lua)).unwrap(), None);
+        assert!(command.complete("", context(&lua)).unwrap().is_empty());
+
+        assert!(LuaCommand::get(&lua, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn command_names_must_be_single_words() {
+        let lua = Lua::new();
+        register_builtin_modules(&lua).unwrap();
+
+        let result = lua
+            .load("require('tirc').command('two words', function() end)")
+            .exec();
+
+        assert!(result.is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::anyhow;
 use indoc::indoc;
@@ -6,10 +9,22 @@ use mlua::{IntoLuaMulti, Lua, LuaSerdeExt, Table, Value};
 use serde::Deserialize;
 
 use crate::{
-    lua::{date_time::create_date_time_module, get_or_create_module, set_loaded_modules},
+    lua::{
+        date_time::create_date_time_module, get_loaded_modules, get_or_create_module,
+        set_loaded_modules,
+    },
     tui::lua::create_tirc_theme_lua_module,
 };
 
+pub use self::{
+    command::{command_names, LuaCommand},
+    watch::ConfigWatcher,
+};
+
+mod command;
+mod validate;
+mod watch;
+
 #[inline]
 fn bool_true() -> bool {
     true
@@ -20,8 +35,124 @@ fn default_port() -> u16 {
     6697
 }
 
-#[derive(Deserialize, Debug)]
+#[inline]
+fn default_reconnect_delay() -> u64 {
+    2
+}
+
+#[inline]
+fn default_reconnect_max_delay() -> u64 {
+    300
+}
+
+/// Controls how a server is reconnected to after the connection dropped.
+/// Delays are in seconds and double after every failed attempt, up to
+/// `max_delay`.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReconnectConfig {
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+
+    #[serde(default = "default_reconnect_delay")]
+    pub delay: u64,
+
+    #[serde(default = "default_reconnect_max_delay")]
+    pub max_delay: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: default_reconnect_delay(),
+            max_delay: default_reconnect_max_delay(),
+        }
+    }
+}
+
+#[inline]
+fn default_flood_burst() -> u32 {
+    5
+}
+
+#[inline]
+fn default_flood_interval() -> u64 {
+    2000
+}
+
+/// Limits how fast messages are sent to a server, which disconnects clients
+/// flooding it. Up to `burst` messages are sent at once, then one every
+/// `interval` milliseconds.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FloodConfig {
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+
+    #[serde(default = "default_flood_burst")]
+    pub burst: u32,
+
+    #[serde(default = "default_flood_interval")]
+    pub interval: u64,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            burst: default_flood_burst(),
+            interval: default_flood_interval(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SaslMechanism {
+    #[default]
+    Plain,
+    External,
+}
+
+impl SaslMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+        }
+    }
+}
+
+/// SASL authentication performed during capability negotiation, before
+/// registration completes.
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SaslConfig {
+    #[serde(default)]
+    pub mechanism: SaslMechanism,
+
+    /// Account to authenticate as with `PLAIN`, defaults to the first
+    /// nickname.
+    pub account: Option<String>,
+
+    /// Password for `PLAIN`.
+    pub password: Option<String>,
+
+    /// Client certificate (PKCS #12) presented during the TLS handshake for
+    /// `EXTERNAL`.
+    pub cert_path: Option<String>,
+
+    pub cert_password: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
+    /// Name identifying the server in buffers and the buffer bar, defaults to
+    /// `host`.
+    pub name: Option<String>,
+
     pub host: String,
 
     #[serde(default = "default_port")]
@@ -38,11 +169,278 @@ pub struct ServerConfig {
 
     #[serde(default)]
     pub autojoin: Vec<String>,
+
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+
+    #[serde(default)]
+    pub flood: FloodConfig,
+
+    /// Seconds of inactivity before the server is pinged.
+    pub ping_interval: Option<u32>,
+
+    /// Seconds to wait for a reply to a ping before considering the
+    /// connection dead.
+    pub ping_timeout: Option<u32>,
+
+    pub sasl: Option<SaslConfig>,
+}
+
+impl ServerConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.host)
+    }
+}
+
+#[inline]
+fn default_restore() -> usize {
+    100
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines, e.g. `[2024-05-01 12:34:56] <alice> hello`.
+    #[default]
+    Text,
+    /// The received IRC lines, tagged with their receive time, which can be
+    /// parsed back into messages.
+    Raw,
+}
+
+/// Controls which buffers are logged to disk and how.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Whether buffers are logged unless overridden in `buffers`.
+    #[serde(default)]
+    pub enabled: bool,
+
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// Directory the logs are written to, defaults to `logs` in the tirc
+    /// XDG data directory.
+    pub directory: Option<String>,
+
+    /// Per buffer overrides of `enabled`, keyed by `server/buffer` or just
+    /// the buffer name to match it on every server.
+    #[serde(default)]
+    pub buffers: HashMap<String, bool>,
+
+    /// Number of messages restored from `raw` logs into a buffer when it is
+    /// opened, `0` disables restoring.
+    #[serde(default = "default_restore")]
+    pub restore: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: LogFormat::default(),
+            directory: None,
+            buffers: HashMap::new(),
+            restore: default_restore(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Whether any buffer is logged.
+    pub fn is_used(&self) -> bool {
+        self.enabled || self.buffers.values().any(|enabled| *enabled)
+    }
+
+    pub fn is_enabled(&self, server: &str, buffer: &str) -> bool {
+        self.buffers
+            .get(&format!("{}/{}", server, buffer))
+            .or_else(|| self.buffers.get(buffer))
+            .copied()
+            .unwrap_or(self.enabled)
+    }
+}
+
+/// Controls which messages from others are highlighted as mentioning the
+/// user.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HighlightConfig {
+    /// Whether mentions of our current nickname are highlights.
+    #[serde(default = "bool_true")]
+    pub nickname: bool,
+
+    /// Words highlighted wherever they appear, ignoring case.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+
+    /// Regular expressions highlighting the messages they match.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+
+    /// Nicknames whose messages are never highlights, e.g. bots.
+    #[serde(default)]
+    pub exclude_nicks: Vec<String>,
+
+    /// Regular expressions of messages that are never highlights.
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+
+    /// Per buffer overrides, keyed by `server/buffer` or just the buffer name
+    /// to match it on every server.
+    #[serde(default)]
+    pub buffers: HashMap<String, HighlightBufferConfig>,
+}
+
+impl Default for HighlightConfig {
+    fn default() -> Self {
+        Self {
+            nickname: true,
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+            exclude_nicks: Vec::new(),
+            exclude_patterns: Vec::new(),
+            buffers: HashMap::new(),
+        }
+    }
+}
+
+/// Highlight rules of a single buffer, on top of the global ones.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HighlightBufferConfig {
+    /// Whether messages in the buffer can be highlights at all.
+    #[serde(default = "bool_true")]
+    pub enabled: bool,
+
+    /// Overrides whether mentions of our nickname are highlights.
+    pub nickname: Option<bool>,
+
+    /// Words highlighted in addition to the global `keywords`.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+
+    /// Patterns highlighted in addition to the global `patterns`.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+#[inline]
+fn default_rate_limit() -> u64 {
+    10
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DesktopNotification {
+    /// `OSC 9`, understood by e.g. iTerm2, kitty, WezTerm and foot.
+    Osc9,
+    /// `OSC 777`, understood by e.g. urxvt and VTE based terminals.
+    Osc777,
+}
+
+/// Controls how the user is notified of highlights and new private
+/// conversations.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Whether highlights are notified.
+    #[serde(default = "bool_true")]
+    pub highlights: bool,
+
+    /// Whether the first message of a new private conversation is notified.
+    #[serde(default = "bool_true")]
+    pub queries: bool,
+
+    /// Whether the terminal bell is rung.
+    #[serde(default = "bool_true")]
+    pub bell: bool,
+
+    /// Escape sequence sent to the terminal to show a desktop notification.
+    pub desktop: Option<DesktopNotification>,
+
+    /// Command run for every notification, with its title and text appended
+    /// as arguments, e.g. `{ 'notify-send', '--app-name=tirc' }`.
+    #[serde(default)]
+    pub command: Vec<String>,
+
+    /// Minimum number of seconds between two notifications about the same
+    /// buffer.
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit: u64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            highlights: true,
+            queries: true,
+            bell: true,
+            desktop: None,
+            command: Vec::new(),
+            rate_limit: default_rate_limit(),
+        }
+    }
+}
+
+#[inline]
+fn default_paste_delay() -> u64 {
+    500
+}
+
+/// Controls how text of several lines pasted into the input is sent.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PasteConfig {
+    /// Milliseconds between the messages of the lines, when the server does
+    /// not support sending them as one message with `draft/multiline`. Paced
+    /// on top of the flood control of the server.
+    #[serde(default = "default_paste_delay")]
+    pub delay: u64,
+}
+
+impl Default for PasteConfig {
+    fn default() -> Self {
+        Self {
+            delay: default_paste_delay(),
+        }
+    }
+}
+
+/// Controls reloading the config while running, which `:reload` does on
+/// demand.
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Whether the config is reloaded when a Lua file in the config
+    /// directory changes.
+    #[serde(default)]
+    pub watch: bool,
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct TircConfig {
     pub servers: Box<[ServerConfig]>,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    #[serde(default)]
+    pub highlight: HighlightConfig,
+
+    #[serde(default)]
+    pub notification: NotificationConfig,
+
+    #[serde(default)]
+    pub paste: PasteConfig,
+
+    #[serde(default)]
+    pub reload: ReloadConfig,
+
+    /// Settings that are valid but likely a mistake or insecure.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
 }
 
 fn get_default_config() -> &'static str {
@@ -69,14 +467,42 @@ fn get_default_config() -> &'static str {
 }
 
 fn register_event(lua: &Lua, (name, func): (String, mlua::Function)) -> mlua::Result<()> {
-    let decorated_name = format!("tirc-event-{}", name);
-    let tbl: mlua::Value = lua.named_registry_value(&decorated_name)?;
+    // Named registry values cannot be listed, so the events with handlers are
+    // remembered to clear them when reloading.
+    let names = match lua.named_registry_value::<Value>("tirc-event-names")? {
+        Value::Table(names) => names,
+        _ => {
+            let names = lua.create_table()?;
+            lua.set_named_registry_value("tirc-event-names", &names)?;
+            names
+        }
+    };
+
+    names.set(name.as_str(), true)?;
+
+    append_registry_function(lua, &format!("tirc-event-{}", name), func)
+}
+
+/// Backs `tirc.add_completion_source(source)`, where `source` is called with
+/// the word to complete and returns candidates for it.
+fn register_completion_source(lua: &Lua, func: mlua::Function) -> mlua::Result<()> {
+    append_registry_function(lua, "tirc-completion-sources", func)
+}
+
+/// Appends `func` to the list of functions stored under `decorated_name` in
+/// the registry.
+fn append_registry_function(
+    lua: &Lua,
+    decorated_name: &str,
+    func: mlua::Function,
+) -> mlua::Result<()> {
+    let tbl: mlua::Value = lua.named_registry_value(decorated_name)?;
 
     match tbl {
         mlua::Value::Nil => {
             let tbl = lua.create_table()?;
             tbl.set(1, func)?;
-            lua.set_named_registry_value(&decorated_name, tbl)?;
+            lua.set_named_registry_value(decorated_name, tbl)?;
             Ok(())
         }
         mlua::Value::Table(tbl) => {
@@ -97,16 +523,37 @@ pub fn emit_event<Args>(lua: &Lua, name: &str, args: Args) -> mlua::Result<()>
 where
     Args: IntoLuaMulti + Clone,
 {
+    for func in event_handlers(lua, name)? {
+        func.call::<()>(args.clone())?;
+    }
+
+    Ok(())
+}
+
+/// The handlers registered via `tirc.on(name, ...)`, in registration order,
+/// for events whose handlers return a value.
+pub fn event_handlers(lua: &Lua, name: &str) -> mlua::Result<Vec<mlua::Function>> {
     let decorated_name = format!("tirc-event-{}", name);
-    let tbl: mlua::Value = lua.named_registry_value(&decorated_name)?;
 
-    if let mlua::Value::Table(tbl) = tbl {
+    match lua.named_registry_value(&decorated_name)? {
+        mlua::Value::Table(tbl) => tbl.sequence_values::<mlua::Function>().collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Collects the candidates every completion source registered via
+/// `tirc.add_completion_source` returns for `word`, in registration order.
+pub fn complete(lua: &Lua, word: &str, context: Table) -> mlua::Result<Vec<String>> {
+    let mut candidates = Vec::new();
+
+    if let Value::Table(tbl) = lua.named_registry_value("tirc-completion-sources")? {
         for func in tbl.sequence_values::<mlua::Function>() {
-            func?.call::<()>(args.clone())?;
+            let source_candidates: Option<Vec<String>> = func?.call((word, context.clone()))?;
+            candidates.extend(source_candidates.unwrap_or_default());
         }
     }
 
-    Ok(())
+    Ok(candidates)
 }
 
 /// Returns the `tirc-ui` registry table, creating it on first access so reads
@@ -166,11 +613,7 @@ fn set_ui(lua: &Lua, value: Table) -> mlua::Result<()> {
 /// Returns `None` when no formatter is registered for `name`, otherwise the
 /// formatter's `mlua::Result` (an `Err` if the Lua callback raised). The caller
 /// is responsible for rendering errors.
-pub fn call_formatter<Args>(
-    lua: &Lua,
-    name: &str,
-    args: Args,
-) -> Option<mlua::Result<mlua::Value>>
+pub fn call_formatter<Args>(lua: &Lua, name: &str, args: Args) -> Option<mlua::Result<mlua::Value>>
 where
     Args: IntoLuaMulti,
 {
@@ -236,6 +679,11 @@ pub fn register_builtin_modules(lua: &Lua) -> anyhow::Result<()> {
 
     tirc_mod.set("version", get_version_lua_value(lua))?;
     tirc_mod.set("on", lua.create_function(register_event)?)?;
+    tirc_mod.set(
+        "add_completion_source",
+        lua.create_function(register_completion_source)?,
+    )?;
+    tirc_mod.set("command", lua.create_function(command::register_command)?)?;
     tirc_mod.set("__get_ui", lua.create_function(get_ui)?)?;
     tirc_mod.set("__set_ui", lua.create_function(set_ui)?)?;
 
@@ -273,20 +721,36 @@ pub fn register_builtin_modules(lua: &Lua) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn load_config(lua: &Lua) -> Result<TircConfig, anyhow::Error> {
-    let config_filename =
-        xdg::BaseDirectories::with_prefix("tirc").place_config_file("init.lua")?;
+/// The config file at `path`, or `$XDG_CONFIG_HOME/tirc/init.lua`.
+pub fn config_filename(path: Option<&Path>) -> Result<PathBuf, anyhow::Error> {
+    Ok(match path {
+        // Relative to the working directory rather than an empty parent.
+        Some(path) => std::path::absolute(path)?,
+        None => xdg::BaseDirectories::with_prefix("tirc").place_config_file("init.lua")?,
+    })
+}
+
+/// Loads the config from `path`, or from `$XDG_CONFIG_HOME/tirc/init.lua`,
+/// which is created with a default config if missing.
+pub fn load_config(lua: &Lua, path: Option<&Path>) -> Result<TircConfig, anyhow::Error> {
+    let config_filename = config_filename(path)?;
     let config_dirname = config_filename
         .parent()
         .expect("Unable to create config directory");
 
-    if !config_filename.exists() {
+    if path.is_none() && !config_filename.exists() {
         std::fs::create_dir_all(config_dirname)?;
 
         std::fs::write(&config_filename, get_default_config())?;
     }
 
-    let config_lua_code = std::fs::read_to_string(&config_filename)?;
+    let config_lua_code = std::fs::read_to_string(&config_filename).map_err(|err| {
+        anyhow!(
+            "Unable to read config {}: {}",
+            config_filename.display(),
+            err
+        )
+    })?;
 
     let config = {
         let globals = lua.globals();
@@ -299,8 +763,14 @@ pub fn load_config(lua: &Lua) -> Result<TircConfig, anyhow::Error> {
         let mut path_array: Vec<String> = package_path.split(';').map(|s| s.to_owned()).collect();
 
         fn prefix_path(array: &mut Vec<String>, path: &Path) {
-            array.insert(0, format!("{}/?.lua", path.display()));
-            array.insert(1, format!("{}/?/init.lua", path.display()));
+            let templates = [
+                format!("{}/?.lua", path.display()),
+                format!("{}/?/init.lua", path.display()),
+            ];
+
+            // Reloading the config must not prefix the path again.
+            array.retain(|template| !templates.contains(template));
+            array.splice(0..0, templates);
         }
 
         prefix_path(&mut path_array, config_dirname);
@@ -314,28 +784,183 @@ pub fn load_config(lua: &Lua) -> Result<TircConfig, anyhow::Error> {
             .set_name(config_filename.display().to_string())
             .call(())?;
 
+        let errors = validate::validate(&value);
+
+        if !errors.is_empty() {
+            anyhow::bail!(
+                "Invalid config {}:\n  {}",
+                config_filename.display(),
+                errors.join("\n  ")
+            );
+        }
+
         globals.set("config", &value)?;
 
-        lua.from_value(value)?
+        let mut config: TircConfig = lua.from_value(value)?;
+        config.warnings = validate::warnings(&config);
+        config
     };
 
     Ok(config)
 }
 
+/// What evaluating a config registered in the Lua state: event handlers,
+/// completion sources, commands, the `tirc.ui` table and the modules loaded
+/// from the config directory.
+struct Registrations {
+    registry: Vec<(String, Value)>,
+    modules: Vec<(String, Value)>,
+}
+
+impl Registrations {
+    /// Removes the registrations from the Lua state, returning them.
+    fn take(lua: &Lua) -> anyhow::Result<Self> {
+        let mut keys = vec![
+            "tirc-event-names".to_owned(),
+            "tirc-completion-sources".to_owned(),
+            "tirc-commands".to_owned(),
+            "tirc-ui".to_owned(),
+        ];
+
+        if let Value::Table(names) = lua.named_registry_value("tirc-event-names")? {
+            for name in names.pairs::<String, Value>() {
+                keys.push(format!("tirc-event-{}", name?.0));
+            }
+        }
+
+        let mut registry = Vec::new();
+
+        for key in keys {
+            let value: Value = lua.named_registry_value(&key)?;
+            lua.unset_named_registry_value(&key)?;
+            registry.push((key, value));
+        }
+
+        let loaded = get_loaded_modules(lua)?;
+        let mut modules = Vec::new();
+
+        for name in Self::config_dir_modules(lua)? {
+            modules.push((name.clone(), loaded.get(name.as_str())?));
+            loaded.set(name, Value::Nil)?;
+        }
+
+        Ok(Self { registry, modules })
+    }
+
+    /// Names of the loaded modules found in the config directory, which
+    /// `require` has to load again to pick up changes.
+    fn config_dir_modules(lua: &Lua) -> anyhow::Result<Vec<String>> {
+        let tirc_mod = get_or_create_module(lua, "_tirc")?;
+        let Some(config_dir) = tirc_mod.get::<Option<String>>("config_dir")? else {
+            return Ok(Vec::new());
+        };
+        let package: Table = lua.globals().get("package")?;
+        let path: String = package.get("path")?;
+        let searchpath: mlua::Function = package.get("searchpath")?;
+        let mut names = Vec::new();
+
+        for pair in get_loaded_modules(lua)?.pairs::<Value, Value>() {
+            let Value::String(name) = pair?.0 else {
+                continue;
+            };
+            let name = name.to_str()?.to_owned();
+            let file: Option<String> = searchpath.call((name.as_str(), path.as_str()))?;
+
+            if file.is_some_and(|file| Path::new(&file).starts_with(&config_dir)) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn restore(self, lua: &Lua) -> anyhow::Result<()> {
+        // Drop whatever the failed evaluation registered.
+        Self::take(lua)?;
+
+        for (key, value) in self.registry {
+            lua.set_named_registry_value(&key, value)?;
+        }
+
+        let loaded = get_loaded_modules(lua)?;
+
+        for (name, module) in self.modules {
+            loaded.set(name, module)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Evaluates the config again, replacing the event handlers, completion
+/// sources and formatters it registered, and passes it to `apply`. Modules in
+/// the config directory, like themes, are required anew.
+///
+/// If the config fails to load or `apply` fails, the previous registrations
+/// are restored, so `apply` should fail before changing anything.
+pub fn reload_config<T>(
+    lua: &Lua,
+    path: Option<&Path>,
+    apply: impl FnOnce(TircConfig) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let registrations = Registrations::take(lua)?;
+    let result = load_config(lua, path).and_then(apply);
+
+    if result.is_err() {
+        registrations.restore(lua)?;
+    }
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tui::lua::to_lua_message;
+    use crate::{
+        tui::lua::{to_lua_client_notice, to_lua_message, to_lua_server},
+        ui::ServerState,
+    };
 
-    /// Loads the builtin modules and activates the default theme, returning a
-    /// closure that renders a raw IRC line through the `message_text` formatter.
-    fn render_message_text(lua: &Lua, raw: &str) -> mlua::Value {
+    fn server_table(lua: &Lua, capabilities: &[&str]) -> mlua::Table {
+        let server = ServerState {
+            host: "irc.example.com".to_string(),
+            nickname: "me".to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+
+        to_lua_server(lua, "net", &server).expect("server table")
+    }
+
+    /// Renders a raw IRC line through the `message_text` formatter on a
+    /// server with the given capabilities enabled.
+    fn render_message_text_with(lua: &Lua, raw: &str, capabilities: &[&str]) -> mlua::Value {
         let message: irc::proto::Message = raw.parse().expect("valid irc message");
         let table = to_lua_message(lua, &message).expect("message table");
 
-        call_formatter(lua, "message_text", (table, "me".to_string()))
-            .expect("message_text formatter registered")
-            .expect("message_text formatter callback")
+        call_formatter(
+            lua,
+            "message_text",
+            (table, "me".to_string(), server_table(lua, capabilities)),
+        )
+        .expect("message_text formatter registered")
+        .expect("message_text formatter callback")
+    }
+
+    fn render_message_text(lua: &Lua, raw: &str) -> mlua::Value {
+        render_message_text_with(lua, raw, &[])
+    }
+
+    /// Concatenates the text content of a span tree, ignoring styles.
+    fn spans_text(value: &mlua::Value) -> String {
+        match value {
+            mlua::Value::String(text) => text.to_string_lossy(),
+            mlua::Value::Table(table) => table
+                .sequence_values::<mlua::Value>()
+                .map(|value| spans_text(&value.expect("span")))
+                .collect(),
+            _ => String::new(),
+        }
     }
 
     fn setup_theme() -> Lua {
@@ -349,6 +974,24 @@ mod tests {
         lua
     }
 
+    /// Finds the foreground color of the `{ content, style }` span holding
+    /// `content`.
+    fn span_fg(value: &mlua::Value, content: &str) -> Option<String> {
+        let mlua::Value::Table(table) = value else {
+            return None;
+        };
+
+        if let (Ok(text), Ok(style)) = (table.get::<String>(1), table.get::<mlua::Table>(2)) {
+            if text == content {
+                return style.get("fg").ok();
+            }
+        }
+
+        table
+            .sequence_values::<mlua::Value>()
+            .find_map(|value| span_fg(&value.ok()?, content))
+    }
+
     #[test]
     fn theme_renders_common_messages_without_error() {
         let lua = setup_theme();
@@ -372,6 +1015,105 @@ mod tests {
         }
     }
 
+    #[test]
+    fn theme_renders_client_notices() {
+        let lua = setup_theme();
+        let table = to_lua_client_notice(&lua, "error", "Disconnected").expect("notice table");
+
+        let value = call_formatter(
+            &lua,
+            "message_text",
+            (table, "me".to_string(), server_table(&lua, &[])),
+        )
+        .expect("message_text formatter registered")
+        .expect("message_text formatter callback");
+        assert!(matches!(value, mlua::Value::Table(_)));
+    }
+
+    #[test]
+    fn theme_marks_drafts_only_with_labeled_response() {
+        let lua = setup_theme();
+        let line = "PRIVMSG #tirc :hello\r\n";
+
+        let value = render_message_text_with(&lua, line, &["labeled-response"]);
+        assert_eq!(spans_text(&value), "<me> hello");
+        assert_eq!(span_fg(&value, "me").as_deref(), Some("DarkGray"));
+
+        let value = render_message_text_with(&lua, line, &[]);
+        assert_eq!(spans_text(&value), "<me> hello");
+        assert_eq!(span_fg(&value, "me").as_deref(), Some("Blue"));
+    }
+
+    #[test]
+    fn theme_marks_queued_messages_as_drafts() {
+        let lua = setup_theme();
+        let message: irc::proto::Message = "@label=1 PRIVMSG #tirc :hello\r\n"
+            .parse()
+            .expect("valid irc message");
+        let table = to_lua_message(&lua, &message).expect("message table");
+        table.set("queued", true).expect("queued flag");
+
+        let value = call_formatter(
+            &lua,
+            "message_text",
+            (table, "me".to_string(), server_table(&lua, &[])),
+        )
+        .expect("message_text formatter registered")
+        .expect("message_text formatter callback");
+        assert_eq!(spans_text(&value), "<me> hello");
+        assert_eq!(span_fg(&value, "me").as_deref(), Some("DarkGray"));
+    }
+
+    #[test]
+    fn theme_marks_highlighted_messages() {
+        let lua = setup_theme();
+        let message: irc::proto::Message = ":alice!u@h PRIVMSG #tirc :me: hi\r\n"
+            .parse()
+            .expect("valid irc message");
+        let table = to_lua_message(&lua, &message).expect("message table");
+        table.set("highlight", true).expect("highlight flag");
+
+        let value = call_formatter(
+            &lua,
+            "message_text",
+            (table, "me".to_string(), server_table(&lua, &[])),
+        )
+        .expect("message_text formatter registered")
+        .expect("message_text formatter callback");
+        assert_eq!(spans_text(&value), "<alice> me: hi");
+        assert_eq!(span_fg(&value, "alice").as_deref(), Some("Red"));
+    }
+
+    #[test]
+    fn completion_sources_are_called_in_order() {
+        let lua = Lua::new();
+        register_builtin_modules(&lua).expect("builtin modules");
+
+        lua.load(indoc! {"
+            local tirc = require('tirc')
+
+            tirc.add_completion_source(function(word, ctx)
+              return { word .. 'ello', ctx.buffer }
+            end)
+            tirc.add_completion_source(function()
+              return nil
+            end)
+            tirc.add_completion_source(function(word)
+              return { word .. 'i' }
+            end)
+        "})
+            .exec()
+            .expect("completion sources");
+
+        let context = lua.create_table().expect("context");
+        context.set("buffer", "#tirc").expect("buffer");
+
+        assert_eq!(
+            complete(&lua, "h", context).expect("candidates"),
+            ["hello", "#tirc", "hi"]
+        );
+    }
+
     #[test]
     fn theme_suppresses_names_replies() {
         let lua = setup_theme();
@@ -379,4 +1121,70 @@ mod tests {
         let value = render_message_text(&lua, ":irc.example.com 366 me #tirc :End of /NAMES\r\n");
         assert!(matches!(value, mlua::Value::Nil));
     }
+
+    #[test]
+    fn reload_replaces_registrations_unless_the_config_fails() {
+        let directory = std::env::temp_dir().join(format!("tirc-reload-{}", std::process::id()));
+        let init = directory.join("init.lua");
+        let write_theme = |greeting: &str| {
+            let theme = format!(
+                "return {{ setup = function()
+                   require('tirc').ui = {{ format = {{ greeting = function() return '{}' end }} }}
+                 end }}",
+                greeting
+            );
+
+            std::fs::write(directory.join("theme.lua"), theme).expect("theme");
+        };
+        let greeting = |lua: &Lua| -> String {
+            let value = call_formatter(lua, "greeting", ()).expect("formatter");
+            lua.from_value(value.expect("greeting")).expect("string")
+        };
+        let handlers = |lua: &Lua| -> usize {
+            match lua
+                .named_registry_value("tirc-event-message")
+                .expect("handlers")
+            {
+                Value::Table(handlers) => handlers.raw_len(),
+                _ => 0,
+            }
+        };
+
+        std::fs::create_dir_all(&directory).expect("config directory");
+        std::fs::write(
+            &init,
+            "local tirc = require('tirc')
+             tirc.use(require('theme'))
+             tirc.on('message', function() end)
+             return { servers = {} }",
+        )
+        .expect("init.lua");
+        write_theme("hello");
+
+        let lua = Lua::new();
+        load_config(&lua, Some(&init)).expect("config");
+        assert_eq!(greeting(&lua), "hello");
+
+        write_theme("hi");
+        reload_config(&lua, Some(&init), Ok).expect("reloaded config");
+        assert_eq!(greeting(&lua), "hi");
+        assert_eq!(handlers(&lua), 1);
+
+        // Applying the reloaded config fails.
+        write_theme("ho");
+        let result = reload_config(&lua, Some(&init), |_| -> anyhow::Result<()> {
+            anyhow::bail!("no servers")
+        });
+        assert!(result.is_err());
+        assert_eq!(greeting(&lua), "hi");
+        assert_eq!(handlers(&lua), 1);
+
+        write_theme("hey");
+        std::fs::write(&init, "require('theme'); error('oops')").expect("init.lua");
+        assert!(reload_config(&lua, Some(&init), Ok).is_err());
+        assert_eq!(greeting(&lua), "hi");
+        assert_eq!(handlers(&lua), 1);
+
+        std::fs::remove_dir_all(&directory).expect("cleanup");
+    }
 }
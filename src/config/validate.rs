@@ -0,0 +1,621 @@
+use mlua::Value;
+
+use super::TircConfig;
+
+/// Port IRC servers accept TLS connections on.
+const TLS_PORT: u16 = 6697;
+
+/// Type of a config value, checked before deserializing the config so
+/// mistakes are reported with their Lua path.
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Boolean,
+    /// A non-negative integer up to the given maximum.
+    Integer(u64),
+    String,
+    /// One of the given strings.
+    OneOf(&'static [&'static str]),
+    List(&'static Kind),
+    /// A table with string keys, e.g. per buffer overrides.
+    Map(&'static Kind),
+    Table(&'static [Field]),
+}
+
+#[derive(Debug)]
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+const fn required(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+const U16: Kind = Kind::Integer(u16::MAX as u64);
+const U32: Kind = Kind::Integer(u32::MAX as u64);
+const U64: Kind = Kind::Integer(i64::MAX as u64);
+const STRINGS: Kind = Kind::List(&Kind::String);
+
+const RECONNECT: &[Field] = &[
+    optional("enabled", Kind::Boolean),
+    optional("delay", U64),
+    optional("max_delay", U64),
+];
+
+const FLOOD: &[Field] = &[
+    optional("enabled", Kind::Boolean),
+    optional("burst", U32),
+    optional("interval", U64),
+];
+
+const SASL: &[Field] = &[
+    optional("mechanism", Kind::OneOf(&["PLAIN", "EXTERNAL"])),
+    optional("account", Kind::String),
+    optional("password", Kind::String),
+    optional("cert_path", Kind::String),
+    optional("cert_password", Kind::String),
+];
+
+const SERVER: &[Field] = &[
+    optional("name", Kind::String),
+    required("host", Kind::String),
+    optional("port", U16),
+    optional("use_tls", Kind::Boolean),
+    optional("accept_invalid_cert", Kind::Boolean),
+    required("nickname", STRINGS),
+    optional("realname", Kind::String),
+    optional("autojoin", STRINGS),
+    optional("reconnect", Kind::Table(RECONNECT)),
+    optional("flood", Kind::Table(FLOOD)),
+    optional("ping_interval", U32),
+    optional("ping_timeout", U32),
+    optional("sasl", Kind::Table(SASL)),
+];
+
+const LOGGING: &[Field] = &[
+    optional("enabled", Kind::Boolean),
+    optional("format", Kind::OneOf(&["text", "raw"])),
+    optional("directory", Kind::String),
+    optional("buffers", Kind::Map(&Kind::Boolean)),
+    optional("restore", U64),
+];
+
+const HIGHLIGHT_BUFFER: &[Field] = &[
+    optional("enabled", Kind::Boolean),
+    optional("nickname", Kind::Boolean),
+    optional("keywords", STRINGS),
+    optional("patterns", STRINGS),
+];
+
+const HIGHLIGHT: &[Field] = &[
+    optional("nickname", Kind::Boolean),
+    optional("keywords", STRINGS),
+    optional("patterns", STRINGS),
+    optional("exclude_nicks", STRINGS),
+    optional("exclude_patterns", STRINGS),
+    optional("buffers", Kind::Map(&Kind::Table(HIGHLIGHT_BUFFER))),
+];
+
+const NOTIFICATION: &[Field] = &[
+    optional("highlights", Kind::Boolean),
+    optional("queries", Kind::Boolean),
+    optional("bell", Kind::Boolean),
+    optional("desktop", Kind::OneOf(&["osc9", "osc777"])),
+    optional("command", STRINGS),
+    optional("rate_limit", U64),
+];
+
+const PASTE: &[Field] = &[optional("delay", U64)];
+
+const RELOAD: &[Field] = &[optional("watch", Kind::Boolean)];
+
+const CONFIG: &[Field] = &[
+    required("servers", Kind::List(&Kind::Table(SERVER))),
+    optional("logging", Kind::Table(LOGGING)),
+    optional("highlight", Kind::Table(HIGHLIGHT)),
+    optional("notification", Kind::Table(NOTIFICATION)),
+    optional("paste", Kind::Table(PASTE)),
+    optional("reload", Kind::Table(RELOAD)),
+];
+
+/// Checks the value returned by `init.lua` for unknown keys, values of the
+/// wrong type and missing required fields, returning a description of each
+/// mistake prefixed with its Lua path, e.g. `servers[2].nickname`.
+pub fn validate(config: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    match config {
+        Value::Table(_) => check(&mut errors, "", config, Kind::Table(CONFIG)),
+        other => errors.push(format!(
+            "init.lua has to return the config table, got {}",
+            other.type_name()
+        )),
+    }
+
+    errors
+}
+
+/// Settings that are valid but likely a mistake or insecure.
+pub fn warnings(config: &TircConfig) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for (index, server) in config.servers.iter().enumerate() {
+        let path = format!("servers[{}]", index + 1);
+
+        if !server.use_tls && server.port == TLS_PORT {
+            warnings.push(format!(
+                "{}: use_tls is false on port {}, which is meant for TLS",
+                path, TLS_PORT
+            ));
+        }
+
+        if server.use_tls && server.accept_invalid_cert {
+            warnings.push(format!(
+                "{}.accept_invalid_cert: the certificate of {} is not verified, the connection \
+                 can be intercepted",
+                path, server.host
+            ));
+        }
+
+        if !server.use_tls
+            && server
+                .sasl
+                .as_ref()
+                .is_some_and(|sasl| sasl.password.is_some())
+        {
+            warnings.push(format!(
+                "{}.sasl: the password is sent unencrypted as use_tls is false",
+                path
+            ));
+        }
+    }
+
+    warnings
+}
+
+fn check(errors: &mut Vec<String>, path: &str, value: &Value, kind: Kind) {
+    let mismatch = |errors: &mut Vec<String>| {
+        errors.push(format!(
+            "{}: expected {}, got {}",
+            path,
+            describe(kind),
+            describe_value(value)
+        ));
+    };
+
+    match (kind, value) {
+        (Kind::Boolean, Value::Boolean(_)) | (Kind::String, Value::String(_)) => {}
+        (Kind::Integer(max), Value::Integer(_) | Value::Number(_)) => {
+            let integer = match value {
+                Value::Integer(integer) => Some(*integer),
+                Value::Number(number) if number.fract() == 0.0 => Some(*number as i64),
+                _ => None,
+            };
+
+            match integer {
+                Some(integer) if integer < 0 => {
+                    errors.push(format!("{}: must not be negative, got {}", path, integer))
+                }
+                Some(integer) if integer as u64 > max => errors.push(format!(
+                    "{}: must be at most {}, got {}",
+                    path, max, integer
+                )),
+                Some(_) => {}
+                None => mismatch(errors),
+            }
+        }
+        (Kind::OneOf(choices), Value::String(string)) => {
+            if !choices.iter().any(|choice| string == *choice) {
+                mismatch(errors);
+            }
+        }
+        (Kind::List(item), Value::Table(table)) => {
+            let len = table.raw_len();
+            let is_list = table.pairs::<Value, Value>().all(|pair| match pair {
+                Ok((Value::Integer(index), _)) => (1..=len as i64).contains(&index),
+                _ => false,
+            });
+
+            if !is_list {
+                mismatch(errors);
+                return;
+            }
+
+            for index in 1..=len {
+                let value: Value = table.raw_get(index).unwrap_or(Value::Nil);
+
+                check(errors, &format!("{}[{}]", path, index), &value, *item);
+            }
+        }
+        (Kind::Map(item), Value::Table(table)) => {
+            for (key, value) in sorted_pairs(table) {
+                match key {
+                    Some(key) => check(errors, &key_path(path, &key), &value, *item),
+                    None => errors.push(format!("{}: keys have to be strings", path)),
+                }
+            }
+        }
+        (Kind::Table(fields), Value::Table(table)) => {
+            for field in fields {
+                let value: Value = table.raw_get(field.name).unwrap_or(Value::Nil);
+                let field_path = key_path(path, field.name);
+
+                match value {
+                    Value::Nil if field.required => {
+                        errors.push(format!("{}: missing required field", field_path))
+                    }
+                    Value::Nil => {}
+                    value => check(errors, &field_path, &value, field.kind),
+                }
+            }
+
+            for (key, _) in sorted_pairs(table) {
+                let Some(key) = key else {
+                    errors.push(format!(
+                        "{}: expected named fields, got a list",
+                        display(path)
+                    ));
+                    break;
+                };
+
+                if fields.iter().any(|field| field.name == key) {
+                    continue;
+                }
+
+                let suggestion = fields
+                    .iter()
+                    .map(|field| field.name)
+                    .find(|name| edit_distance(name, &key) <= 2)
+                    .map(|name| format!(", did you mean '{}'?", name))
+                    .unwrap_or_default();
+
+                errors.push(format!(
+                    "{}: unknown key{}",
+                    key_path(path, &key),
+                    suggestion
+                ));
+            }
+        }
+        _ => mismatch(errors),
+    }
+}
+
+/// The entries of `table` sorted by key, so errors are reported in a stable
+/// order. Keys that are not strings are `None`.
+fn sorted_pairs(table: &mlua::Table) -> Vec<(Option<String>, Value)> {
+    let mut pairs: Vec<(Option<String>, Value)> = table
+        .pairs::<Value, Value>()
+        .filter_map(Result::ok)
+        .map(|(key, value)| match key {
+            Value::String(key) => (Some(key.to_string_lossy()), value),
+            _ => (None, value),
+        })
+        .collect();
+
+    pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    pairs
+}
+
+/// Appends `key` to `path`, quoting keys which are not identifiers, e.g.
+/// `highlight.buffers['#tirc']`.
+fn key_path(path: &str, key: &str) -> String {
+    let is_identifier = key
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && !key.starts_with(|char: char| char.is_ascii_digit())
+        && !key.is_empty();
+
+    match (path.is_empty(), is_identifier) {
+        (true, true) => key.to_owned(),
+        (false, true) => format!("{}.{}", path, key),
+        (_, false) => format!("{}['{}']", path, key.replace('\'', "\\'")),
+    }
+}
+
+fn display(path: &str) -> &str {
+    if path.is_empty() {
+        "config"
+    } else {
+        path
+    }
+}
+
+fn describe(kind: Kind) -> String {
+    match kind {
+        Kind::Boolean => "a boolean".to_owned(),
+        Kind::Integer(_) => "an integer".to_owned(),
+        Kind::String => "a string".to_owned(),
+        Kind::OneOf(choices) => format!("one of '{}'", choices.join("', '")),
+        Kind::List(item) => format!("a list of {}", plural(*item)),
+        Kind::Map(_) | Kind::Table(_) => "a table".to_owned(),
+    }
+}
+
+fn plural(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Boolean => "booleans",
+        Kind::Integer(_) => "integers",
+        Kind::String | Kind::OneOf(_) => "strings",
+        Kind::List(_) => "lists",
+        Kind::Map(_) | Kind::Table(_) => "tables",
+    }
+}
+
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::String(string) => format!("'{}'", string.to_string_lossy()),
+        Value::Boolean(boolean) => boolean.to_string(),
+        Value::Integer(integer) => integer.to_string(),
+        Value::Number(number) => number.to_string(),
+        other => other.type_name().to_owned(),
+    }
+}
+
+/// Levenshtein distance between two keys, to suggest the intended key of a
+/// typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::{Lua, LuaSerdeExt};
+
+    use super::*;
+
+    fn validate_lua(lua_config: &str) -> Vec<String> {
+        let lua = Lua::new();
+
+        validate(&lua.load(lua_config).eval().unwrap())
+    }
+
+    fn warnings_lua(lua_config: &str) -> Vec<String> {
+        let lua = Lua::new();
+        let config: TircConfig = lua
+            .from_value(lua.load(lua_config).eval().unwrap())
+            .unwrap();
+
+        warnings(&config)
+    }
+
+    #[test]
+    fn complete_config_is_valid() {
+        let lua_config = r#"{
+            servers = {
+              {
+                name = 'libera',
+                host = 'irc.libera.chat',
+                port = 6697,
+                use_tls = true,
+                accept_invalid_cert = false,
+                nickname = { 'tirc', 'tirc_' },
+                realname = 'tirc',
+                autojoin = { '#tirc' },
+                reconnect = { enabled = true, delay = 2, max_delay = 300 },
+                flood = { enabled = true, burst = 5, interval = 2000 },
+                ping_interval = 180,
+                ping_timeout = 20,
+                sasl = { mechanism = 'PLAIN', account = 'tirc', password = 'secret' },
+              },
+            },
+            logging = { enabled = true, format = 'raw', buffers = { ['#tirc'] = false }, restore = 50 },
+            highlight = {
+              keywords = { 'tirc' },
+              exclude_nicks = { 'bot' },
+              buffers = { ['libera/#tirc'] = { enabled = true, nickname = false } },
+            },
+            notification = { desktop = 'osc9', command = { 'notify-send' }, rate_limit = 5 },
+            paste = { delay = 250 },
+        }"#;
+
+        assert_eq!(validate_lua(lua_config), Vec::<String>::new());
+        assert_eq!(warnings_lua(lua_config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_suggestions() {
+        assert_eq!(
+            validate_lua(
+                "{ servers = { { host = 'a', nickname = { 'me' }, use_tsl = true } },
+                   notifications = {} }"
+            ),
+            [
+                "servers[1].use_tsl: unknown key, did you mean 'use_tls'?",
+                "notifications: unknown key, did you mean 'notification'?",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_types_are_reported_with_their_path() {
+        assert_eq!(
+            validate_lua(
+                "{ servers = {
+                     { host = 'a', nickname = { 'me' } },
+                     { host = 'b', nickname = 'me', port = '6697', sasl = { mechanism = 'plain' } },
+                   },
+                   highlight = { buffers = { ['#tirc'] = { keywords = { 1 } } } },
+                   notification = { rate_limit = -1 } }"
+            ),
+            [
+                "servers[2].port: expected an integer, got '6697'",
+                "servers[2].nickname: expected a list of strings, got 'me'",
+                "servers[2].sasl.mechanism: expected one of 'PLAIN', 'EXTERNAL', got 'plain'",
+                "highlight.buffers['#tirc'].keywords[1]: expected a string, got 1",
+                "notification.rate_limit: must not be negative, got -1",
+            ]
+        );
+        assert_eq!(
+            validate_lua("{ servers = { host = 'a', nickname = { 'me' } } }"),
+            ["servers: expected a list of tables, got table"]
+        );
+        assert_eq!(
+            validate_lua("nil"),
+            ["init.lua has to return the config table, got nil"]
+        );
+    }
+
+    #[test]
+    fn missing_required_fields_are_reported() {
+        assert_eq!(
+            validate_lua("{ servers = { { host = 'a' }, { nickname = { 'me' } } } }"),
+            [
+                "servers[1].nickname: missing required field",
+                "servers[2].host: missing required field",
+            ]
+        );
+        assert_eq!(validate_lua("{}"), ["servers: missing required field"]);
+    }
+
+    #[test]
+    fn insecure_settings_are_warned_about() {
+        assert_eq!(
+            warnings_lua(
+                "{ servers = {
+                     { host = 'a', nickname = { 'me' }, use_tls = false,
+                       sasl = { password = 'secret' } },
+                     { host = 'b', nickname = { 'me' }, accept_invalid_cert = true },
+                   } }"
+            ),
+            [
+                "servers[1]: use_tls is false on port 6697, which is meant for TLS",
+                "servers[1].sasl: the password is sent unencrypted as use_tls is false",
+                "servers[2].accept_invalid_cert: the certificate of b is not verified, the \
+                 connection can be intercepted",
+            ]
+        );
+    }
+
+    /// A config with a value for every key of the schema, and an unknown key
+    /// in the tables described by `extra`.
+    fn sample(lua: &Lua, kind: Kind, extra: Option<&[Field]>) -> Value {
+        match kind {
+            Kind::Boolean => Value::Boolean(true),
+            Kind::Integer(_) => Value::Integer(1),
+            Kind::String => Value::String(lua.create_string("x").unwrap()),
+            Kind::OneOf(choices) => Value::String(lua.create_string(choices[0]).unwrap()),
+            Kind::List(item) => {
+                let list = lua
+                    .create_sequence_from([sample(lua, *item, extra)])
+                    .unwrap();
+
+                Value::Table(list)
+            }
+            Kind::Map(item) => {
+                let map = lua.create_table().unwrap();
+                map.set("x", sample(lua, *item, extra)).unwrap();
+
+                Value::Table(map)
+            }
+            Kind::Table(fields) => {
+                let table = lua.create_table().unwrap();
+
+                for field in fields {
+                    table
+                        .set(field.name, sample(lua, field.kind, extra))
+                        .unwrap();
+                }
+
+                if extra.is_some_and(|extra| std::ptr::eq(extra, fields)) {
+                    table.set("unknown", true).unwrap();
+                }
+
+                Value::Table(table)
+            }
+        }
+    }
+
+    /// The field lists of all tables in the schema.
+    fn tables(kind: Kind, tables: &mut Vec<&'static [Field]>) {
+        match kind {
+            Kind::List(item) | Kind::Map(item) => self::tables(*item, tables),
+            Kind::Table(fields) => {
+                tables.push(fields);
+
+                for field in fields {
+                    self::tables(field.kind, tables);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn schema_keys_are_config_fields() {
+        let lua = Lua::new();
+        let value = sample(&lua, Kind::Table(CONFIG), None);
+
+        assert_eq!(validate(&value), Vec::<String>::new());
+
+        let result: mlua::Result<TircConfig> = lua.from_value(value);
+
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    fn config_fields_are_in_the_schema() {
+        let lua = Lua::new();
+        let mut schema_tables = Vec::new();
+        tables(Kind::Table(CONFIG), &mut schema_tables);
+
+        for fields in schema_tables {
+            let value = sample(&lua, Kind::Table(CONFIG), Some(fields));
+            let error = lua
+                .from_value::<TircConfig>(value)
+                .expect_err("unknown keys are rejected")
+                .to_string();
+
+            // serde lists the fields of the struct the unknown key is in.
+            let (_, expected) = error.split_once("expected").expect("list of fields");
+            let mut struct_fields: Vec<&str> = expected.split('`').skip(1).step_by(2).collect();
+            let mut schema_fields: Vec<&str> = fields.iter().map(|field| field.name).collect();
+            struct_fields.sort_unstable();
+            schema_fields.sort_unstable();
+
+            assert_eq!(struct_fields, schema_fields);
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let lua = Lua::new();
+
+        super::super::register_builtin_modules(&lua).unwrap();
+
+        let value: Value = lua
+            .load(super::super::get_default_config())
+            .call(())
+            .unwrap();
+
+        assert_eq!(validate(&value), Vec::<String>::new());
+    }
+}
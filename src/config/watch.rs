@@ -0,0 +1,102 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Notices changes to the Lua files of the config directory by comparing
+/// their modification times, polled on every tick.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    directory: PathBuf,
+    files: Vec<(PathBuf, SystemTime)>,
+}
+
+impl ConfigWatcher {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        let directory = directory.into();
+        let files = lua_files(&directory);
+
+        Self { directory, files }
+    }
+
+    /// Whether a Lua file was added, removed or modified since the last call.
+    pub fn changed(&mut self) -> bool {
+        let files = lua_files(&self.directory);
+
+        if files == self.files {
+            return false;
+        }
+
+        self.files = files;
+
+        true
+    }
+}
+
+/// The Lua files below `directory` with their modification times, sorted by
+/// path. Unreadable entries are skipped.
+fn lua_files(directory: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let mut files = Vec::new();
+    let mut directories = vec![directory.to_owned()];
+
+    while let Some(directory) = directories.pop() {
+        let Ok(entries) = std::fs::read_dir(&directory) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+
+            if metadata.is_dir() {
+                directories.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "lua") {
+                if let Ok(modified) = metadata.modified() {
+                    files.push((path, modified));
+                }
+            }
+        }
+    }
+
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn changes_to_lua_files_are_noticed() {
+        let directory =
+            std::env::temp_dir().join(format!("tirc-config-watch-{}", std::process::id()));
+        let theme = directory.join("themes").join("mine.lua");
+
+        std::fs::create_dir_all(theme.parent().unwrap()).unwrap();
+        std::fs::write(directory.join("init.lua"), "return {}").unwrap();
+
+        let mut watcher = ConfigWatcher::new(&directory);
+        assert!(!watcher.changed());
+
+        std::fs::write(directory.join("notes.txt"), "").unwrap();
+        assert!(!watcher.changed());
+
+        std::fs::write(&theme, "return {}").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        let file = std::fs::File::options().write(true).open(&theme).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert!(watcher.changed());
+
+        std::fs::remove_file(&theme).unwrap();
+        assert!(watcher.changed());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use indexmap::IndexMap;
+use irc::client::{data::User, Client};
+
+/// Clients of the connected servers, keyed by server name. Clones share the
+/// same clients, which lets Lua event handlers look up channels and users.
+#[derive(Clone, Default)]
+pub struct Clients {
+    clients: Arc<Mutex<IndexMap<String, Client>>>,
+}
+
+impl Clients {
+    /// Locks the clients. The guard must not be held while calling into Lua,
+    /// whose handlers may lock them again.
+    pub fn lock(&self) -> MutexGuard<'_, IndexMap<String, Client>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds the client of a new connection to `server`, replacing the client
+    /// of a previous connection.
+    pub fn insert(&self, server: &str, client: Client) {
+        self.lock().insert(server.to_owned(), client);
+    }
+
+    pub fn remove(&self, server: &str) {
+        self.lock().shift_remove(server);
+    }
+
+    pub fn contains(&self, server: &str) -> bool {
+        self.lock().contains_key(server)
+    }
+
+    /// Our nickname on `server`, `None` if not connected.
+    pub fn nickname(&self, server: &str) -> Option<String> {
+        self.lock()
+            .get(server)
+            .map(|client| client.current_nickname().to_owned())
+    }
+
+    /// Channels joined on `server`.
+    pub fn channels(&self, server: &str) -> Vec<String> {
+        self.lock()
+            .get(server)
+            .and_then(Client::list_channels)
+            .unwrap_or_default()
+    }
+
+    /// Users in `channel` on `server`, `None` if the channel is not joined.
+    pub fn users(&self, server: &str, channel: &str) -> Option<Vec<User>> {
+        self.lock().get(server)?.list_users(channel)
+    }
+}
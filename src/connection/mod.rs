@@ -0,0 +1,680 @@
+use std::{collections::BTreeSet, io, time::Duration};
+
+use futures::{
+    future,
+    stream::{self, LocalBoxStream, SelectAll},
+    StreamExt,
+};
+use indexmap::IndexMap;
+use irc::{
+    client::{prelude::Config, Client, ClientStream},
+    proto::Message,
+};
+
+use crate::config::{ReconnectConfig, SaslMechanism, ServerConfig};
+
+pub use self::{
+    clients::Clients,
+    outgoing::{Flushed, Outgoing, Queued},
+    registration::{Notice, Registration},
+};
+
+mod clients;
+mod outgoing;
+mod registration;
+
+/// How long a connection attempt may take before it fails and the backoff
+/// starts.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something that happened on the connection to a server.
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// A connection attempt finished, successfully or not.
+    Connected(irc::error::Result<Box<Client>>),
+    Message(Box<Message>),
+    /// The connection was lost, either with an error (e.g. a ping timeout) or
+    /// because the server closed it.
+    Disconnected(Option<irc::error::Error>),
+}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        let initial = Duration::from_secs(config.delay);
+
+        Self {
+            initial,
+            max: Duration::from_secs(config.max_delay).max(initial),
+            next: initial,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt and doubles the
+    /// delay of the attempt after it.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Builds the `irc` client configuration for a server.
+pub fn client_config(server_config: &ServerConfig) -> anyhow::Result<Config> {
+    let nickname = server_config.nickname.first().cloned().ok_or_else(|| {
+        anyhow::anyhow!(
+            "Server '{}' has an empty nickname list in init.lua",
+            server_config.host
+        )
+    })?;
+
+    let (client_cert_path, client_cert_pass) = match &server_config.sasl {
+        Some(sasl) if sasl.mechanism == SaslMechanism::Plain && sasl.password.is_none() => {
+            anyhow::bail!(
+                "Server '{}' uses SASL PLAIN without a password in init.lua",
+                server_config.name()
+            )
+        }
+        Some(sasl) if sasl.mechanism == SaslMechanism::External && sasl.cert_path.is_none() => {
+            anyhow::bail!(
+                "Server '{}' uses SASL EXTERNAL without a cert_path in init.lua",
+                server_config.name()
+            )
+        }
+        Some(sasl) => (sasl.cert_path.clone(), sasl.cert_password.clone()),
+        None => (None, None),
+    };
+
+    Ok(Config {
+        nickname: Some(nickname),
+        alt_nicks: server_config.nickname[1..].to_vec(),
+        realname: server_config.realname.clone(),
+        server: Some(server_config.host.clone()),
+        port: Some(server_config.port),
+        use_tls: Some(server_config.use_tls),
+        dangerously_accept_invalid_certs: Some(server_config.accept_invalid_cert),
+        channels: server_config.autojoin.clone(),
+        ping_time: server_config.ping_interval,
+        ping_timeout: server_config.ping_timeout,
+        client_cert_path,
+        client_cert_pass,
+        version: Some(format!(
+            "tirc v{} - https://github.com/topaxi/tirc",
+            env!("CARGO_PKG_VERSION")
+        )),
+        ..Default::default()
+    })
+}
+
+struct Connection {
+    server_config: ServerConfig,
+    config: Config,
+    reconnect: ReconnectConfig,
+    backoff: Backoff,
+    registration: Registration,
+    /// Whether the next reconnect applies a changed config, which happens
+    /// right away even if reconnecting is disabled.
+    restart: bool,
+    /// Tags the events of the current connection attempt and its client, so
+    /// that those of cancelled attempts and removed servers are ignored.
+    generation: u64,
+    /// Whether a client is connected, as opposed to waiting for a connection
+    /// attempt or a reconnect.
+    connected: bool,
+    /// Nickname used when the last connection was lost, restored on
+    /// reconnect.
+    nickname: Option<String>,
+    /// Channels joined when the last connection was lost, rejoined on
+    /// reconnect.
+    channels: Vec<String>,
+}
+
+impl Connection {
+    fn new(server_config: &ServerConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            server_config: server_config.clone(),
+            config: client_config(server_config)?,
+            reconnect: server_config.reconnect.clone(),
+            backoff: Backoff::new(&server_config.reconnect),
+            registration: Registration::new(
+                server_config.sasl.clone(),
+                &server_config.nickname[0],
+                server_config.realname.clone(),
+            ),
+            restart: false,
+            generation: 0,
+            connected: false,
+            nickname: None,
+            channels: Vec::new(),
+        })
+    }
+
+    /// The client configuration for the next connection: a nickname picked
+    /// other than the configured ones is tried first, and the channels
+    /// joined on the last connection are rejoined.
+    fn restored_config(&self) -> Config {
+        let mut config = self.config.clone();
+
+        if let Some(nickname) = self
+            .nickname
+            .as_ref()
+            .filter(|nickname| !self.server_config.nickname.contains(nickname))
+        {
+            config.nickname = Some(nickname.clone());
+            config.alt_nicks = self.server_config.nickname.clone();
+        }
+
+        for channel in &self.channels {
+            if !config.channels.contains(channel) {
+                config.channels.push(channel.clone());
+            }
+        }
+
+        config
+    }
+
+    /// Whether the connection has to be reestablished to apply
+    /// `server_config`, as opposed to only the reconnect and flood settings
+    /// changing.
+    fn requires_restart(&self, server_config: &ServerConfig) -> bool {
+        let unchanged = ServerConfig {
+            reconnect: server_config.reconnect.clone(),
+            flood: server_config.flood.clone(),
+            ..self.server_config.clone()
+        };
+
+        unchanged != *server_config
+    }
+}
+
+/// How the servers of a reloaded config differ from the previous ones.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ServerChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Servers whose connection has to be reestablished.
+    pub changed: Vec<String>,
+}
+
+/// Owns the connection lifecycle of every configured server: it connects,
+/// watches the message streams for disconnects and reconnects with
+/// exponential backoff.
+///
+/// All activity is multiplexed into a single stream of [`ServerEvent`]s tagged
+/// with the server name, consumed through [`Supervisor::next`].
+pub struct Supervisor {
+    connections: IndexMap<String, Connection>,
+    events: SelectAll<LocalBoxStream<'static, (String, u64, ServerEvent)>>,
+    generation: u64,
+}
+
+impl Supervisor {
+    pub fn new(servers: &[ServerConfig]) -> anyhow::Result<Self> {
+        Ok(Self {
+            connections: Self::connections(servers)?,
+            events: SelectAll::new(),
+            generation: 0,
+        })
+    }
+
+    fn connections(servers: &[ServerConfig]) -> anyhow::Result<IndexMap<String, Connection>> {
+        let mut connections = IndexMap::new();
+
+        for server_config in servers {
+            let name = server_config.name().to_owned();
+
+            if connections.contains_key(&name) {
+                anyhow::bail!(
+                    "Server name '{}' is configured more than once in init.lua",
+                    name
+                );
+            }
+
+            connections.insert(name, Connection::new(server_config)?);
+        }
+
+        Ok(connections)
+    }
+
+    /// Switches to the servers of a reloaded config, keeping the connections
+    /// of unchanged servers.
+    ///
+    /// Added servers are connected to. The caller quits the clients of
+    /// removed and changed servers; changed servers reconnect with their new
+    /// config once disconnected, or right away if they are not connected,
+    /// while events of removed servers are ignored.
+    pub fn reconfigure(&mut self, servers: &[ServerConfig]) -> anyhow::Result<ServerChanges> {
+        let mut connections = Self::connections(servers)?;
+        let mut changes = ServerChanges::default();
+        let mut connect = Vec::new();
+
+        for (name, connection) in connections.iter_mut() {
+            match self.connections.shift_remove(name) {
+                Some(previous) if previous.requires_restart(&connection.server_config) => {
+                    // Rejoins the channels, but a changed config may prefer
+                    // other nicknames.
+                    connection.channels = previous.channels;
+
+                    if previous.connected {
+                        // Reconnects once the client quit.
+                        connection.restart = true;
+                        connection.generation = previous.generation;
+                        connection.connected = true;
+                    } else {
+                        // Cancels a scheduled attempt with the old config.
+                        connect.push(name.clone());
+                    }
+
+                    changes.changed.push(name.clone());
+                }
+                Some(previous) => {
+                    // Keeps the negotiated capabilities and the nickname and
+                    // channels to restore.
+                    let Connection {
+                        server_config,
+                        reconnect,
+                        backoff,
+                        ..
+                    } = std::mem::replace(connection, previous);
+
+                    if connection.reconnect != reconnect {
+                        connection.reconnect = reconnect;
+                        connection.backoff = backoff;
+                    }
+
+                    connection.server_config = server_config;
+                }
+                None => {
+                    connect.push(name.clone());
+                    changes.added.push(name.clone());
+                }
+            }
+        }
+
+        changes.removed = self.connections.keys().cloned().collect();
+        self.connections = connections;
+
+        for server in &connect {
+            self.connect(server, Duration::ZERO);
+        }
+
+        Ok(changes)
+    }
+
+    pub fn servers(&self) -> impl Iterator<Item = &str> {
+        self.connections.keys().map(String::as_str)
+    }
+
+    pub fn contains(&self, server: &str) -> bool {
+        self.connections.contains_key(server)
+    }
+
+    /// Starts connecting to every server.
+    pub fn connect_all(&mut self) {
+        let servers: Vec<String> = self.connections.keys().cloned().collect();
+
+        for server in servers {
+            self.connect(&server, Duration::ZERO);
+        }
+    }
+
+    /// Schedules a connection attempt, replacing any attempt scheduled
+    /// before.
+    fn connect(&mut self, server: &str, delay: Duration) {
+        let Some(connection) = self.connections.get_mut(server) else {
+            return;
+        };
+
+        self.generation += 1;
+        connection.generation = self.generation;
+        connection.connected = false;
+
+        let (server, generation) = (server.to_owned(), connection.generation);
+        let config = connection.restored_config();
+
+        self.events.push(
+            stream::once(async move {
+                tokio::time::sleep(delay).await;
+                let client = tokio::time::timeout(CONNECT_TIMEOUT, Client::from_config(config))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out").into())
+                    })
+                    .map(Box::new);
+                (server, generation, ServerEvent::Connected(client))
+            })
+            .boxed_local(),
+        );
+    }
+
+    /// Watches the message stream of a freshly connected client. The stream
+    /// ends with a single [`ServerEvent::Disconnected`].
+    pub fn watch(&mut self, server: &str, stream: ClientStream) {
+        let Some(connection) = self.connections.get(server) else {
+            return;
+        };
+
+        let (server, generation) = (server.to_owned(), connection.generation);
+
+        self.events.push(
+            stream
+                .map(|message| match message {
+                    Ok(message) => ServerEvent::Message(Box::new(message)),
+                    Err(err) => ServerEvent::Disconnected(Some(err)),
+                })
+                .chain(stream::once(future::ready(ServerEvent::Disconnected(None))))
+                .scan(false, |disconnected, event| {
+                    if *disconnected {
+                        return future::ready(None);
+                    }
+
+                    *disconnected = matches!(event, ServerEvent::Disconnected(_));
+
+                    future::ready(Some(event))
+                })
+                .map(move |event| (server.clone(), generation, event))
+                .boxed_local(),
+        );
+    }
+
+    /// Starts registering a freshly connected client of `server`.
+    pub fn register(&mut self, server: &str, client: &Client) -> irc::error::Result<()> {
+        match self.connections.get_mut(server) {
+            Some(connection) => connection.registration.start(client),
+            None => Ok(()),
+        }
+    }
+
+    /// Lets the registration of `server` react to `message`, returning a line
+    /// to report to the user, if any.
+    pub fn handle_message(
+        &mut self,
+        server: &str,
+        client: &Client,
+        message: &Message,
+    ) -> irc::error::Result<Option<Notice>> {
+        match self.connections.get_mut(server) {
+            Some(connection) => connection.registration.handle_message(client, message),
+            None => Ok(None),
+        }
+    }
+
+    /// Capabilities enabled on the current connection to `server`.
+    pub fn capabilities(&self, server: &str) -> Option<&BTreeSet<String>> {
+        self.connections
+            .get(server)
+            .map(|connection| connection.registration.enabled_capabilities())
+    }
+
+    /// The value an enabled capability of the current connection to `server`
+    /// was offered with.
+    pub fn capability_value(&self, server: &str, capability: &str) -> Option<&str> {
+        self.connections
+            .get(server)?
+            .registration
+            .capability_value(capability)
+    }
+
+    /// Resets the backoff of `server` once registration succeeded.
+    pub fn registered(&mut self, server: &str) {
+        if let Some(connection) = self.connections.get_mut(server) {
+            connection.backoff.reset();
+        }
+    }
+
+    /// Remembers the `nickname` and the joined `channels` of the lost
+    /// connection to `server`, restored once reconnected.
+    pub fn disconnected(&mut self, server: &str, nickname: &str, channels: Vec<String>) {
+        let Some(connection) = self.connections.get_mut(server) else {
+            return;
+        };
+
+        // A changed config may prefer other nicknames.
+        if !nickname.is_empty() && !connection.restart {
+            connection.nickname = Some(nickname.to_owned());
+        }
+
+        connection.channels = channels;
+    }
+
+    /// Schedules a reconnection attempt for `server`.
+    ///
+    /// Returns the delay before the attempt, or `None` if reconnecting is
+    /// disabled for this server. Servers whose config changed reconnect
+    /// right away.
+    pub fn reconnect(&mut self, server: &str) -> Option<Duration> {
+        let connection = self.connections.get_mut(server)?;
+        let restart = std::mem::take(&mut connection.restart);
+
+        if !connection.reconnect.enabled && !restart {
+            return None;
+        }
+
+        let delay = if restart {
+            Duration::ZERO
+        } else {
+            connection.backoff.next_delay()
+        };
+        self.connect(server, delay);
+
+        Some(delay)
+    }
+
+    /// The next event of a configured server, skipping those of cancelled
+    /// connection attempts and removed servers.
+    pub async fn next(&mut self) -> Option<(String, ServerEvent)> {
+        loop {
+            let (server, generation, event) = self.events.next().await?;
+
+            let Some(connection) = self.connections.get_mut(&server) else {
+                continue;
+            };
+
+            if connection.generation != generation {
+                continue;
+            }
+
+            match event {
+                ServerEvent::Connected(Ok(_)) => connection.connected = true,
+                ServerEvent::Connected(Err(_)) | ServerEvent::Disconnected(_) => {
+                    connection.connected = false
+                }
+                ServerEvent::Message(_) => {}
+            }
+
+            return Some((server, event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::LuaSerdeExt;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let mut backoff = Backoff::new(&ReconnectConfig {
+            enabled: true,
+            delay: 2,
+            max_delay: 10,
+        });
+
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [2, 4, 8, 10, 10]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay().as_secs(), 2);
+    }
+
+    #[test]
+    fn reconnect_restores_nickname_and_channels() {
+        let lua = mlua::Lua::new();
+        let server_config: ServerConfig = lua
+            .from_value(
+                lua.load("{ host = 'irc.example.com', nickname = { 'tirc', 'tirc_' }, autojoin = { '#tirc' } }")
+                    .eval()
+                    .unwrap(),
+            )
+            .unwrap();
+        let mut supervisor = Supervisor::new(&[server_config]).unwrap();
+
+        // A configured alternate nickname is not preferred over the others.
+        supervisor.disconnected("irc.example.com", "tirc_", vec!["#rust".to_string()]);
+        let delay = supervisor.reconnect("irc.example.com");
+        assert_eq!(delay, Some(Duration::from_secs(2)));
+
+        let config = supervisor.connections["irc.example.com"].restored_config();
+        assert_eq!(config.nickname.as_deref(), Some("tirc"));
+        assert_eq!(config.alt_nicks, ["tirc_"]);
+        assert_eq!(config.channels, ["#tirc", "#rust"]);
+
+        // Parted channels are not rejoined, a picked nickname is restored.
+        supervisor.disconnected("irc.example.com", "picked", Vec::new());
+        supervisor.reconnect("irc.example.com");
+
+        let connection = &supervisor.connections["irc.example.com"];
+        let config = connection.restored_config();
+        assert_eq!(config.nickname.as_deref(), Some("picked"));
+        assert_eq!(config.alt_nicks, ["tirc", "tirc_"]);
+        assert_eq!(config.channels, ["#tirc"]);
+        assert_eq!(connection.config.nickname.as_deref(), Some("tirc"));
+    }
+
+    #[test]
+    fn reconfigure_restarts_only_changed_servers() {
+        let lua = mlua::Lua::new();
+        let servers = |servers: &str| -> Vec<ServerConfig> {
+            lua.from_value(lua.load(servers).eval().unwrap()).unwrap()
+        };
+        let mut supervisor = Supervisor::new(&servers(
+            "{ { host = 'a.example.com', nickname = { 'tirc' } },
+               { host = 'b.example.com', nickname = { 'tirc' } },
+               { host = 'c.example.com', nickname = { 'tirc' }, reconnect = { enabled = false } } }",
+        ))
+        .unwrap();
+
+        supervisor.disconnected("a.example.com", "picked", Vec::new());
+        supervisor.reconnect("a.example.com");
+        supervisor.connections["c.example.com"].connected = true;
+
+        let changes = supervisor
+            .reconfigure(&servers(
+                "{ { host = 'a.example.com', nickname = { 'tirc' }, flood = { burst = 1 } },
+                   { host = 'c.example.com', nickname = { 'other' }, reconnect = { enabled = false } },
+                   { host = 'd.example.com', nickname = { 'tirc' } } }",
+            ))
+            .unwrap();
+
+        assert_eq!(
+            changes,
+            ServerChanges {
+                added: vec!["d.example.com".to_string()],
+                removed: vec!["b.example.com".to_string()],
+                changed: vec!["c.example.com".to_string()],
+            }
+        );
+        assert_eq!(
+            supervisor.servers().collect::<Vec<_>>(),
+            ["a.example.com", "c.example.com", "d.example.com"]
+        );
+
+        // Unchanged servers keep restoring the last nickname.
+        let config = supervisor.connections["a.example.com"].restored_config();
+        assert_eq!(config.nickname.as_deref(), Some("picked"));
+
+        // Changed servers reconnect right away with the new config.
+        supervisor.disconnected("c.example.com", "picked", Vec::new());
+        assert_eq!(supervisor.reconnect("c.example.com"), Some(Duration::ZERO));
+        let config = supervisor.connections["c.example.com"].restored_config();
+        assert_eq!(config.nickname.as_deref(), Some("other"));
+        assert_eq!(supervisor.reconnect("c.example.com"), None);
+
+        assert!(supervisor
+            .reconfigure(&servers(
+                "{ { host = 'a.example.com', nickname = { 'tirc' } },
+                   { name = 'a.example.com', host = 'e.example.com', nickname = { 'tirc' } } }",
+            ))
+            .is_err());
+        assert!(supervisor.contains("d.example.com"));
+    }
+
+    #[tokio::test]
+    async fn events_of_cancelled_attempts_and_removed_servers_are_ignored() {
+        let lua = mlua::Lua::new();
+        let servers = |servers: &str| -> Vec<ServerConfig> {
+            lua.from_value(lua.load(servers).eval().unwrap()).unwrap()
+        };
+        let mut supervisor = Supervisor::new(&servers(
+            "{ { host = 'a.example.com', nickname = { 'tirc' } },
+               { host = 'b.example.com', nickname = { 'tirc' } } }",
+        ))
+        .unwrap();
+
+        supervisor.reconnect("a.example.com");
+        supervisor.reconnect("b.example.com");
+        let (a, b) = (
+            supervisor.connections["a.example.com"].generation,
+            supervisor.connections["b.example.com"].generation,
+        );
+
+        // The attempt scheduled for a changed server is replaced by one with
+        // the new config, right away.
+        let changes = supervisor
+            .reconfigure(&servers(
+                "{ { host = 'a.example.com', nickname = { 'other' } } }",
+            ))
+            .unwrap();
+        assert_eq!(changes.changed, ["a.example.com"]);
+        let connection = &supervisor.connections["a.example.com"];
+        assert_eq!(connection.config.nickname.as_deref(), Some("other"));
+        assert!(!connection.restart);
+        assert_ne!(connection.generation, a);
+
+        // A server added again does not take the events of the removed one.
+        supervisor
+            .reconfigure(&servers(
+                "{ { host = 'a.example.com', nickname = { 'other' } },
+                   { host = 'b.example.com', nickname = { 'tirc' } } }",
+            ))
+            .unwrap();
+        assert_ne!(supervisor.connections["b.example.com"].generation, b);
+
+        supervisor.events = SelectAll::new();
+        for (server, generation) in [("a.example.com", a), ("b.example.com", b)] {
+            supervisor.events.push(
+                stream::once(future::ready((
+                    server.to_owned(),
+                    generation,
+                    ServerEvent::Disconnected(None),
+                )))
+                .boxed_local(),
+            );
+        }
+        let generation = supervisor.connections["b.example.com"].generation;
+        supervisor.events.push(
+            stream::once(future::ready((
+                "b.example.com".to_owned(),
+                generation,
+                ServerEvent::Disconnected(None),
+            )))
+            .boxed_local(),
+        );
+
+        let (server, _) = supervisor.next().await.unwrap();
+        assert_eq!(server, "b.example.com");
+        assert!(supervisor.next().await.is_none());
+    }
+}
@@ -0,0 +1,592 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use irc::{
+    client::Sender,
+    proto::{Command, Message},
+};
+
+use crate::config::{FloodConfig, ServerConfig};
+
+/// Token bucket holding up to `burst` messages, refilled with one message
+/// every `interval`. Messages sent at once, e.g. the lines of a batch, may
+/// take more tokens than are left, a debt paid off before sending more.
+#[derive(Debug)]
+struct TokenBucket {
+    burst: u32,
+    interval: Duration,
+    /// Negative while in debt.
+    tokens: i64,
+    /// When the last token was added, or the bucket was last full.
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: &FloodConfig, now: Instant) -> Self {
+        let burst = config.burst.max(1);
+
+        Self {
+            burst,
+            interval: Duration::from_millis(config.interval),
+            tokens: burst.into(),
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let burst = i64::from(self.burst);
+
+        if self.interval.is_zero() {
+            self.tokens = burst;
+            return;
+        }
+
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let missing = (burst - self.tokens) as u128;
+        let earned = (elapsed.as_nanos() / self.interval.as_nanos()).min(missing) as u32;
+
+        self.tokens += i64::from(earned);
+
+        if self.tokens == burst {
+            self.refilled = now;
+        } else {
+            self.refilled += self.interval * earned;
+        }
+    }
+
+    /// Takes a token for each of `count` messages, returning false if none
+    /// is left.
+    fn take(&mut self, now: Instant, count: usize) -> bool {
+        self.refill(now);
+
+        if self.tokens <= 0 {
+            return false;
+        }
+
+        self.tokens -= count as i64;
+
+        true
+    }
+
+    /// When the next token is available.
+    fn ready_at(&self) -> Instant {
+        if self.tokens > 0 {
+            self.refilled
+        } else {
+            self.refilled + self.interval * (1 - self.tokens) as u32
+        }
+    }
+}
+
+/// Messages queued together, sent at once for a token each, e.g. the lines
+/// of a `draft/multiline` batch.
+#[derive(Debug, Default)]
+pub struct Queued {
+    /// Name of the buffer the messages were sent from, if any.
+    pub target: Option<String>,
+    /// Labels of the local copies shown as drafts in that buffer until the
+    /// messages are sent.
+    pub drafts: Vec<String>,
+    pub messages: Vec<Message>,
+    /// Time to leave after the messages sent before, e.g. between the lines
+    /// of a paste, on top of the rate limit.
+    pub pace: Option<Duration>,
+}
+
+impl Queued {
+    pub fn new(message: impl Into<Message>) -> Self {
+        Self {
+            messages: vec![message.into()],
+            ..Default::default()
+        }
+    }
+
+    /// Whether the messages skip the queue, as quitting should not wait for
+    /// chatter. Pings are answered by the client itself, bypassing the queue.
+    fn is_priority(&self) -> bool {
+        self.messages
+            .iter()
+            .all(|message| matches!(message.command, Command::QUIT(_)))
+    }
+}
+
+/// What [`Outgoing::flush`] sent, and the servers it could not send to.
+#[derive(Debug, Default)]
+pub struct Flushed {
+    /// The messages sent, with the server they were sent to.
+    pub sent: Vec<(String, Queued)>,
+    /// The error of each server the next messages could not be sent to. They
+    /// stay queued until the lost connection is dropped, see
+    /// [`Outgoing::disconnect`].
+    pub errors: Vec<(String, anyhow::Error)>,
+}
+
+#[derive(Debug)]
+struct ServerQueue {
+    /// Sender of the current connection, `None` while disconnected.
+    sender: Option<Sender>,
+    flood: FloodConfig,
+    /// `None` if flood control is disabled.
+    bucket: Option<TokenBucket>,
+    queued: VecDeque<Queued>,
+    /// When queued messages were last sent.
+    last_sent: Option<Instant>,
+}
+
+impl ServerQueue {
+    fn new(config: &FloodConfig, now: Instant) -> Self {
+        Self {
+            sender: None,
+            flood: config.clone(),
+            bucket: config.enabled.then(|| TokenBucket::new(config, now)),
+            queued: VecDeque::new(),
+            last_sent: None,
+        }
+    }
+
+    /// Takes the next queued messages if the rate limit allows sending them.
+    fn pop_due(&mut self, now: Instant) -> Option<Queued> {
+        let count = self.queued.front()?.messages.len();
+
+        if self
+            .paced_until()
+            .is_some_and(|paced_until| paced_until > now)
+        {
+            return None;
+        }
+
+        if let Some(bucket) = &mut self.bucket {
+            if !bucket.take(now, count) {
+                return None;
+            }
+        }
+
+        self.last_sent = Some(now);
+        self.queued.pop_front()
+    }
+
+    /// Until when the pace of the next queued messages holds them back.
+    fn paced_until(&self) -> Option<Instant> {
+        let pace = self.queued.front()?.pace?;
+
+        Some(self.last_sent? + pace)
+    }
+
+    /// When the next queued messages can be sent.
+    fn ready_at(&self) -> Option<Instant> {
+        self.queued.front()?;
+
+        let ready_at = self
+            .bucket
+            .as_ref()
+            .map_or_else(Instant::now, TokenBucket::ready_at);
+
+        Some(
+            self.paced_until()
+                .map_or(ready_at, |paced_until| ready_at.max(paced_until)),
+        )
+    }
+}
+
+/// Central queue of the messages sent to every server, so that neither
+/// typing, pasting nor Lua plugins get us disconnected for flooding.
+///
+/// Messages are sent through [`Outgoing::flush`] as the rate limit of their
+/// server allows. Clones share the same queues.
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+    queues: Arc<Mutex<HashMap<String, ServerQueue>>>,
+}
+
+impl Outgoing {
+    pub fn new(servers: &[ServerConfig]) -> Self {
+        let now = Instant::now();
+        let queues = servers
+            .iter()
+            .map(|server_config| {
+                (
+                    server_config.name().to_owned(),
+                    ServerQueue::new(&server_config.flood, now),
+                )
+            })
+            .collect();
+
+        Self {
+            queues: Arc::new(Mutex::new(queues)),
+        }
+    }
+
+    /// Applies the flood control of a reloaded config, adding queues for new
+    /// servers and dropping those of removed ones.
+    pub fn configure(&self, servers: &[ServerConfig]) {
+        let now = Instant::now();
+        let mut queues = self.queues();
+
+        queues.retain(|server, _| servers.iter().any(|config| config.name() == server));
+
+        for server_config in servers {
+            let flood = &server_config.flood;
+
+            match queues.get_mut(server_config.name()) {
+                Some(queue) if queue.flood != *flood => {
+                    queue.flood = flood.clone();
+                    queue.bucket = flood.enabled.then(|| TokenBucket::new(flood, now));
+                }
+                Some(_) => {}
+                None => {
+                    queues.insert(
+                        server_config.name().to_owned(),
+                        ServerQueue::new(flood, now),
+                    );
+                }
+            }
+        }
+    }
+
+    fn queues(&self) -> MutexGuard<'_, HashMap<String, ServerQueue>> {
+        self.queues.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sends the queued messages of `server` through the client of a new
+    /// connection.
+    pub fn connect(&self, server: &str, sender: Sender) {
+        if let Some(queue) = self.queues().get_mut(server) {
+            queue.sender = Some(sender);
+        }
+    }
+
+    /// Forgets the connection to `server`, returning the messages that were
+    /// not sent before it was lost.
+    pub fn disconnect(&self, server: &str) -> Vec<Queued> {
+        match self.queues().get_mut(server) {
+            Some(queue) => {
+                queue.sender = None;
+                queue.queued.drain(..).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Queues `message` to `server`.
+    pub fn send(&self, server: &str, message: impl Into<Message>) -> anyhow::Result<()> {
+        self.enqueue(server, Queued::new(message))
+    }
+
+    /// Queues messages to `server`, unless they have priority, in which case
+    /// they are sent right away.
+    pub fn enqueue(&self, server: &str, queued: Queued) -> anyhow::Result<()> {
+        let mut queues = self.queues();
+        let Some((queue, sender)) = queues
+            .get_mut(server)
+            .and_then(|queue| queue.sender.clone().map(|sender| (queue, sender)))
+        else {
+            anyhow::bail!("Not connected to server '{}'", server);
+        };
+
+        if queued.is_priority() {
+            if let Some(bucket) = &mut queue.bucket {
+                bucket.take(Instant::now(), queued.messages.len());
+            }
+
+            for message in queued.messages {
+                sender.send(message)?;
+            }
+        } else {
+            queue.queued.push_back(queued);
+        }
+
+        Ok(())
+    }
+
+    /// Sends `QUIT` right away to every connected server. Connections lost in
+    /// the meantime are skipped, so that quitting cannot fail.
+    pub fn quit(&self, message: &str) {
+        for queue in self.queues().values() {
+            if let Some(sender) = &queue.sender {
+                let _ = sender.send(Command::QUIT(Some(message.to_owned())));
+            }
+        }
+    }
+
+    /// Sends the queued messages the rate limits allow.
+    pub fn flush(&self) -> Flushed {
+        let now = Instant::now();
+        let mut flushed = Flushed::default();
+
+        for (server, queue) in self.queues().iter_mut() {
+            let Some(sender) = queue.sender.clone() else {
+                continue;
+            };
+
+            while let Some(queued) = queue.pop_due(now) {
+                let result = queued
+                    .messages
+                    .iter()
+                    .try_for_each(|message| sender.send(message.clone()));
+
+                if let Err(err) = result {
+                    queue.queued.push_front(queued);
+                    flushed.errors.push((server.clone(), err.into()));
+                    break;
+                }
+
+                flushed.sent.push((server.clone(), queued));
+            }
+        }
+
+        flushed
+    }
+
+    /// When the next queued message of any server can be sent, `None` if
+    /// nothing is queued.
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.queues()
+            .values()
+            .filter_map(ServerQueue::ready_at)
+            .min()
+    }
+
+    /// Removes the queued messages to `server` sent from the buffer `target`,
+    /// or from any buffer if `None`, returning them.
+    pub fn cancel(&self, server: &str, target: Option<&str>) -> Vec<Queued> {
+        let mut queues = self.queues();
+        let Some(queue) = queues.get_mut(server) else {
+            return Vec::new();
+        };
+        let (cancelled, kept) = queue.queued.drain(..).partition(|queued| {
+            target.is_none_or(|target| queued.target.as_deref() == Some(target))
+        });
+
+        queue.queued = kept;
+
+        cancelled.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use irc::client::{data::Config, Client};
+    use mlua::LuaSerdeExt;
+
+    use super::*;
+
+    fn flood(burst: u32, interval: u64) -> FloodConfig {
+        FloodConfig {
+            enabled: true,
+            burst,
+            interval,
+        }
+    }
+
+    fn privmsg(text: &str) -> Queued {
+        Queued {
+            target: Some("#tirc".to_owned()),
+            ..Queued::new(Command::PRIVMSG("#tirc".to_owned(), text.to_owned()))
+        }
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_paces() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&flood(2, 1000), now);
+
+        assert!(bucket.take(now, 1));
+        assert!(bucket.take(now, 1));
+        assert!(!bucket.take(now, 1));
+        assert_eq!(bucket.ready_at(), now + Duration::from_secs(1));
+
+        assert!(!bucket.take(now + Duration::from_millis(999), 1));
+        assert!(bucket.take(now + Duration::from_millis(1500), 1));
+        assert!(!bucket.take(now + Duration::from_millis(1500), 1));
+        assert!(bucket.take(now + Duration::from_secs(2), 1));
+
+        // Idle time does not earn more than a burst.
+        let later = now + Duration::from_secs(60);
+        assert!(bucket.take(later, 1));
+        assert!(bucket.take(later, 1));
+        assert!(!bucket.take(later, 1));
+    }
+
+    #[test]
+    fn messages_sent_at_once_take_a_token_each() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&flood(2, 1000), now);
+
+        assert!(bucket.take(now, 5));
+        assert_eq!(bucket.ready_at(), now + Duration::from_secs(4));
+        assert!(!bucket.take(now + Duration::from_millis(3999), 1));
+        assert!(bucket.take(now + Duration::from_secs(4), 1));
+        assert!(!bucket.take(now + Duration::from_secs(4), 1));
+    }
+
+    #[tokio::test]
+    async fn messages_that_cannot_be_sent_stay_queued() {
+        let client = |nickname: &str| {
+            Client::from_config(Config {
+                nickname: Some(nickname.to_owned()),
+                use_mock_connection: true,
+                ..Default::default()
+            })
+        };
+        let up = client("up").await.unwrap();
+        let down = client("down").await.unwrap();
+        let outgoing = Outgoing {
+            queues: Arc::new(Mutex::new(HashMap::from([
+                (
+                    "up".to_owned(),
+                    ServerQueue::new(&flood(5, 1000), Instant::now()),
+                ),
+                (
+                    "down".to_owned(),
+                    ServerQueue::new(&flood(5, 1000), Instant::now()),
+                ),
+            ]))),
+        };
+
+        outgoing.connect("up", up.sender());
+        outgoing.connect("down", down.sender());
+        drop(down);
+
+        for server in ["up", "down"] {
+            outgoing.enqueue(server, privmsg("one")).unwrap();
+            outgoing.enqueue(server, privmsg("two")).unwrap();
+        }
+
+        let Flushed { sent, errors } = outgoing.flush();
+        let sent: Vec<&str> = sent.iter().map(|(server, _)| server.as_str()).collect();
+        let errors: Vec<&str> = errors.iter().map(|(server, _)| server.as_str()).collect();
+
+        assert_eq!(sent, ["up", "up"]);
+        assert_eq!(errors, ["down"]);
+        assert_eq!(outgoing.disconnect("down").len(), 2);
+    }
+
+    #[test]
+    fn queued_messages_are_sent_as_the_rate_limit_allows() {
+        let now = Instant::now();
+        let mut queue = ServerQueue::new(&flood(1, 1000), now);
+
+        assert!(queue.ready_at().is_none());
+
+        queue.queued.push_back(privmsg("one"));
+        queue.queued.push_back(privmsg("two"));
+
+        assert!(queue.pop_due(now).is_some());
+        assert!(queue.pop_due(now).is_none());
+        assert_eq!(queue.ready_at(), Some(now + Duration::from_secs(1)));
+        assert!(queue.pop_due(now + Duration::from_secs(1)).is_some());
+        assert!(queue.ready_at().is_none());
+    }
+
+    #[test]
+    fn disabled_flood_control_sends_everything() {
+        let now = Instant::now();
+        let mut queue = ServerQueue::new(
+            &FloodConfig {
+                enabled: false,
+                ..flood(1, 1000)
+            },
+            now,
+        );
+
+        for text in ["one", "two", "three"] {
+            queue.queued.push_back(privmsg(text));
+        }
+
+        assert_eq!(std::iter::from_fn(|| queue.pop_due(now)).count(), 3);
+    }
+
+    #[test]
+    fn paced_messages_wait_on_top_of_the_rate_limit() {
+        let now = Instant::now();
+        let mut queue = ServerQueue::new(&flood(5, 1000), now);
+        let pace = Some(Duration::from_millis(500));
+
+        queue.queued.push_back(privmsg("one"));
+        queue.queued.push_back(Queued {
+            pace,
+            ..privmsg("two")
+        });
+
+        assert!(queue.pop_due(now).is_some());
+        assert!(queue.pop_due(now).is_none());
+        assert_eq!(queue.ready_at(), Some(now + Duration::from_millis(500)));
+        assert!(queue.pop_due(now + Duration::from_millis(500)).is_some());
+    }
+
+    #[test]
+    fn quit_has_priority() {
+        assert!(Queued::new(Command::QUIT(None)).is_priority());
+        assert!(!Queued::new(Command::PONG("srv".to_owned(), None)).is_priority());
+        assert!(!privmsg("hi").is_priority());
+    }
+
+    #[test]
+    fn quitting_while_disconnected_succeeds() {
+        let outgoing = Outgoing {
+            queues: Arc::new(Mutex::new(HashMap::from([(
+                "net".to_owned(),
+                ServerQueue::new(&flood(1, 1000), Instant::now()),
+            )]))),
+        };
+
+        outgoing.quit("tirc");
+
+        assert!(outgoing.send("net", Command::QUIT(None)).is_err());
+        assert!(outgoing.next_flush().is_none());
+    }
+
+    #[test]
+    fn cancel_removes_the_messages_of_a_buffer() {
+        let outgoing = Outgoing {
+            queues: Arc::new(Mutex::new(HashMap::from([(
+                "net".to_owned(),
+                ServerQueue::new(&flood(1, 1000), Instant::now()),
+            )]))),
+        };
+
+        {
+            let mut queues = outgoing.queues();
+            let queue = queues.get_mut("net").unwrap();
+
+            queue.queued.push_back(privmsg("one"));
+            queue
+                .queued
+                .push_back(Queued::new(Command::LIST(None, None)));
+            queue.queued.push_back(privmsg("two"));
+        }
+
+        assert_eq!(outgoing.cancel("net", Some("#rust")).len(), 0);
+        assert_eq!(outgoing.cancel("net", Some("#tirc")).len(), 2);
+        assert_eq!(outgoing.cancel("net", None).len(), 1);
+        assert!(outgoing.next_flush().is_none());
+        assert!(outgoing.cancel("other", None).is_empty());
+    }
+
+    #[test]
+    fn configure_follows_the_servers_of_a_reloaded_config() {
+        let lua = mlua::Lua::new();
+        let servers = |servers: &str| -> Vec<ServerConfig> {
+            lua.from_value(lua.load(servers).eval().unwrap()).unwrap()
+        };
+        let outgoing = Outgoing::new(&servers(
+            "{ { host = 'a', nickname = { 'tirc' } }, { host = 'b', nickname = { 'tirc' } } }",
+        ));
+
+        outgoing.configure(&servers(
+            "{ { host = 'a', nickname = { 'tirc' }, flood = { enabled = false } },
+               { host = 'c', nickname = { 'tirc' } } }",
+        ));
+
+        let queues = outgoing.queues();
+        let mut servers: Vec<&str> = queues.keys().map(String::as_str).collect();
+        servers.sort();
+
+        assert_eq!(servers, ["a", "c"]);
+        assert!(queues["a"].bucket.is_none());
+        assert!(queues["c"].bucket.is_some());
+    }
+}
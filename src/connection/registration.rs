@@ -0,0 +1,773 @@
+use std::collections::BTreeSet;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use indexmap::IndexMap;
+use irc::{
+    client::{prelude::Capability, Client},
+    proto::{CapSubCommand, Command, Message, Response},
+};
+
+use crate::{
+    config::{SaslConfig, SaslMechanism},
+    ui::NoticeLevel,
+};
+
+/// Capabilities tirc makes use of, requested whenever the server offers them.
+const SUPPORTED_CAPABILITIES: &[Capability] = &[
+    Capability::EchoMessage,
+    Capability::MultiPrefix,
+    Capability::ExtendedJoin,
+    Capability::AwayNotify,
+    Capability::ChgHost,
+    Capability::AccountNotify,
+    Capability::ServerTime,
+    Capability::UserhostInNames,
+    Capability::Batch,
+    Capability::CapNotify,
+    Capability::Custom("labeled-response"),
+    Capability::Custom("message-tags"),
+    Capability::Custom("draft/chathistory"),
+    Capability::Custom("draft/multiline"),
+];
+
+/// Maximum length of a single `AUTHENTICATE` payload chunk.
+const AUTHENTICATE_CHUNK_SIZE: usize = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SaslState {
+    /// SASL is not configured, not acknowledged yet or finished.
+    Inactive,
+    /// Waiting for `AUTHENTICATE +` or the final numeric.
+    Authenticating,
+}
+
+/// A line to report in the status buffer of the server.
+pub type Notice = (NoticeLevel, String);
+
+/// Drives connection registration: CAP 302 negotiation of the capabilities
+/// the server offers and tirc supports, and, if configured, SASL
+/// authentication before `CAP END` lets registration complete.
+///
+/// After registration it keeps tracking the enabled capabilities through
+/// `CAP NEW` and `CAP DEL`.
+#[derive(Debug)]
+pub struct Registration {
+    sasl: Option<SaslConfig>,
+    account: String,
+    realname: Option<String>,
+    /// Whether `CAP END` is still outstanding.
+    negotiating: bool,
+    /// Whether a (possibly multi-line) `CAP LS` reply is being received.
+    listing: bool,
+    /// Number of `CAP REQ` sent but not yet ACKed or NAKed.
+    pending_requests: usize,
+    sasl_state: SaslState,
+    /// Capabilities offered by the server, with their value if any.
+    available: IndexMap<String, Option<String>>,
+    enabled: BTreeSet<String>,
+}
+
+impl Registration {
+    /// Creates the registration of a connection. `account` is the `PLAIN`
+    /// account used when `sasl.account` is not set.
+    pub fn new(sasl: Option<SaslConfig>, account: &str, realname: Option<String>) -> Self {
+        Self {
+            sasl,
+            account: account.to_owned(),
+            realname,
+            negotiating: false,
+            listing: false,
+            pending_requests: 0,
+            sasl_state: SaslState::Inactive,
+            available: IndexMap::new(),
+            enabled: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    /// Capabilities the server acknowledged.
+    pub fn enabled_capabilities(&self) -> &BTreeSet<String> {
+        &self.enabled
+    }
+
+    /// The value an enabled capability was offered with, e.g. the limits of
+    /// `draft/multiline`.
+    pub fn capability_value(&self, capability: &str) -> Option<&str> {
+        if !self.enabled.contains(capability) {
+            return None;
+        }
+
+        self.available.get(capability)?.as_deref()
+    }
+
+    /// Sends the opening lines of the registration on a new connection.
+    pub fn start(&mut self, client: &Client) -> irc::error::Result<()> {
+        self.negotiating = true;
+        self.listing = true;
+        self.pending_requests = 0;
+        self.sasl_state = SaslState::Inactive;
+        self.available.clear();
+        self.enabled.clear();
+
+        client.send(Command::CAP(
+            None,
+            CapSubCommand::LS,
+            Some("302".to_owned()),
+            None,
+        ))?;
+
+        // Same as `Client::identify`, but without `CAP END`, which holds
+        // registration back until negotiation finished.
+        let nickname = client.current_nickname().to_owned();
+        let realname = self.realname.clone().unwrap_or_else(|| nickname.clone());
+
+        client.send(Command::NICK(nickname.clone()))?;
+        client.send(Command::USER(nickname, "0".to_owned(), realname))?;
+
+        Ok(())
+    }
+
+    /// Advances the negotiation with a message received from the server.
+    pub fn handle_message(
+        &mut self,
+        client: &Client,
+        message: &Message,
+    ) -> irc::error::Result<Option<Notice>> {
+        match &message.command {
+            Command::CAP(_, CapSubCommand::LS, a, b) if self.listing => {
+                let (capabilities, more) = cap_reply_params(a, b);
+
+                self.offer(capabilities);
+
+                if more {
+                    return Ok(None);
+                }
+
+                self.listing = false;
+
+                let (sasl, notice) = self.sasl_request();
+                let request = self.supported_request();
+
+                // Requested on its own so that a server refusing any other
+                // capability does not prevent authentication.
+                if sasl {
+                    self.request(client, &["sasl".to_owned()])?;
+                }
+
+                if !request.is_empty() {
+                    self.request(client, &request)?;
+                }
+
+                if self.pending_requests == 0 {
+                    self.finish(client)?;
+                }
+
+                Ok(notice)
+            }
+            Command::CAP(_, CapSubCommand::NEW, a, b) => {
+                let (capabilities, _) = cap_reply_params(a, b);
+
+                self.offer(capabilities);
+
+                let request = self.supported_request();
+
+                if !request.is_empty() {
+                    self.request(client, &request)?;
+                }
+
+                Ok(None)
+            }
+            Command::CAP(_, CapSubCommand::DEL, a, b) => {
+                let (capabilities, _) = cap_reply_params(a, b);
+
+                for capability in capabilities.split_whitespace() {
+                    self.available.shift_remove(capability);
+                    self.enabled.remove(capability);
+                }
+
+                Ok(None)
+            }
+            Command::CAP(_, CapSubCommand::ACK, a, b) => {
+                let (capabilities, _) = cap_reply_params(a, b);
+
+                self.pending_requests = self.pending_requests.saturating_sub(1);
+
+                for capability in capabilities.split_whitespace() {
+                    match capability.strip_prefix('-') {
+                        Some(capability) => self.enabled.remove(capability),
+                        None => self.enabled.insert(capability.to_owned()),
+                    };
+                }
+
+                let sasl = capabilities.split_whitespace().any(|c| c == "sasl");
+
+                match &self.sasl {
+                    Some(config) if sasl && self.negotiating => {
+                        self.sasl_state = SaslState::Authenticating;
+                        client.send(Command::AUTHENTICATE(config.mechanism.as_str().to_owned()))?;
+                    }
+                    _ => self.finish_if_done(client)?,
+                }
+
+                Ok(None)
+            }
+            Command::CAP(_, CapSubCommand::NAK, a, b) => {
+                let (capabilities, _) = cap_reply_params(a, b);
+
+                self.pending_requests = self.pending_requests.saturating_sub(1);
+                self.finish_if_done(client)?;
+
+                Ok(Some((
+                    NoticeLevel::Error,
+                    format!("Server refused capabilities: {}", capabilities),
+                )))
+            }
+            Command::AUTHENTICATE(data)
+                if data == "+" && self.sasl_state == SaslState::Authenticating =>
+            {
+                for chunk in authenticate_chunks(&self.credentials()) {
+                    client.send(Command::AUTHENTICATE(chunk))?;
+                }
+
+                Ok(None)
+            }
+            Command::Response(Response::RPL_WELCOME, _) => {
+                // Servers without capability negotiation register right away.
+                self.negotiating = false;
+                self.listing = false;
+
+                Ok(None)
+            }
+            Command::Response(response, args) if self.sasl_state == SaslState::Authenticating => {
+                self.handle_sasl_response(client, response, args)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_sasl_response(
+        &mut self,
+        client: &Client,
+        response: &Response,
+        args: &[String],
+    ) -> irc::error::Result<Option<Notice>> {
+        let mechanism = self
+            .sasl
+            .as_ref()
+            .map_or(SaslMechanism::Plain, |sasl| sasl.mechanism)
+            .as_str();
+        let text = args.last().cloned().unwrap_or_default();
+
+        match response {
+            Response::RPL_SASLSUCCESS => {
+                self.sasl_state = SaslState::Inactive;
+                self.finish_if_done(client)?;
+
+                Ok(Some((
+                    NoticeLevel::Info,
+                    format!("SASL {} authentication successful", mechanism),
+                )))
+            }
+            Response::RPL_SASLMECHS => {
+                let mechanisms = args.get(1).map_or("", String::as_str);
+                let supported = mechanisms
+                    .split(',')
+                    .any(|supported| supported.eq_ignore_ascii_case(mechanism));
+
+                Ok(Some(if supported {
+                    (
+                        NoticeLevel::Info,
+                        format!("Server supports SASL mechanisms: {}", mechanisms),
+                    )
+                } else {
+                    (
+                        NoticeLevel::Warning,
+                        format!(
+                            "Server does not support SASL {}, only: {}",
+                            mechanism, mechanisms
+                        ),
+                    )
+                }))
+            }
+            Response::ERR_NICKLOCKED
+            | Response::ERR_SASLFAIL
+            | Response::ERR_SASLTOOLONG
+            | Response::ERR_SASLABORT
+            | Response::ERR_SASLALREADY => {
+                self.sasl_state = SaslState::Inactive;
+                self.finish_if_done(client)?;
+
+                Ok(Some((
+                    NoticeLevel::Error,
+                    format!("SASL {} authentication failed: {}", mechanism, text),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Records the capabilities of a `CAP LS` or `CAP NEW` reply.
+    fn offer(&mut self, capabilities: &str) {
+        for capability in capabilities.split_whitespace() {
+            let (name, value) = match capability.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (capability, None),
+            };
+
+            self.available.insert(name.to_owned(), value);
+        }
+    }
+
+    /// Supported capabilities the server offers that are not enabled yet.
+    fn supported_request(&self) -> Vec<String> {
+        SUPPORTED_CAPABILITIES
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|capability| {
+                self.available.contains_key(*capability) && !self.enabled.contains(*capability)
+            })
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Whether to request `sasl`, which it is if configured and the server
+    /// supports the configured mechanism, otherwise explains why
+    /// authentication is skipped.
+    fn sasl_request(&self) -> (bool, Option<Notice>) {
+        let Some(sasl) = &self.sasl else {
+            return (false, None);
+        };
+
+        let mechanism = sasl.mechanism.as_str();
+
+        match self.available.get("sasl") {
+            None => (
+                false,
+                Some((
+                    NoticeLevel::Error,
+                    "Server does not support SASL".to_owned(),
+                )),
+            ),
+            Some(Some(mechanisms))
+                if !mechanisms
+                    .split(',')
+                    .any(|available| available.eq_ignore_ascii_case(mechanism)) =>
+            {
+                (
+                    false,
+                    Some((
+                        NoticeLevel::Error,
+                        format!(
+                            "Server does not support SASL {}, available mechanisms: {}",
+                            mechanism, mechanisms
+                        ),
+                    )),
+                )
+            }
+            Some(_) => (true, None),
+        }
+    }
+
+    fn request(&mut self, client: &Client, capabilities: &[String]) -> irc::error::Result<()> {
+        self.pending_requests += 1;
+
+        client.send(Command::CAP(
+            None,
+            CapSubCommand::REQ,
+            None,
+            Some(capabilities.join(" ")),
+        ))
+    }
+
+    /// The decoded `AUTHENTICATE` payload for the configured mechanism.
+    fn credentials(&self) -> Vec<u8> {
+        let Some(sasl) = &self.sasl else {
+            return Vec::new();
+        };
+
+        match sasl.mechanism {
+            SaslMechanism::Plain => {
+                let account = sasl.account.as_deref().unwrap_or(&self.account);
+                let password = sasl.password.as_deref().unwrap_or_default();
+
+                format!("{account}\0{account}\0{password}").into_bytes()
+            }
+            SaslMechanism::External => Vec::new(),
+        }
+    }
+
+    /// Ends negotiation once every request was answered and authentication
+    /// finished.
+    fn finish_if_done(&mut self, client: &Client) -> irc::error::Result<()> {
+        if self.negotiating
+            && !self.listing
+            && self.pending_requests == 0
+            && self.sasl_state == SaslState::Inactive
+        {
+            self.finish(client)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends negotiation, letting registration complete.
+    fn finish(&mut self, client: &Client) -> irc::error::Result<()> {
+        self.negotiating = false;
+        client.send(Command::CAP(None, CapSubCommand::END, None, None))
+    }
+}
+
+/// Extracts the capability list from the trailing params of a `CAP` reply,
+/// along with whether more lines follow (`CAP * LS * :...`).
+fn cap_reply_params<'a>(a: &'a Option<String>, b: &'a Option<String>) -> (&'a str, bool) {
+    match (a.as_deref(), b.as_deref()) {
+        (Some("*"), Some(capabilities)) => (capabilities, true),
+        (Some(capabilities), None) => (capabilities, false),
+        (_, Some(capabilities)) => (capabilities, false),
+        (None, None) => ("", false),
+    }
+}
+
+/// Encodes an `AUTHENTICATE` payload into base64 chunks of at most 400
+/// bytes. A payload that is empty or ends on a chunk boundary is terminated by
+/// a lone `+`.
+fn authenticate_chunks(payload: &[u8]) -> Vec<String> {
+    let encoded = STANDARD.encode(payload);
+    let mut chunks: Vec<String> = encoded
+        .as_bytes()
+        .chunks(AUTHENTICATE_CHUNK_SIZE)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect();
+
+    if encoded.len().is_multiple_of(AUTHENTICATE_CHUNK_SIZE) {
+        chunks.push("+".to_owned());
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use irc::client::prelude::Config;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn authenticate_chunks_terminate_on_boundaries() {
+        assert_eq!(authenticate_chunks(b""), ["+"]);
+        assert_eq!(authenticate_chunks(b"a\0a\0pw"), ["YQBhAHB3"]);
+
+        let chunks = authenticate_chunks(&[0; 300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 400);
+        assert_eq!(chunks[1], "+");
+    }
+
+    #[test]
+    fn cap_reply_params_detects_continuations() {
+        let star = Some("*".to_owned());
+        let caps = Some("sasl batch".to_owned());
+
+        assert_eq!(cap_reply_params(&star, &caps), ("sasl batch", true));
+        assert_eq!(cap_reply_params(&caps, &None), ("sasl batch", false));
+    }
+
+    /// Acknowledges every requested capability.
+    fn ack(line: &str) -> Option<String> {
+        let capabilities = line.strip_prefix("CAP REQ ")?;
+        Some(format!(
+            ":srv CAP tirc ACK :{}",
+            capabilities.trim_start_matches(':')
+        ))
+    }
+
+    /// Runs a registration against a scripted local server that stops after
+    /// receiving `last_line`. Returns the lines the server received, the
+    /// notices reported to the user and the finished registration.
+    async fn register_with_mock_server(
+        sasl: Option<SaslConfig>,
+        last_line: &'static str,
+        respond: fn(&str) -> Vec<String>,
+    ) -> (Vec<String>, Vec<Notice>, Registration) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut received = Vec::new();
+
+            while let Some(line) = lines.next_line().await.unwrap() {
+                for response in respond(&line) {
+                    writer.write_all(response.as_bytes()).await.unwrap();
+                    writer.write_all(b"\r\n").await.unwrap();
+                }
+
+                let done = line == last_line;
+                received.push(line);
+
+                if done {
+                    break;
+                }
+            }
+
+            received
+        });
+
+        let mut client = Client::from_config(Config {
+            nickname: Some("tirc".to_owned()),
+            server: Some("127.0.0.1".to_owned()),
+            port: Some(port),
+            use_tls: Some(false),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut stream = client.stream().unwrap();
+        let mut registration = Registration::new(sasl, "tirc", None);
+        let mut notices = Vec::new();
+
+        registration.start(&client).unwrap();
+
+        // The stream ends once the server hung up after `last_line`.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(message)) = stream.next().await {
+                if let Some(notice) = registration.handle_message(&client, &message).unwrap() {
+                    notices.push(notice);
+                }
+            }
+        })
+        .await
+        .expect("server hung up");
+
+        (server.await.unwrap(), notices, registration)
+    }
+
+    #[tokio::test]
+    async fn requests_only_offered_capabilities() {
+        let (received, notices, registration) =
+            register_with_mock_server(None, "CAP END", |line| match line {
+                "CAP LS 302" => vec![
+                    ":srv CAP * LS * :multi-prefix draft/unknown".to_owned(),
+                    ":srv CAP * LS :batch=whatever server-time".to_owned(),
+                ],
+                line => ack(line).into_iter().collect(),
+            })
+            .await;
+
+        assert_eq!(
+            received,
+            [
+                "CAP LS 302",
+                "NICK tirc",
+                "USER tirc 0 * tirc",
+                "CAP REQ :multi-prefix server-time batch",
+                "CAP END",
+            ]
+        );
+        assert!(notices.is_empty());
+        assert_eq!(
+            registration.enabled_capabilities(),
+            &BTreeSet::from([
+                "batch".to_owned(),
+                "multi-prefix".to_owned(),
+                "server-time".to_owned()
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_capabilities_still_end_negotiation() {
+        let (received, notices, registration) =
+            register_with_mock_server(None, "CAP END", |line| match line {
+                "CAP LS 302" => vec![":srv CAP * LS :batch".to_owned()],
+                "CAP REQ batch" | "CAP REQ :batch" => {
+                    vec![":srv CAP tirc NAK :batch".to_owned()]
+                }
+                _ => vec![],
+            })
+            .await;
+
+        assert_eq!(received.last().map(String::as_str), Some("CAP END"));
+        assert_eq!(
+            notices,
+            [(
+                NoticeLevel::Error,
+                "Server refused capabilities: batch".to_owned()
+            )]
+        );
+        assert!(registration.enabled_capabilities().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tracks_new_and_deleted_capabilities() {
+        let (received, _, registration) =
+            register_with_mock_server(None, "CAP REQ server-time", |line| match line {
+                "CAP LS 302" => vec![":srv CAP * LS :batch cap-notify".to_owned()],
+                "CAP END" => vec![
+                    ":srv CAP tirc NEW :server-time draft/unknown".to_owned(),
+                    ":srv CAP tirc DEL :batch".to_owned(),
+                ],
+                line => ack(line).into_iter().collect(),
+            })
+            .await;
+
+        assert_eq!(
+            received[3..],
+            [
+                "CAP REQ :batch cap-notify",
+                "CAP END",
+                "CAP REQ server-time"
+            ]
+        );
+        assert_eq!(
+            registration.enabled_capabilities(),
+            &BTreeSet::from(["cap-notify".to_owned(), "server-time".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn sasl_plain_exchange() {
+        let sasl = SaslConfig {
+            mechanism: SaslMechanism::Plain,
+            account: Some("acct".to_owned()),
+            password: Some("secret".to_owned()),
+            cert_path: None,
+            cert_password: None,
+        };
+
+        let (received, notices, _) =
+            register_with_mock_server(Some(sasl), "CAP END", |line| match line {
+                "CAP LS 302" => vec![
+                    ":srv CAP * LS * :multi-prefix batch".to_owned(),
+                    ":srv CAP * LS :sasl=PLAIN,EXTERNAL server-time".to_owned(),
+                ],
+                "AUTHENTICATE PLAIN" => vec!["AUTHENTICATE +".to_owned()],
+                line if line.starts_with("AUTHENTICATE ") => vec![
+                    ":srv 900 tirc tirc!u@h acct :You are now logged in as acct".to_owned(),
+                    ":srv 903 tirc :SASL authentication successful".to_owned(),
+                ],
+                line => ack(line).into_iter().collect(),
+            })
+            .await;
+
+        let expected = format!("AUTHENTICATE {}", STANDARD.encode("acct\0acct\0secret"));
+        assert_eq!(
+            received[3..],
+            [
+                "CAP REQ sasl",
+                "CAP REQ :multi-prefix server-time batch",
+                "AUTHENTICATE PLAIN",
+                &expected,
+                "CAP END",
+            ]
+        );
+        assert_eq!(
+            notices,
+            [(
+                NoticeLevel::Info,
+                "SASL PLAIN authentication successful".to_owned()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_capabilities_do_not_prevent_sasl() {
+        let sasl = SaslConfig {
+            mechanism: SaslMechanism::External,
+            account: None,
+            password: None,
+            cert_path: None,
+            cert_password: None,
+        };
+
+        let (received, notices, registration) =
+            register_with_mock_server(Some(sasl), "CAP END", |line| match line {
+                "CAP LS 302" => vec![":srv CAP * LS :sasl batch".to_owned()],
+                "CAP REQ batch" => vec![":srv CAP tirc NAK :batch".to_owned()],
+                "AUTHENTICATE EXTERNAL" => vec!["AUTHENTICATE +".to_owned()],
+                "AUTHENTICATE +" => {
+                    vec![":srv 903 tirc :SASL authentication successful".to_owned()]
+                }
+                line => ack(line).into_iter().collect(),
+            })
+            .await;
+
+        assert_eq!(
+            received[3..],
+            [
+                "CAP REQ sasl",
+                "CAP REQ batch",
+                "AUTHENTICATE EXTERNAL",
+                "AUTHENTICATE +",
+                "CAP END",
+            ]
+        );
+        assert_eq!(
+            notices,
+            [
+                (
+                    NoticeLevel::Error,
+                    "Server refused capabilities: batch".to_owned()
+                ),
+                (
+                    NoticeLevel::Info,
+                    "SASL EXTERNAL authentication successful".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            registration.enabled_capabilities(),
+            &BTreeSet::from(["sasl".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn sasl_failure_is_reported() {
+        let sasl = SaslConfig {
+            mechanism: SaslMechanism::External,
+            account: None,
+            password: None,
+            cert_path: None,
+            cert_password: None,
+        };
+
+        let (received, notices, _) =
+            register_with_mock_server(Some(sasl), "CAP END", |line| match line {
+                "CAP LS 302" => vec![":srv CAP * LS :sasl".to_owned()],
+                "AUTHENTICATE EXTERNAL" => vec!["AUTHENTICATE +".to_owned()],
+                "AUTHENTICATE +" => vec![
+                    ":srv 908 tirc PLAIN :are available SASL mechanisms".to_owned(),
+                    ":srv 904 tirc :SASL authentication failed".to_owned(),
+                ],
+                line => ack(line).into_iter().collect(),
+            })
+            .await;
+
+        assert_eq!(received.last().map(String::as_str), Some("CAP END"));
+        assert_eq!(
+            notices,
+            [
+                (
+                    NoticeLevel::Warning,
+                    "Server does not support SASL EXTERNAL, only: PLAIN".to_owned()
+                ),
+                (
+                    NoticeLevel::Error,
+                    "SASL EXTERNAL authentication failed: SASL authentication failed".to_owned()
+                ),
+            ]
+        );
+    }
+}
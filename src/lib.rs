@@ -1,4 +1,8 @@
+pub mod cli;
 pub mod config;
+pub mod connection;
+pub mod logging;
 pub mod lua;
+pub mod notification;
 pub mod tui;
 pub mod ui;
@@ -0,0 +1,691 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use irc::proto::{message::Tag, Command, Message, Prefix};
+use regex::Regex;
+use tokio::task::{self, JoinHandle};
+
+use crate::{
+    config::{LogFormat, LoggingConfig},
+    ui::{commands, BufferId, TircMessage},
+};
+
+struct LogFile {
+    date: NaiveDate,
+    file: File,
+}
+
+/// Appends the messages of logged buffers to daily rotated files at
+/// `<directory>/<server>/<buffer>/<YYYY-MM-DD>.log`.
+pub struct Logger {
+    config: LoggingConfig,
+    /// `None` if no buffer is logged and there is no XDG data directory.
+    directory: Option<PathBuf>,
+    /// When tirc started, messages logged since are not restored.
+    started_at: DateTime<Local>,
+    files: RefCell<HashMap<BufferId, LogFile>>,
+}
+
+impl Logger {
+    /// Creates the logger, and the log directory if any buffer is logged.
+    pub fn new(config: &LoggingConfig) -> anyhow::Result<Self> {
+        let directory = match &config.directory {
+            Some(directory) => Some(PathBuf::from(directory)),
+            None => xdg::BaseDirectories::with_prefix("tirc")
+                .get_data_home()
+                .map(|data_home| data_home.join("logs")),
+        };
+
+        if config.is_used() {
+            let directory = directory
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("Unable to find the XDG data directory for logs"))?;
+
+            fs::create_dir_all(directory).map_err(|err| {
+                anyhow::anyhow!(
+                    "Unable to create the log directory {}: {}",
+                    directory.display(),
+                    err
+                )
+            })?;
+        }
+
+        Ok(Self {
+            directory,
+            ..Self::with_directory(config, PathBuf::new())
+        })
+    }
+
+    fn with_directory(config: &LoggingConfig, directory: PathBuf) -> Self {
+        Self {
+            config: config.clone(),
+            directory: Some(directory),
+            started_at: Local::now(),
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.config.format
+    }
+
+    pub fn is_enabled(&self, buffer_id: &BufferId) -> bool {
+        self.config.is_enabled(&buffer_id.server, &buffer_id.name)
+    }
+
+    /// Number of messages to restore into a newly opened buffer.
+    pub fn restore_limit(&self, buffer_id: &BufferId) -> usize {
+        if self.is_enabled(buffer_id) {
+            self.config.restore
+        } else {
+            0
+        }
+    }
+
+    /// Directory holding the log files of a buffer. Buffer names are case
+    /// insensitive, so an existing directory whose name only differs in case
+    /// is used, otherwise the directory is named as the buffer.
+    pub fn buffer_directory(&self, buffer_id: &BufferId) -> io::Result<PathBuf> {
+        let directory = self
+            .directory
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no log directory"))?;
+        let server = directory.join(file_name(&buffer_id.server));
+        let name = file_name(&buffer_id.name);
+        let existing = subdirectories(&server)?
+            .into_iter()
+            .find(|path| fold_case(&directory_name(path)) == fold_case(&name));
+
+        Ok(existing.unwrap_or_else(|| server.join(name)))
+    }
+
+    /// Appends `message` to the log of `buffer_id` if that buffer is logged.
+    /// Only messages from IRC are logged, not lines generated by tirc, nor
+    /// messages that may hold a password, e.g. `IDENTIFY` sent to NickServ.
+    /// `nickname` is the source of our own messages, which have no prefix.
+    pub fn log(
+        &self,
+        buffer_id: &BufferId,
+        message: &TircMessage,
+        nickname: &str,
+    ) -> io::Result<()> {
+        let TircMessage::Irc(date_time, message, _) = message else {
+            return Ok(());
+        };
+
+        if !self.is_enabled(buffer_id) || commands::is_secret(message) {
+            return Ok(());
+        }
+
+        let line = match self.config.format {
+            LogFormat::Text => format_text(date_time, message, nickname),
+            LogFormat::Raw => format_raw(date_time, message),
+        };
+
+        let date = date_time.date_naive();
+        let mut files = self.files.borrow_mut();
+        let log_file = match files.get_mut(buffer_id) {
+            Some(log_file) if log_file.date == date => log_file,
+            _ => {
+                let path = self
+                    .buffer_directory(buffer_id)?
+                    .join(format!("{}.log", date.format("%Y-%m-%d")));
+                let file = open_log_file(&path)?;
+
+                files
+                    .entry(buffer_id.clone())
+                    .insert_entry(LogFile { date, file })
+                    .into_mut()
+            }
+        };
+
+        writeln!(log_file.file, "{}", line)
+    }
+
+    /// Reads the last `limit` messages logged in `raw` format for a buffer
+    /// before tirc started, oldest first, along with their receive time.
+    pub fn read_history(
+        &self,
+        buffer_id: &BufferId,
+        limit: usize,
+    ) -> io::Result<Vec<(DateTime<Local>, Message)>> {
+        let paths = log_files(&self.buffer_directory(buffer_id)?)?;
+        let mut history = Vec::new();
+
+        for path in paths.iter().rev() {
+            if history.len() >= limit {
+                break;
+            }
+
+            let mut messages: Vec<_> = fs::read_to_string(path)?
+                .lines()
+                .filter_map(parse_raw)
+                .filter(|(date_time, _)| *date_time < self.started_at)
+                .collect();
+
+            let skip = messages.len().saturating_sub(limit - history.len());
+            messages.drain(..skip);
+            messages.append(&mut history);
+            history = messages;
+        }
+
+        Ok(history)
+    }
+
+    /// Searches the logs of every buffer for lines logged before tirc started
+    /// whose text matches `pattern`, oldest first within each buffer. Reading
+    /// every log file may take long, so the search runs off the UI thread.
+    pub fn grep(&self, pattern: Regex) -> JoinHandle<io::Result<Vec<LogMatch>>> {
+        let directory = self.directory.clone();
+        let started_at = self.started_at;
+
+        task::spawn_blocking(move || match directory {
+            Some(directory) => grep(&directory, started_at, &pattern),
+            None => Ok(Vec::new()),
+        })
+    }
+}
+
+/// A logged line matching a search.
+#[derive(Debug)]
+pub struct LogMatch {
+    /// The buffer the line was logged for, named after its log directory.
+    pub buffer_id: BufferId,
+    pub date_time: DateTime<Local>,
+    /// The line in `text` format, without its timestamp.
+    pub text: String,
+}
+
+/// Searches the logs in `directory`, see [`Logger::grep`].
+fn grep(
+    directory: &Path,
+    started_at: DateTime<Local>,
+    pattern: &Regex,
+) -> io::Result<Vec<LogMatch>> {
+    let mut matches = Vec::new();
+
+    for server in subdirectories(directory)? {
+        for buffer in subdirectories(&server)? {
+            let buffer_id = BufferId::new(directory_name(&server), directory_name(&buffer));
+
+            for path in log_files(&buffer)? {
+                for line in fs::read_to_string(path)?.lines() {
+                    let Some((date_time, text)) = parse_line(line) else {
+                        continue;
+                    };
+
+                    if date_time < started_at && pattern.is_match(&text) {
+                        matches.push(LogMatch {
+                            buffer_id: buffer_id.clone(),
+                            date_time,
+                            text,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+/// The daily log files in a buffer directory, oldest first.
+fn log_files(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+            .collect(),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    // Daily files are named by date.
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn subdirectories(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn directory_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Parses a line written in either format into its time and its text as
+/// written in `text` format, without the timestamp.
+fn parse_line(line: &str) -> Option<(DateTime<Local>, String)> {
+    if let Some((date_time, message)) = parse_raw(line) {
+        let text = format_text(&date_time, &message, "");
+        let (_, text) = text.split_once("] ")?;
+
+        return Some((date_time, text.to_owned()));
+    }
+
+    let (timestamp, text) = line.strip_prefix('[')?.split_once("] ")?;
+    let date_time = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").ok()?;
+    let date_time = Local.from_local_datetime(&date_time).earliest()?;
+
+    Some((date_time, text.to_owned()))
+}
+
+/// Parses a line written in `raw` format, skipping lines without a `time`
+/// tag, e.g. lines written in `text` format.
+fn parse_raw(line: &str) -> Option<(DateTime<Local>, Message)> {
+    let message: Message = line.parse().ok()?;
+    let time = message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|tag| tag.0 == "time")?
+        .1
+        .as_deref()?;
+    let date_time = DateTime::parse_from_rfc3339(time)
+        .ok()?
+        .with_timezone(&Local);
+
+    Some((date_time, message))
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Makes a server or buffer name safe to use as a single path component.
+fn file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|char| match char {
+            '/' | '\\' | '\0' => '_',
+            char => char,
+        })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// Folds the case of a name as IRC does by default (`rfc1459`), where
+/// `[]\~` are the uppercase of `{}|^`.
+pub fn fold_case(name: &str) -> String {
+    name.chars()
+        .map(|char| match char {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            char => char.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// The raw line, with a `time` tag holding the receive time unless the server
+/// already sent one. Labels only identify messages within a session and are
+/// dropped.
+fn format_raw(date_time: &DateTime<Local>, message: &Message) -> String {
+    let mut message = message.clone();
+    let tags = message.tags.get_or_insert_with(Vec::new);
+
+    tags.retain(|tag| tag.0 != "label");
+
+    if !tags.iter().any(|tag| tag.0 == "time") {
+        let time = date_time
+            .with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        tags.push(Tag("time".to_owned(), Some(time)));
+    }
+
+    message.to_string().trim_end().to_owned()
+}
+
+fn format_text(date_time: &DateTime<Local>, message: &Message, nickname: &str) -> String {
+    let source = match &message.prefix {
+        Some(Prefix::Nickname(nickname, _, _)) => nickname.as_str(),
+        Some(Prefix::ServerName(server)) => server.as_str(),
+        None => nickname,
+    };
+    let reason = |reason: &Option<String>| {
+        reason
+            .as_ref()
+            .map(|reason| format!(" ({})", reason))
+            .unwrap_or_default()
+    };
+
+    let text = match &message.command {
+        Command::PRIVMSG(_, text) => match text.strip_prefix("\u{1}ACTION ") {
+            Some(action) => format!("* {} {}", source, action.trim_end_matches('\u{1}')),
+            None => format!("<{}> {}", source, text),
+        },
+        Command::NOTICE(_, text) => format!("-{}- {}", source, text),
+        Command::JOIN(channel, _, _) => format!("--> {} has joined {}", source, channel),
+        Command::PART(channel, message) => {
+            format!("<-- {} has left {}{}", source, channel, reason(message))
+        }
+        Command::QUIT(message) => format!("<-- {} has quit{}", source, reason(message)),
+        Command::KICK(channel, user, message) => format!(
+            "<-- {} was kicked from {} by {}{}",
+            user,
+            channel,
+            source,
+            reason(message)
+        ),
+        Command::NICK(nickname) => format!("-- {} is now known as {}", source, nickname),
+        Command::TOPIC(channel, Some(topic)) => {
+            format!(
+                "-- {} changed the topic of {} to: {}",
+                source, channel, topic
+            )
+        }
+        _ => message.to_string().trim_end().to_owned(),
+    };
+
+    format!("[{}] {}", date_time.format("%Y-%m-%d %H:%M:%S"), text)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("tirc-logging-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn message(lua: &mlua::Lua, date_time: DateTime<Local>, raw: &str) -> TircMessage {
+        let message: Message = raw.parse().expect("valid irc message");
+        let TircMessage::Irc(_, message, lua_message) =
+            TircMessage::from_message(message.into(), lua).unwrap()
+        else {
+            unreachable!()
+        };
+
+        TircMessage::Irc(date_time.into(), message, lua_message)
+    }
+
+    #[test]
+    fn text_format_is_readable() {
+        let date_time = Local.with_ymd_and_hms(2024, 5, 1, 12, 34, 56).unwrap();
+        let text = |raw: &str| format_text(&date_time, &raw.parse().unwrap(), "me");
+
+        assert_eq!(
+            text(":alice!u@h PRIVMSG #tirc :hello"),
+            "[2024-05-01 12:34:56] <alice> hello"
+        );
+        assert_eq!(
+            text("PRIVMSG #tirc :\u{1}ACTION waves\u{1}"),
+            "[2024-05-01 12:34:56] * me waves"
+        );
+        assert_eq!(
+            text(":alice!u@h PART #tirc :bye"),
+            "[2024-05-01 12:34:56] <-- alice has left #tirc (bye)"
+        );
+    }
+
+    #[test]
+    fn raw_format_keeps_server_time() {
+        let date_time = Utc
+            .with_ymd_and_hms(2024, 5, 1, 12, 34, 56)
+            .unwrap()
+            .with_timezone(&Local);
+
+        assert_eq!(
+            format_raw(
+                &date_time,
+                &":a!u@h PRIVMSG #tirc :hi there".parse().unwrap()
+            ),
+            "@time=2024-05-01T12:34:56.000Z :a!u@h PRIVMSG #tirc :hi there"
+        );
+        assert_eq!(
+            format_raw(
+                &date_time,
+                &"@time=2020-01-01T00:00:00.000Z :a!u@h PRIVMSG #tirc :hi there"
+                    .parse()
+                    .unwrap()
+            ),
+            "@time=2020-01-01T00:00:00.000Z :a!u@h PRIVMSG #tirc :hi there"
+        );
+    }
+
+    #[test]
+    fn log_directory_is_created_only_when_logging() {
+        let root = temp_directory("create");
+        let directory = root.join("nested").join("logs");
+        let config = |enabled| LoggingConfig {
+            enabled,
+            directory: Some(directory.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+
+        Logger::new(&config(false)).unwrap();
+        assert!(!directory.exists());
+
+        Logger::new(&config(true)).unwrap();
+        assert!(directory.is_dir());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn logs_rotate_daily_per_buffer() {
+        let lua = mlua::Lua::new();
+        let directory = temp_directory("rotate");
+        let config = LoggingConfig {
+            enabled: true,
+            buffers: HashMap::from([("net/#secret".to_owned(), false)]),
+            ..Default::default()
+        };
+        let logger = Logger::with_directory(&config, directory.clone());
+
+        let first = Local.with_ymd_and_hms(2024, 5, 1, 23, 59, 0).unwrap();
+        let second = Local.with_ymd_and_hms(2024, 5, 2, 0, 1, 0).unwrap();
+        let tirc = BufferId::new("net", "#Tirc");
+        let secret = BufferId::new("net", "#secret");
+
+        // The same buffer under another case shares its directory.
+        let upper = BufferId::new("net", "#TIRC");
+
+        for (buffer_id, date_time) in [
+            (&tirc, first),
+            (&tirc, second),
+            (&secret, first),
+            (&upper, second),
+        ] {
+            let message = message(&lua, date_time, ":a!u@h PRIVMSG #tirc :hi there");
+            logger.log(buffer_id, &message, "me").unwrap();
+        }
+
+        let read = |date: &str| {
+            fs::read_to_string(directory.join("net/#Tirc").join(format!("{}.log", date))).unwrap()
+        };
+
+        assert_eq!(read("2024-05-01"), "[2024-05-01 23:59:00] <a> hi there\n");
+        assert_eq!(
+            read("2024-05-02"),
+            "[2024-05-02 00:01:00] <a> hi there\n[2024-05-02 00:01:00] <a> hi there\n"
+        );
+        assert!(!directory.join("net/#tirc").exists());
+        assert!(!directory.join("net/#secret").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn passwords_are_not_logged() {
+        let lua = mlua::Lua::new();
+        let directory = temp_directory("secret");
+        let config = LoggingConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let logger = Logger::with_directory(&config, directory.clone());
+        let date_time = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+
+        for (buffer, raw) in [
+            ("NickServ", "PRIVMSG NickServ :IDENTIFY hunter2"),
+            (
+                "NickServ",
+                ":NickServ!s@services NOTICE me :You are now identified",
+            ),
+            ("*", "PASS hunter2"),
+            ("*", "OPER admin hunter2"),
+        ] {
+            let message = message(&lua, date_time, raw);
+            logger
+                .log(&BufferId::new("net", buffer), &message, "me")
+                .unwrap();
+        }
+
+        let read = |buffer: &str| {
+            fs::read_to_string(directory.join("net").join(buffer).join("2024-05-01.log"))
+                .unwrap_or_default()
+        };
+
+        assert_eq!(
+            read("NickServ"),
+            "[2024-05-01 12:00:00] -NickServ- You are now identified\n"
+        );
+        assert_eq!(read("*"), "");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn history_is_restored_from_raw_logs() {
+        let lua = mlua::Lua::new();
+        let directory = temp_directory("restore");
+        let config = LoggingConfig {
+            enabled: true,
+            format: LogFormat::Raw,
+            ..Default::default()
+        };
+        let logger = Logger::with_directory(&config, directory.clone());
+        let buffer_id = BufferId::new("net", "#tirc");
+
+        for (day, text) in [(1, "one"), (2, "two"), (2, "three")] {
+            let date_time = Local.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap();
+            let raw = format!("@label=1 PRIVMSG #tirc :{} more", text);
+            let message = message(&lua, date_time, &raw);
+
+            logger.log(&buffer_id, &message, "me").unwrap();
+        }
+
+        let history = logger.read_history(&buffer_id, 2).unwrap();
+        let date_time = Local.with_ymd_and_hms(2024, 5, 2, 12, 0, 0).unwrap();
+        let privmsg = |text: &str| Command::PRIVMSG("#tirc".to_owned(), text.to_owned());
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].0, date_time);
+        assert_eq!(history[0].1.command, privmsg("two more"));
+        assert_eq!(history[1].1.command, privmsg("three more"));
+        assert!(history[1]
+            .1
+            .tags
+            .iter()
+            .flatten()
+            .all(|tag| tag.0 != "label"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn grep_searches_logs_of_every_format() {
+        let lua = mlua::Lua::new();
+        let directory = temp_directory("grep");
+        let date_time = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+
+        for (format, buffer, text) in [
+            (LogFormat::Text, "#tirc", "found in text"),
+            (LogFormat::Raw, "alice", "found in raw"),
+            (LogFormat::Text, "#rust", "missed"),
+        ] {
+            let config = LoggingConfig {
+                enabled: true,
+                format,
+                ..Default::default()
+            };
+            let logger = Logger::with_directory(&config, directory.clone());
+            let raw = format!(":alice!u@h PRIVMSG {} :{}", buffer, text);
+
+            logger
+                .log(
+                    &BufferId::new("net", buffer),
+                    &message(&lua, date_time, &raw),
+                    "me",
+                )
+                .unwrap();
+        }
+
+        let logger = Logger::with_directory(&LoggingConfig::default(), directory.clone());
+        let matches = logger
+            .grep(Regex::new("found").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let matches: Vec<(String, DateTime<Local>, String)> = matches
+            .into_iter()
+            .map(|found| (found.buffer_id.name, found.date_time, found.text))
+            .collect();
+
+        assert_eq!(
+            matches,
+            [
+                (
+                    "#tirc".to_owned(),
+                    date_time,
+                    "<alice> found in text".to_owned()
+                ),
+                (
+                    "alice".to_owned(),
+                    date_time,
+                    "<alice> found in raw".to_owned()
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn case_is_folded_as_irc_does() {
+        assert_eq!(fold_case("#Tirc[Dev]\\~"), "#tirc{dev}|^");
+        assert_eq!(fold_case("#Ünïcode"), "#Ünïcode");
+    }
+
+    #[test]
+    fn file_names_cannot_escape_the_log_directory() {
+        assert_eq!(file_name("#a/b"), "#a_b");
+        assert_eq!(file_name(".."), "_..");
+    }
+}
@@ -1,6 +1,7 @@
 use mlua::{Lua, Table, Value};
 
 pub mod date_time;
+pub mod sender;
 
 pub fn get_loaded_modules(lua: &Lua) -> mlua::Result<mlua::Table> {
     let globals = lua.globals();
@@ -0,0 +1,230 @@
+use anyhow::bail;
+use irc::proto::{Command, Message};
+use mlua::{FromLuaMulti, Lua, Table, Variadic};
+
+use crate::{
+    connection::{Clients, Outgoing},
+    tui::lua::to_lua_user,
+};
+
+/// Creates the `TircSender` table handed to Lua event handlers, whose
+/// messages go through the outgoing queue of `server` and whose accessors
+/// read the state of its client.
+pub fn create_sender(
+    lua: &Lua,
+    server: &str,
+    outgoing: &Outgoing,
+    clients: &Clients,
+) -> mlua::Result<Table> {
+    let sender = lua.create_table()?;
+    let commands = Commands {
+        lua,
+        sender: &sender,
+        server,
+        outgoing,
+    };
+
+    sender.set("server", server)?;
+
+    commands.add("send_privmsg", |(target, text): (String, String)| {
+        Ok(Command::PRIVMSG(target, text).into())
+    })?;
+    commands.add("send_notice", |(target, text): (String, String)| {
+        Ok(Command::NOTICE(target, text).into())
+    })?;
+    commands.add("join", |(channel, key): (String, Option<String>)| {
+        Ok(Command::JOIN(channel, key, None).into())
+    })?;
+    commands.add("part", |(channel, reason): (String, Option<String>)| {
+        Ok(Command::PART(channel, reason).into())
+    })?;
+    commands.add(
+        "kick",
+        |(channel, nickname, reason): (String, String, Option<String>)| {
+            Ok(Command::KICK(channel, nickname, reason).into())
+        },
+    )?;
+    commands.add(
+        "mode",
+        |(target, modes, params): (String, Option<String>, Variadic<String>)| {
+            let params = std::iter::once(target).chain(modes).chain(params).collect();
+
+            Ok(Command::Raw("MODE".to_owned(), params).into())
+        },
+    )?;
+    commands.add("topic", |(channel, topic): (String, Option<String>)| {
+        Ok(Command::TOPIC(channel, topic).into())
+    })?;
+    commands.add(
+        "nick",
+        |nickname: String| Ok(Command::NICK(nickname).into()),
+    )?;
+    commands.add("whois", |nickname: String| {
+        Ok(Command::WHOIS(None, nickname).into())
+    })?;
+    commands.add(
+        "ctcp",
+        |(target, request, params): (String, String, Option<String>)| {
+            let request = request.to_ascii_uppercase();
+            let text = match params {
+                Some(params) => format!("\x01{} {}\x01", request, params),
+                None => format!("\x01{}\x01", request),
+            };
+
+            Ok(Command::PRIVMSG(target, text).into())
+        },
+    )?;
+    commands.add("action", |(target, text): (String, String)| {
+        Ok(Command::PRIVMSG(target, format!("\x01ACTION {}\x01", text)).into())
+    })?;
+    commands.add("raw", |line: String| {
+        if line.trim().is_empty() {
+            bail!("empty IRC line");
+        }
+
+        Ok(line.parse::<Message>()?)
+    })?;
+
+    let (server_name, clients_ref) = (server.to_owned(), clients.clone());
+    sender.set(
+        "nickname",
+        lua.create_function(move |_, ()| Ok(clients_ref.nickname(&server_name)))?,
+    )?;
+
+    let (server_name, clients_ref) = (server.to_owned(), clients.clone());
+    sender.set(
+        "channels",
+        lua.create_function(move |_, ()| Ok(clients_ref.channels(&server_name)))?,
+    )?;
+
+    let (server_name, clients_ref) = (server.to_owned(), clients.clone());
+    sender.set(
+        "users",
+        lua.create_function(move |lua, channel: String| {
+            let Some(users) = clients_ref.users(&server_name, &channel) else {
+                return Ok(None);
+            };
+
+            users
+                .iter()
+                .map(|user| to_lua_user(lua, user))
+                .collect::<mlua::Result<Vec<Table>>>()
+                .map(Some)
+        })?,
+    )?;
+
+    sender.set(
+        "for_server",
+        lua.create_function(|lua, name: String| get_sender(lua, &name))?,
+    )?;
+
+    Ok(sender)
+}
+
+/// The sender of `server`, `None` if it has not connected yet.
+pub fn get_sender(lua: &Lua, server: &str) -> mlua::Result<Option<Table>> {
+    match lua.named_registry_value::<Option<Table>>("senders")? {
+        Some(senders) => senders.get(server),
+        None => Ok(None),
+    }
+}
+
+/// Adds the functions sending commands to a sender table.
+struct Commands<'a> {
+    lua: &'a Lua,
+    sender: &'a Table,
+    server: &'a str,
+    outgoing: &'a Outgoing,
+}
+
+impl Commands<'_> {
+    /// Adds the function `name`, queueing the message `build` makes of its
+    /// arguments to the server.
+    fn add<A>(&self, name: &str, build: fn(A) -> anyhow::Result<Message>) -> mlua::Result<()>
+    where
+        A: FromLuaMulti + 'static,
+    {
+        let (server, outgoing) = (self.server.to_owned(), self.outgoing.clone());
+        let name_owned = name.to_owned();
+
+        self.sender.set(
+            name,
+            self.lua.create_function(move |_, args: A| {
+                let send = || -> anyhow::Result<()> {
+                    let message = build(args)?;
+                    let line = message.to_string();
+
+                    // A line break would smuggle in another command.
+                    if line.trim_end_matches("\r\n").contains(['\r', '\n']) {
+                        bail!("line breaks are not allowed");
+                    }
+
+                    outgoing.send(&server, message)
+                };
+
+                send().map_err(|err| mlua::Error::external(format!("{}: {}", name_owned, err)))
+            })?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender(lua: &Lua) -> Table {
+        let senders = lua.create_table().unwrap();
+        let sender = create_sender(lua, "net", &Outgoing::new(&[]), &Clients::default()).unwrap();
+
+        senders.set("net", &sender).unwrap();
+        lua.set_named_registry_value("senders", senders).unwrap();
+        lua.globals().set("irc", &sender).unwrap();
+
+        sender
+    }
+
+    fn error(lua: &Lua, code: &str) -> String {
+        lua.load(code).exec().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accessors_describe_the_connection() {
+        let lua = Lua::new();
+        sender(&lua);
+
+        let (server, nickname, channels, users, same, other): (
+            String,
+            Option<String>,
+            Vec<String>,
+            Option<Table>,
+            bool,
+            Option<Table>,
+        ) = lua
+            .load(
+                "return irc.server, irc.nickname(), irc.channels(), irc.users('#tirc'),
+                        irc.for_server('net') == irc, irc.for_server('other')",
+            )
+            .eval()
+            .unwrap();
+
+        assert_eq!(server, "net");
+        assert_eq!(nickname, None);
+        assert!(channels.is_empty());
+        assert!(users.is_none());
+        assert!(same);
+        assert!(other.is_none());
+    }
+
+    #[test]
+    fn commands_are_validated_and_queued() {
+        let lua = Lua::new();
+        sender(&lua);
+
+        assert!(error(&lua, "irc.join('#tirc')").contains("join: Not connected to server 'net'"));
+        assert!(error(&lua, "irc.mode('#tirc', '+b', 'a!*@*')").contains("Not connected"));
+        assert!(error(&lua, "irc.raw('')").contains("raw: empty IRC line"));
+        assert!(error(&lua, "irc.send_privmsg('#tirc', 'hi\\r\\nQUIT')")
+            .contains("send_privmsg: line breaks are not allowed"));
+        assert!(error(&lua, "irc.kick('#tirc')").contains("bad argument"));
+    }
+}
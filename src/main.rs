@@ -1,95 +1,327 @@
 extern crate irc;
 
-use std::{sync::Arc, time::Duration};
+use std::time::Duration;
 
 use crossterm::event::{Event as CrosstermEvent, EventStream};
 use futures::prelude::*;
-use futures::stream::FusedStream;
 use irc::client::{prelude::*, ClientStream};
 
 use tirc::{
-    config::{load_config, TircConfig},
-    ui::{self, Event, InputHandler},
+    cli::{self, Args},
+    config::{config_filename, load_config, reload_config, ConfigWatcher, TircConfig},
+    connection::{Clients, Outgoing, ServerEvent, Supervisor},
+    logging::Logger,
+    lua::sender::create_sender,
+    notification::Notifier,
+    ui::{
+        self, events::server_event, highlight::Highlighter, input_history::InputHistory,
+        paste::MultilineLimits, Event, InputHandler, NoticeLevel, TircMessage,
+    },
 };
 
 const TICK_RATE: Duration = Duration::from_millis(1000);
 
-fn create_lua_irc_sender(
+async fn setup_irc(
+    server: &str,
+    irc: &mut Client,
     lua: &mlua::Lua,
-    sender: irc::client::Sender,
-) -> mlua::Result<mlua::Table> {
-    let shared_sender = Arc::new(sender);
-    let tbl = lua.create_table()?;
-
-    let sender = Arc::clone(&shared_sender);
-    tbl.set(
-        "send_privmsg",
-        lua.create_function(move |_, (target, message): (String, String)| {
-            sender
-                .send_privmsg(target, message)
-                .map_err(mlua::Error::external)
-        })?,
-    )?;
-
-    let sender = Arc::clone(&shared_sender);
-    tbl.set(
-        "send_notice",
-        lua.create_function(move |_, (target, message): (String, String)| {
-            sender
-                .send_notice(target, message)
-                .map_err(mlua::Error::external)
-        })?,
-    )?;
-
-    Ok(tbl)
+    outgoing: &Outgoing,
+    clients: &Clients,
+) -> Result<ClientStream, anyhow::Error> {
+    let stream = irc.stream()?;
+
+    let senders: mlua::Table = match lua.named_registry_value("senders")? {
+        mlua::Value::Table(senders) => senders,
+        _ => {
+            let senders = lua.create_table()?;
+            lua.set_named_registry_value("senders", &senders)?;
+            senders
+        }
+    };
+
+    senders.set(server, create_sender(lua, server, outgoing, clients)?)?;
+
+    Ok(stream)
 }
 
-async fn setup_irc(
-    config: &TircConfig,
+fn push_notice(
     lua: &mlua::Lua,
-) -> Result<(Client, ClientStream), anyhow::Error> {
-    let mut irc = create_irc_client(config).await?;
-    let stream = irc.stream()?;
+    state: &mut ui::State,
+    server: &str,
+    level: NoticeLevel,
+    text: &str,
+) -> Result<(), anyhow::Error> {
+    state.push_message(lua, server, TircMessage::client_notice(level, text, lua)?)?;
+
+    Ok(())
+}
+
+/// Schedules a reconnect of `server`.
+fn reconnect(
+    lua: &mlua::Lua,
+    supervisor: &mut Supervisor,
+    state: &mut ui::State,
+    server: &str,
+) -> Result<(), anyhow::Error> {
+    match supervisor.reconnect(server) {
+        Some(delay) => push_notice(
+            lua,
+            state,
+            server,
+            NoticeLevel::Info,
+            &format!("Reconnecting to {} in {}s", server, delay.as_secs()),
+        ),
+        None => push_notice(
+            lua,
+            state,
+            server,
+            NoticeLevel::Info,
+            &format!("Not reconnecting to {}, reconnect is disabled", server),
+        ),
+    }
+}
+
+/// Reloads the config and applies it: Lua handlers and formatters are
+/// replaced, servers connected to or disconnected from as they were added,
+/// removed or changed, and the other settings take effect.
+fn reload(
+    lua: &mlua::Lua,
+    args: &Args,
+    supervisor: &mut Supervisor,
+    input_handler: &mut InputHandler<'_>,
+    state: &mut ui::State,
+) -> Result<TircConfig, anyhow::Error> {
+    let (config, logger, highlighter, changes) =
+        reload_config(lua, args.config.as_deref(), |mut config| {
+            args.apply(&mut config)?;
+
+            if config.servers.is_empty() {
+                anyhow::bail!("No server configured in init.lua (servers is empty)");
+            }
+
+            let logger = Logger::new(&config.logging)?;
+            let highlighter = Highlighter::new(&config.highlight)?;
+            // Last, as it is applied unless it fails.
+            let changes = supervisor.reconfigure(&config.servers)?;
+
+            Ok((config, logger, highlighter, changes))
+        })?;
+    let notifier = Notifier::new(&config.notification);
+
+    for server in changes.removed.iter().chain(&changes.changed) {
+        input_handler.quit_server(server)?;
+    }
+
+    for server in &changes.removed {
+        input_handler.remove_client(state, server);
+        state.remove_server(server);
+    }
 
-    lua.set_named_registry_value("sender", create_lua_irc_sender(lua, irc.sender())?)?;
+    input_handler.outgoing().configure(&config.servers);
+    input_handler.reconfigure(logger, highlighter, notifier, config.paste.clone());
+    state.reset_render_caches();
 
-    irc.send_cap_req(&[
-        Capability::EchoMessage,
-        Capability::MultiPrefix,
-        Capability::ExtendedJoin,
-        Capability::AwayNotify,
-        Capability::ChgHost,
-        Capability::AccountNotify,
-        Capability::ServerTime,
-        Capability::UserhostInNames,
-        Capability::Batch,
-        Capability::Custom("labeled-response"),
-    ])?;
+    for server_config in config.servers.iter() {
+        let server = server_config.name();
 
-    irc.identify()?;
+        match state.servers.get_mut(server) {
+            Some(server_state) => server_state.host = server_config.host.clone(),
+            None => state.add_server(server, &server_config.host),
+        }
+    }
+
+    let mut text = String::from("Reloaded config");
+
+    for (servers, action) in [
+        (&changes.added, "connecting to"),
+        (&changes.removed, "disconnected from"),
+        (&changes.changed, "reconnecting to"),
+    ] {
+        if !servers.is_empty() {
+            text.push_str(&format!(", {} {}", action, servers.join(", ")));
+        }
+    }
+
+    let server = state.current_buffer.server.clone();
+    push_notice(lua, state, &server, NoticeLevel::Info, &text)?;
 
-    Ok((irc, stream))
+    for warning in &config.warnings {
+        let text = format!("Config: {}", warning);
+        push_notice(lua, state, &server, NoticeLevel::Warning, &text)?;
+    }
+
+    Ok(config)
 }
 
-async fn root_task(lua: &mlua::Lua, config: &TircConfig) -> Result<(), anyhow::Error> {
-    let (irc, irc_stream) = setup_irc(config, lua).await?;
-    let mut irc_stream = irc_stream.fuse();
-
-    let mut state = ui::State {
-        server: config
-            .servers
-            .first()
-            .ok_or_else(|| anyhow::anyhow!("No server configured in init.lua"))?
-            .host
-            .clone(),
-        ..Default::default()
-    };
+/// Reacts to connection state changes of `server`, reporting them in its
+/// status buffer.
+async fn handle_connection_event(
+    lua: &mlua::Lua,
+    supervisor: &mut Supervisor,
+    input_handler: &mut InputHandler<'_>,
+    state: &mut ui::State,
+    server: &str,
+    event: ServerEvent,
+) -> Result<(), anyhow::Error> {
+    match event {
+        ServerEvent::Connected(Ok(mut irc)) => {
+            match setup_irc(
+                server,
+                &mut irc,
+                lua,
+                input_handler.outgoing(),
+                input_handler.clients(),
+            )
+            .await
+            {
+                Ok(stream) => {
+                    supervisor.watch(server, stream);
+
+                    if let Err(err) = supervisor.register(server, &irc) {
+                        push_notice(
+                            lua,
+                            state,
+                            server,
+                            NoticeLevel::Error,
+                            &format!("Registration with {} failed: {}", server, err),
+                        )?;
+
+                        // Replaces the connection, whose events are ignored.
+                        return reconnect(lua, supervisor, state, server);
+                    }
+
+                    input_handler.add_client(server, *irc);
+
+                    push_notice(
+                        lua,
+                        state,
+                        server,
+                        NoticeLevel::Info,
+                        &format!("Connected to {}", server),
+                    )?;
+
+                    let event = server_event(lua, server)?;
+                    input_handler.emit_event(state, "connect", server, event)?;
+                }
+                Err(err) => {
+                    push_notice(
+                        lua,
+                        state,
+                        server,
+                        NoticeLevel::Error,
+                        &format!("Connection to {} failed: {}", server, err),
+                    )?;
+                    reconnect(lua, supervisor, state, server)?;
+                }
+            }
+        }
+        ServerEvent::Connected(Err(err)) => {
+            push_notice(
+                lua,
+                state,
+                server,
+                NoticeLevel::Error,
+                &format!("Connection to {} failed: {}", server, err),
+            )?;
+            reconnect(lua, supervisor, state, server)?;
+        }
+        ServerEvent::Disconnected(err) => {
+            let channels = state.joined_channels(server);
+            supervisor.disconnected(server, state.nickname(server), channels);
+
+            let dropped = input_handler.remove_client(state, server);
+
+            if let Some(server_state) = state.servers.get_mut(server) {
+                server_state.capabilities.clear();
+                server_state.multiline = None;
+            }
+
+            state.clear_batches(server);
+
+            let text = match &err {
+                Some(err) => format!("Disconnected from {}: {}", server, err),
+                None => format!("Disconnected from {}", server),
+            };
+
+            push_notice(lua, state, server, NoticeLevel::Error, &text)?;
+
+            let event = server_event(lua, server)?;
+            event.set("error", err.map(|err| err.to_string()))?;
+            input_handler.emit_event(state, "disconnect", server, event)?;
+
+            if dropped > 0 {
+                push_notice(
+                    lua,
+                    state,
+                    server,
+                    NoticeLevel::Error,
+                    &format!("Dropped {} unsent queued messages", dropped),
+                )?;
+            }
+
+            reconnect(lua, supervisor, state, server)?;
+        }
+        ServerEvent::Message(_) => {}
+    }
+
+    Ok(())
+}
+
+async fn root_task(lua: &mlua::Lua, args: &Args, config: &TircConfig) -> Result<(), anyhow::Error> {
+    if config.servers.is_empty() {
+        anyhow::bail!("No server configured in init.lua (servers is empty)");
+    }
+
+    let mut supervisor = Supervisor::new(&config.servers)?;
+    let mut state = ui::State::default();
+
+    for server_config in config.servers.iter() {
+        state.add_server(server_config.name(), &server_config.host);
+    }
 
     let mut tui = tirc::tui::Tui::new()?;
 
     tui.initialize_terminal()?;
 
-    let mut input_handler = InputHandler::new(lua, irc, tui);
+    let mut input_handler = InputHandler::new(
+        lua,
+        tui,
+        Logger::new(&config.logging)?,
+        Highlighter::new(&config.highlight)?,
+        Notifier::new(&config.notification),
+        InputHistory::new()?,
+        Outgoing::new(&config.servers),
+    )
+    .with_paste(config.paste.clone());
+
+    for warning in &config.warnings {
+        let buffer_id = state.current_buffer.clone();
+        let text = format!("Config: {}", warning);
+        let message = TircMessage::client_notice(NoticeLevel::Warning, &text, lua)?;
+
+        state.push_message_to(&buffer_id, message);
+    }
+
+    for server in supervisor.servers() {
+        push_notice(
+            lua,
+            &mut state,
+            server,
+            NoticeLevel::Info,
+            &format!("Connecting to {}", server),
+        )?;
+    }
+
+    supervisor.connect_all();
+
+    let config_directory = config_filename(args.config.as_deref())?
+        .parent()
+        .expect("Unable to find config directory")
+        .to_owned();
+    let mut watcher = config
+        .reload
+        .watch
+        .then(|| ConfigWatcher::new(&config_directory));
 
     let mut events = EventStream::new();
     let mut tick = tokio::time::interval(TICK_RATE);
@@ -101,24 +333,108 @@ async fn root_task(lua: &mlua::Lua, config: &TircConfig) -> Result<(), anyhow::E
         input_handler.sync_state(&mut state)?;
         input_handler.render_ui(&state)?;
 
+        let next_flush = input_handler.next_flush();
+
         let event = tokio::select! {
             Some(event) = events.next() => match event? {
                 CrosstermEvent::Key(key) => Event::Input(key),
+                CrosstermEvent::FocusGained => Event::Focus(true),
+                CrosstermEvent::FocusLost => Event::Focus(false),
+                CrosstermEvent::Paste(text) => Event::Paste(text),
                 _ => continue,
             },
-            message = irc_stream.next(), if !irc_stream.is_terminated() => {
-                match message.transpose()? {
-                    Some(message) => Event::Message(Box::new(message)),
-                    None => continue,
+            Some((server, event)) = supervisor.next() => match event {
+                ServerEvent::Message(message) => {
+                    if let Command::Response(Response::RPL_WELCOME, _) = message.command {
+                        supervisor.registered(&server);
+                    }
+
+                    let notice = match input_handler.clients().lock().get(&server) {
+                        Some(irc) => supervisor.handle_message(&server, irc, &message)?,
+                        None => None,
+                    };
+
+                    if let Some((level, text)) = notice {
+                        push_notice(lua, &mut state, &server, level, &text)?;
+                    }
+
+                    if let Command::Response(Response::RPL_WELCOME, _) = message.command {
+                        let event = server_event(lua, &server)?;
+                        event.set("nickname", input_handler.clients().nickname(&server))?;
+                        input_handler.emit_event(&mut state, "registered", &server, event)?;
+                    }
+
+                    if let Command::CAP(..) = message.command {
+                        if let (Some(server_state), Some(capabilities)) =
+                            (state.servers.get_mut(&server), supervisor.capabilities(&server))
+                        {
+                            server_state.capabilities = capabilities.clone();
+                            server_state.multiline = supervisor
+                                .capability_value(&server, ui::paste::CAPABILITY)
+                                .and_then(MultilineLimits::parse);
+                        }
+                    }
+
+                    Event::Message(server, message)
                 }
-            }
+                event => {
+                    handle_connection_event(
+                        lua,
+                        &mut supervisor,
+                        &mut input_handler,
+                        &mut state,
+                        &server,
+                        event,
+                    )
+                    .await?;
+
+                    continue;
+                }
+            },
+            _ = tokio::time::sleep_until(next_flush.unwrap_or_else(std::time::Instant::now).into()),
+                if next_flush.is_some() => Event::Flush,
+            log_matches = input_handler.log_search() => Event::LogSearch(log_matches),
             _ = tick.tick() => Event::Tick,
             _ = &mut terminate => break,
         };
 
-        if let Err(err) = input_handler.handle_event(&mut state, event) {
-            eprintln!("Error: {:?}", err);
-            break;
+        let ticked = matches!(event, Event::Tick);
+        let result = input_handler
+            .handle_event(&mut state, event)
+            .and_then(|()| {
+                if ticked && watcher.as_mut().is_some_and(ConfigWatcher::changed) {
+                    Err(ui::Reload.into())
+                } else {
+                    Ok(())
+                }
+            });
+
+        match result {
+            Ok(()) => {}
+            Err(err) if err.is::<ui::Quit>() => break,
+            Err(err) if err.is::<ui::Reload>() => {
+                match reload(lua, args, &mut supervisor, &mut input_handler, &mut state) {
+                    Ok(config) => {
+                        watcher = config
+                            .reload
+                            .watch
+                            .then(|| ConfigWatcher::new(&config_directory));
+                    }
+                    Err(err) => {
+                        let server = state.current_buffer.server.clone();
+                        let text = format!("Unable to reload config: {:#}", err);
+
+                        push_notice(lua, &mut state, &server, NoticeLevel::Error, &text)?;
+                    }
+                }
+            }
+            Err(err) => {
+                let buffer_id = state.current_buffer.clone();
+                let message =
+                    TircMessage::client_notice(NoticeLevel::Error, &format!("{:#}", err), lua)?;
+
+                state.push_message_to(&buffer_id, message);
+            }
         }
     }
 
@@ -137,8 +453,7 @@ async fn terminate_signal() {
             signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
         let mut interrupt =
             signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
-        let mut hangup =
-            signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
 
         tokio::select! {
             _ = terminate.recv() => {}
@@ -153,9 +468,56 @@ async fn terminate_signal() {
     }
 }
 
+/// Checks what `root_task` would fail on before connecting.
+fn check_config(config: &TircConfig) -> Result<(), anyhow::Error> {
+    if config.servers.is_empty() {
+        anyhow::bail!("No server configured in init.lua (servers is empty)");
+    }
+
+    Supervisor::new(&config.servers)?;
+    Highlighter::new(&config.highlight)?;
+
+    Ok(())
+}
+
 fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse(std::env::args().skip(1))?;
+
+    if args.help {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    if args.version {
+        println!("tirc {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
     let lua = mlua::Lua::new();
-    let config = load_config(&lua)?;
+    let config = load_config(&lua, args.config.as_deref()).and_then(|mut config| {
+        args.apply(&mut config)?;
+        Ok(config)
+    });
+
+    if args.check_config {
+        match config.and_then(|config| check_config(&config).map(|()| config)) {
+            Ok(config) => {
+                for warning in &config.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+
+                println!("Config is valid");
+            }
+            Err(err) => {
+                eprintln!("{:#}", err);
+                std::process::exit(1);
+            }
+        }
+
+        return Ok(());
+    }
+
+    let config = config?;
 
     tirc::tui::Tui::install_panic_hook();
 
@@ -165,39 +527,5 @@ fn main() -> Result<(), anyhow::Error> {
         .enable_all()
         .build()?;
 
-    rt.block_on(root_task(&lua, &config))
-}
-
-async fn create_irc_client(config: &TircConfig) -> Result<Client, anyhow::Error> {
-    let server_config = config
-        .servers
-        .first()
-        .ok_or_else(|| anyhow::anyhow!("No server configured in init.lua (servers is empty)"))?;
-
-    let nickname = server_config.nickname.first().cloned().ok_or_else(|| {
-        anyhow::anyhow!(
-            "Server '{}' has an empty nickname list in init.lua",
-            server_config.host
-        )
-    })?;
-
-    let client_config = Config {
-        nickname: Some(nickname),
-        alt_nicks: server_config.nickname[1..].to_vec(),
-        realname: server_config.realname.clone(),
-        server: Some(server_config.host.clone()),
-        port: Some(server_config.port),
-        use_tls: Some(server_config.use_tls),
-        dangerously_accept_invalid_certs: Some(server_config.accept_invalid_cert),
-        channels: server_config.autojoin.clone(),
-        version: Some(format!(
-            "tirc v{} - https://github.com/topaxi/tirc",
-            env!("CARGO_PKG_VERSION")
-        )),
-        ..Default::default()
-    };
-
-    let client = Client::from_config(client_config).await?;
-
-    Ok(client)
+    rt.block_on(root_task(&lua, &args, &config))
 }
@@ -0,0 +1,249 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    io::{self, Write},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use irc::proto::Command;
+
+use crate::{
+    config::{DesktopNotification, NotificationConfig},
+    ui::{BufferId, TircMessage},
+};
+
+/// Notifies the user of messages they may have missed, by ringing the
+/// terminal bell, asking the terminal for a desktop notification and running
+/// a command, as configured.
+pub struct Notifier {
+    config: NotificationConfig,
+    /// Whether tirc runs inside tmux, which only forwards escape sequences
+    /// to the outer terminal when wrapped.
+    tmux: bool,
+    /// When the last notification about each buffer was sent.
+    sent: RefCell<HashMap<BufferId, Instant>>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationConfig) -> Self {
+        Self {
+            config: config.clone(),
+            tmux: env::var_os("TMUX").is_some(),
+            sent: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a message is worth a notification, given whether it is a
+    /// highlight and whether it opened a new private conversation.
+    pub fn is_wanted(&self, highlight: bool, new_query: bool) -> bool {
+        (highlight && self.config.highlights) || (new_query && self.config.queries)
+    }
+
+    /// Notifies of `message` filed into `buffer_id`, unless a notification
+    /// about the same buffer was sent less than `rate_limit` seconds ago.
+    pub fn notify(&self, buffer_id: &BufferId, message: &TircMessage) -> io::Result<()> {
+        if !self.start(buffer_id, Instant::now()) {
+            return Ok(());
+        }
+
+        let title = format!("{} ({})", buffer_id.name, buffer_id.server);
+        let text = notification_text(message);
+        let sequences = self.escape_sequences(&title, &text);
+
+        if !sequences.is_empty() {
+            let mut stdout = io::stdout().lock();
+
+            stdout.write_all(sequences.as_bytes())?;
+            stdout.flush()?;
+        }
+
+        if let Some((program, args)) = self.config.command.split_first() {
+            // The runtime reaps the process once it exits.
+            tokio::process::Command::new(program)
+                .args(args)
+                .args([&title, &text])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", program, err)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a notification about `buffer_id` at `now`, returning false if
+    /// it is rate limited instead.
+    fn start(&self, buffer_id: &BufferId, now: Instant) -> bool {
+        let mut sent = self.sent.borrow_mut();
+        let rate_limit = Duration::from_secs(self.config.rate_limit);
+
+        if sent
+            .get(buffer_id)
+            .is_some_and(|last| now.duration_since(*last) < rate_limit)
+        {
+            return false;
+        }
+
+        sent.insert(buffer_id.clone(), now);
+
+        true
+    }
+
+    fn escape_sequences(&self, title: &str, text: &str) -> String {
+        let mut sequences = String::new();
+
+        if self.config.bell {
+            // tmux turns the bell into an alert of its own.
+            sequences.push('\x07');
+        }
+
+        let desktop = match self.config.desktop {
+            Some(DesktopNotification::Osc9) => {
+                format!("\x1b]9;{}: {}\x07", sanitize(title), sanitize(text))
+            }
+            Some(DesktopNotification::Osc777) => format!(
+                "\x1b]777;notify;{};{}\x07",
+                sanitize(title).replace(';', ","),
+                sanitize(text)
+            ),
+            None => return sequences,
+        };
+
+        if self.tmux {
+            sequences.push_str(&tmux_passthrough(&desktop));
+        } else {
+            sequences.push_str(&desktop);
+        }
+
+        sequences
+    }
+}
+
+/// Describes a message like a client would show it, e.g. `<alice> hello`.
+fn notification_text(message: &TircMessage) -> String {
+    let TircMessage::Irc(_, message, _) = message else {
+        return String::new();
+    };
+    let nickname = message.source_nickname().unwrap_or_default();
+
+    match &message.command {
+        Command::PRIVMSG(_, text) => match text
+            .strip_prefix("\x01ACTION ")
+            .map(|action| action.trim_end_matches('\x01'))
+        {
+            Some(action) => format!("* {} {}", nickname, action),
+            None => format!("<{}> {}", nickname, text),
+        },
+        Command::NOTICE(_, text) => format!("-{}- {}", nickname, text),
+        _ => String::new(),
+    }
+}
+
+/// Removes control characters, which would end or corrupt the escape
+/// sequence, like IRC formatting codes.
+fn sanitize(text: &str) -> String {
+    text.chars().filter(|char| !char.is_control()).collect()
+}
+
+/// Wraps an escape sequence so tmux passes it through to the outer terminal.
+fn tmux_passthrough(sequence: &str) -> String {
+    format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notifier(config: NotificationConfig, tmux: bool) -> Notifier {
+        Notifier {
+            config,
+            tmux,
+            sent: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn message(lua: &mlua::Lua, raw: &str) -> TircMessage {
+        TircMessage::from_message(raw.parse::<irc::proto::Message>().unwrap().into(), lua).unwrap()
+    }
+
+    #[test]
+    fn notifications_are_rate_limited_per_buffer() {
+        let notifier = notifier(NotificationConfig::default(), false);
+        let now = Instant::now();
+        let alice = BufferId::new("net", "alice");
+        let bob = BufferId::new("net", "bob");
+
+        assert!(notifier.start(&alice, now));
+        assert!(notifier.start(&bob, now));
+        assert!(!notifier.start(&alice, now + Duration::from_secs(9)));
+        assert!(notifier.start(&alice, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn escape_sequences_follow_the_config() {
+        let osc9 = notifier(
+            NotificationConfig {
+                desktop: Some(DesktopNotification::Osc9),
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(
+            osc9.escape_sequences("#tirc (net)", "<alice> \x02hi\x02"),
+            "\x07\x1b]9;#tirc (net): <alice> hi\x07"
+        );
+
+        let osc777 = notifier(
+            NotificationConfig {
+                bell: false,
+                desktop: Some(DesktopNotification::Osc777),
+                ..Default::default()
+            },
+            true,
+        );
+        assert_eq!(
+            osc777.escape_sequences("a;b", "c;d"),
+            "\x1bPtmux;\x1b\x1b]777;notify;a,b;c;d\x07\x1b\\"
+        );
+
+        let silent = notifier(
+            NotificationConfig {
+                bell: false,
+                ..Default::default()
+            },
+            false,
+        );
+        assert_eq!(silent.escape_sequences("title", "text"), "");
+    }
+
+    #[test]
+    fn wanted_notifications_follow_the_config() {
+        let notifier = notifier(
+            NotificationConfig {
+                queries: false,
+                ..Default::default()
+            },
+            false,
+        );
+
+        assert!(notifier.is_wanted(true, false));
+        assert!(!notifier.is_wanted(false, true));
+        assert!(!notifier.is_wanted(false, false));
+    }
+
+    #[test]
+    fn notification_text_reads_like_the_buffer() {
+        let lua = mlua::Lua::new();
+        let text = |raw: &str| notification_text(&message(&lua, raw));
+
+        assert_eq!(text(":alice!u@h PRIVMSG me :hi"), "<alice> hi");
+        assert_eq!(
+            text(":alice!u@h PRIVMSG me :\x01ACTION waves\x01"),
+            "* alice waves"
+        );
+        assert_eq!(text(":alice!u@h NOTICE me :psst"), "-alice- psst");
+    }
+}
@@ -3,9 +3,12 @@ use std::str::FromStr;
 use irc::client::data::User;
 use irc::proto::{Command, Message, Prefix};
 use mlua::LuaSerdeExt;
-use ratatui::style::Color;
+use ratatui::style::{Color, Modifier};
 
-use crate::lua::get_or_create_module;
+use crate::{
+    lua::get_or_create_module,
+    ui::{BufferId, ChatBuffer, ServerState},
+};
 
 fn get_tirc_theme_module(lua: &mlua::Lua) -> mlua::Table {
     get_or_create_module(lua, "tirc.tui.theme").expect("Unable to create tirc.tui.theme module")
@@ -37,6 +40,10 @@ pub fn create_tirc_theme_lua_module(lua: &mlua::Lua) -> mlua::Result<mlua::Table
                 style = style.bg(Color::from_str(&color).unwrap());
             }
 
+            if let Ok(Some(true)) = tbl.get::<Option<bool>>("bold") {
+                style = style.add_modifier(Modifier::BOLD);
+            }
+
             lua.to_value(&style)
         })?,
     )?;
@@ -154,6 +161,120 @@ pub fn to_lua_message(lua: &mlua::Lua, message: &Message) -> mlua::Result<mlua::
     Ok(table)
 }
 
+/// Builds a Lua representation of a line generated by tirc itself.
+///
+/// It has the same shape as an IRC message, using the `TIRC` command with the
+/// text as its only param, plus the severity in `level`:
+///
+/// ```lua
+/// {
+///   command = 'TIRC',
+///   params = { 'Disconnected from irc.example.com' },
+///   level = 'error',              -- 'info', 'warning' or 'error'
+///   tags = {},
+/// }
+/// ```
+pub fn to_lua_client_notice(lua: &mlua::Lua, level: &str, text: &str) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+
+    table.set("command", "TIRC")?;
+    table.set("params", lua.create_sequence_from([text])?)?;
+    table.set("level", level)?;
+    table.set("tags", lua.create_table()?)?;
+    table.set("raw", text)?;
+
+    let metatable = lua.create_table()?;
+    metatable.set(
+        "__tostring",
+        lua.create_function(|_, table: mlua::Table| table.get::<String>("raw"))?,
+    )?;
+    table.set_metatable(Some(metatable))?;
+
+    Ok(table)
+}
+
+/// Builds a Lua representation of the batch a message was received in,
+/// set as the `batch` field of the message:
+///
+/// ```lua
+/// {
+///   type = 'netsplit',            -- lowercased batch type
+///   params = { 'irc.a.net', 'irc.b.net' },
+/// }
+/// ```
+pub fn to_lua_batch(lua: &mlua::Lua, kind: &str, params: &[String]) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+
+    table.set("type", kind)?;
+    table.set(
+        "params",
+        lua.create_sequence_from(params.iter().map(String::as_str))?,
+    )?;
+
+    Ok(table)
+}
+
+/// Builds a Lua representation of a buffer as listed in the buffer bar.
+///
+/// ```lua
+/// {
+///   server = 'libera',
+///   name = '#tirc',
+///   label = '#tirc',              -- `(libera)` for status buffers of several servers
+///   current = false,
+///   unread = 3,                   -- messages from others since last viewed
+///   highlights = 1,               -- unread messages mentioning the user
+/// }
+/// ```
+pub fn to_lua_buffer(
+    lua: &mlua::Lua,
+    buffer_id: &BufferId,
+    buffer: &ChatBuffer,
+    label: &str,
+    current: bool,
+) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+
+    table.set("server", buffer_id.server.as_str())?;
+    table.set("name", buffer_id.name.as_str())?;
+    table.set("label", label)?;
+    table.set("current", current)?;
+    table.set("unread", buffer.unread)?;
+    table.set("highlights", buffer.highlights)?;
+
+    Ok(table)
+}
+
+/// Builds a Lua representation of a server connection.
+///
+/// ```lua
+/// {
+///   name = 'libera',
+///   host = 'irc.libera.chat',
+///   nickname = 'tirc',
+///   capabilities = { ['server-time'] = true, ['labeled-response'] = true },
+/// }
+/// ```
+pub fn to_lua_server(
+    lua: &mlua::Lua,
+    name: &str,
+    server: &ServerState,
+) -> mlua::Result<mlua::Table> {
+    let table = lua.create_table()?;
+
+    table.set("name", name)?;
+    table.set("host", server.host.as_str())?;
+    table.set("nickname", server.nickname.as_str())?;
+
+    let capabilities = lua.create_table()?;
+    for capability in &server.capabilities {
+        capabilities.set(capability.as_str(), true)?;
+    }
+    table.set("capabilities", capabilities)?;
+
+    Ok(table)
+}
+
 /// Builds a Lua representation of a channel user.
 ///
 /// ```lua
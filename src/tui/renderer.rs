@@ -1,23 +1,30 @@
+use std::cell::{Cell, Ref};
+
 use irc::client::data::AccessLevel;
 use mlua::LuaSerdeExt;
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Line, Span},
+    text::{Line, Span, Text},
     widgets::{Block, Borders, List, ListDirection, ListItem, Paragraph},
 };
+use regex::Regex;
 use tui_input::Input;
 
 use crate::{
     config,
     lua::date_time::date_time_to_table,
-    ui::{Mode, State, TircMessage},
+    tui::lua::{to_lua_buffer, to_lua_server},
+    ui::{search, BufferId, ChatBuffer, Mode, Scroll, ScrollPosition, State, TircMessage},
 };
 
 use super::wrap::wrap_line;
 
 #[derive(Debug)]
-pub struct Renderer {}
+pub struct Renderer {
+    /// Area of the message list in the last rendered frame.
+    messages_area: Cell<Rect>,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct RenderedMessage<'a> {
@@ -33,7 +40,9 @@ impl Default for Renderer {
 
 impl Renderer {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            messages_area: Cell::new(Rect::default()),
+        }
     }
 
     fn get_layout(&self) -> Layout {
@@ -157,8 +166,9 @@ impl Renderer {
         lua: &mlua::Lua,
         message: &mlua::Table,
         nickname: &str,
+        server: &mlua::Table,
     ) -> Result<Vec<Span<'_>>, anyhow::Error> {
-        self.format_spans(lua, "message_text", (message, nickname))
+        self.format_spans(lua, "message_text", (message, nickname, server))
     }
 
     fn render_buffer_title(
@@ -166,137 +176,421 @@ impl Renderer {
         lua: &mlua::Lua,
         state: &State,
     ) -> Result<Vec<Span<'_>>, anyhow::Error> {
+        let host = state
+            .current_server()
+            .map(|server| server.host.clone())
+            .unwrap_or_default();
+
         self.format_spans(
             lua,
             "buffer_title",
             (
-                state.server.clone(),
-                state.nickname.clone(),
-                state.current_buffer.clone(),
+                host,
+                state.nickname(&state.current_buffer.server).to_owned(),
+                state.current_buffer.name.clone(),
             ),
         )
     }
 
+    /// Wraps a rendered message to `width`, indenting continuation lines past
+    /// the time column.
+    fn wrap_message<'a>(message: &'a RenderedMessage<'_>, width: usize) -> Text<'a> {
+        let initial_indent = message.time.clone();
+
+        // TODO: This is a hack to have the time | user separator included in the
+        // subsequent indent. It would be better to have a more explicit solution.
+        let subsequent_indent = if !initial_indent.is_empty() {
+            Box::new([
+                Span::raw(
+                    " ".repeat(
+                        initial_indent
+                            .iter()
+                            .take(initial_indent.len() - 1)
+                            .map(|span| span.width())
+                            .sum(),
+                    ),
+                ),
+                initial_indent.iter().last().unwrap().clone(),
+            ])
+        } else {
+            Box::new([Span::raw(""), Span::raw("")])
+        };
+
+        wrap_line(
+            &message.message,
+            super::wrap::Options {
+                width,
+                initial_indent,
+                subsequent_indent,
+                break_words: true,
+            },
+        )
+    }
+
+    /// Number of rows a message takes in the message list, zero if it is not
+    /// rendered at all.
+    fn message_height(
+        &self,
+        lua: &mlua::Lua,
+        server: &mlua::Table,
+        tirc_message: &TircMessage,
+        width: usize,
+    ) -> usize {
+        self.render_message(lua, server, tirc_message)
+            .filter(|message| message.message.width() > 0)
+            .map_or(0, |message| Self::wrap_message(&message, width).lines.len())
+    }
+
+    /// Number of rows of the message list as last rendered.
+    pub fn messages_height(&self) -> usize {
+        self.messages_area.get().height as usize
+    }
+
+    /// Width and height of the message list as last rendered.
+    pub fn messages_size(&self) -> (u16, u16) {
+        let area = self.messages_area.get();
+
+        (area.width, area.height)
+    }
+
+    /// Computes the scroll position of the current buffer after `scroll`,
+    /// measuring messages at the size the message list was last rendered
+    /// with.
+    pub fn scroll(&self, state: &State, lua: &mlua::Lua, scroll: Scroll) -> Option<ScrollPosition> {
+        let area = self.messages_area.get();
+        let buffer_id = &state.current_buffer;
+        let buffer = state.buffers.get(buffer_id)?;
+        let server = state
+            .servers
+            .get(&buffer_id.server)
+            .and_then(|server| to_lua_server(lua, &buffer_id.server, server).ok())?;
+
+        // One row is taken by the "more below" indicator while scrolled.
+        let view_height = (area.height as usize).saturating_sub(1);
+        let first_unread = Self::first_unread(buffer);
+
+        buffer.scrolled(scroll, view_height, |tirc_message| {
+            let height = self.message_height(lua, &server, tirc_message, area.width as usize);
+            let marked = height > 0
+                && first_unread
+                    .is_some_and(|first_unread| std::ptr::eq(first_unread, tirc_message));
+
+            height + usize::from(marked)
+        })
+    }
+
+    /// The first message that arrived after the buffer was last left, which
+    /// the new messages marker is drawn above.
+    fn first_unread(buffer: &ChatBuffer) -> Option<&TircMessage> {
+        buffer.messages.get(buffer.last_read?)
+    }
+
+    /// Text of each message of a buffer as rendered, `None` for messages that
+    /// are not rendered. Only messages not rendered since they changed go
+    /// through the formatters.
+    pub fn message_texts<'a>(
+        &self,
+        state: &'a State,
+        lua: &mlua::Lua,
+        buffer_id: &BufferId,
+    ) -> Option<Ref<'a, Vec<Option<String>>>> {
+        let buffer = state.buffers.get(buffer_id)?;
+
+        if buffer.rendered_texts.borrow().len() < buffer.messages.len() {
+            let server = state.servers.get(&buffer_id.server)?;
+            let server = to_lua_server(lua, &buffer_id.server, server).ok()?;
+            let mut texts = buffer.rendered_texts.borrow_mut();
+            let rendered = texts.len();
+
+            texts.extend(buffer.messages[rendered..].iter().map(|tirc_message| {
+                self.render_message(lua, &server, tirc_message)
+                    .map(|message| message.message.to_string())
+            }));
+        }
+
+        Some(buffer.rendered_texts.borrow())
+    }
+
+    /// Highlights the matches of a search in a rendered line, splitting its
+    /// spans where a match starts or ends.
+    fn highlight_matches<'a>(mut line: Line<'a>, pattern: &Regex) -> Line<'a> {
+        let ranges = search::match_ranges(pattern, &line.to_string());
+
+        if ranges.is_empty() {
+            return line;
+        }
+
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+        let mut spans = Vec::new();
+        let mut offset = 0;
+
+        for span in std::mem::take(&mut line.spans) {
+            let content = span.content.as_ref();
+            let end = offset + content.len();
+            let mut cursor = offset;
+
+            for &(start, stop) in &ranges {
+                if stop <= cursor || start >= end {
+                    continue;
+                }
+
+                let start = start.max(cursor);
+                let stop = stop.min(end);
+
+                if start > cursor {
+                    spans.push(Span::styled(
+                        content[cursor - offset..start - offset].to_owned(),
+                        span.style,
+                    ));
+                }
+
+                spans.push(Span::styled(
+                    content[start - offset..stop - offset].to_owned(),
+                    span.style.patch(highlight),
+                ));
+                cursor = stop;
+            }
+
+            if cursor < end {
+                spans.push(Span::styled(
+                    content[cursor - offset..].to_owned(),
+                    span.style,
+                ));
+            }
+
+            offset = end;
+        }
+
+        line.spans = spans;
+        line
+    }
+
     fn render_messages(&self, f: &mut ratatui::Frame, state: &State, lua: &mlua::Lua, rect: Rect) {
-        let current_buffer_name = &state.current_buffer;
-        let buffers = &state.buffers;
+        let block = Block::default()
+            .title(
+                self.render_buffer_title(lua, state)
+                    .unwrap_or_else(|_| vec![]),
+            )
+            .borders(Borders::NONE);
+        let area = block.inner(rect);
+
+        f.render_widget(block, rect);
+        self.messages_area.set(area);
+
+        let current_buffer_id = &state.current_buffer;
+        let current_buffer = state.buffers.get(current_buffer_id).unwrap();
+        let Some(server) = state
+            .servers
+            .get(&current_buffer_id.server)
+            .and_then(|server| to_lua_server(lua, &current_buffer_id.server, server).ok())
+        else {
+            return;
+        };
 
-        let current_buffer = buffers.get(current_buffer_name).unwrap();
+        let message_count = current_buffer.messages.len();
+        let (end, hidden_rows) = match current_buffer.scroll_position {
+            Some(position) => ((position.message + 1).min(message_count), position.offset),
+            None => (message_count, 0),
+        };
+
+        let list_area = if current_buffer.is_scrolled() {
+            Rect {
+                height: area.height.saturating_sub(1),
+                ..area
+            }
+        } else {
+            area
+        };
+        let width = list_area.width as usize;
+        let view_height = list_area.height as usize + hidden_rows;
 
-        let messages = current_buffer
-            .messages
+        let first_unread = Self::first_unread(current_buffer);
+
+        // Do not render _all_ messages, only the ones that fit in the available space,
+        // starting from the one at the bottom edge of the view.
+        let mut rows = 0;
+        let messages = current_buffer.messages[..end]
             .iter()
             .rev()
-            // Do not render _all_ messages, only the ones that fit in the available space
-            // We render a bit more as some messages might get filtered out. Although some might
-            // wrap and make even out the edge case.
-            // TODO: Make message list scrollable
-            .take((rect.height as usize) + (rect.height as usize) / 2)
-            .filter_map(|tirc_message| self.render_message(state, lua, tirc_message))
+            .filter_map(|tirc_message| {
+                let marked = first_unread
+                    .is_some_and(|first_unread| std::ptr::eq(first_unread, tirc_message));
+
+                Some((marked, self.render_message(lua, &server, tirc_message)?))
+            })
+            .filter(|(_, message)| message.message.width() > 0)
+            .map(|(marked, mut message)| {
+                if let Some(pattern) = &state.search {
+                    let line = std::mem::take(&mut *message.message);
+                    *message.message = Self::highlight_matches(line, pattern);
+                }
+
+                (marked, message)
+            })
+            .take_while(|(marked, message)| {
+                let fits = rows < view_height;
+                rows += Self::wrap_message(message, width).lines.len() + usize::from(*marked);
+                fits
+            })
             .collect::<Vec<_>>();
 
         let messages = messages
             .iter()
-            .filter(|message| message.message.width() > 0)
-            .map(|message| {
-                let initial_indent = message.time.clone();
-
-                // TODO: This is a hack to have the time | user separator included in the
-                // subsequent indent. It would be better to have a more explicit solution.
-                let subsequent_indent = if initial_indent.len() > 0 {
-                    Box::new([
-                        Span::raw(
-                            " ".repeat(
-                                initial_indent
-                                    .iter()
-                                    .take(initial_indent.len() - 1)
-                                    .map(|span| span.width())
-                                    .sum(),
-                            ),
-                        ),
-                        initial_indent.iter().last().unwrap().clone(),
-                    ])
-                } else {
-                    Box::new([Span::raw(""), Span::raw("")])
-                };
+            .enumerate()
+            .map(|(index, (marked, message))| {
+                let mut text = Self::wrap_message(message, width);
 
-                wrap_line(
-                    &message.message,
-                    super::wrap::Options {
-                        width: rect.width as usize,
-                        initial_indent,
-                        subsequent_indent,
-                        break_words: true,
-                    },
-                )
-            })
-            .map(ListItem::new);
-
-        let list = List::new(messages)
-            .block(
-                Block::default()
-                    .title(
-                        self.render_buffer_title(lua, state)
-                            .unwrap_or_else(|_| vec![]),
-                    )
-                    .borders(Borders::NONE),
-            )
-            .direction(ListDirection::BottomToTop);
+                if *marked {
+                    let marker = format!("{:-^width$}", " new messages ", width = width);
 
-        f.render_widget(list, rect);
+                    text.lines
+                        .insert(0, Line::styled(marker, Style::default().fg(Color::Red)));
+                }
+
+                if index == 0 {
+                    let visible_rows = text.lines.len().saturating_sub(hidden_rows);
+                    text.lines.truncate(visible_rows);
+                }
+
+                ListItem::new(text)
+            });
+
+        let list = List::new(messages).direction(ListDirection::BottomToTop);
+
+        f.render_widget(list, list_area);
+
+        if let Some(position) = current_buffer.scroll_position {
+            let below = message_count.saturating_sub(position.message + 1);
+            let indicator = if below > 0 {
+                format!("-- more below ({} messages) --", below)
+            } else {
+                "-- more below --".to_owned()
+            };
+
+            f.render_widget(
+                Paragraph::new(indicator).style(Style::default().add_modifier(Modifier::REVERSED)),
+                Rect {
+                    y: list_area.bottom(),
+                    height: area.height.min(1),
+                    ..area
+                },
+            );
+        }
     }
 
     fn render_message(
         &self,
-        state: &State,
         lua: &mlua::Lua,
+        server: &mlua::Table,
         tirc_message: &TircMessage,
     ) -> Option<RenderedMessage<'_>> {
-        if let TircMessage::Irc(date_time, message, lua_message) = tirc_message {
-            let mut time_spans = date_time_to_table(lua, date_time)
-                .ok()
-                .and_then(|date_time| {
-                    self.render_message_time(lua, &date_time, lua_message).ok()
-                })
-                .unwrap_or_default();
+        let date_time = tirc_message.get_date_time();
+        let lua_message = tirc_message.get_lua_message();
 
-            if time_spans.len() == 1 {
-                time_spans.push(Span::raw(""));
-            }
+        let mut time_spans = date_time_to_table(lua, date_time)
+            .ok()
+            .and_then(|date_time| self.render_message_time(lua, &date_time, lua_message).ok())
+            .unwrap_or_default();
+
+        if time_spans.len() == 1 {
+            time_spans.push(Span::raw(""));
+        }
 
-            let message_spans = self
-                .render_message_text(lua, lua_message, &state.nickname)
-                .unwrap_or_else(|_| vec![Span::raw(message.to_string())]);
+        let nickname: String = server.get("nickname").unwrap_or_default();
+        let message_spans = self
+            .render_message_text(lua, lua_message, &nickname, server)
+            .unwrap_or_else(|_| vec![Span::raw(Self::get_raw_message(tirc_message))]);
 
-            if message_spans.is_empty() {
-                return None;
-            }
+        if message_spans.is_empty() {
+            return None;
+        }
 
-            Some(RenderedMessage {
-                time: time_spans.into_boxed_slice(),
-                message: Box::new(Line::from(message_spans)),
-            })
+        Some(RenderedMessage {
+            time: time_spans.into_boxed_slice(),
+            message: Box::new(Line::from(message_spans)),
+        })
+    }
+
+    fn get_raw_message(tirc_message: &TircMessage) -> String {
+        match tirc_message {
+            TircMessage::Irc(_, message, _) => message.to_string(),
+            TircMessage::Lua(_, lua_message) => lua_message.get("raw").unwrap_or_default(),
+        }
+    }
+
+    /// Label of a buffer in the buffer bar. With more than one server the status
+    /// buffers are labelled by their server, as `(status)` would be ambiguous.
+    fn get_buffer_label(state: &State, buffer_id: &BufferId) -> String {
+        if buffer_id.is_status() && state.servers.len() > 1 {
+            format!("({})", buffer_id.server)
         } else {
-            None
+            buffer_id.name.clone()
         }
     }
 
-    fn render_buffer_bar(&self, f: &mut ratatui::Frame, state: &State, rect: Rect) {
-        let current_buffer_name = &state.current_buffer;
+    /// The buffer bar as formatted by the `buffer_bar` formatter, which gets
+    /// the list of buffers.
+    fn format_buffer_bar(
+        &self,
+        lua: &mlua::Lua,
+        state: &State,
+    ) -> Result<Vec<Span<'_>>, anyhow::Error> {
+        let buffers = lua.create_table()?;
 
-        let buffers: Vec<Span> = state
-            .buffers
-            .keys()
-            .flat_map(|str| {
-                let mut style = Style::default();
+        for (index, (buffer_id, buffer)) in state.buffers.iter().enumerate() {
+            let label = Self::get_buffer_label(state, buffer_id);
+            let current = *buffer_id == state.current_buffer;
 
-                if str == current_buffer_name {
-                    style = style.add_modifier(Modifier::BOLD);
-                }
+            buffers.set(
+                index + 1,
+                to_lua_buffer(lua, buffer_id, buffer, &label, current)?,
+            )?;
+        }
 
-                [Span::styled(str.to_owned(), style), Span::raw(" ")]
-            })
-            .collect();
+        self.format_spans(lua, "buffer_bar", buffers)
+    }
+
+    fn render_buffer_bar(
+        &self,
+        f: &mut ratatui::Frame,
+        state: &State,
+        lua: &mlua::Lua,
+        rect: Rect,
+    ) {
+        let mut buffers = self.format_buffer_bar(lua, state).unwrap_or_default();
+
+        // Without a formatter, buffers with unread messages are colored and
+        // show their count.
+        if buffers.is_empty() {
+            buffers = state
+                .buffers
+                .iter()
+                .flat_map(|(buffer_id, buffer)| {
+                    let mut style = Style::default();
+
+                    if buffer.highlights > 0 {
+                        style = style.fg(Color::Red);
+                    } else if buffer.unread > 0 {
+                        style = style.fg(Color::Yellow);
+                    }
+
+                    if *buffer_id == state.current_buffer {
+                        style = style.add_modifier(Modifier::BOLD);
+                    }
+
+                    let count = match buffer.unread {
+                        0 => String::new(),
+                        unread => format!("({})", unread),
+                    };
+
+                    [
+                        Span::styled(Self::get_buffer_label(state, buffer_id), style),
+                        Span::styled(count, style),
+                        Span::raw(" "),
+                    ]
+                })
+                .collect();
+        }
 
         let buffer_bar = Paragraph::new(Line::from(buffers));
 
@@ -304,10 +598,13 @@ impl Renderer {
     }
 
     fn render_input(&mut self, f: &mut ratatui::Frame, state: &State, input: &Input, rect: Rect) {
-        let prefix = match state.mode {
-            Mode::Normal => "",
-            Mode::Command => ":",
-            Mode::Insert => "❯ ",
+        let prefix = match (&state.confirmation, &state.history_search, state.mode) {
+            (Some(question), _, _) => format!("{} ", question),
+            (None, Some(query), _) => format!("(reverse-i-search)`{}': ", query),
+            (None, None, Mode::Normal) => String::new(),
+            (None, None, Mode::Command) => ":".to_owned(),
+            (None, None, Mode::Insert) => "❯ ".to_owned(),
+            (None, None, Mode::Search) => "/".to_owned(),
         };
         let prefix_len = prefix.chars().count() as u16;
         let width = f.area().width.max(3) - prefix_len; // keep 2 for borders and 1 for cursor
@@ -320,7 +617,7 @@ impl Renderer {
         match state.mode {
             Mode::Normal => {}
 
-            Mode::Command | Mode::Insert => {
+            Mode::Command | Mode::Insert | Mode::Search => {
                 // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
                 f.set_cursor_position((
                     // Put cursor past the end of the input text
@@ -383,7 +680,7 @@ impl Renderer {
             });
         let list = List::new(users).block(
             Block::default()
-                .title(state.current_buffer.to_owned())
+                .title(state.current_buffer.name.to_owned())
                 .borders(Borders::LEFT),
         );
         f.render_widget(list, rect);
@@ -412,7 +709,7 @@ impl Renderer {
             self.render_messages(f, state, lua, chunks[0]);
         }
 
-        self.render_buffer_bar(f, state, chunks[2]);
+        self.render_buffer_bar(f, state, lua, chunks[2]);
         self.render_input(f, state, input, chunks[1]);
     }
 }
@@ -557,4 +854,86 @@ mod tests {
         assert_eq!(spans[5].style.bg, None);
         Ok(())
     }
+
+    #[test]
+    fn test_highlight_matches_splits_styled_spans() {
+        let blue = Style::default().fg(Color::Blue);
+        let line = Line::from(vec![
+            Span::styled("<alice>", blue),
+            Span::raw(" "),
+            Span::raw("hello alice"),
+        ]);
+
+        let line = Renderer::highlight_matches(line, &search::compile("lice"));
+        let spans: Vec<(&str, bool)> = line
+            .spans
+            .iter()
+            .map(|span| {
+                (
+                    span.content.as_ref(),
+                    span.style.add_modifier.contains(Modifier::REVERSED),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            spans,
+            [
+                ("<a", false),
+                ("lice", true),
+                (">", false),
+                (" ", false),
+                ("hello a", false),
+                ("lice", true),
+            ]
+        );
+        assert_eq!(line.spans[1].style.fg, Some(Color::Blue));
+    }
+
+    #[test]
+    fn test_default_theme_formats_buffer_activity() -> anyhow::Result<(), anyhow::Error> {
+        let renderer = Renderer::new();
+        let lua = mlua::Lua::new();
+        crate::config::register_builtin_modules(&lua)?;
+        lua.load("require('tirc.tui.themes.default').setup({})")
+            .exec()?;
+
+        let mut state = State::new();
+        state.add_server("net", "irc.example.com");
+        for (name, unread, highlights) in [("#quiet", 2, 0), ("#loud", 3, 1)] {
+            let buffer_id = BufferId::new("net", name);
+            state.create_buffer_if_not_exists(&buffer_id);
+
+            let buffer = state.buffers.get_mut(&buffer_id).unwrap();
+            buffer.unread = unread;
+            buffer.highlights = highlights;
+        }
+
+        let spans = renderer.format_buffer_bar(&lua, &state)?;
+        let spans: Vec<(&str, Option<Color>, bool)> = spans
+            .iter()
+            .map(|span| {
+                (
+                    span.content.as_ref(),
+                    span.style.fg,
+                    span.style.add_modifier.contains(Modifier::BOLD),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            spans,
+            [
+                ("(status)", Some(Color::White), true),
+                (" ", None, false),
+                ("#quiet", Some(Color::Yellow), false),
+                ("(2)", Some(Color::Yellow), false),
+                (" ", None, false),
+                ("#loud", Some(Color::Red), false),
+                ("(1!)", Some(Color::Red), false),
+                (" ", None, false),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -1,16 +1,22 @@
-use crossterm::event::{DisableMouseCapture, EnableMouseCapture};
+use crossterm::event::{
+    DisableBracketedPaste, DisableFocusChange, DisableMouseCapture, EnableBracketedPaste,
+    EnableFocusChange, EnableMouseCapture,
+};
 use crossterm::execute;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
-use irc::client::Client;
 use mlua::Lua;
+use std::cell::Ref;
 use std::io::{self, Stdout};
 use ratatui::backend::CrosstermBackend;
 use tui_input::backend::crossterm::EventHandler;
-use tui_input::Input;
+use tui_input::{Input, InputRequest};
 
-use crate::ui::State;
+use crate::ui::{
+    search::{self, SearchDirection},
+    BufferId, Scroll, ScrollPosition, State, TopPosition,
+};
 
 use super::renderer::Renderer;
 
@@ -44,7 +50,13 @@ impl Tui {
 
     fn restore_terminal() -> io::Result<()> {
         disable_raw_mode()?;
-        execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+        execute!(
+            io::stdout(),
+            LeaveAlternateScreen,
+            DisableMouseCapture,
+            DisableFocusChange,
+            DisableBracketedPaste
+        )?;
 
         Ok(())
     }
@@ -57,10 +69,124 @@ impl Tui {
         self.input.reset();
     }
 
+    /// Inserts `text` at the cursor of the input line.
+    pub fn insert_input(&mut self, text: &str) {
+        for char in text.chars() {
+            self.input.handle(InputRequest::InsertChar(char));
+        }
+    }
+
+    /// Replaces the input line, placing the cursor at the character index
+    /// `cursor`.
+    pub fn set_input(&mut self, value: String, cursor: usize) {
+        self.input = Input::new(value).with_cursor(cursor);
+    }
+
     pub fn handle_event(&mut self, event: &crossterm::event::Event) {
         self.input.handle_event(event);
     }
 
+    /// Number of rows showing messages in the last rendered frame.
+    pub fn messages_height(&self) -> usize {
+        self.renderer.messages_height()
+    }
+
+    /// Scrolls the message list of the current buffer, returning whether it
+    /// shows the oldest message now.
+    pub fn scroll(&self, lua: &Lua, state: &mut State, scroll: Scroll) -> bool {
+        let top = self.top_position(lua, state);
+        let position = match scroll {
+            Scroll::Top => top,
+            Scroll::Bottom => None,
+            _ => self.renderer.scroll(state, lua, scroll),
+        };
+
+        if let Some(buffer) = state.buffers.get_mut(&state.current_buffer) {
+            buffer.scroll_position = position;
+        }
+
+        position == top
+    }
+
+    /// The position showing the oldest message of the current buffer,
+    /// computed again only once the buffer changed above it or the message
+    /// list was resized.
+    fn top_position(&self, lua: &Lua, state: &mut State) -> Option<ScrollPosition> {
+        let (width, height) = self.renderer.messages_size();
+        let cached = state
+            .buffers
+            .get(&state.current_buffer)
+            .and_then(|buffer| buffer.top_position)
+            .filter(|top| top.width == width && top.height == height);
+
+        if let Some(top) = cached {
+            return top.position;
+        }
+
+        let position = self.renderer.scroll(state, lua, Scroll::Top);
+
+        if let Some(buffer) = state.buffers.get_mut(&state.current_buffer) {
+            buffer.top_position = Some(TopPosition {
+                width,
+                height,
+                position,
+            });
+        }
+
+        position
+    }
+
+    /// Text of each message of a buffer as rendered, `None` for messages that
+    /// are not rendered. `None` if the buffer or its server is unknown.
+    pub fn message_texts<'a>(
+        &self,
+        lua: &Lua,
+        state: &'a State,
+        buffer_id: &BufferId,
+    ) -> Option<Ref<'a, Vec<Option<String>>>> {
+        self.renderer.message_texts(state, lua, buffer_id)
+    }
+
+    /// Scrolls the current buffer to the closest message matching the last
+    /// search in `direction` from the last match, or from the bottom of the
+    /// view, returning whether one was found.
+    pub fn search(&self, lua: &Lua, state: &mut State, direction: SearchDirection) -> bool {
+        let found = {
+            let (Some(pattern), Some(buffer), Some(texts)) = (
+                &state.search,
+                state.buffers.get(&state.current_buffer),
+                self.message_texts(lua, state, &state.current_buffer),
+            ) else {
+                return false;
+            };
+
+            let origin = buffer
+                .search_match
+                .or(buffer.scroll_position.map(|position| position.message))
+                .unwrap_or(buffer.messages.len());
+
+            search::find(texts.len(), origin, direction, |index| {
+                texts[index]
+                    .as_ref()
+                    .is_some_and(|text| pattern.is_match(text))
+            })
+        };
+
+        match found {
+            Some(index) => {
+                self.scroll(lua, state, Scroll::To(index));
+
+                // The view may not move onto the match, e.g. near the top.
+                if let Some(buffer) = state.buffers.get_mut(&state.current_buffer) {
+                    buffer.search_match = Some(index);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn initialize_terminal(&mut self) -> Result<(), anyhow::Error> {
         enable_raw_mode()?;
 
@@ -69,13 +195,15 @@ impl Tui {
         execute!(
             self.terminal.backend_mut(),
             EnterAlternateScreen,
-            EnableMouseCapture
+            EnableMouseCapture,
+            EnableFocusChange,
+            EnableBracketedPaste
         )?;
 
         Ok(())
     }
 
-    pub fn render(&mut self, _irc: &Client, lua: &Lua, state: &State) -> Result<(), anyhow::Error> {
+    pub fn render(&mut self, lua: &Lua, state: &State) -> Result<(), anyhow::Error> {
         self.terminal.draw(|f| {
             self.renderer.render(f, state, lua, &self.input);
         })?;
@@ -91,7 +219,9 @@ impl Drop for Tui {
         let _ = execute!(
             self.terminal.backend_mut(),
             LeaveAlternateScreen,
-            DisableMouseCapture
+            DisableMouseCapture,
+            DisableFocusChange,
+            DisableBracketedPaste
         );
     }
 }
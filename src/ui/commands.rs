@@ -0,0 +1,349 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use irc::{
+    client::prelude::ChannelExt,
+    proto::{Command, Message},
+};
+
+use super::BufferId;
+
+/// CTCP requests sent to the current buffer unless a target is given first.
+const CTCP_COMMANDS: &[&str] = &[
+    "ACTION",
+    "CLIENTINFO",
+    "FINGER",
+    "PING",
+    "SOURCE",
+    "TIME",
+    "USERINFO",
+    "VERSION",
+];
+
+/// Commands sent to the server whose parameters may hold passwords.
+const SECRET_COMMANDS: &[&str] = &[
+    "PASS",
+    "OPER",
+    "AUTHENTICATE",
+    "NICKSERV",
+    "NS",
+    "CHANSERV",
+    "CS",
+    "OPERSERV",
+    "OS",
+    "BOTSERV",
+    "HOSTSERV",
+    "MEMOSERV",
+];
+
+/// Away message of `:away` without one.
+const DEFAULT_AWAY_MESSAGE: &str = "Away";
+
+/// Takes the channel given as the first of `words`, or the channel of the
+/// current buffer.
+fn channel(buffer_id: &BufferId, words: &mut Vec<&str>, command: &str) -> anyhow::Result<String> {
+    match words.first() {
+        Some(word) if word.is_channel_name() => Ok(words.remove(0).to_owned()),
+        _ if buffer_id.name.is_channel_name() => Ok(buffer_id.name.clone()),
+        _ => bail!("Not in a channel, name one, e.g. :{} #channel", command),
+    }
+}
+
+fn words(args: &str) -> Vec<&str> {
+    args.split_whitespace().collect()
+}
+
+/// `:quote <raw line>`, sent as typed.
+pub fn quote(line: &str) -> anyhow::Result<Message> {
+    let line = line.trim();
+
+    if line.is_empty() {
+        bail!("Missing IRC line, e.g. :quote MODE #tirc +m");
+    }
+
+    Message::from_str(line).map_err(|err| anyhow!("Invalid IRC line: {}", err))
+}
+
+/// `:topic [channel] [topic]`, asking for the topic without one.
+pub fn topic(buffer_id: &BufferId, args: &str) -> anyhow::Result<Command> {
+    let args = args.trim();
+    let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let (channel, topic) = if first.is_channel_name() {
+        (first.to_owned(), rest.trim())
+    } else {
+        (channel(buffer_id, &mut Vec::new(), "topic")?, args)
+    };
+    let topic = Some(topic.to_owned()).filter(|topic| !topic.is_empty());
+
+    Ok(Command::TOPIC(channel, topic))
+}
+
+/// `:kick [channel] <nickname> [reason]`.
+pub fn kick(buffer_id: &BufferId, args: &str) -> anyhow::Result<Command> {
+    let mut words = words(args);
+    let channel = channel(buffer_id, &mut words, "kick")?;
+
+    if words.is_empty() {
+        bail!("Missing nickname, e.g. :kick [channel] <nickname> [reason]");
+    }
+
+    let nickname = words.remove(0).to_owned();
+    let reason = Some(words.join(" ")).filter(|reason| !reason.is_empty());
+
+    Ok(Command::KICK(channel, nickname, reason))
+}
+
+/// `:ban [channel] <nickname or mask>...`, or `:unban` if not `ban`. Bare
+/// nicknames are banned as `nickname!*@*`.
+pub fn ban(buffer_id: &BufferId, args: &str, ban: bool) -> anyhow::Result<Command> {
+    let name = if ban { "ban" } else { "unban" };
+    let mut words = words(args);
+    let channel = channel(buffer_id, &mut words, name)?;
+
+    if words.is_empty() {
+        bail!("Missing nickname or mask, e.g. :{} [channel] <mask>", name);
+    }
+
+    let masks: Vec<String> = words
+        .iter()
+        .map(|mask| {
+            if mask.contains(['!', '@']) {
+                mask.to_string()
+            } else {
+                format!("{}!*@*", mask)
+            }
+        })
+        .collect();
+    let modes = format!("{}{}", if ban { '+' } else { '-' }, "b".repeat(masks.len()));
+    let mut params = vec![channel, modes];
+
+    params.extend(masks);
+
+    Ok(Command::Raw("MODE".to_owned(), params))
+}
+
+/// `:invite <nickname> [channel]`.
+pub fn invite(buffer_id: &BufferId, args: &str) -> anyhow::Result<Command> {
+    let mut words = words(args);
+
+    if words.is_empty() {
+        bail!("Missing nickname, e.g. :invite <nickname> [channel]");
+    }
+
+    let nickname = words.remove(0).to_owned();
+    let channel = channel(buffer_id, &mut words, "invite")?;
+
+    if !words.is_empty() {
+        bail!("Too many arguments, e.g. :invite <nickname> [channel]");
+    }
+
+    Ok(Command::INVITE(nickname, channel))
+}
+
+/// `:mode [target] [modes [params]]` of the channel of the current buffer,
+/// or of `nickname` elsewhere. Without modes the current ones are asked for.
+pub fn mode(buffer_id: &BufferId, nickname: &str, args: &str) -> anyhow::Result<Command> {
+    let mut words = words(args);
+    // `+` starts modes here rather than a channel name.
+    let is_target = |word: &str| {
+        (word.is_channel_name() && !word.starts_with('+')) || word.eq_ignore_ascii_case(nickname)
+    };
+    let target = match words.first() {
+        Some(word) if is_target(word) => words.remove(0).to_owned(),
+        _ if buffer_id.name.is_channel_name() => buffer_id.name.clone(),
+        _ if !nickname.is_empty() => nickname.to_owned(),
+        _ => bail!("Not registered yet, name a target, e.g. :mode #channel +m"),
+    };
+    if let Some(modes) = words.first().filter(|modes| !modes.starts_with(['+', '-'])) {
+        bail!("Invalid modes {}, expected e.g. +o or -m", modes);
+    }
+
+    let mut params = vec![target];
+
+    params.extend(words.into_iter().map(str::to_owned));
+
+    Ok(Command::Raw("MODE".to_owned(), params))
+}
+
+/// `:away [message]`, or `:back` without `message`.
+pub fn away(message: Option<&str>) -> Command {
+    let message = message.map(|message| match message.trim() {
+        "" => DEFAULT_AWAY_MESSAGE.to_owned(),
+        message => message.to_owned(),
+    });
+
+    Command::AWAY(message)
+}
+
+/// `:ctcp [target] <request> [params]`, sent to the current buffer without a
+/// target.
+pub fn ctcp(buffer_id: &BufferId, args: &str) -> anyhow::Result<Command> {
+    let mut words = words(args);
+    let target = match words.first() {
+        Some(word) if CTCP_COMMANDS.contains(&word.to_ascii_uppercase().as_str()) => {
+            if buffer_id.is_status() {
+                bail!("Not in a channel or query, name a target, e.g. :ctcp <target> VERSION");
+            }
+
+            buffer_id.name.clone()
+        }
+        Some(_) => words.remove(0).to_owned(),
+        None => bail!("Missing request, e.g. :ctcp [target] VERSION"),
+    };
+
+    if words.is_empty() {
+        bail!("Missing request, e.g. :ctcp [target] VERSION");
+    }
+
+    let request = words.remove(0).to_ascii_uppercase();
+    let text = match words.as_slice() {
+        [] => format!("\x01{}\x01", request),
+        params => format!("\x01{} {}\x01", request, params.join(" ")),
+    };
+
+    Ok(Command::PRIVMSG(target, text))
+}
+
+/// Whether `target` is one of the network services, e.g. NickServ, whose
+/// commands may hold passwords.
+pub fn is_service(target: &str) -> bool {
+    let nickname = target.split('@').next().unwrap_or_default();
+
+    !nickname.is_channel_name() && nickname.to_ascii_lowercase().ends_with("serv")
+}
+
+/// Whether a message we send may hold a password: `PASS`, `OPER`,
+/// `AUTHENTICATE`, or a message to services.
+pub fn is_secret(message: &Message) -> bool {
+    match &message.command {
+        Command::PASS(_)
+        | Command::OPER(_, _)
+        | Command::AUTHENTICATE(_)
+        | Command::NICKSERV(_)
+        | Command::CHANSERV(_)
+        | Command::OPERSERV(_)
+        | Command::BOTSERV(_)
+        | Command::HOSTSERV(_)
+        | Command::MEMOSERV(_) => true,
+        Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => is_service(target),
+        // Lines with unexpected parameters, or aliases like `NS`.
+        Command::Raw(command, _) => SECRET_COMMANDS
+            .iter()
+            .any(|secret| command.eq_ignore_ascii_case(secret)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(command: anyhow::Result<Command>) -> String {
+        Message::from(command.unwrap())
+            .to_string()
+            .trim_end()
+            .to_owned()
+    }
+
+    #[test]
+    fn quote_sends_raw_lines() {
+        assert_eq!(
+            quote("PRIVMSG #tirc :hello world").unwrap().to_string(),
+            "PRIVMSG #tirc :hello world\r\n"
+        );
+        assert!(quote("  ").is_err());
+    }
+
+    #[test]
+    fn channel_commands_default_to_the_current_channel() {
+        let channel = BufferId::new("net", "#tirc");
+        let status = BufferId::status("net");
+
+        assert_eq!(line(topic(&channel, "")), "TOPIC #tirc");
+        assert_eq!(line(topic(&channel, "new topic")), "TOPIC #tirc :new topic");
+        assert_eq!(line(topic(&status, "#rust hi")), "TOPIC #rust hi");
+        assert!(topic(&status, "hi").is_err());
+
+        assert_eq!(line(kick(&channel, "troll")), "KICK #tirc troll");
+        assert_eq!(
+            line(kick(&status, "#rust troll go away")),
+            "KICK #rust troll :go away"
+        );
+        assert!(kick(&channel, "#rust").is_err());
+
+        assert_eq!(line(invite(&channel, "alice")), "INVITE alice #tirc");
+        assert_eq!(line(invite(&status, "alice #rust")), "INVITE alice #rust");
+        assert!(invite(&status, "alice").is_err());
+    }
+
+    #[test]
+    fn bans_complete_nicknames_to_masks() {
+        let channel = BufferId::new("net", "#tirc");
+
+        assert_eq!(
+            line(ban(&channel, "troll", true)),
+            "MODE #tirc +b troll!*@*"
+        );
+        assert_eq!(
+            line(ban(&channel, "#rust *!*@spam.example a", true)),
+            "MODE #rust +bb *!*@spam.example a!*@*"
+        );
+        assert_eq!(
+            line(ban(&channel, "troll", false)),
+            "MODE #tirc -b troll!*@*"
+        );
+        assert!(ban(&channel, "", true).is_err());
+    }
+
+    #[test]
+    fn modes_default_to_the_channel_or_own_nickname() {
+        let channel = BufferId::new("net", "#tirc");
+        let query = BufferId::new("net", "alice");
+
+        assert_eq!(line(mode(&channel, "me", "")), "MODE #tirc");
+        assert_eq!(
+            line(mode(&channel, "me", "+o alice")),
+            "MODE #tirc +o alice"
+        );
+        assert_eq!(line(mode(&query, "me", "+i")), "MODE me +i");
+        assert_eq!(line(mode(&channel, "me", "ME -i")), "MODE ME -i");
+        assert!(mode(&channel, "me", "o").is_err());
+    }
+
+    #[test]
+    fn away_and_ctcp() {
+        assert_eq!(line(Ok(away(Some("")))), "AWAY Away");
+        assert_eq!(line(Ok(away(Some("lunch break")))), "AWAY :lunch break");
+        assert_eq!(line(Ok(away(None))), "AWAY");
+
+        let query = BufferId::new("net", "alice");
+
+        assert_eq!(
+            line(ctcp(&query, "version")),
+            "PRIVMSG alice \x01VERSION\x01"
+        );
+        assert_eq!(
+            line(ctcp(&query, "bob ping 123")),
+            "PRIVMSG bob :\x01PING 123\x01"
+        );
+        assert!(ctcp(&BufferId::status("net"), "VERSION").is_err());
+        assert!(ctcp(&query, "bob").is_err());
+    }
+
+    #[test]
+    fn passwords_are_secret() {
+        let secret = |line: &str| is_secret(&quote(line).unwrap());
+
+        assert!(secret("PASS hunter2"));
+        assert!(secret("oper admin hunter2"));
+        assert!(secret("PRIVMSG NickServ :IDENTIFY hunter2"));
+        assert!(secret("NICKSERV IDENTIFY hunter2"));
+        assert!(secret("ns identify tirc hunter2"));
+        assert!(secret(
+            "PRIVMSG nickserv@services.libera.chat :IDENTIFY hunter2"
+        ));
+        assert!(!secret("PRIVMSG #tirc :pass me the salt"));
+        assert!(!secret("PRIVMSG alice :IDENTIFY is a NickServ command"));
+        assert!(!secret("PRIVMSG #chanserv :hi"));
+    }
+}
@@ -0,0 +1,308 @@
+use irc::client::prelude::{ChannelExt, Command};
+
+use super::{Mode, State, TircMessage};
+
+/// Completion of the word before the cursor of the input line, cycled
+/// through its candidates on repeated `Tab` and `Shift-Tab`.
+#[derive(Debug)]
+pub struct Completion {
+    /// Input before the completed word.
+    head: String,
+    /// Input after the cursor.
+    tail: String,
+    candidates: Vec<String>,
+    index: usize,
+    /// Input and cursor after the last completion. Editing either of them
+    /// starts a new completion.
+    applied: (String, usize),
+}
+
+impl Completion {
+    /// Completes the word before `cursor`, counted in characters, with the
+    /// first candidate, or the last one when going `backwards`. Returns None
+    /// without candidates.
+    pub fn new(
+        line: &str,
+        cursor: usize,
+        candidates: Vec<String>,
+        backwards: bool,
+    ) -> Option<Self> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let end = byte_index(line, cursor);
+        let (start, _) = word_at(line, cursor);
+        let mut completion = Self {
+            head: line[..start].to_owned(),
+            tail: line[end..].to_owned(),
+            index: if backwards { candidates.len() - 1 } else { 0 },
+            candidates,
+            applied: Default::default(),
+        };
+
+        completion.apply();
+
+        Some(completion)
+    }
+
+    /// Whether the input is still the one left by this completion.
+    pub fn is_continued_by(&self, line: &str, cursor: usize) -> bool {
+        self.applied.0 == line && self.applied.1 == cursor
+    }
+
+    /// Replaces the completed word with the next or previous candidate.
+    pub fn cycle(&mut self, backwards: bool) {
+        let count = self.candidates.len();
+
+        self.index = if backwards {
+            (self.index + count - 1) % count
+        } else {
+            (self.index + 1) % count
+        };
+
+        self.apply();
+    }
+
+    /// The completed input and the cursor position after the completion.
+    pub fn applied(&self) -> (&str, usize) {
+        (&self.applied.0, self.applied.1)
+    }
+
+    fn apply(&mut self) {
+        let completed = format!("{}{}", self.head, self.candidates[self.index]);
+        let cursor = completed.chars().count();
+
+        self.applied = (completed + &self.tail, cursor);
+    }
+}
+
+/// The byte index where the word before `cursor` starts, and that word.
+pub fn word_at(line: &str, cursor: usize) -> (usize, &str) {
+    let before = &line[..byte_index(line, cursor)];
+    let start = before.rfind(char::is_whitespace).map_or(0, |index| {
+        index + before[index..].chars().next().unwrap().len_utf8()
+    });
+
+    (start, &before[start..])
+}
+
+/// Candidates for completing `word` in the current buffer, in the order they
+/// are cycled through, with what follows them appended. `head` is the input
+/// before the word and `extra` the candidates of Lua completion sources.
+pub fn candidates(
+    state: &State,
+    head: &str,
+    word: &str,
+    commands: &[&str],
+    extra: Vec<String>,
+) -> Vec<String> {
+    let is_command = matches!(state.mode, Mode::Command) && head.is_empty();
+    let builtin = if is_command {
+        commands.iter().map(|command| command.to_string()).collect()
+    } else if word.is_channel_name() {
+        channels(state)
+    } else {
+        nicknames(state)
+    };
+    // Addressing someone at the start of a message, e.g. `alice: hi`.
+    let suffix = |candidate: &str| match state.mode {
+        Mode::Insert if head.is_empty() && !candidate.is_channel_name() => ": ",
+        _ => " ",
+    };
+    let word = word.to_lowercase();
+    let mut candidates: Vec<String> = Vec::new();
+
+    for candidate in builtin.into_iter().chain(extra) {
+        if candidate.to_lowercase().starts_with(&word) && !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let suffix = suffix(&candidate);
+            candidate + suffix
+        })
+        .collect()
+}
+
+/// Nicknames of the members of the current buffer, those who spoke most
+/// recently first.
+fn nicknames(state: &State) -> Vec<String> {
+    let buffer_id = &state.current_buffer;
+    let own_nickname = state.nickname(&buffer_id.server);
+    let mut nicknames: Vec<String> = Vec::new();
+
+    if !buffer_id.is_status() && !buffer_id.name.is_channel_name() {
+        nicknames.push(buffer_id.name.clone());
+    }
+
+    let mut users: Vec<&str> = state
+        .users_in_current_buffer
+        .iter()
+        .map(|user| user.get_nickname())
+        .collect();
+
+    users.sort_by_key(|nickname| nickname.to_lowercase());
+
+    // Those who left since they spoke are not offered.
+    let speakers = state
+        .buffers
+        .get(buffer_id)
+        .into_iter()
+        .flat_map(|buffer| buffer.messages.iter().rev())
+        .filter_map(|message| match message {
+            TircMessage::Irc(_, message, _) => match message.command {
+                Command::PRIVMSG(..) | Command::NOTICE(..) => message.source_nickname(),
+                _ => None,
+            },
+            TircMessage::Lua(..) => None,
+        })
+        .filter_map(|speaker| {
+            users
+                .iter()
+                .find(|user| user.eq_ignore_ascii_case(speaker))
+                .copied()
+        });
+
+    for nickname in speakers.chain(users.iter().copied()) {
+        if !nickname.eq_ignore_ascii_case(own_nickname)
+            && !nicknames.iter().any(|known| known == nickname)
+        {
+            nicknames.push(nickname.to_owned());
+        }
+    }
+
+    nicknames
+}
+
+/// Channels of the current server, the current one first.
+fn channels(state: &State) -> Vec<String> {
+    let current = &state.current_buffer;
+
+    std::iter::once(current)
+        .chain(
+            state
+                .buffers
+                .keys()
+                .filter(|buffer_id| *buffer_id != current),
+        )
+        .filter(|buffer_id| buffer_id.server == current.server && buffer_id.name.is_channel_name())
+        .map(|buffer_id| buffer_id.name.clone())
+        .collect()
+}
+
+fn byte_index(line: &str, cursor: usize) -> usize {
+    line.char_indices()
+        .nth(cursor)
+        .map_or(line.len(), |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use irc::client::data::User;
+
+    use super::*;
+    use crate::ui::BufferId;
+
+    fn state(lua: &mlua::Lua, mode: Mode) -> State {
+        let mut state = State::default();
+        state.add_server("net", "irc.example.com");
+        state.servers["net"].nickname = "me".to_owned();
+        state.mode = mode;
+
+        let channel = BufferId::new("net", "#tirc");
+        state.create_buffer_if_not_exists(&BufferId::new("net", "#rust"));
+        state.create_buffer_if_not_exists(&channel);
+        state.set_current_buffer(&channel);
+
+        for raw in [
+            ":carol!u@h PRIVMSG #tirc :bye",
+            ":bob!u@h PRIVMSG #tirc :hi",
+            ":Me!u@h PRIVMSG #tirc :hey",
+        ] {
+            let message =
+                TircMessage::from_message(raw.parse::<irc::proto::Message>().unwrap().into(), lua);
+            state.push_message_to(&channel, message.unwrap());
+        }
+
+        state.users_in_current_buffer = ["alice", "@Bart", "bob", "me"]
+            .into_iter()
+            .map(User::new)
+            .collect();
+
+        state
+    }
+
+    #[test]
+    fn nicknames_are_members_recent_speakers_first() {
+        let lua = mlua::Lua::new();
+        let insert = state(&lua, Mode::Insert);
+
+        assert_eq!(
+            candidates(&insert, "", "b", &[], Vec::new()),
+            ["bob: ", "Bart: "]
+        );
+        assert_eq!(
+            candidates(&insert, "hi ", "", &[], Vec::new()),
+            ["bob ", "alice ", "Bart "]
+        );
+        assert_eq!(
+            candidates(&insert, "", "c", &[], Vec::new()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn channels_and_commands_are_completed() {
+        let lua = mlua::Lua::new();
+        let insert = state(&lua, Mode::Insert);
+        assert_eq!(
+            candidates(&insert, "", "#", &[], Vec::new()),
+            ["#tirc ", "#rust "]
+        );
+
+        let command = state(&lua, Mode::Command);
+        let commands = ["join", "j", "whois"];
+        assert_eq!(
+            candidates(&command, "", "j", &commands, vec!["jump".to_owned()]),
+            ["join ", "j ", "jump "]
+        );
+        assert_eq!(
+            candidates(&command, "whois ", "AL", &commands, Vec::new()),
+            ["alice "]
+        );
+    }
+
+    #[test]
+    fn completion_cycles_through_candidates() {
+        let candidates = vec!["alice: ".to_owned(), "alfred: ".to_owned()];
+        let mut completion = Completion::new("al tail", 2, candidates, false).unwrap();
+
+        assert_eq!(completion.applied(), ("alice:  tail", 7));
+        assert!(completion.is_continued_by("alice:  tail", 7));
+        assert!(!completion.is_continued_by("alice:  tai", 7));
+
+        completion.cycle(false);
+        assert_eq!(completion.applied(), ("alfred:  tail", 8));
+
+        completion.cycle(false);
+        assert_eq!(completion.applied(), ("alice:  tail", 7));
+
+        completion.cycle(true);
+        assert_eq!(completion.applied(), ("alfred:  tail", 8));
+
+        assert!(Completion::new("x", 1, Vec::new(), false).is_none());
+    }
+
+    #[test]
+    fn word_at_finds_the_word_before_the_cursor() {
+        assert_eq!(word_at("hello wor", 9), (6, "wor"));
+        assert_eq!(word_at("hello wor", 7), (6, "w"));
+        assert_eq!(word_at("héllo wör", 8), (7, "wö"));
+        assert_eq!(word_at("hello ", 6), (6, ""));
+        assert_eq!(word_at("", 0), (0, ""));
+    }
+}
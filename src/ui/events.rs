@@ -0,0 +1,345 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use irc::proto::{mode::ModeType, Command, Message, Mode as IrcMode};
+use mlua::{Lua, Table};
+
+use super::{BufferId, Mode, State};
+
+/// A change to the open buffers or the current one, see `BufferTracker`.
+#[derive(Debug, PartialEq)]
+pub enum BufferEvent {
+    Open(BufferId),
+    Close(BufferId),
+    Switch {
+        previous: Option<BufferId>,
+        current: BufferId,
+    },
+}
+
+impl BufferEvent {
+    /// Name of the Lua event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BufferEvent::Open(_) => "buffer_open",
+            BufferEvent::Close(_) => "buffer_close",
+            BufferEvent::Switch { .. } => "buffer_switch",
+        }
+    }
+
+    pub fn buffer_id(&self) -> &BufferId {
+        match self {
+            BufferEvent::Open(buffer_id) | BufferEvent::Close(buffer_id) => buffer_id,
+            BufferEvent::Switch { current, .. } => current,
+        }
+    }
+
+    /// The `TircBufferEvent` table handed to the handlers.
+    pub fn to_lua(&self, lua: &Lua) -> mlua::Result<Table> {
+        let buffer_id = self.buffer_id();
+        let event = server_event(lua, &buffer_id.server)?;
+
+        event.set("buffer", buffer_id.name.as_str())?;
+
+        if let BufferEvent::Switch {
+            previous: Some(previous),
+            ..
+        } = self
+        {
+            event.set("previous_server", previous.server.as_str())?;
+            event.set("previous_buffer", previous.name.as_str())?;
+        }
+
+        Ok(event)
+    }
+}
+
+/// Notices buffers opened, closed or switched to by comparing the buffers of
+/// the state with those seen on the last call, wherever they changed.
+#[derive(Debug, Default)]
+pub struct BufferTracker {
+    buffers: Vec<BufferId>,
+    current: Option<BufferId>,
+}
+
+impl BufferTracker {
+    /// The changes since the last call: closed buffers first, then opened
+    /// ones in buffer bar order, then a switch of the current buffer.
+    pub fn changes(&mut self, state: &State) -> Vec<BufferEvent> {
+        let mut events: Vec<BufferEvent> = self
+            .buffers
+            .iter()
+            .filter(|buffer_id| !state.buffers.contains_key(*buffer_id))
+            .cloned()
+            .map(BufferEvent::Close)
+            .collect();
+
+        events.extend(
+            state
+                .buffers
+                .keys()
+                .filter(|buffer_id| !self.buffers.contains(buffer_id))
+                .cloned()
+                .map(BufferEvent::Open),
+        );
+
+        if state.buffers.contains_key(&state.current_buffer)
+            && self.current.as_ref() != Some(&state.current_buffer)
+        {
+            events.push(BufferEvent::Switch {
+                previous: self.current.take(),
+                current: state.current_buffer.clone(),
+            });
+            self.current = Some(state.current_buffer.clone());
+        }
+
+        self.buffers = state.buffers.keys().cloned().collect();
+
+        events
+    }
+}
+
+/// A table with the `server` an event is about.
+pub fn server_event(lua: &Lua, server: &str) -> mlua::Result<Table> {
+    let event = lua.create_table()?;
+
+    event.set("server", server)?;
+
+    Ok(event)
+}
+
+/// The event an IRC message from `server` is about, if any: `join`, `part`,
+/// `nick_change` or `mode_change`, with its table. `nickname` is our own on
+/// the server.
+pub fn irc_event(
+    lua: &Lua,
+    server: &str,
+    nickname: &str,
+    message: &Message,
+) -> mlua::Result<Option<(&'static str, Table)>> {
+    let event = server_event(lua, server)?;
+    let source = message.source_nickname();
+
+    if let Some(source) = source {
+        event.set("nickname", source)?;
+        event.set("is_self", source == nickname)?;
+    }
+
+    let name = match &message.command {
+        Command::JOIN(channel, _, _) => {
+            event.set("channel", channel.as_str())?;
+            "join"
+        }
+        Command::PART(channel, reason) => {
+            event.set("channel", channel.as_str())?;
+            event.set("reason", reason.as_deref())?;
+            "part"
+        }
+        Command::NICK(new_nickname) => {
+            event.set("old_nickname", source)?;
+            event.set("new_nickname", new_nickname.as_str())?;
+            "nick_change"
+        }
+        Command::ChannelMODE(target, modes) => {
+            event.set("target", target.as_str())?;
+            event.set("changes", mode_changes(lua, modes)?)?;
+            "mode_change"
+        }
+        Command::UserMODE(target, modes) => {
+            event.set("target", target.as_str())?;
+            event.set("changes", mode_changes(lua, modes)?)?;
+            "mode_change"
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some((name, event)))
+}
+
+/// The `TircModeChange` tables of a MODE message.
+fn mode_changes<T: ModeType>(lua: &Lua, modes: &[IrcMode<T>]) -> mlua::Result<Vec<Table>> {
+    modes
+        .iter()
+        .map(|mode| {
+            let change = lua.create_table()?;
+
+            change.set("mode", mode.flag())?;
+            change.set("arg", mode.arg())?;
+
+            Ok(change)
+        })
+        .collect()
+}
+
+/// The `TircKeyEvent` table of a key typed in the current buffer.
+pub fn key_event(lua: &Lua, state: &State, key: &KeyEvent) -> mlua::Result<Table> {
+    let event = server_event(lua, &state.current_buffer.server)?;
+    let mode = match state.mode {
+        Mode::Normal => "normal",
+        Mode::Command => "command",
+        Mode::Insert => "insert",
+        Mode::Search => "search",
+    };
+
+    event.set("buffer", state.current_buffer.name.as_str())?;
+    event.set("key", key_name(key))?;
+    event.set("mode", mode)?;
+
+    Ok(event)
+}
+
+/// Names a key the way the docs do, e.g. `a`, `Ctrl-r`, `Alt-Enter` or
+/// `Shift-Left`. Shift is implied by the character of character keys.
+pub fn key_name(key: &KeyEvent) -> String {
+    let code = match key.code {
+        KeyCode::Char(' ') => "Space".to_owned(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        KeyCode::Enter => "Enter".to_owned(),
+        KeyCode::Esc => "Esc".to_owned(),
+        KeyCode::Tab => "Tab".to_owned(),
+        KeyCode::BackTab => "BackTab".to_owned(),
+        KeyCode::Backspace => "Backspace".to_owned(),
+        KeyCode::Delete => "Delete".to_owned(),
+        KeyCode::Insert => "Insert".to_owned(),
+        KeyCode::Home => "Home".to_owned(),
+        KeyCode::End => "End".to_owned(),
+        KeyCode::PageUp => "PageUp".to_owned(),
+        KeyCode::PageDown => "PageDown".to_owned(),
+        KeyCode::Up => "Up".to_owned(),
+        KeyCode::Down => "Down".to_owned(),
+        KeyCode::Left => "Left".to_owned(),
+        KeyCode::Right => "Right".to_owned(),
+        code => format!("{:?}", code),
+    };
+    let mut name = String::new();
+
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        name.push_str("Ctrl-");
+    }
+
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        name.push_str("Alt-");
+    }
+
+    if key.modifiers.contains(KeyModifiers::SHIFT)
+        && !matches!(key.code, KeyCode::Char(_) | KeyCode::BackTab)
+    {
+        name.push_str("Shift-");
+    }
+
+    name.push_str(&code);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_changes_are_noticed() {
+        let mut state = State::new();
+        let mut tracker = BufferTracker::default();
+        let status = BufferId::status("net");
+        let channel = BufferId::new("net", "#tirc");
+
+        state.add_server("net", "irc.example.org");
+
+        assert_eq!(
+            tracker.changes(&state),
+            [
+                BufferEvent::Open(status.clone()),
+                BufferEvent::Switch {
+                    previous: None,
+                    current: status.clone(),
+                },
+            ]
+        );
+        assert!(tracker.changes(&state).is_empty());
+
+        state.create_buffer_if_not_exists(&channel);
+        state.set_current_buffer(&channel);
+
+        assert_eq!(
+            tracker.changes(&state),
+            [
+                BufferEvent::Open(channel.clone()),
+                BufferEvent::Switch {
+                    previous: Some(status.clone()),
+                    current: channel.clone(),
+                },
+            ]
+        );
+
+        state.add_server("other", "irc.example.com");
+        state.remove_server("net");
+
+        assert_eq!(
+            tracker.changes(&state),
+            [
+                BufferEvent::Close(status),
+                BufferEvent::Close(channel.clone()),
+                BufferEvent::Open(BufferId::status("other")),
+                BufferEvent::Switch {
+                    previous: Some(channel),
+                    current: BufferId::status("other"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn irc_messages_map_to_events() {
+        let lua = Lua::new();
+        let event = |raw: &str| {
+            let message: Message = raw.parse().unwrap();
+
+            irc_event(&lua, "net", "me", &message).unwrap()
+        };
+
+        let (name, join) = event(":me!u@h JOIN #tirc").unwrap();
+        assert_eq!(name, "join");
+        assert_eq!(join.get::<String>("channel").unwrap(), "#tirc");
+        assert!(join.get::<bool>("is_self").unwrap());
+
+        let (name, part) = event(":alice!u@h PART #tirc :bye").unwrap();
+        assert_eq!(name, "part");
+        assert_eq!(part.get::<String>("nickname").unwrap(), "alice");
+        assert_eq!(part.get::<String>("reason").unwrap(), "bye");
+        assert!(!part.get::<bool>("is_self").unwrap());
+
+        let (name, nick) = event(":alice!u@h NICK alicia").unwrap();
+        assert_eq!(name, "nick_change");
+        assert_eq!(nick.get::<String>("old_nickname").unwrap(), "alice");
+        assert_eq!(nick.get::<String>("new_nickname").unwrap(), "alicia");
+
+        let (name, mode) = event(":op!u@h MODE #tirc +o-v alice bob").unwrap();
+        assert_eq!(name, "mode_change");
+        assert_eq!(mode.get::<String>("target").unwrap(), "#tirc");
+
+        let changes: Vec<Table> = mode.get("changes").unwrap();
+        let changes: Vec<(String, Option<String>)> = changes
+            .iter()
+            .map(|change| (change.get("mode").unwrap(), change.get("arg").unwrap()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("+o".to_owned(), Some("alice".to_owned())),
+                ("-v".to_owned(), Some("bob".to_owned())),
+            ]
+        );
+
+        assert!(event(":alice!u@h PRIVMSG #tirc :hi").is_none());
+    }
+
+    #[test]
+    fn keys_are_named_like_the_docs() {
+        let key = |code, modifiers| key_name(&KeyEvent::new(code, modifiers));
+
+        assert_eq!(key(KeyCode::Char('a'), KeyModifiers::NONE), "a");
+        assert_eq!(key(KeyCode::Char('A'), KeyModifiers::SHIFT), "A");
+        assert_eq!(key(KeyCode::Char('r'), KeyModifiers::CONTROL), "Ctrl-r");
+        assert_eq!(key(KeyCode::Enter, KeyModifiers::ALT), "Alt-Enter");
+        assert_eq!(key(KeyCode::Left, KeyModifiers::SHIFT), "Shift-Left");
+        assert_eq!(key(KeyCode::F(5), KeyModifiers::NONE), "F5");
+    }
+}
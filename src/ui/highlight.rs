@@ -0,0 +1,341 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use irc::proto::Command;
+use regex::{Regex, RegexBuilder};
+
+use crate::config::HighlightConfig;
+
+use super::{BufferId, TircMessage};
+
+/// Compiled highlight rules of a buffer, or the global ones.
+#[derive(Debug)]
+struct Rules {
+    enabled: bool,
+    nickname: Option<bool>,
+    keywords: Vec<Regex>,
+    patterns: Vec<Regex>,
+}
+
+impl Rules {
+    fn new(
+        enabled: bool,
+        nickname: Option<bool>,
+        keywords: &[String],
+        patterns: &[String],
+    ) -> Result<Self, regex::Error> {
+        Ok(Self {
+            enabled,
+            nickname,
+            keywords: keywords.iter().map(|keyword| word(keyword)).collect(),
+            patterns: compile_all(patterns)?,
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        self.keywords
+            .iter()
+            .chain(&self.patterns)
+            .any(|pattern| pattern.is_match(text))
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            nickname: Some(true),
+            keywords: Vec::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+/// Decides which messages count as activity in a buffer, and which of those
+/// mention the user according to the highlight rules of the config.
+#[derive(Debug, Default)]
+pub struct Highlighter {
+    rules: Rules,
+    /// Rules of single buffers, keyed by lowercased `server/buffer` or
+    /// buffer name.
+    buffers: HashMap<String, Rules>,
+    exclude_nicks: Vec<String>,
+    exclude_patterns: Vec<Regex>,
+    /// Our nickname on each server along with the pattern matching it,
+    /// rebuilt once it changed.
+    nicknames: RefCell<HashMap<String, (String, Regex)>>,
+}
+
+impl Highlighter {
+    pub fn new(config: &HighlightConfig) -> anyhow::Result<Self> {
+        let invalid = |err| anyhow::anyhow!("Invalid highlight pattern: {}", err);
+        let buffers = config
+            .buffers
+            .iter()
+            .map(|(key, buffer)| {
+                let rules = Rules::new(
+                    buffer.enabled,
+                    buffer.nickname,
+                    &buffer.keywords,
+                    &buffer.patterns,
+                )?;
+
+                Ok((key.to_lowercase(), rules))
+            })
+            .collect::<Result<_, regex::Error>>()
+            .map_err(invalid)?;
+
+        Ok(Self {
+            rules: Rules::new(
+                true,
+                Some(config.nickname),
+                &config.keywords,
+                &config.patterns,
+            )
+            .map_err(invalid)?,
+            buffers,
+            exclude_nicks: config
+                .exclude_nicks
+                .iter()
+                .map(|nickname| nickname.to_lowercase())
+                .collect(),
+            exclude_patterns: compile_all(&config.exclude_patterns).map_err(invalid)?,
+            nicknames: RefCell::default(),
+        })
+    }
+
+    /// Whether `message` is a message or notice sent by someone else, as
+    /// opposed to e.g. joins or our own messages.
+    pub fn is_activity(&self, message: &TircMessage, nickname: &str) -> bool {
+        let TircMessage::Irc(_, message, _) = message else {
+            return false;
+        };
+
+        matches!(message.command, Command::PRIVMSG(..) | Command::NOTICE(..))
+            && message
+                .source_nickname()
+                .is_some_and(|source| !source.eq_ignore_ascii_case(nickname))
+    }
+
+    /// Whether `message`, filed into `buffer_id`, is activity mentioning
+    /// `nickname` or matching the rules of the buffer.
+    pub fn is_highlight(
+        &self,
+        message: &TircMessage,
+        buffer_id: &BufferId,
+        nickname: &str,
+    ) -> bool {
+        if !self.is_activity(message, nickname) {
+            return false;
+        }
+
+        let TircMessage::Irc(_, irc_message, _) = message else {
+            return false;
+        };
+        let (Command::PRIVMSG(_, text) | Command::NOTICE(_, text)) = &irc_message.command else {
+            return false;
+        };
+        let source = irc_message
+            .source_nickname()
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if self.exclude_nicks.contains(&source)
+            || self
+                .exclude_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(text))
+        {
+            return false;
+        }
+
+        let buffer = self
+            .buffers
+            .get(&format!("{}/{}", buffer_id.server, buffer_id.name).to_lowercase())
+            .or_else(|| self.buffers.get(&buffer_id.name.to_lowercase()));
+
+        if buffer.is_some_and(|buffer| !buffer.enabled) {
+            return false;
+        }
+
+        let mentions_nickname = buffer
+            .and_then(|buffer| buffer.nickname)
+            .or(self.rules.nickname)
+            .unwrap_or(true);
+
+        (mentions_nickname
+            && !nickname.is_empty()
+            && self.mentions(&buffer_id.server, nickname, text))
+            || self.rules.matches(text)
+            || buffer.is_some_and(|buffer| buffer.matches(text))
+    }
+
+    /// Whether `text` mentions `nickname`, our nickname on `server`.
+    fn mentions(&self, server: &str, nickname: &str, text: &str) -> bool {
+        let mut nicknames = self.nicknames.borrow_mut();
+
+        match nicknames.get_mut(server) {
+            Some((cached, pattern)) => {
+                if cached != nickname {
+                    *cached = nickname.to_owned();
+                    *pattern = word(nickname);
+                }
+
+                pattern.is_match(text)
+            }
+            None => {
+                let pattern = word(nickname);
+                let mentions = pattern.is_match(text);
+
+                nicknames.insert(server.to_owned(), (nickname.to_owned(), pattern));
+                mentions
+            }
+        }
+    }
+
+    /// Evaluates the rules for `message` and marks it as a highlight, which
+    /// formatters see as `msg.highlight`. Returns whether it is one.
+    pub fn mark(
+        &self,
+        message: &TircMessage,
+        buffer_id: &BufferId,
+        nickname: &str,
+    ) -> mlua::Result<bool> {
+        let highlight = self.is_highlight(message, buffer_id, nickname);
+
+        if highlight {
+            message.get_lua_message().set("highlight", true)?;
+        }
+
+        Ok(highlight)
+    }
+}
+
+fn compile_all(patterns: &[String]) -> Result<Vec<Regex>, regex::Error> {
+    patterns.iter().map(|pattern| Regex::new(pattern)).collect()
+}
+
+/// Matches `text` as a whole word, ignoring case. Nicknames may contain
+/// characters like `[` or `|`, so words are delimited by anything that is not
+/// alphanumeric rather than by `\b`.
+fn word(text: &str) -> Regex {
+    RegexBuilder::new(&format!(r"(?:^|[^\w]){}(?:$|[^\w])", regex::escape(text)))
+        .case_insensitive(true)
+        .build()
+        .expect("escaped text is a valid pattern")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HighlightBufferConfig;
+
+    fn message(lua: &mlua::Lua, raw: &str) -> TircMessage {
+        TircMessage::from_message(raw.parse::<irc::proto::Message>().unwrap().into(), lua).unwrap()
+    }
+
+    #[test]
+    fn mentions_of_nickname_and_keywords_are_highlights() {
+        let lua = mlua::Lua::new();
+        let highlighter = Highlighter::new(&HighlightConfig {
+            keywords: vec!["tirc".to_owned()],
+            patterns: vec![r"^deploy(ed)?\b".to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        let buffer_id = BufferId::new("net", "#c");
+        let is_highlight =
+            |raw: &str| highlighter.is_highlight(&message(&lua, raw), &buffer_id, "Me|away");
+
+        assert!(is_highlight(":a!u@h PRIVMSG #c :me|AWAY: hi"));
+        assert!(is_highlight(":a!u@h PRIVMSG #c :try TIRC!"));
+        assert!(is_highlight(":a!u@h PRIVMSG #c :deployed to prod"));
+        assert!(!is_highlight(":a!u@h PRIVMSG #c :tircd is different"));
+        assert!(!is_highlight(":a!u@h PRIVMSG #c :hello"));
+        assert!(!is_highlight(":Me|away!u@h PRIVMSG #c :tirc"));
+        assert!(!is_highlight(":a!u@h JOIN #tirc"));
+    }
+
+    #[test]
+    fn nickname_pattern_follows_nick_changes() {
+        let lua = mlua::Lua::new();
+        let highlighter = Highlighter::default();
+        let buffer_id = BufferId::new("net", "#c");
+        let is_highlight = |nickname: &str| {
+            highlighter.is_highlight(
+                &message(&lua, ":a!u@h PRIVMSG #c :old: hi"),
+                &buffer_id,
+                nickname,
+            )
+        };
+
+        assert!(is_highlight("old"));
+        assert!(!is_highlight("new"));
+        assert_eq!(highlighter.nicknames.borrow()["net"].0, "new");
+    }
+
+    #[test]
+    fn exclusions_and_buffer_rules_take_precedence() {
+        let lua = mlua::Lua::new();
+        let highlighter = Highlighter::new(&HighlightConfig {
+            keywords: vec!["tirc".to_owned()],
+            exclude_nicks: vec!["Bot".to_owned()],
+            exclude_patterns: vec!["^!".to_owned()],
+            buffers: HashMap::from([
+                (
+                    "#Busy".to_owned(),
+                    HighlightBufferConfig {
+                        enabled: true,
+                        nickname: Some(false),
+                        keywords: vec!["rust".to_owned()],
+                        patterns: Vec::new(),
+                    },
+                ),
+                (
+                    "net/#muted".to_owned(),
+                    HighlightBufferConfig {
+                        enabled: false,
+                        nickname: None,
+                        keywords: Vec::new(),
+                        patterns: Vec::new(),
+                    },
+                ),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        let is_highlight = |buffer: &str, raw: &str| {
+            highlighter.is_highlight(&message(&lua, raw), &BufferId::new("net", buffer), "me")
+        };
+
+        assert!(!is_highlight("#c", ":bot!u@h PRIVMSG #c :me: tirc"));
+        assert!(!is_highlight("#c", ":a!u@h PRIVMSG #c :!tirc"));
+        assert!(!is_highlight("#busy", ":a!u@h PRIVMSG #busy :me: hi"));
+        assert!(is_highlight("#busy", ":a!u@h PRIVMSG #busy :rust"));
+        assert!(is_highlight("#busy", ":a!u@h PRIVMSG #busy :tirc"));
+        assert!(!is_highlight("#muted", ":a!u@h PRIVMSG #muted :me: tirc"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let config = HighlightConfig {
+            patterns: vec!["(unclosed".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(Highlighter::new(&config).is_err());
+    }
+
+    #[test]
+    fn only_messages_from_others_are_activity() {
+        let lua = mlua::Lua::new();
+        let highlighter = Highlighter::default();
+        let is_activity = |raw: &str| highlighter.is_activity(&message(&lua, raw), "me");
+
+        assert!(is_activity(":a!u@h PRIVMSG #c :hi"));
+        assert!(is_activity(":a!u@h NOTICE me :hi"));
+        assert!(!is_activity(":me!u@h PRIVMSG #c :hi"));
+        assert!(!is_activity(":ME!u@h PRIVMSG #c :hi"));
+        assert!(!is_activity("PRIVMSG #c :hi"));
+        assert!(!is_activity(":a!u@h PART #c"));
+    }
+}
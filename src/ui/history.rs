@@ -0,0 +1,102 @@
+use chrono::{SecondsFormat, Utc};
+use irc::proto::Command;
+
+use super::TircMessage;
+
+/// Capability enabling the `CHATHISTORY` command.
+pub const CAPABILITY: &str = "draft/chathistory";
+
+/// Number of messages fetched per request.
+const LIMIT: usize = 100;
+
+/// A `CHATHISTORY` request of a buffer awaiting its batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryRequest {
+    /// The newest messages, e.g. after joining a channel.
+    Latest,
+    /// The messages before the oldest message of the buffer.
+    Before,
+}
+
+fn chathistory(subcommand: &str, target: &str, reference: String) -> Command {
+    Command::Raw(
+        "CHATHISTORY".to_owned(),
+        vec![
+            subcommand.to_owned(),
+            target.to_owned(),
+            reference,
+            LIMIT.to_string(),
+        ],
+    )
+}
+
+pub fn latest(target: &str) -> Command {
+    chathistory("LATEST", target, "*".to_owned())
+}
+
+pub fn before(target: &str, reference: String) -> Command {
+    chathistory("BEFORE", target, reference)
+}
+
+/// Identifies `message` in a request, by `msgid` if it has one, otherwise by
+/// its timestamp. Lines generated by tirc cannot be referenced.
+pub fn reference(message: &TircMessage) -> Option<String> {
+    if let TircMessage::Lua(_, _) = message {
+        return None;
+    }
+
+    match message.get_msgid() {
+        Some(msgid) => Some(format!("msgid={}", msgid)),
+        None => Some(format!(
+            "timestamp={}",
+            message
+                .get_date_time()
+                .with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use irc::proto::Message;
+
+    use super::*;
+    use crate::ui::NoticeLevel;
+
+    #[test]
+    fn requests_are_formatted_as_chathistory_commands() {
+        let message: Message = latest("#tirc").into();
+        assert_eq!(message.to_string(), "CHATHISTORY LATEST #tirc * 100\r\n");
+
+        let message: Message = before("#tirc", "msgid=abc".to_owned()).into();
+        assert_eq!(
+            message.to_string(),
+            "CHATHISTORY BEFORE #tirc msgid=abc 100\r\n"
+        );
+    }
+
+    #[test]
+    fn references_prefer_msgid_over_timestamp() {
+        let lua = mlua::Lua::new();
+        let message = |raw: &str| {
+            TircMessage::from_message(raw.parse::<Message>().unwrap().into(), &lua).unwrap()
+        };
+
+        assert_eq!(
+            reference(&message(
+                "@msgid=abc;time=2024-05-01T12:00:00.000Z :a!u@h PRIVMSG #tirc :hi"
+            )),
+            Some("msgid=abc".to_owned())
+        );
+        assert_eq!(
+            reference(&message(
+                "@time=2024-05-01T12:00:00.000Z :a!u@h PRIVMSG #tirc :hi"
+            )),
+            Some("timestamp=2024-05-01T12:00:00.000Z".to_owned())
+        );
+
+        let notice = TircMessage::client_notice(NoticeLevel::Info, "hi", &lua).unwrap();
+        assert_eq!(reference(&notice), None);
+    }
+}
@@ -1,41 +1,544 @@
 use std::{
-    fmt,
+    collections::HashSet,
+    fmt, io,
     rc::Rc,
     sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
-use crossterm::event::{Event as CrosstermEvent, KeyCode};
+use chrono::{DateTime, Local};
+use crossterm::event::{Event as CrosstermEvent, KeyCode, KeyEvent, KeyModifiers};
+use futures::future;
 use irc::{
-    client::prelude::Client,
+    client::prelude::{ChannelExt, Client},
     proto::{message::Tag, Command, Message},
 };
 use mlua::Lua;
+use regex::Regex;
+use tokio::task::JoinHandle;
 
-use crate::{config::emit_event, tui::Tui};
+use crate::{
+    config::{self, emit_event, event_handlers, PasteConfig},
+    connection::{Clients, Flushed, Outgoing, Queued},
+    logging::{self, LogMatch, Logger},
+    lua::sender::get_sender,
+    notification::Notifier,
+    tui::Tui,
+};
+
+use super::{
+    commands,
+    completion::{self, Completion},
+    events::{self, BufferTracker},
+    highlight::Highlighter,
+    history::{self, HistoryRequest},
+    input_history::{HistoryKey, InputHistory, ReverseSearch},
+    paste,
+    search::{self, SearchDirection},
+    split, BufferId, Mode, NoticeLevel, Scroll, State, TircMessage,
+};
+
+/// Number of `:grep` results listed at most.
+const MAX_SEARCH_RESULTS: usize = 1000;
+
+/// Runs a built-in command with the text typed after its name, if any.
+type BuiltinHandler =
+    fn(&mut InputHandler<'_>, &mut State, Option<&str>) -> Result<(), anyhow::Error>;
+
+/// A built-in command of `handle_command`, completed in command mode and
+/// listed by `:help`.
+struct Builtin {
+    /// The name of the command, then its aliases.
+    names: &'static [&'static str],
+    /// Usage shown by `:help`.
+    usage: &'static str,
+    /// Whether it needs a connection to the server of the current buffer.
+    online: bool,
+    run: BuiltinHandler,
+}
+
+impl Builtin {
+    fn get(name: &str) -> Option<&'static Builtin> {
+        BUILTINS
+            .iter()
+            .find(|builtin| builtin.names.contains(&name))
+    }
+
+    /// Names and aliases of every built-in command.
+    fn names() -> impl Iterator<Item = &'static str> {
+        BUILTINS
+            .iter()
+            .flat_map(|builtin| builtin.names.iter().copied())
+    }
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        names: &["away"],
+        usage: ":away [message] - mark yourself as away",
+        online: true,
+        run: |handler, state, args| {
+            handler.send_to_current(state, commands::away(Some(args.unwrap_or_default())))
+        },
+    },
+    Builtin {
+        names: &["back"],
+        usage: ":back - mark yourself as no longer away",
+        online: true,
+        run: |handler, state, _| handler.send_to_current(state, commands::away(None)),
+    },
+    Builtin {
+        names: &["ban"],
+        usage: ":ban [channel] <nickname or mask>... - ban nickname!*@* or a mask",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::ban(&state.current_buffer, args.unwrap_or_default(), true)?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["cancel"],
+        usage: ":cancel - cancel the messages queued from this buffer, or from any buffer of the \
+                server in its status buffer",
+        online: false,
+        run: |handler, state, args| {
+            no_arguments(args)?;
+            handler.cancel_queued(state)
+        },
+    },
+    Builtin {
+        names: &["cc"],
+        usage: ":cc [number] - jump to the first or given :grep result",
+        online: false,
+        run: |handler, state, args| {
+            let index = match args {
+                None => handler.search_result.unwrap_or(0),
+                Some(number) => number
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| number.checked_sub(1))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid search result: {}", number))?,
+            };
+
+            handler.jump_to_result(state, index)
+        },
+    },
+    Builtin {
+        names: &["cn"],
+        usage: ":cn - jump to the next :grep result",
+        online: false,
+        run: |handler, state, args| {
+            no_arguments(args)?;
+            let index = handler.search_result.map_or(0, |index| index + 1);
+            handler.jump_to_result(state, index)
+        },
+    },
+    Builtin {
+        names: &["cp"],
+        usage: ":cp - jump to the previous :grep result",
+        online: false,
+        run: |handler, state, args| {
+            no_arguments(args)?;
+            let index = handler.search_result.unwrap_or(0).saturating_sub(1);
+            handler.jump_to_result(state, index)
+        },
+    },
+    Builtin {
+        names: &["ctcp"],
+        usage: ":ctcp [target] <request> [params] - send a CTCP request, e.g. VERSION",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::ctcp(&state.current_buffer, args.unwrap_or_default())?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["describe", "desc"],
+        usage: ":describe <target> <action> - send an action to target",
+        online: true,
+        run: |handler, state, args| handler.describe(state, required(args)?),
+    },
+    Builtin {
+        names: &["grep"],
+        usage: ":grep <pattern> - search the messages of every buffer",
+        online: false,
+        run: |handler, state, args| handler.grep(state, required(args)?),
+    },
+    Builtin {
+        names: &["help"],
+        usage: ":help [command] - list the commands or show the usage of one",
+        online: false,
+        run: |handler, state, args| {
+            let name = args.map(str::trim).filter(|name| !name.is_empty());
+            handler.help(state, name)
+        },
+    },
+    Builtin {
+        names: &["invite"],
+        usage: ":invite <nickname> [channel] - invite nickname to a channel",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::invite(&state.current_buffer, args.unwrap_or_default())?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["join", "j"],
+        usage: ":join <channel> - join a channel",
+        online: true,
+        run: |handler, state, args| {
+            let channel = required(args)?;
+            handler.send_to_current(state, Command::JOIN(channel.to_owned(), None, None))
+        },
+    },
+    Builtin {
+        names: &["kick"],
+        usage: ":kick [channel] <nickname> [reason] - kick nickname from a channel",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::kick(&state.current_buffer, args.unwrap_or_default())?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["list"],
+        usage: ":list - list the channels of the server",
+        online: true,
+        run: |handler, state, args| {
+            no_arguments(args)?;
+            handler.send_to_current(state, Command::LIST(None, None))
+        },
+    },
+    Builtin {
+        names: &["me"],
+        usage: ":me <action> - send an action to this buffer",
+        online: true,
+        run: |handler, state, args| {
+            let message = format!("\x01ACTION {}\x01", required(args)?);
+            let buffer_id = state.current_buffer.clone();
+            handler.say(state, &buffer_id.server, &buffer_id.name, &message)
+        },
+    },
+    Builtin {
+        names: &["mode"],
+        usage: ":mode [target] [modes [params]] - show or change the modes of a channel or yours",
+        online: true,
+        run: |handler, state, args| {
+            let buffer_id = &state.current_buffer;
+            let nickname = state.nickname(&buffer_id.server);
+            let command = commands::mode(buffer_id, nickname, args.unwrap_or_default())?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["msg", "m"],
+        usage: ":msg <target> [message] - open a query with target, sending message",
+        // Opening the query works offline, sending the message checks.
+        online: false,
+        run: |handler, state, args| handler.open_query(state, required(args)?),
+    },
+    Builtin {
+        names: &["nick", "n"],
+        usage: ":nick <nickname> - change your nickname",
+        online: true,
+        run: |handler, state, args| {
+            // TODO: Update nickname in irc client, as it doesn't seem to update
+            let nickname = required(args)?;
+            handler.send_to_current(state, Command::NICK(nickname.to_owned()))
+        },
+    },
+    Builtin {
+        names: &["notice"],
+        usage: ":notice <target> <message> - send a notice to target",
+        online: true,
+        run: |handler, state, args| handler.notice(state, required(args)?),
+    },
+    Builtin {
+        names: &["part", "p"],
+        usage: ":part <channel> - leave a channel",
+        online: true,
+        run: |handler, state, args| {
+            let channel = required(args)?;
+            handler.send_to_current(state, Command::PART(channel.to_owned(), None))
+        },
+    },
+    Builtin {
+        names: &["quit", "q"],
+        usage: ":quit - disconnect from every server and quit",
+        online: false,
+        run: |handler, _, args| {
+            no_arguments(args)?;
+
+            // Quitting skips the queue.
+            handler.outgoing.quit("tirc");
 
-use super::{state::ChatBuffer, Mode, State, TircMessage};
+            Err(Quit.into())
+        },
+    },
+    Builtin {
+        names: &["quote"],
+        usage: ":quote <line> - send a raw IRC line, e.g. :quote MODE #tirc +m",
+        online: true,
+        run: |handler, state, args| {
+            handler.send_to_current(state, commands::quote(required(args)?)?)
+        },
+    },
+    Builtin {
+        names: &["reload"],
+        usage: ":reload - reload init.lua and the modules it requires",
+        online: false,
+        run: |_, _, args| {
+            no_arguments(args)?;
+            Err(Reload.into())
+        },
+    },
+    Builtin {
+        names: &["topic"],
+        usage: ":topic [channel] [topic] - show or change the topic of a channel",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::topic(&state.current_buffer, args.unwrap_or_default())?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["unban"],
+        usage: ":unban [channel] <nickname or mask>... - lift a ban",
+        online: true,
+        run: |handler, state, args| {
+            let command = commands::ban(&state.current_buffer, args.unwrap_or_default(), false)?;
+            handler.send_to_current(state, command)
+        },
+    },
+    Builtin {
+        names: &["whois"],
+        usage: ":whois <nickname> - ask the server about nickname",
+        online: true,
+        run: |handler, state, args| {
+            let nickname = required(args)?;
+            handler.send_to_current(state, Command::WHOIS(None, nickname.to_owned()))
+        },
+    },
+];
+
+/// Returned by the handler of a built-in command given arguments it does not
+/// take, or none when it needs some.
+#[derive(Debug)]
+struct InvalidArguments;
+
+impl fmt::Display for InvalidArguments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid arguments")
+    }
+}
+
+impl std::error::Error for InvalidArguments {}
+
+/// The arguments of a built-in command that needs some.
+fn required(args: Option<&str>) -> Result<&str, anyhow::Error> {
+    args.ok_or_else(|| InvalidArguments.into())
+}
+
+/// Rejects the arguments of a built-in command that takes none.
+fn no_arguments(args: Option<&str>) -> Result<(), anyhow::Error> {
+    match args {
+        Some(_) => Err(InvalidArguments.into()),
+        None => Ok(()),
+    }
+}
 
 static COUNTER: AtomicUsize = AtomicUsize::new(1);
 fn get_id() -> usize {
     COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
+/// Returned as the error of `InputHandler::handle_event` when the user quits.
+#[derive(Debug)]
+pub struct Quit;
+
+impl fmt::Display for Quit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("quit")
+    }
+}
+
+impl std::error::Error for Quit {}
+
+/// Returned as the error of `InputHandler::handle_event` when the user asks
+/// to reload the config.
+#[derive(Debug)]
+pub struct Reload;
+
+impl fmt::Display for Reload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("reload")
+    }
+}
+
+impl std::error::Error for Reload {}
+
 #[derive(Debug)]
 pub enum Event<I> {
     Input(I),
-    Message(Box<Message>),
+    /// A message received from the server with the given name.
+    Message(String, Box<Message>),
+    /// The terminal gained (`true`) or lost focus.
+    Focus(bool),
+    /// Text pasted into the terminal.
+    Paste(String),
+    /// Queued messages may be sent.
+    Flush,
+    /// The logs searched by `:grep`, see `InputHandler::log_search`.
+    LogSearch(io::Result<Vec<LogMatch>>),
     Tick,
 }
 
+/// A `:grep` whose results are listed once the logs are searched off the UI
+/// thread.
+struct LogSearch {
+    /// The search results buffer the results are listed in.
+    results_id: BufferId,
+    query: String,
+    pattern: Regex,
+    task: JoinHandle<io::Result<Vec<LogMatch>>>,
+}
+
 pub struct InputHandler<'lua> {
     lua: &'lua Lua,
-    irc: Client,
+    clients: Clients,
+    outgoing: Outgoing,
     ui: Tui,
+    logger: Logger,
+    highlighter: Highlighter,
+    notifier: Notifier,
+    paste: PasteConfig,
+    /// Whether the terminal has focus, assumed until told otherwise.
+    focused: bool,
+    /// Buffers that were opened already.
+    opened: HashSet<BufferId>,
+    /// First key of a two key sequence in normal mode, e.g. `gg`.
+    pending_key: Option<KeyCode>,
+    /// Messages found by the last `:grep`, by buffer and time.
+    search_results: Vec<(BufferId, DateTime<Local>)>,
+    /// Index of the search result jumped to last.
+    search_result: Option<usize>,
+    /// `:grep` waiting for its logs to be searched.
+    log_search: Option<LogSearch>,
+    /// Completion cycled through by repeating `Tab`.
+    completion: Option<Completion>,
+    input_history: InputHistory,
+    /// Reverse search through the input history, its query is
+    /// `State::history_search`.
+    reverse_search: Option<ReverseSearch>,
+    /// Lines pasted into the input of a buffer, sent once confirmed.
+    pending_paste: Option<(BufferId, Vec<String>)>,
+    /// Buffers seen by the Lua `buffer_*` events.
+    buffer_tracker: BufferTracker,
+    /// Lua `key` handlers that failed, no longer called as they would fail
+    /// again on every key.
+    failed_key_handlers: Vec<mlua::Function>,
 }
 
 impl<'lua> InputHandler<'lua> {
-    pub fn new(lua: &'lua Lua, irc: Client, ui: Tui) -> Self {
-        Self { lua, irc, ui }
+    pub fn new(
+        lua: &'lua Lua,
+        ui: Tui,
+        logger: Logger,
+        highlighter: Highlighter,
+        notifier: Notifier,
+        input_history: InputHistory,
+        outgoing: Outgoing,
+    ) -> Self {
+        Self {
+            lua,
+            clients: Clients::default(),
+            outgoing,
+            ui,
+            logger,
+            highlighter,
+            notifier,
+            paste: PasteConfig::default(),
+            focused: true,
+            opened: HashSet::new(),
+            pending_key: None,
+            search_results: Vec::new(),
+            search_result: None,
+            log_search: None,
+            completion: None,
+            input_history,
+            reverse_search: None,
+            pending_paste: None,
+            buffer_tracker: BufferTracker::default(),
+            failed_key_handlers: Vec::new(),
+        }
+    }
+
+    /// Registers the connected client of a server, keyed by the server name
+    /// used in `BufferId::server`. Replaces the client of a previous
+    /// connection.
+    pub fn add_client(&mut self, server: &str, client: Client) {
+        self.outgoing.connect(server, client.sender());
+        self.clients.insert(server, client);
+    }
+
+    /// The clients of the connected servers.
+    pub fn clients(&self) -> &Clients {
+        &self.clients
+    }
+
+    /// Forgets the client of a server whose connection was lost, dropping
+    /// the messages still queued to it. Returns the number of dropped
+    /// messages.
+    pub fn remove_client(&mut self, state: &mut State, server: &str) -> usize {
+        self.clients.remove(server);
+
+        let dropped = self.outgoing.disconnect(server);
+        Self::remove_drafts(state, server, &dropped);
+
+        dropped.len()
+    }
+
+    /// Quits the connection to `server`, if connected, e.g. because it was
+    /// removed from the config.
+    pub fn quit_server(&mut self, server: &str) -> Result<(), anyhow::Error> {
+        if self.clients.contains(server) {
+            self.outgoing
+                .send(server, Command::QUIT(Some("tirc".to_owned())))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the lines of pastes as the paste settings of the config say.
+    pub fn with_paste(mut self, paste: PasteConfig) -> Self {
+        self.paste = paste;
+        self
+    }
+
+    /// Applies the logging, highlight, notification and paste settings of a
+    /// reloaded config.
+    pub fn reconfigure(
+        &mut self,
+        logger: Logger,
+        highlighter: Highlighter,
+        notifier: Notifier,
+        paste: PasteConfig,
+    ) {
+        self.logger = logger;
+        self.highlighter = highlighter;
+        self.notifier = notifier;
+        self.paste = paste;
+        self.failed_key_handlers.clear();
+    }
+
+    /// The queue of messages to send to the servers.
+    pub fn outgoing(&self) -> &Outgoing {
+        &self.outgoing
+    }
+
+    /// When queued messages may be sent next, see `Event::Flush`.
+    pub fn next_flush(&self) -> Option<std::time::Instant> {
+        self.outgoing.next_flush()
     }
 
     pub fn ui(&self) -> &Tui {
@@ -43,38 +546,501 @@ impl<'lua> InputHandler<'lua> {
     }
 
     pub fn sync_state(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
-        state.nickname = self.irc.current_nickname().to_string();
+        for (server, client) in self.clients.lock().iter() {
+            if let Some(server_state) = state.servers.get_mut(server) {
+                server_state.nickname = client.current_nickname().to_string();
+            }
+
+            for channel in client.list_channels().unwrap_or_default() {
+                state.create_buffer_if_not_exists(&BufferId::new(server, channel));
+            }
+        }
+
+        let current_buffer = &state.current_buffer;
+
+        state.users_in_current_buffer = match self.clients.lock().get(&current_buffer.server) {
+            Some(client) if !current_buffer.is_status() => client
+                .list_users(&current_buffer.name)
+                .unwrap_or_default()
+                .into(),
+            _ => Rc::new([]),
+        };
+
+        self.open_buffers(state)?;
+
+        for event in self.buffer_tracker.changes(state) {
+            let lua_event = event.to_lua(self.lua)?;
+
+            self.emit_event(state, event.name(), &event.buffer_id().server, lua_event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Dispatches a Lua event along with the sender of `server`. A failing
+    /// handler is reported in the current buffer rather than failing the
+    /// caller.
+    pub fn emit_event(
+        &self,
+        state: &mut State,
+        name: &str,
+        server: &str,
+        event: mlua::Table,
+    ) -> Result<(), anyhow::Error> {
+        let result = get_sender(self.lua, server)
+            .and_then(|sender| emit_event(self.lua, name, (event, sender)));
+
+        self.report_lua_error(state, name, result)
+    }
 
-        let channels = self.irc.list_channels().unwrap_or_default();
+    /// Reports the error of a Lua event handler in the current buffer, going
+    /// on as if no handler was registered.
+    fn report_lua_error<T: Default>(
+        &self,
+        state: &mut State,
+        name: &str,
+        result: mlua::Result<T>,
+    ) -> Result<T, anyhow::Error> {
+        match result {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                let text = format!("Error in a Lua {} handler: {}", name, err);
+                let message = TircMessage::client_notice(NoticeLevel::Error, &text, self.lua)?;
+                let buffer_id = state.current_buffer.clone();
 
-        let buffers = &mut state.buffers;
+                state.push_message_to(&buffer_id, message);
 
-        for channel in channels {
-            if buffers.get(&channel).is_none() {
-                buffers.insert(channel, ChatBuffer::default());
+                Ok(T::default())
             }
         }
+    }
 
-        if state.current_buffer == State::get_default_buffer_name() {
-            state.users_in_current_buffer = Rc::new([]);
-        } else {
-            state.users_in_current_buffer = self
-                .irc
-                .list_users(&state.current_buffer)
-                .unwrap_or_default()
-                .into();
+    /// Whether a Lua `key` handler took the key by returning `true`, which
+    /// keeps tirc from handling it. A handler that fails is reported once
+    /// and skipped from then on.
+    fn key_taken(&mut self, state: &mut State, key: &KeyEvent) -> Result<bool, anyhow::Error> {
+        let result = (|| -> mlua::Result<_> {
+            let event = events::key_event(self.lua, state, key)?;
+            let sender = get_sender(self.lua, &state.current_buffer.server)?;
+
+            Ok(Some((event, sender, event_handlers(self.lua, "key")?)))
+        })();
+        let Some((event, sender, handlers)) = self.report_lua_error(state, "key", result)? else {
+            return Ok(false);
+        };
+
+        for handler in handlers {
+            if self.failed_key_handlers.contains(&handler) {
+                continue;
+            }
+
+            match handler.call::<Option<bool>>((&event, sender.clone())) {
+                Ok(Some(true)) => return Ok(true),
+                Ok(_) => {}
+                Err(err) => {
+                    self.failed_key_handlers.push(handler);
+                    self.report_lua_error::<()>(state, "key", Err(err))?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Passes a message typed into `buffer_id` through the Lua
+    /// `input_submit` handlers, each of which may return `false` to cancel
+    /// it or a string to send instead. Returns the text to send.
+    fn submit_input(
+        &self,
+        state: &mut State,
+        buffer_id: &BufferId,
+        text: &str,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let result = (|| {
+            let event = events::server_event(self.lua, &buffer_id.server)?;
+            let sender = get_sender(self.lua, &buffer_id.server)?;
+
+            event.set("buffer", buffer_id.name.as_str())?;
+            event.set("text", text)?;
+
+            for handler in event_handlers(self.lua, "input_submit")? {
+                match handler.call::<mlua::Value>((&event, sender.clone()))? {
+                    mlua::Value::Boolean(false) => return Ok(None),
+                    mlua::Value::String(text) => event.set("text", text)?,
+                    _ => {}
+                }
+            }
+
+            event.get("text").map(Some)
+        })();
+
+        match result {
+            Ok(text) => Ok(text),
+            // Sent unchanged rather than lost.
+            Err(err) => {
+                self.report_lua_error(state, "input_submit", Err::<(), _>(err))?;
+
+                Ok(Some(text.to_owned()))
+            }
+        }
+    }
+
+    /// Backfills the buffers opened since the last call, from the logs and
+    /// for queries from the server's history.
+    fn open_buffers(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
+        let buffer_ids: Vec<BufferId> = state
+            .buffers
+            .keys()
+            .filter(|buffer_id| !self.opened.contains(*buffer_id))
+            .cloned()
+            .collect();
+
+        for buffer_id in buffer_ids {
+            self.opened.insert(buffer_id.clone());
+
+            if buffer_id.is_status() || buffer_id.is_search_results() {
+                continue;
+            }
+
+            self.restore_history(state, &buffer_id)?;
+
+            // Channels are backfilled once joined.
+            if !buffer_id.name.is_channel_name() {
+                self.request_history(state, &buffer_id, HistoryRequest::Latest)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restores the logged history of a buffer, separated from live messages
+    /// by a marker.
+    fn restore_history(
+        &self,
+        state: &mut State,
+        buffer_id: &BufferId,
+    ) -> Result<(), anyhow::Error> {
+        let limit = self.logger.restore_limit(buffer_id);
+
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let history = match self.logger.read_history(buffer_id, limit) {
+            Ok(history) => history,
+            Err(err) => {
+                let text = format!("Unable to restore history: {}", err);
+                let message = TircMessage::client_notice(NoticeLevel::Error, &text, self.lua)?;
+
+                state.push_message_to(buffer_id, message);
+                return Ok(());
+            }
+        };
+
+        if history.is_empty() {
+            return Ok(());
+        }
+
+        let mut messages = history
+            .into_iter()
+            .map(|(date_time, message)| {
+                TircMessage::from_message_at(message.into(), date_time, self.lua)
+            })
+            .collect::<mlua::Result<Vec<_>>>()?;
+
+        messages.push(TircMessage::client_notice(
+            NoticeLevel::Info,
+            "--- restored history ---",
+            self.lua,
+        )?);
+
+        state.restore_messages(buffer_id, messages);
+
+        Ok(())
+    }
+
+    /// Requests the `CHATHISTORY` of a buffer, unless the server does not
+    /// support it or a request of the buffer is pending already.
+    fn request_history(
+        &self,
+        state: &mut State,
+        buffer_id: &BufferId,
+        request: HistoryRequest,
+    ) -> Result<(), anyhow::Error> {
+        let supported = state
+            .servers
+            .get(&buffer_id.server)
+            .is_some_and(|server| server.capabilities.contains(history::CAPABILITY));
+        let Some(buffer) = state.buffers.get_mut(buffer_id) else {
+            return Ok(());
+        };
+
+        if !self.clients.contains(&buffer_id.server)
+            || !supported
+            || buffer_id.is_status()
+            || buffer.history_request.is_some()
+        {
+            return Ok(());
+        }
+
+        let command = match request {
+            HistoryRequest::Latest => history::latest(&buffer_id.name),
+            HistoryRequest::Before if buffer.history_exhausted => return Ok(()),
+            HistoryRequest::Before => match buffer.messages.iter().find_map(history::reference) {
+                Some(reference) => history::before(&buffer_id.name, reference),
+                None => history::latest(&buffer_id.name),
+            },
+        };
+
+        self.outgoing.send(&buffer_id.server, command)?;
+        buffer.history_request = Some(request);
+
+        Ok(())
+    }
+
+    /// Scrolls the current buffer, fetching older history once the view
+    /// reaches its oldest message.
+    fn scroll(&self, state: &mut State, scroll: Scroll) -> Result<(), anyhow::Error> {
+        let at_top = self.ui.scroll(self.lua, state, scroll);
+
+        // Searching goes on from the new view.
+        if let Some(buffer) = state.buffers.get_mut(&state.current_buffer) {
+            buffer.search_match = None;
+        }
+
+        if at_top && matches!(scroll, Scroll::Up(_) | Scroll::Top) {
+            let buffer_id = state.current_buffer.clone();
+            self.request_history(state, &buffer_id, HistoryRequest::Before)?;
         }
 
         Ok(())
     }
 
     pub fn render_ui(&mut self, state: &State) -> Result<(), anyhow::Error> {
-        self.ui.render(&self.irc, self.lua, state)?;
+        self.ui.render(self.lua, state)?;
 
         Ok(())
     }
 
-    fn send_privmsg<S1, S2>(&self, target: S1, message: S2) -> anyhow::Result<Message>
+    /// Fails unless connected to the server the current buffer belongs to.
+    fn ensure_connected(&self, state: &State) -> anyhow::Result<()> {
+        let server = &state.current_buffer.server;
+
+        if !self.clients.contains(server) {
+            anyhow::bail!("Not connected to server '{}'", server);
+        }
+
+        Ok(())
+    }
+
+    /// Files `message` received from (or sent to) `server` into its buffer,
+    /// appends it to the log of that buffer and notifies of it if the user is
+    /// not looking at that buffer.
+    fn push_message(
+        &self,
+        state: &mut State,
+        server: &str,
+        message: TircMessage,
+    ) -> anyhow::Result<()> {
+        let appended = state.push_message(self.lua, server, message)?;
+        let nickname = state.nickname(server).to_owned();
+
+        for (index, buffer_id) in appended.iter().enumerate() {
+            // Messages appended to the same buffer later are below this one.
+            let below = appended[index + 1..]
+                .iter()
+                .filter(|id| *id == buffer_id)
+                .count();
+            let buffer = state.buffers.get_mut(buffer_id).unwrap();
+            let Some(message) = buffer
+                .messages
+                .len()
+                .checked_sub(below + 1)
+                .map(|index| &buffer.messages[index])
+            else {
+                continue;
+            };
+
+            // Drafts are logged once sent, see `flush`.
+            if !message.is_queued() {
+                self.logger
+                    .log(buffer_id, message, &nickname)
+                    .map_err(|err| anyhow::anyhow!("Unable to write log: {}", err))?;
+            }
+
+            if !self.highlighter.is_activity(message, &nickname) {
+                continue;
+            }
+
+            let is_current = *buffer_id == state.current_buffer;
+            let highlight = message.is_highlight();
+            // Buffers are opened after the messages creating them are pushed.
+            let new_query = !buffer_id.is_status()
+                && !buffer_id.name.is_channel_name()
+                && !self.opened.contains(buffer_id);
+
+            if !(is_current && self.focused) && self.notifier.is_wanted(highlight, new_query) {
+                self.notifier
+                    .notify(buffer_id, message)
+                    .map_err(|err| anyhow::anyhow!("Unable to notify: {}", err))?;
+            }
+
+            if !is_current {
+                buffer.mark_unread(highlight);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends `text` to `target` on `server`, split into as many messages as
+    /// needed to reach others whole, and files them into the buffer of
+    /// `target`.
+    fn say(
+        &self,
+        state: &mut State,
+        server: &str,
+        target: &str,
+        text: &str,
+    ) -> Result<(), anyhow::Error> {
+        for message in Self::privmsgs(state, server, target, text) {
+            self.enqueue(
+                state,
+                server,
+                target,
+                vec![message.clone()],
+                vec![message],
+                None,
+            )?;
+        }
+
+        self.flush(state)
+    }
+
+    /// Queues `messages` sent from the buffer `target` on `server`, filing
+    /// the labeled `drafts` into that buffer until they are sent.
+    fn enqueue(
+        &self,
+        state: &mut State,
+        server: &str,
+        target: &str,
+        messages: Vec<Message>,
+        drafts: Vec<Message>,
+        pace: Option<Duration>,
+    ) -> Result<(), anyhow::Error> {
+        if BufferId::new(server, target).is_read_only() {
+            anyhow::bail!("{} is read-only", target);
+        }
+
+        let labels = drafts
+            .iter()
+            .filter_map(|draft| {
+                draft
+                    .tags
+                    .iter()
+                    .flatten()
+                    .find(|Tag(key, _)| key == "label")
+                    .and_then(|Tag(_, value)| value.clone())
+            })
+            .collect();
+
+        self.outgoing.enqueue(
+            server,
+            Queued {
+                target: Some(target.to_owned()),
+                drafts: labels,
+                messages,
+                pace,
+            },
+        )?;
+
+        for draft in drafts {
+            let draft = TircMessage::from_message(draft.into(), self.lua)?;
+
+            draft.get_lua_message().set("queued", true)?;
+            self.push_message(state, server, draft)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the queued messages the rate limits allow, showing their drafts
+    /// as sent and logging them. Servers the messages could not be sent to
+    /// are reported in their status buffer.
+    fn flush(&self, state: &mut State) -> Result<(), anyhow::Error> {
+        let Flushed { sent, errors } = self.outgoing.flush();
+
+        for (server, err) in errors {
+            let text = format!("Unable to send to {}: {}", server, err);
+            let message = TircMessage::client_notice(NoticeLevel::Error, &text, self.lua)?;
+
+            state.push_message(self.lua, &server, message)?;
+        }
+
+        for (server, queued) in sent {
+            let nickname = state.nickname(&server).to_owned();
+            let Some((buffer_id, buffer)) = queued
+                .target
+                .and_then(|target| state.buffers.get_key_value(&BufferId::new(server, target)))
+            else {
+                continue;
+            };
+
+            for label in &queued.drafts {
+                if let Some(index) = buffer.find_label(label) {
+                    let message = &buffer.messages[index];
+
+                    message.get_lua_message().set("queued", mlua::Value::Nil)?;
+                    self.logger
+                        .log(buffer_id, message, &nickname)
+                        .map_err(|err| anyhow::anyhow!("Unable to write log: {}", err))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the drafts of messages to `server` that will not be sent.
+    fn remove_drafts(state: &mut State, server: &str, queued: &[Queued]) {
+        for queued in queued {
+            let Some(buffer) = queued
+                .target
+                .as_ref()
+                .and_then(|target| state.buffers.get_mut(&BufferId::new(server, target)))
+            else {
+                continue;
+            };
+
+            for label in &queued.drafts {
+                buffer.remove_label(label);
+            }
+        }
+    }
+
+    /// Bytes left for the text of a `command` to `target` on `server`.
+    fn payload_length(state: &State, server: &str, command: &str, target: &str) -> usize {
+        let user_host = state
+            .servers
+            .get(server)
+            .and_then(|server_state| server_state.user_host.as_deref());
+
+        split::payload_length(state.nickname(server), user_host, command, target)
+    }
+
+    /// `PRIVMSG`s sending `text` to `target` on `server`, split to fit into
+    /// the lines the server relays to others.
+    fn privmsgs(state: &State, server: &str, target: &str, text: &str) -> Vec<Message> {
+        let payload_length = Self::payload_length(state, server, "PRIVMSG", target);
+
+        split::split_message(text, payload_length)
+            .into_iter()
+            .map(|part| Self::privmsg(target, part))
+            .collect()
+    }
+
+    /// A `PRIVMSG` labeled so its echo can replace the local copy.
+    fn privmsg<S1, S2>(target: S1, message: S2) -> Message
     where
         S1: fmt::Display,
         S2: fmt::Display,
@@ -83,90 +1049,665 @@ impl<'lua> InputHandler<'lua> {
 
         message.tags = Some(vec![Tag("label".to_string(), Some(get_id().to_string()))]);
 
-        self.irc.send(message.clone())?;
+        message
+    }
+
+    /// Inserts pasted text into the input, switching from Normal to Insert
+    /// mode. Text of several lines pasted as a message is sent line by line
+    /// instead, once confirmed.
+    fn handle_paste(&mut self, state: &mut State, text: &str) {
+        let lines = paste::lines(text);
 
-        Ok(message)
+        // Pasting starts typing a message, as `i` would.
+        if let Mode::Normal = state.mode {
+            state.mode = Mode::Insert;
+        }
+
+        match state.mode {
+            Mode::Insert if lines.len() > 1 => {
+                let buffer_id = state.current_buffer.clone();
+
+                state.confirmation = Some(format!(
+                    "Send {} lines to {}? [y/N]",
+                    lines.len(),
+                    buffer_id.name
+                ));
+                self.pending_paste = Some((buffer_id, lines));
+            }
+            _ => self.ui.insert_input(&lines.join(" ")),
+        }
     }
 
-    fn handle_command(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
-        state.mode = Mode::Normal;
+    /// Sends the pending paste if `event` confirms it, otherwise drops it.
+    fn confirm_paste(&mut self, state: &mut State, event: KeyEvent) -> Result<(), anyhow::Error> {
+        state.confirmation = None;
 
-        let command: Box<[&str]> = self.ui.input().value().splitn(2, ' ').collect();
+        let Some((buffer_id, lines)) = self.pending_paste.take() else {
+            return Ok(());
+        };
 
-        match *command {
-            ["m" | "msg", target_and_message] => {
-                match *target_and_message.splitn(2, ' ').collect::<Box<[&str]>>() {
-                    [target, message] => {
-                        state.create_buffer_if_not_exists(target);
-                        state.set_current_buffer(target);
+        if !matches!(event.code, KeyCode::Char('y' | 'Y')) {
+            return Ok(());
+        }
 
-                        if !message.trim().is_empty() {
-                            state.push_message(TircMessage::from_message(
-                                self.send_privmsg(target, message)?.into(),
-                                self.lua,
-                            )?)
-                        }
-                    }
-                    [target] => {
-                        state.create_buffer_if_not_exists(target);
-                        state.set_current_buffer(target);
+        let server = &buffer_id.server;
+        let server_state = state.servers.get(server);
+        let payload_length = Self::payload_length(state, server, "PRIVMSG", &buffer_id.name);
+        // Lines of a batch cannot be split.
+        let batch = server_state
+            .and_then(|server_state| server_state.multiline.as_ref())
+            .filter(|_| lines.iter().all(|line| line.len() <= payload_length))
+            .and_then(|limits| {
+                paste::multiline_batch(&buffer_id.name, &lines, &get_id().to_string(), limits)
+            });
+
+        match batch {
+            Some(messages) => {
+                let echoed = server_state
+                    .is_some_and(|server_state| server_state.capabilities.contains("echo-message"));
+                // With echo-message the server echoes the batch back, so no
+                // drafts are needed.
+                let drafts = if echoed {
+                    Vec::new()
+                } else {
+                    lines
+                        .iter()
+                        .map(|line| Self::privmsg(&buffer_id.name, line))
+                        .collect()
+                };
+
+                self.enqueue(state, server, &buffer_id.name, messages, drafts, None)?;
+                self.flush(state)?;
+            }
+            None => {
+                // The queue keeps to the flood limit, servers disconnect
+                // clients sending many lines at once, and pastes are paced
+                // further by `paste.delay`.
+                let delay = Duration::from_millis(self.paste.delay);
+
+                for (index, line) in lines.iter().enumerate() {
+                    for message in Self::privmsgs(state, server, &buffer_id.name, line) {
+                        let pace = (index > 0).then_some(delay);
+
+                        self.enqueue(
+                            state,
+                            server,
+                            &buffer_id.name,
+                            vec![message.clone()],
+                            vec![message],
+                            pace,
+                        )?;
                     }
-                    _ => {}
                 }
+
+                self.flush(state)?;
             }
-            ["me", message] => {
-                let message = format!("\x01ACTION {}\x01", message);
-                state.push_message(TircMessage::from_message(
-                    self.send_privmsg(&state.current_buffer, message)?.into(),
-                    self.lua,
-                )?);
-            }
-            ["desc" | "describe", target_and_message] => {
-                if let [target, message] =
-                    *target_and_message.splitn(2, ' ').collect::<Box<[&str]>>()
-                {
-                    let message = format!("\x01ACTION {}\x01", message);
-                    state.create_buffer_if_not_exists(target);
-                    state.push_message(TircMessage::from_message(
-                        self.send_privmsg(target, message)?.into(),
-                        self.lua,
-                    )?);
-                }
+        }
+
+        self.scroll(state, Scroll::Bottom)
+    }
+
+    /// Completes the word before the cursor, or replaces the last completion
+    /// with the next candidate, or the previous one when going `backwards`.
+    fn complete(&mut self, state: &State, backwards: bool) -> Result<(), anyhow::Error> {
+        let line = self.ui.input().value();
+        let cursor = self.ui.input().cursor();
+
+        match &mut self.completion {
+            Some(completion) if completion.is_continued_by(line, cursor) => {
+                completion.cycle(backwards);
             }
-            ["notice", target_and_message] => {
-                if let [target, message] =
-                    *target_and_message.splitn(2, ' ').collect::<Box<[&str]>>()
-                {
-                    state.create_buffer_if_not_exists(target);
-                    self.irc.send_notice(target, message)?;
+            _ => {
+                let (start, word) = completion::word_at(line, cursor);
+                let context = self.lua.create_table()?;
+
+                context.set(
+                    "mode",
+                    if matches!(state.mode, Mode::Command) {
+                        "command"
+                    } else {
+                        "insert"
+                    },
+                )?;
+                context.set("line", line)?;
+                context.set("server", state.current_buffer.server.as_str())?;
+                context.set("buffer", state.current_buffer.name.as_str())?;
+
+                let head = &line[..start];
+                let mut extra = config::complete(self.lua, word, context.clone())?;
+                let lua_commands = config::command_names(self.lua)?;
+                let mut commands: Vec<&str> = Builtin::names().collect();
+
+                commands.extend(lua_commands.iter().map(String::as_str));
+                commands.sort();
+
+                // Arguments of commands, completed by Lua commands themselves.
+                if let (Mode::Command, Some((name, _))) = (&state.mode, head.split_once(' ')) {
+                    if name == "help" {
+                        extra.extend(commands.iter().map(|name| name.to_string()));
+                    } else if let Some(command) = config::LuaCommand::get(self.lua, name)? {
+                        extra.extend(command.complete(word, context)?);
+                    }
                 }
+
+                let candidates = completion::candidates(state, head, word, &commands, extra);
+
+                self.completion = Completion::new(line, cursor, candidates, backwards);
+            }
+        }
+
+        if let Some(completion) = &self.completion {
+            let (line, cursor) = completion.applied();
+            self.ui.set_input(line.to_owned(), cursor);
+        }
+
+        Ok(())
+    }
+
+    /// The input history of the current mode: messages are recalled per
+    /// buffer, commands in every buffer.
+    fn history_key(state: &State) -> HistoryKey {
+        match state.mode {
+            Mode::Command => HistoryKey::Command,
+            _ => HistoryKey::Buffer(state.current_buffer.clone()),
+        }
+    }
+
+    /// Replaces the input with a line recalled from the input history,
+    /// placing the cursor at its end.
+    fn recall(&mut self, line: String) {
+        let cursor = line.chars().count();
+        self.ui.set_input(line, cursor);
+    }
+
+    /// Handles a key typed while searching the input history after `Ctrl-R`.
+    /// Typing refines the search, `Ctrl-R` finds an older match, `Esc`
+    /// restores the input and any other key keeps the match and is handled
+    /// as usual, so `Enter` sends or runs it like in a shell.
+    fn handle_history_search(
+        &mut self,
+        state: &mut State,
+        event: KeyEvent,
+    ) -> Result<(), anyhow::Error> {
+        let (Some(query), Some(search)) = (&mut state.history_search, &mut self.reverse_search)
+        else {
+            return Ok(());
+        };
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        let before = match event.code {
+            KeyCode::Char('r') if control => search.index,
+            KeyCode::Char(char) if !control => {
+                query.push(char);
+                search.index + 1
             }
-            ["q" | "quit"] => {
-                self.irc.send_quit("tirc")?;
-                return Err(anyhow::Error::msg("quit"));
+            KeyCode::Backspace => {
+                query.pop();
+                self.input_history.len(&search.key)
             }
-            ["j" | "join", channel] => {
-                self.irc.send_join(channel)?;
+            KeyCode::Esc => {
+                let original = search.original.clone();
+
+                state.history_search = None;
+                self.reverse_search = None;
+                self.recall(original);
+
+                return Ok(());
             }
-            ["p" | "part", channel] => {
-                self.irc.send_part(channel)?;
+            _ => {
+                state.history_search = None;
+                self.reverse_search = None;
+
+                return self.handle_event(state, Event::Input(event));
+            }
+        };
+
+        if let Some((index, line)) = self.input_history.search(&search.key, query, before) {
+            let line = line.to_owned();
+
+            search.index = index;
+            self.recall(line);
+        }
+
+        Ok(())
+    }
+
+    /// Runs the command typed after `:`, registered from Lua or built-in.
+    fn handle_command(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
+        state.mode = Mode::Normal;
+
+        let input = self.ui.input().value().to_owned();
+        let (name, args) = input.split_once(' ').unwrap_or((&input, ""));
+
+        if name.is_empty() {
+            return Ok(());
+        }
+
+        // Lua commands override the built-in ones of the same name.
+        let Some(command) = config::LuaCommand::get(self.lua, name)? else {
+            return self.run_builtin_command(state, &input);
+        };
+
+        match command.run(args, self.command_context(state)?)? {
+            Some(line) => self.run_builtin_command(state, &line),
+            None => Ok(()),
+        }
+    }
+
+    /// The context Lua commands are run with: the current buffer and the
+    /// sender of its server.
+    fn command_context(&self, state: &State) -> mlua::Result<mlua::Table> {
+        let buffer_id = &state.current_buffer;
+        let context = self.lua.create_table()?;
+
+        context.set("server", buffer_id.server.as_str())?;
+        context.set("buffer", buffer_id.name.as_str())?;
+
+        if let mlua::Value::Table(senders) = self.lua.named_registry_value("senders")? {
+            context.set(
+                "irc",
+                senders.get::<mlua::Value>(buffer_id.server.as_str())?,
+            )?;
+        }
+
+        Ok(context)
+    }
+
+    fn run_builtin_command(&mut self, state: &mut State, input: &str) -> Result<(), anyhow::Error> {
+        let (name, args) = match input.split_once(' ') {
+            Some((name, args)) => (name, Some(args)),
+            None => (input, None),
+        };
+        let builtin = Builtin::get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown command :{}, see :help", name))?;
+
+        if builtin.online {
+            self.ensure_connected(state)?;
+        }
+
+        match (builtin.run)(self, state, args) {
+            Err(err) if err.is::<InvalidArguments>() => {
+                anyhow::bail!("Invalid arguments for :{}, see :help {}", name, name)
             }
-            ["n" | "nick", nickname] => {
-                // TODO: Update nickname in irc client, as it doesn't seem to update
-                self.irc.send(Command::NICK(nickname.to_owned()))?;
+            Err(err) => Err(err),
+            Ok(()) if builtin.online => self.flush(state),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Sends `message` to the server of the current buffer.
+    fn send_to_current(
+        &self,
+        state: &State,
+        message: impl Into<Message>,
+    ) -> Result<(), anyhow::Error> {
+        self.outgoing.send(&state.current_buffer.server, message)
+    }
+
+    /// Opens a query with the target of `:msg`, sending the rest of the line
+    /// to it.
+    fn open_query(
+        &mut self,
+        state: &mut State,
+        target_and_message: &str,
+    ) -> Result<(), anyhow::Error> {
+        let server = state.current_buffer.server.clone();
+        let (target, message) = target_and_message
+            .split_once(' ')
+            .unwrap_or((target_and_message, ""));
+        let buffer_id = BufferId::new(&server, target);
+
+        state.create_buffer_if_not_exists(&buffer_id);
+        state.set_current_buffer(&buffer_id);
+
+        if message.trim().is_empty() {
+            return Ok(());
+        }
+
+        self.ensure_connected(state)?;
+        self.say(state, &server, target, message)?;
+        self.flush(state)
+    }
+
+    /// Sends the action of `:describe` to its target.
+    fn describe(
+        &mut self,
+        state: &mut State,
+        target_and_message: &str,
+    ) -> Result<(), anyhow::Error> {
+        let server = state.current_buffer.server.clone();
+
+        if let Some((target, message)) = target_and_message.split_once(' ') {
+            let message = format!("\x01ACTION {}\x01", message);
+            state.create_buffer_if_not_exists(&BufferId::new(&server, target));
+            self.say(state, &server, target, &message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues the notice of `:notice` to its target.
+    fn notice(&mut self, state: &mut State, target_and_message: &str) -> Result<(), anyhow::Error> {
+        let server = state.current_buffer.server.clone();
+
+        if let Some((target, message)) = target_and_message.split_once(' ') {
+            state.create_buffer_if_not_exists(&BufferId::new(&server, target));
+
+            let payload_length = Self::payload_length(state, &server, "NOTICE", target);
+
+            for part in split::split_message(message, payload_length) {
+                let notice = Command::NOTICE(target.to_owned(), part);
+
+                self.outgoing.enqueue(
+                    &server,
+                    Queued {
+                        target: Some(target.to_owned()),
+                        ..Queued::new(notice)
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the commands, or shows the usage of the command `name`.
+    fn help(&self, state: &mut State, name: Option<&str>) -> Result<(), anyhow::Error> {
+        let text = match name.map(|name| name.trim_start_matches(':')) {
+            None => {
+                let mut names: Vec<String> = Builtin::names().map(str::to_owned).collect();
+
+                names.extend(config::command_names(self.lua)?);
+                names.sort();
+                names.dedup();
+
+                format!("Commands: {}, see :help <command>", names.join(", "))
+            }
+            Some(name) => match config::LuaCommand::get(self.lua, name)? {
+                Some(command) => {
+                    let help = command.help.as_deref().unwrap_or("no help available");
+
+                    if command.name == name {
+                        format!(":{} - {}", name, help)
+                    } else {
+                        format!(":{} (alias of :{}) - {}", name, command.name, help)
+                    }
+                }
+                None => Builtin::get(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown command :{}, see :help", name))?
+                    .usage
+                    .to_owned(),
+            },
+        };
+        let buffer_id = state.current_buffer.clone();
+        let message = TircMessage::client_notice(NoticeLevel::Info, &text, self.lua)?;
+
+        state.push_message_to(&buffer_id, message);
+
+        Ok(())
+    }
+
+    /// Cancels the messages queued from the current buffer, or every message
+    /// queued to its server from its status buffer, removing their drafts.
+    fn cancel_queued(&self, state: &mut State) -> Result<(), anyhow::Error> {
+        let buffer_id = state.current_buffer.clone();
+        let target = Some(buffer_id.name.as_str()).filter(|_| !buffer_id.is_status());
+        let cancelled = self.outgoing.cancel(&buffer_id.server, target);
+
+        Self::remove_drafts(state, &buffer_id.server, &cancelled);
+
+        let text = match cancelled.len() {
+            0 => "No queued messages to cancel".to_owned(),
+            1 => "Cancelled 1 queued message".to_owned(),
+            count => format!("Cancelled {} queued messages", count),
+        };
+        let message = TircMessage::client_notice(NoticeLevel::Info, &text, self.lua)?;
+
+        state.push_message_to(&buffer_id, message);
+
+        Ok(())
+    }
+
+    /// Searches the current buffer for the query typed after `/`, or for the
+    /// last search if none was typed.
+    fn handle_search(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
+        state.mode = Mode::Normal;
+
+        let query = self.ui.input().value();
+
+        if !query.is_empty() {
+            state.set_search(search::compile(query));
+        }
+
+        self.ui.reset_input();
+
+        self.search(state, SearchDirection::Older)
+    }
+
+    fn search(&self, state: &mut State, direction: SearchDirection) -> Result<(), anyhow::Error> {
+        let Some(pattern) = state.search.as_ref().map(|pattern| pattern.to_string()) else {
+            return Ok(());
+        };
+
+        if !self.ui.search(self.lua, state, direction) {
+            anyhow::bail!("Pattern not found: {}", pattern);
+        }
+
+        Ok(())
+    }
+
+    /// Lists the messages of every buffer, and of the logs, matching `query`
+    /// in the search results buffer, oldest first, once the logs are searched.
+    fn grep(&mut self, state: &mut State, query: &str) -> Result<(), anyhow::Error> {
+        let pattern = search::compile(query);
+        let text = format!("Searching for /{}/", query);
+        let notice = TircMessage::client_notice(NoticeLevel::Info, &text, self.lua)?;
+
+        let results_id = BufferId::search_results(&state.current_buffer.server);
+
+        self.show_search_results(state, &results_id, vec![notice]);
+        self.log_search = Some(LogSearch {
+            results_id,
+            query: query.to_owned(),
+            task: self.logger.grep(pattern.clone()),
+            pattern,
+        });
+
+        Ok(())
+    }
+
+    /// Waits for the logs searched by the last `:grep`, forever if there is
+    /// none, see `Event::LogSearch`.
+    pub async fn log_search(&mut self) -> io::Result<Vec<LogMatch>> {
+        let Some(search) = &mut self.log_search else {
+            return future::pending().await;
+        };
+
+        (&mut search.task)
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)))
+    }
+
+    /// Lists the results of `:grep` once its logs are searched, along with
+    /// the messages of the buffers.
+    fn grep_results(
+        &mut self,
+        state: &mut State,
+        log_matches: io::Result<Vec<LogMatch>>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(LogSearch {
+            results_id,
+            query,
+            pattern,
+            ..
+        }) = self.log_search.take()
+        else {
+            return Ok(());
+        };
+        let mut results: Vec<(BufferId, DateTime<Local>, String)> = Vec::new();
+
+        for (buffer_id, buffer) in &state.buffers {
+            if buffer_id.is_search_results() {
+                continue;
             }
-            ["whois", nickname] => {
-                self.irc.send(Command::WHOIS(None, nickname.to_owned()))?;
+
+            let Some(texts) = self.ui.message_texts(self.lua, state, buffer_id) else {
+                continue;
+            };
+
+            for (message, text) in buffer.messages.iter().zip(texts.iter()) {
+                if let Some(text) = text.as_ref().filter(|text| pattern.is_match(text)) {
+                    results.push((buffer_id.clone(), *message.get_date_time(), text.clone()));
+                }
             }
-            ["list"] => {
-                self.irc.send(Command::LIST(None, None))?;
+        }
+
+        let log_matches =
+            log_matches.map_err(|err| anyhow::anyhow!("Unable to search logs: {}", err))?;
+
+        for found in log_matches {
+            // Log directories are named after the buffer in any case.
+            let buffer_id = state
+                .buffers
+                .keys()
+                .find(|buffer_id| {
+                    buffer_id.server == found.buffer_id.server
+                        && logging::fold_case(&buffer_id.name)
+                            == logging::fold_case(&found.buffer_id.name)
+                })
+                .cloned()
+                .unwrap_or(found.buffer_id);
+
+            // History restored from the logs is found in the buffer already.
+            let restored = results.iter().any(|(id, date_time, _)| {
+                *id == buffer_id && date_time.timestamp() == found.date_time.timestamp()
+            });
+
+            if !restored {
+                results.push((buffer_id, found.date_time, found.text));
             }
-            _ => {}
+        }
+
+        results.sort_by_key(|(_, date_time, _)| *date_time);
+
+        let total = results.len();
+        results.drain(..total.saturating_sub(MAX_SEARCH_RESULTS));
+
+        let header = if total > results.len() {
+            format!(
+                "{} results for /{}/, showing the newest {}",
+                total, query, MAX_SEARCH_RESULTS
+            )
+        } else {
+            format!("{} results for /{}/", total, query)
+        };
+        let mut messages = vec![TircMessage::client_notice(
+            NoticeLevel::Info,
+            &header,
+            self.lua,
+        )?];
+
+        for (index, (buffer_id, date_time, text)) in results.iter().enumerate() {
+            let buffer_name = if state.servers.len() > 1 {
+                format!("{}/{}", buffer_id.server, buffer_id.name)
+            } else {
+                buffer_id.name.clone()
+            };
+            let text = format!(
+                "[{}] {} {} {}",
+                index + 1,
+                date_time.format("%Y-%m-%d"),
+                buffer_name,
+                text
+            );
+            let message =
+                TircMessage::client_notice_at(NoticeLevel::Info, &text, *date_time, self.lua)?;
+
+            message.get_lua_message().set("result", index + 1)?;
+            messages.push(message);
+        }
+
+        self.show_search_results(state, &results_id, messages);
+        state.set_search(pattern);
+        self.search_results = results
+            .into_iter()
+            .map(|(buffer_id, date_time, _)| (buffer_id, date_time))
+            .collect();
+        self.search_result = None;
+
+        Ok(())
+    }
+
+    /// Replaces the messages of a search results buffer, switching to it.
+    fn show_search_results(
+        &self,
+        state: &mut State,
+        results_id: &BufferId,
+        messages: Vec<TircMessage>,
+    ) {
+        state.create_buffer_if_not_exists(results_id);
+
+        state
+            .buffers
+            .get_mut(results_id)
+            .unwrap()
+            .set_messages(messages);
+
+        state.set_current_buffer(results_id);
+    }
+
+    /// Shows the message of a search result in its buffer.
+    fn jump_to_result(&mut self, state: &mut State, index: usize) -> Result<(), anyhow::Error> {
+        let Some((buffer_id, date_time)) = self.search_results.get(index).cloned() else {
+            anyhow::bail!("No search result {}", index + 1);
+        };
+
+        self.search_result = Some(index);
+
+        state.create_buffer_if_not_exists(&buffer_id);
+        self.open_buffers(state)?;
+        state.set_current_buffer(&buffer_id);
+
+        // Text logs only keep the time to the second.
+        let messages = &state.buffers[&buffer_id].messages;
+        let message = messages
+            .iter()
+            .position(|message| *message.get_date_time() == date_time)
+            .or_else(|| {
+                messages.iter().position(|message| {
+                    message.get_date_time().timestamp() == date_time.timestamp()
+                })
+            });
+
+        if let Some(message) = message {
+            self.ui.scroll(self.lua, state, Scroll::To(message));
+            state.buffers[&buffer_id].search_match = Some(message);
         }
 
         Ok(())
     }
 
+    /// Jumps to the search result at the bottom of the view of the search
+    /// results buffer.
+    fn jump_to_result_in_view(&mut self, state: &mut State) -> Result<(), anyhow::Error> {
+        let buffer = &state.buffers[&state.current_buffer];
+        let bottom = buffer
+            .scroll_position
+            .map(|position| position.message)
+            .or_else(|| buffer.messages.len().checked_sub(1));
+        let result = bottom.and_then(|index| {
+            buffer.messages[index]
+                .get_lua_message()
+                .get::<Option<usize>>("result")
+                .ok()
+                .flatten()
+        });
+
+        match result {
+            Some(result) => self.jump_to_result(state, result - 1),
+            None => Ok(()),
+        }
+    }
+
     fn key_code_is_digit(key_code: KeyCode) -> bool {
         match key_code {
             KeyCode::Char(char) => char.is_ascii_digit(),
@@ -181,12 +1722,32 @@ impl<'lua> InputHandler<'lua> {
         }
     }
 
+    /// Rows to scroll for a full page of messages, keeping the indicator row
+    /// shown while scrolled out of the way.
+    fn page_height(&self) -> usize {
+        self.ui.messages_height().saturating_sub(1).max(1)
+    }
+
     pub fn handle_event(
         &mut self,
         state: &mut State,
         event: Event<crossterm::event::KeyEvent>,
     ) -> Result<(), anyhow::Error> {
+        if let Event::Input(key) = &event {
+            if self.key_taken(state, key)? {
+                return Ok(());
+            }
+        }
+
+        let pending_key = match event {
+            Event::Input(_) => self.pending_key.take(),
+            _ => self.pending_key,
+        };
+
         match (state.mode, event) {
+            (_, Event::Input(event)) if state.confirmation.is_some() => {
+                self.confirm_paste(state, event)?;
+            }
             (Mode::Normal, Event::Input(event)) if event.code == KeyCode::Tab => {
                 state.next_buffer();
             }
@@ -201,58 +1762,251 @@ impl<'lua> InputHandler<'lua> {
                 }
             }
             (Mode::Normal, Event::Input(event)) => match event.code {
+                KeyCode::Char('u') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    let rows = (self.page_height() / 2).max(1);
+                    self.scroll(state, Scroll::Up(rows))?;
+                }
+                KeyCode::Char('d') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    let rows = (self.page_height() / 2).max(1);
+                    self.scroll(state, Scroll::Down(rows))?;
+                }
+                // Other Ctrl combos must not act like the plain letter.
+                KeyCode::Char(_) if event.modifiers.contains(KeyModifiers::CONTROL) => {}
+                KeyCode::PageUp => {
+                    self.scroll(state, Scroll::Up(self.page_height()))?;
+                }
+                KeyCode::PageDown => {
+                    self.scroll(state, Scroll::Down(self.page_height()))?;
+                }
+                KeyCode::Char('g') if pending_key == Some(KeyCode::Char('g')) => {
+                    self.scroll(state, Scroll::Top)?;
+                }
+                KeyCode::Char('g') => {
+                    self.pending_key = Some(event.code);
+                }
+                KeyCode::Char('G') => {
+                    self.scroll(state, Scroll::Bottom)?;
+                }
+                KeyCode::Char('i') if state.current_buffer.is_read_only() => {
+                    anyhow::bail!("{} is read-only", state.current_buffer.name);
+                }
                 KeyCode::Char('i') => {
                     state.mode = Mode::Insert;
                 }
                 KeyCode::Char(':') => {
                     state.mode = Mode::Command;
                 }
+                KeyCode::Char('/') => {
+                    state.mode = Mode::Search;
+                }
+                KeyCode::Char('n') => {
+                    self.search(state, SearchDirection::Older)?;
+                }
+                KeyCode::Char('N') => {
+                    self.search(state, SearchDirection::Newer)?;
+                }
+                KeyCode::Esc => {
+                    state.search = None;
+                }
+                KeyCode::Enter if state.current_buffer.is_search_results() => {
+                    self.jump_to_result_in_view(state)?;
+                }
                 _ => {}
             },
-            (Mode::Command | Mode::Insert, Event::Input(event)) => match event.code {
-                KeyCode::Esc => {
-                    state.mode = Mode::Normal;
+            (Mode::Command | Mode::Insert, Event::Input(event))
+                if state.history_search.is_some() =>
+            {
+                self.handle_history_search(state, event)?;
+            }
+            (Mode::Command | Mode::Insert | Mode::Search, Event::Input(event)) => {
+                match event.code {
+                    KeyCode::Esc => {
+                        state.mode = Mode::Normal;
 
-                    self.ui.reset_input();
-                }
-                KeyCode::Enter => {
-                    match state.mode {
-                        Mode::Command => {
-                            self.handle_command(state)?;
+                        self.ui.reset_input();
+                    }
+                    KeyCode::Tab | KeyCode::BackTab if !matches!(state.mode, Mode::Search) => {
+                        self.complete(state, event.code == KeyCode::BackTab)?;
+                    }
+                    KeyCode::Up | KeyCode::Down if !matches!(state.mode, Mode::Search) => {
+                        let key = Self::history_key(state);
+                        let input = self.ui.input().value();
+                        let recalled = if event.code == KeyCode::Up {
+                            self.input_history.previous(&key, input)
+                        } else {
+                            self.input_history.next(&key, input)
+                        };
+
+                        if let Some(line) = recalled {
+                            self.recall(line);
+                        }
+                    }
+                    KeyCode::Char('r')
+                        if event.modifiers.contains(KeyModifiers::CONTROL)
+                            && !matches!(state.mode, Mode::Search) =>
+                    {
+                        let key = Self::history_key(state);
+
+                        self.reverse_search = Some(ReverseSearch {
+                            index: self.input_history.len(&key),
+                            original: self.ui.input().value().to_owned(),
+                            key,
+                        });
+                        state.history_search = Some(String::new());
+                    }
+                    KeyCode::Enter => {
+                        if !matches!(state.mode, Mode::Search) {
+                            self.input_history
+                                .add(&Self::history_key(state), self.ui.input().value())
+                                .map_err(|err| {
+                                    anyhow::anyhow!("Unable to write input history: {}", err)
+                                })?;
                         }
-                        Mode::Insert => {
-                            let message = self.ui.input().value();
 
-                            if !message.trim().is_empty() {
-                                let current_buffer = &state.current_buffer;
-                                let message = self.send_privmsg(current_buffer, message)?;
-                                let tirc_message =
-                                    TircMessage::from_message(message.into(), self.lua)?;
+                        match state.mode {
+                            Mode::Command => {
+                                self.handle_command(state)?;
+                            }
+                            Mode::Search => {
+                                self.handle_search(state)?;
+                            }
+                            Mode::Insert => {
+                                let message = self.ui.input().value();
+
+                                let current_buffer = state.current_buffer.clone();
+                                let message = match message.trim() {
+                                    "" => None,
+                                    _ => self.submit_input(state, &current_buffer, message)?,
+                                };
 
-                                state.push_message(tirc_message);
+                                if let Some(message) =
+                                    message.filter(|message| !message.trim().is_empty())
+                                {
+                                    self.say(
+                                        state,
+                                        &current_buffer.server,
+                                        &current_buffer.name,
+                                        &message,
+                                    )?;
+                                    self.scroll(state, Scroll::Bottom)?;
+                                }
                             }
+                            _ => {}
                         }
-                        _ => {}
-                    }
 
-                    self.ui.reset_input();
-                }
-                _ => {
-                    self.ui.handle_event(&CrosstermEvent::Key(event));
+                        self.ui.reset_input();
+                    }
+                    _ => {
+                        self.ui.handle_event(&CrosstermEvent::Key(event));
+                    }
                 }
-            },
-            (_, Event::Message(message)) => {
+            }
+            (_, Event::Message(server, message)) => {
+                state.update_user_host(&server, &message);
+
                 let tirc_message = TircMessage::from_message(message, self.lua)?;
                 let lua_message = tirc_message.get_lua_message().to_owned();
-                let lua_irc_sender: mlua::Table = self.lua.named_registry_value("sender")?;
 
-                emit_event(self.lua, "message", (lua_message, lua_irc_sender))?;
+                let buffer_id = state.target_buffer(&server, &tirc_message);
+                let highlight =
+                    self.highlighter
+                        .mark(&tirc_message, &buffer_id, state.nickname(&server))?;
 
-                state.push_message(tirc_message);
+                // Messages fetched from the history are not live traffic.
+                if !state.is_history(&server, &tirc_message) {
+                    self.emit_event(state, "message", &server, lua_message.clone())?;
+
+                    if highlight {
+                        self.emit_event(state, "highlight", &server, lua_message)?;
+                    }
+
+                    if let TircMessage::Irc(_, message, _) = &tirc_message {
+                        let nickname = state.nickname(&server);
+
+                        if let Some((name, event)) =
+                            events::irc_event(self.lua, &server, nickname, message)?
+                        {
+                            self.emit_event(state, name, &server, event)?;
+                        }
+                    }
+                }
+
+                let joined_channel = match &tirc_message {
+                    TircMessage::Irc(_, message, _) => match &message.command {
+                        Command::JOIN(channel, _, _)
+                            if message.source_nickname() == Some(state.nickname(&server)) =>
+                        {
+                            Some(BufferId::new(&server, channel))
+                        }
+                        _ => None,
+                    },
+                    TircMessage::Lua(_, _) => None,
+                };
+
+                self.push_message(state, &server, tirc_message)?;
+
+                if let Some(buffer_id) = joined_channel {
+                    self.request_history(state, &buffer_id, HistoryRequest::Latest)?;
+                }
+            }
+            (_, Event::Paste(text)) => {
+                self.handle_paste(state, &text);
+            }
+            (_, Event::Focus(focused)) => {
+                self.focused = focused;
+            }
+            (_, Event::Flush) => {
+                self.flush(state)?;
+            }
+            (_, Event::LogSearch(log_matches)) => {
+                self.grep_results(state, log_matches)?;
+            }
+            (_, Event::Tick) => {
+                let server = state.current_buffer.server.clone();
+                let event = self.lua.create_table()?;
+
+                event.set("time", Local::now().timestamp())?;
+                self.emit_event(state, "tick", &server, event)?;
             }
-            (_, Event::Tick) => {}
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtins_have_unique_names() {
+        let mut names: Vec<&str> = Builtin::names().collect();
+        let count = names.len();
+
+        names.sort();
+        names.dedup();
+
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn local_commands_work_offline() {
+        for name in [
+            "cancel", "cc", "cn", "cp", "grep", "help", "msg", "quit", "reload",
+        ] {
+            assert!(
+                !Builtin::get(name).unwrap().online,
+                ":{} needs a connection",
+                name
+            );
+        }
+
+        for name in ["join", "me", "notice", "quote", "topic", "whois"] {
+            assert!(
+                Builtin::get(name).unwrap().online,
+                ":{} works offline",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{commands, BufferId};
+
+/// Number of lines kept in each history.
+const MAX_ENTRIES: usize = 1000;
+
+/// Which history an input line belongs to: messages are recalled in the
+/// buffer they were sent to, commands everywhere.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HistoryKey {
+    Command,
+    Buffer(BufferId),
+}
+
+/// Browsing the entries of a history with `Up` and `Down`.
+#[derive(Debug)]
+struct Navigation {
+    key: HistoryKey,
+    /// Input typed before browsing, which entries have to start with.
+    prefix: String,
+    /// Index of the recalled entry, the number of entries for the prefix.
+    index: usize,
+    /// Input after the last recall. Editing it starts browsing anew.
+    applied: String,
+}
+
+/// Reverse incremental search started by `Ctrl-R`.
+#[derive(Debug)]
+pub struct ReverseSearch {
+    pub key: HistoryKey,
+    /// Input before searching, restored when the search is cancelled.
+    pub original: String,
+    /// Index of the entry found last, matches are searched before it.
+    pub index: usize,
+}
+
+/// Lines typed into the input, persisted to a file so they can be recalled
+/// after a restart.
+#[derive(Debug, Default)]
+pub struct InputHistory {
+    path: Option<PathBuf>,
+    /// Entries of each history, oldest first.
+    entries: HashMap<HistoryKey, Vec<String>>,
+    navigation: Option<Navigation>,
+}
+
+impl InputHistory {
+    /// Loads the history persisted at `input_history` in the tirc XDG state
+    /// directory.
+    pub fn new() -> anyhow::Result<Self> {
+        let path = xdg::BaseDirectories::with_prefix("tirc").place_state_file("input_history")?;
+
+        Ok(Self::load(path)?)
+    }
+
+    /// Loads the history persisted at `path`, dropping entries beyond
+    /// `MAX_ENTRIES` from the file.
+    fn load(path: PathBuf) -> io::Result<Self> {
+        let mut history = Self {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(history),
+            Err(err) => return Err(err),
+        };
+        let mut lines = 0;
+
+        for line in BufReader::new(file).lines() {
+            if let Some((key, entry)) = parse_line(&line?) {
+                history.entries.entry(key).or_default().push(entry);
+                lines += 1;
+            }
+        }
+
+        for entries in history.entries.values_mut() {
+            entries.drain(..entries.len().saturating_sub(MAX_ENTRIES));
+        }
+
+        if history.entries.values().map(Vec::len).sum::<usize>() < lines {
+            history.save()?;
+        }
+
+        Ok(history)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut contents = String::new();
+
+        for (key, entries) in &self.entries {
+            for entry in entries {
+                contents.push_str(&format_line(key, entry));
+                contents.push('\n');
+            }
+        }
+
+        open(path, false)?.write_all(contents.as_bytes())
+    }
+
+    /// Adds a line sent or executed from the input, unless it repeats the
+    /// last one or may contain a password.
+    pub fn add(&mut self, key: &HistoryKey, line: &str) -> io::Result<()> {
+        self.navigation = None;
+
+        let entries = self.entries.entry(key.clone()).or_default();
+
+        if line.trim().is_empty()
+            || is_secret(key, line)
+            || entries.last().is_some_and(|last| last == line)
+        {
+            return Ok(());
+        }
+
+        entries.push(line.to_owned());
+
+        if entries.len() > MAX_ENTRIES {
+            entries.remove(0);
+        }
+
+        if let Some(path) = &self.path {
+            let mut file = open(path, true)?;
+
+            writeln!(file, "{}", format_line(key, line))?;
+        }
+
+        Ok(())
+    }
+
+    /// Recalls the newest entry older than the one recalled last that starts
+    /// with the input typed before browsing, like `Up` in a shell.
+    pub fn previous(&mut self, key: &HistoryKey, input: &str) -> Option<String> {
+        let entries = self.entries.get(key)?;
+        let navigation = match self.navigation.take() {
+            Some(navigation) if navigation.key == *key && navigation.applied == input => navigation,
+            _ => Navigation {
+                key: key.clone(),
+                prefix: input.to_owned(),
+                index: entries.len(),
+                applied: input.to_owned(),
+            },
+        };
+        let found = entries[..navigation.index.min(entries.len())]
+            .iter()
+            .rposition(|entry| {
+                entry.starts_with(&navigation.prefix) && *entry != navigation.applied
+            });
+
+        let Some(index) = found else {
+            self.navigation = Some(navigation);
+            return None;
+        };
+
+        self.navigation = Some(Navigation {
+            index,
+            applied: entries[index].clone(),
+            ..navigation
+        });
+
+        Some(entries[index].clone())
+    }
+
+    /// Recalls the next newer entry starting with the input typed before
+    /// browsing, or that input after the newest entry, like `Down` in a
+    /// shell.
+    pub fn next(&mut self, key: &HistoryKey, input: &str) -> Option<String> {
+        let entries = self.entries.get(key)?;
+        let navigation = self
+            .navigation
+            .as_mut()
+            .filter(|navigation| navigation.key == *key && navigation.applied == input)?;
+        let found = entries
+            .iter()
+            .enumerate()
+            .skip(navigation.index + 1)
+            .find(|(_, entry)| {
+                entry.starts_with(&navigation.prefix) && **entry != navigation.applied
+            });
+
+        match found {
+            Some((index, entry)) => {
+                navigation.index = index;
+                navigation.applied = entry.clone();
+            }
+            None if navigation.index < entries.len() => {
+                navigation.index = entries.len();
+                navigation.applied = navigation.prefix.clone();
+            }
+            None => return None,
+        }
+
+        Some(navigation.applied.clone())
+    }
+
+    /// Finds the newest entry containing `query` older than the entry at
+    /// `before`, returning its index.
+    pub fn search(&self, key: &HistoryKey, query: &str, before: usize) -> Option<(usize, &str)> {
+        let entries = self.entries.get(key)?;
+
+        entries[..before.min(entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+            .map(|index| (index, entries[index].as_str()))
+    }
+
+    /// Number of entries of a history, where searches start from.
+    pub fn len(&self, key: &HistoryKey) -> usize {
+        self.entries.get(key).map_or(0, Vec::len)
+    }
+}
+
+/// Opens the history file for writing, creating it readable only by the
+/// user as it holds private messages.
+fn open(path: &Path, append: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+
+    options
+        .create(true)
+        .append(append)
+        .write(true)
+        .truncate(!append);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)
+}
+
+/// Whether a line may send a password: a message typed in a query with
+/// services, `msg NickServ IDENTIFY secret` or `quote PASS secret`.
+fn is_secret(key: &HistoryKey, line: &str) -> bool {
+    let HistoryKey::Buffer(buffer_id) = key else {
+        return match line.trim_start().split_once(' ') {
+            Some(("m" | "msg" | "notice", args)) => args
+                .split_whitespace()
+                .next()
+                .is_some_and(commands::is_service),
+            Some(("quote", line)) => {
+                commands::quote(line).is_ok_and(|message| commands::is_secret(&message))
+            }
+            _ => false,
+        };
+    };
+
+    commands::is_service(&buffer_id.name)
+}
+
+/// Formats an entry as `command<TAB>line` or `buffer<TAB>server<TAB>name<TAB>line`.
+fn format_line(key: &HistoryKey, entry: &str) -> String {
+    let entry = entry
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+
+    match key {
+        HistoryKey::Command => format!("command\t{}", entry),
+        HistoryKey::Buffer(buffer_id) => {
+            format!(
+                "buffer\t{}\t{}\t{}",
+                buffer_id.server, buffer_id.name, entry
+            )
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(HistoryKey, String)> {
+    let (key, entry) = match line.split_once('\t')? {
+        ("command", entry) => (HistoryKey::Command, entry),
+        ("buffer", rest) => {
+            let mut fields = rest.splitn(3, '\t');
+            let buffer_id = BufferId::new(fields.next()?, fields.next()?);
+
+            (HistoryKey::Buffer(buffer_id), fields.next()?)
+        }
+        _ => return None,
+    };
+    let mut unescaped = String::with_capacity(entry.len());
+    let mut chars = entry.chars();
+
+    while let Some(char) = chars.next() {
+        unescaped.push(match char {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                other => other,
+            },
+            char => char,
+        });
+    }
+
+    Some((key, unescaped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(lines: &[&str]) -> InputHistory {
+        let mut history = InputHistory::default();
+
+        for line in lines {
+            history.add(&HistoryKey::Command, line).unwrap();
+        }
+
+        history
+    }
+
+    #[test]
+    fn browsing_filters_by_the_typed_prefix() {
+        let key = HistoryKey::Command;
+        let mut history = history(&["join #a", "msg bob hi", "join #b", "join #b"]);
+
+        assert_eq!(history.previous(&key, "jo").as_deref(), Some("join #b"));
+        assert_eq!(
+            history.previous(&key, "join #b").as_deref(),
+            Some("join #a")
+        );
+        assert_eq!(history.previous(&key, "join #a"), None);
+        assert_eq!(history.next(&key, "join #a").as_deref(), Some("join #b"));
+        assert_eq!(history.next(&key, "join #b").as_deref(), Some("jo"));
+        assert_eq!(history.next(&key, "jo"), None);
+
+        // Editing a recalled line browses with the edited line as prefix.
+        assert_eq!(history.previous(&key, "").as_deref(), Some("join #b"));
+        assert_eq!(history.previous(&key, "m").as_deref(), Some("msg bob hi"));
+    }
+
+    #[test]
+    fn histories_are_separate() {
+        let channel = HistoryKey::Buffer(BufferId::new("net", "#tirc"));
+        let mut history = history(&["quit"]);
+        history.add(&channel, "hello").unwrap();
+
+        assert_eq!(history.previous(&channel, "").as_deref(), Some("hello"));
+        assert_eq!(
+            history.previous(&HistoryKey::Command, "").as_deref(),
+            Some("quit")
+        );
+        assert_eq!(
+            history.previous(&HistoryKey::Buffer(BufferId::new("net", "#other")), ""),
+            None
+        );
+    }
+
+    #[test]
+    fn search_finds_older_matches() {
+        let key = HistoryKey::Command;
+        let history = history(&["join #rust", "msg bob hi", "join #tirc"]);
+        let count = history.len(&key);
+
+        assert_eq!(history.search(&key, "join", count), Some((2, "join #tirc")));
+        assert_eq!(history.search(&key, "join", 2), Some((0, "join #rust")));
+        assert_eq!(history.search(&key, "join", 0), None);
+        assert_eq!(history.search(&key, "part", count), None);
+    }
+
+    #[test]
+    fn passwords_are_not_kept() {
+        let key = HistoryKey::Command;
+        let mut history = history(&[
+            "msg NickServ identify secret",
+            "quote PASS secret",
+            "quote PRIVMSG NickServ :IDENTIFY secret",
+            "msg bob the password is in the oper channel",
+        ]);
+
+        assert_eq!(
+            history.previous(&key, "").as_deref(),
+            Some("msg bob the password is in the oper channel")
+        );
+        assert_eq!(
+            history.previous(&key, "msg bob the password is in the oper channel"),
+            None
+        );
+
+        let services = HistoryKey::Buffer(BufferId::new("net", "NickServ"));
+        history.add(&services, "IDENTIFY secret").unwrap();
+
+        assert_eq!(history.previous(&services, ""), None);
+    }
+
+    #[test]
+    fn chat_mentioning_password_commands_is_kept() {
+        let channel = HistoryKey::Buffer(BufferId::new("net", "#tirc"));
+        let mut history = history(&["quote JOIN #oper", "msg bob oper is down"]);
+        history.add(&channel, "pass me the salt").unwrap();
+
+        assert_eq!(
+            history.previous(&channel, "").as_deref(),
+            Some("pass me the salt")
+        );
+        assert_eq!(
+            history.previous(&HistoryKey::Command, "").as_deref(),
+            Some("msg bob oper is down")
+        );
+        assert_eq!(
+            history
+                .previous(&HistoryKey::Command, "msg bob oper is down")
+                .as_deref(),
+            Some("quote JOIN #oper")
+        );
+    }
+
+    #[test]
+    fn history_is_persisted() {
+        let path = std::env::temp_dir().join(format!("tirc-input-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let channel = HistoryKey::Buffer(BufferId::new("net", "#tirc"));
+
+        let mut history = InputHistory::load(path.clone()).unwrap();
+        history.add(&HistoryKey::Command, "join #tirc").unwrap();
+        history.add(&channel, "multi\\line\nwith\ttab").unwrap();
+
+        let mut history = InputHistory::load(path.clone()).unwrap();
+        assert_eq!(
+            history.previous(&channel, "").as_deref(),
+            Some("multi\\line\nwith\ttab")
+        );
+        assert_eq!(
+            history.previous(&HistoryKey::Command, "").as_deref(),
+            Some("join #tirc")
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
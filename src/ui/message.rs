@@ -1,6 +1,24 @@
 use mlua::Lua;
 
-use crate::tui::lua::to_lua_message;
+use crate::tui::lua::{to_lua_client_notice, to_lua_message};
+
+/// Severity of a line generated by tirc itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoticeLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl NoticeLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoticeLevel::Info => "info",
+            NoticeLevel::Warning => "warning",
+            NoticeLevel::Error => "error",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum TircMessage {
@@ -13,14 +31,85 @@ pub enum TircMessage {
 }
 
 impl TircMessage {
+    /// Creates a message received now, or at the time given by its
+    /// `server-time` tag.
     pub fn from_message(message: Box<irc::proto::Message>, lua: &Lua) -> mlua::Result<Self> {
+        let date_time = get_tag(&message, "time")
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map_or_else(chrono::Local::now, |time| {
+                time.with_timezone(&chrono::Local)
+            });
+
+        Self::from_message_at(message, date_time, lua)
+    }
+
+    /// Creates a message received at `date_time`, e.g. when restored from
+    /// the logs.
+    pub fn from_message_at(
+        message: Box<irc::proto::Message>,
+        date_time: chrono::DateTime<chrono::Local>,
+        lua: &Lua,
+    ) -> mlua::Result<Self> {
         let lua_message = to_lua_message(lua, &message)?.into();
 
-        Ok(TircMessage::Irc(
-            chrono::Local::now().into(),
-            message,
-            lua_message,
-        ))
+        Ok(TircMessage::Irc(date_time.into(), message, lua_message))
+    }
+
+    /// Creates a line generated by tirc itself rather than received from a
+    /// server, e.g. connection state changes or command errors.
+    pub fn client_notice(level: NoticeLevel, text: &str, lua: &Lua) -> mlua::Result<Self> {
+        Self::client_notice_at(level, text, chrono::Local::now(), lua)
+    }
+
+    /// Creates a line generated by tirc about something that happened at
+    /// `date_time`, e.g. a search result.
+    pub fn client_notice_at(
+        level: NoticeLevel,
+        text: &str,
+        date_time: chrono::DateTime<chrono::Local>,
+        lua: &Lua,
+    ) -> mlua::Result<Self> {
+        let lua_message = to_lua_client_notice(lua, level.as_str(), text)?.into();
+
+        Ok(TircMessage::Lua(date_time.into(), lua_message))
+    }
+
+    pub fn get_date_time(&self) -> &chrono::DateTime<chrono::Local> {
+        match self {
+            TircMessage::Irc(date_time, _, _) => date_time,
+            TircMessage::Lua(date_time, _) => date_time,
+        }
+    }
+
+    /// The value of a message tag of an IRC message.
+    pub fn get_tag(&self, name: &str) -> Option<&str> {
+        match self {
+            TircMessage::Irc(_, message, _) => get_tag(message, name),
+            TircMessage::Lua(_, _) => None,
+        }
+    }
+
+    /// The `msgid` tag identifying the message across connections.
+    pub fn get_msgid(&self) -> Option<&str> {
+        self.get_tag("msgid")
+    }
+
+    /// Whether the message was marked as a highlight by the highlight rules.
+    pub fn is_highlight(&self) -> bool {
+        self.get_lua_message()
+            .get::<Option<bool>>("highlight")
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+    }
+
+    /// Whether the message is our own, waiting in the outgoing queue.
+    pub fn is_queued(&self) -> bool {
+        self.get_lua_message()
+            .get::<Option<bool>>("queued")
+            .ok()
+            .flatten()
+            .unwrap_or(false)
     }
 
     pub fn get_lua_message(&self) -> &mlua::Table {
@@ -30,3 +119,13 @@ impl TircMessage {
         }
     }
 }
+
+fn get_tag<'a>(message: &'a irc::proto::Message, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|tag| tag.0 == name)?
+        .1
+        .as_deref()
+}
@@ -1,9 +1,27 @@
+pub mod commands;
+pub mod completion;
+pub mod events;
+pub mod highlight;
+pub mod history;
 mod input;
+pub mod input_history;
 mod message;
+pub mod paste;
+pub mod search;
+pub mod split;
 mod state;
 
 pub use self::input::Event;
 pub use self::input::InputHandler;
+pub use self::input::Quit;
+pub use self::input::Reload;
+pub use self::message::NoticeLevel;
 pub use self::message::TircMessage;
+pub use self::state::BufferId;
+pub use self::state::ChatBuffer;
 pub use self::state::Mode;
+pub use self::state::Scroll;
+pub use self::state::ScrollPosition;
+pub use self::state::ServerState;
 pub use self::state::State;
+pub use self::state::TopPosition;
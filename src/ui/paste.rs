@@ -0,0 +1,157 @@
+use irc::proto::{message::Tag, BatchSubCommand, Command, Message};
+
+/// Capability allowing messages of several lines, sent as a batch.
+pub const CAPABILITY: &str = "draft/multiline";
+
+/// Limits of `draft/multiline` batches the server advertises as the value of
+/// the capability, e.g. `max-bytes=4096,max-lines=100`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MultilineLimits {
+    /// Bytes of the text of all lines together, newlines included.
+    pub max_bytes: usize,
+    pub max_lines: Option<usize>,
+}
+
+impl MultilineLimits {
+    /// Parses the value of the capability, which has to include `max-bytes`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut max_bytes = None;
+        let mut max_lines = None;
+
+        for (key, value) in value.split(',').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "max-bytes" => max_bytes = value.parse().ok(),
+                "max-lines" => max_lines = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            max_bytes: max_bytes?,
+            max_lines,
+        })
+    }
+
+    fn allow(&self, lines: &[String]) -> bool {
+        let bytes = lines.iter().map(String::len).sum::<usize>() + lines.len().saturating_sub(1);
+
+        bytes <= self.max_bytes
+            && self
+                .max_lines
+                .is_none_or(|max_lines| lines.len() <= max_lines)
+    }
+}
+
+/// Splits pasted text into the lines to send. Blank lines are dropped, as
+/// messages cannot be empty.
+pub fn lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Messages sending `lines` to `target` as one `draft/multiline` batch with
+/// the reference tag `reference`, or None if the batch exceeds `limits`.
+pub fn multiline_batch(
+    target: &str,
+    lines: &[String],
+    reference: &str,
+    limits: &MultilineLimits,
+) -> Option<Vec<Message>> {
+    if !limits.allow(lines) {
+        return None;
+    }
+
+    let batch = |reference: String, params: Option<Vec<String>>| -> Message {
+        Command::BATCH(
+            reference,
+            params
+                .as_ref()
+                .map(|_| BatchSubCommand::CUSTOM(CAPABILITY.to_owned())),
+            params,
+        )
+        .into()
+    };
+    let mut messages = vec![batch(
+        format!("+{}", reference),
+        Some(vec![target.to_owned()]),
+    )];
+
+    for line in lines {
+        let mut message: Message = Command::PRIVMSG(target.to_owned(), line.clone()).into();
+
+        message.tags = Some(vec![Tag("batch".to_owned(), Some(reference.to_owned()))]);
+        messages.push(message);
+    }
+
+    messages.push(batch(format!("-{}", reference), None));
+
+    Some(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pasted_text_is_split_into_lines() {
+        assert_eq!(
+            lines("fn main() {\r\n\n    println!();\r\n}\n"),
+            ["fn main() {", "    println!();", "}"]
+        );
+    }
+
+    #[test]
+    fn multiline_limits_are_parsed() {
+        assert_eq!(
+            MultilineLimits::parse("max-bytes=4096,max-lines=100"),
+            Some(MultilineLimits {
+                max_bytes: 4096,
+                max_lines: Some(100)
+            })
+        );
+        assert_eq!(
+            MultilineLimits::parse("max-bytes=40"),
+            Some(MultilineLimits {
+                max_bytes: 40,
+                max_lines: None
+            })
+        );
+        assert_eq!(MultilineLimits::parse("max-lines=100"), None);
+    }
+
+    #[test]
+    fn multiline_batches_respect_the_limits() {
+        let lines = vec!["one".to_owned(), "two".to_owned()];
+        let limits = MultilineLimits {
+            max_bytes: 7,
+            max_lines: Some(2),
+        };
+
+        let messages = multiline_batch("#tirc", &lines, "ref", &limits).unwrap();
+        let raw: Vec<String> = messages.iter().map(Message::to_string).collect();
+        assert_eq!(
+            raw,
+            [
+                "BATCH +ref draft/multiline #tirc\r\n",
+                "@batch=ref PRIVMSG #tirc one\r\n",
+                "@batch=ref PRIVMSG #tirc two\r\n",
+                "BATCH -ref\r\n",
+            ]
+        );
+
+        let limits = MultilineLimits {
+            max_bytes: 6,
+            max_lines: None,
+        };
+        assert!(multiline_batch("#tirc", &lines, "ref", &limits).is_none());
+
+        let limits = MultilineLimits {
+            max_bytes: 100,
+            max_lines: Some(1),
+        };
+        assert!(multiline_batch("#tirc", &lines, "ref", &limits).is_none());
+    }
+}
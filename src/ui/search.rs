@@ -0,0 +1,81 @@
+use regex::{Regex, RegexBuilder};
+
+/// Direction of a search through the messages of a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchDirection {
+    /// Towards the oldest message, like `/` and `n`.
+    Older,
+    /// Towards the newest message, like `N`.
+    Newer,
+}
+
+/// Compiles a search query into a pattern. Queries are regular expressions,
+/// searched as plain substrings when they are not valid ones, and match
+/// case-insensitively unless they contain an uppercase letter.
+pub fn compile(query: &str) -> Regex {
+    let case_insensitive = !query.chars().any(char::is_uppercase);
+    let build = |pattern: &str| {
+        RegexBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+    };
+
+    build(query)
+        .unwrap_or_else(|_| build(&regex::escape(query)).expect("escaped query is a valid pattern"))
+}
+
+/// Finds the index of the closest message matching the search, starting
+/// next to the message at `origin` in `direction`. `origin` may be the number
+/// of messages to search from past the newest message.
+pub fn find(
+    count: usize,
+    origin: usize,
+    direction: SearchDirection,
+    mut is_match: impl FnMut(usize) -> bool,
+) -> Option<usize> {
+    match direction {
+        SearchDirection::Older => (0..origin.min(count)).rev().find(|&index| is_match(index)),
+        SearchDirection::Newer => (origin + 1..count).find(|&index| is_match(index)),
+    }
+}
+
+/// Byte ranges of the matches of `pattern` in `text`, skipping empty matches
+/// which cannot be highlighted.
+pub fn match_ranges(pattern: &Regex, text: &str) -> Vec<(usize, usize)> {
+    pattern
+        .find_iter(text)
+        .filter(|found| !found.is_empty())
+        .map(|found| (found.start(), found.end()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_use_smart_case_and_fall_back_to_substrings() {
+        assert!(compile("hello").is_match("HeLLo there"));
+        assert!(!compile("Hello").is_match("hello there"));
+        assert!(compile("h.llo").is_match("hallo"));
+        assert!(compile("(unclosed").is_match("an (unclosed paren"));
+    }
+
+    #[test]
+    fn find_searches_from_the_origin_in_both_directions() {
+        let matches = [true, false, true, false];
+        let is_match = |index: usize| matches[index];
+
+        assert_eq!(find(4, 4, SearchDirection::Older, is_match), Some(2));
+        assert_eq!(find(4, 2, SearchDirection::Older, is_match), Some(0));
+        assert_eq!(find(4, 0, SearchDirection::Older, is_match), None);
+        assert_eq!(find(4, 0, SearchDirection::Newer, is_match), Some(2));
+        assert_eq!(find(4, 2, SearchDirection::Newer, is_match), None);
+    }
+
+    #[test]
+    fn match_ranges_skip_empty_matches() {
+        assert_eq!(match_ranges(&compile("o"), "foo bar"), [(1, 2), (2, 3)]);
+        assert_eq!(match_ranges(&compile("x*"), "foo"), []);
+    }
+}
@@ -0,0 +1,125 @@
+/// Maximum length of an IRC line, `\r\n` included and message tags excluded.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// Longest user and host assumed in our prefix until the server tells it.
+const MAX_USER_HOST_LENGTH: usize = 10 + 1 + 63;
+
+const ACTION_START: &str = "\x01ACTION ";
+const ACTION_END: &str = "\x01";
+
+/// Bytes left for the text of a `command` to `target`, once the server
+/// prefixed it with `nickname!user@host` before relaying it to others.
+pub fn payload_length(
+    nickname: &str,
+    user_host: Option<&str>,
+    command: &str,
+    target: &str,
+) -> usize {
+    let prefix = nickname.len() + 1 + user_host.map_or(MAX_USER_HOST_LENGTH, str::len);
+    // `:prefix COMMAND target :text\r\n`
+    let overhead = 1 + prefix + 1 + command.len() + 1 + target.len() + 2 + 2;
+
+    MAX_LINE_LENGTH.saturating_sub(overhead)
+}
+
+/// Splits `text` into parts of at most `max_length` bytes, breaking lines
+/// between words where possible. A CTCP `ACTION` is split into actions.
+pub fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    match text
+        .strip_prefix(ACTION_START)
+        .and_then(|action| action.strip_suffix(ACTION_END))
+    {
+        Some(action) => {
+            let max_length = max_length.saturating_sub(ACTION_START.len() + ACTION_END.len());
+
+            split(action, max_length)
+                .into_iter()
+                .map(|part| format!("{}{}{}", ACTION_START, part, ACTION_END))
+                .collect()
+        }
+        None => split(text, max_length),
+    }
+}
+
+fn split(mut text: &str, max_length: usize) -> Vec<String> {
+    // Every part needs room for at least one character.
+    let max_length = max_length.max(4);
+    let mut parts = Vec::new();
+
+    while text.len() > max_length {
+        let mut end = max_length;
+
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        // Break before the last space which fits, unless the part would be
+        // empty because the text starts with a very long word.
+        let space = match text.as_bytes()[end] {
+            b' ' => Some(end),
+            _ => text[..end].rfind(' '),
+        };
+        let (part, rest) = match space {
+            Some(space) if space > 0 => (&text[..space], &text[space + 1..]),
+            _ => text.split_at(end),
+        };
+
+        parts.push(part.to_owned());
+        text = rest;
+    }
+
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(text.to_owned());
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_length_accounts_for_the_prefix() {
+        assert_eq!(
+            payload_length("me", Some("u@h"), "PRIVMSG", "#tirc"),
+            512 - ":me!u@h PRIVMSG #tirc :\r\n".len()
+        );
+        assert_eq!(
+            payload_length("me", None, "PRIVMSG", "#tirc"),
+            512 - ":me! PRIVMSG #tirc :\r\n".len() - 74
+        );
+    }
+
+    #[test]
+    fn messages_are_split_between_words() {
+        assert_eq!(split_message("short", 10), ["short"]);
+        assert_eq!(split_message("", 10), [""]);
+        assert_eq!(
+            split_message("one two three four", 9),
+            ["one two", "three", "four"]
+        );
+        assert_eq!(
+            split_message("abcdefghij klm", 6),
+            ["abcdef", "ghij", "klm"]
+        );
+    }
+
+    #[test]
+    fn messages_are_not_split_inside_characters() {
+        let parts = split_message("ééééé", 5);
+
+        assert_eq!(parts, ["éé", "éé", "é"]);
+        assert!(parts.iter().all(|part| part.len() <= 5));
+    }
+
+    #[test]
+    fn actions_are_split_into_actions() {
+        let action = "\x01ACTION waves at everyone\x01";
+
+        assert_eq!(
+            split_message(action, 9 + 12),
+            ["\x01ACTION waves at\x01", "\x01ACTION everyone\x01"]
+        );
+    }
+}
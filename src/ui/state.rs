@@ -1,35 +1,452 @@
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap, HashSet},
+    rc::Rc,
+};
 
 use indexmap::IndexMap;
 
 use irc::{
     client::data::User,
-    proto::{Command, Message},
+    proto::{message::Tag, Command, Message, Prefix, Response},
 };
+use mlua::Lua;
+use regex::Regex;
+
+use crate::tui::lua::to_lua_batch;
 
-use super::message::TircMessage;
+use super::{
+    history::HistoryRequest,
+    message::{NoticeLevel, TircMessage},
+    paste::MultilineLimits,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub enum Mode {
     Normal,
     Command,
     Insert,
+    /// Typing a search query after `/`.
+    Search,
+}
+
+/// Where the message list of a buffer is scrolled to, anchored to a message
+/// so that new messages arriving below do not move the view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrollPosition {
+    /// Index of the message at the bottom edge of the view.
+    pub message: usize,
+    /// Number of rows of that message hidden below the bottom edge.
+    pub offset: usize,
+}
+
+/// The position `Scroll::Top` scrolls to, cached for the size of the message
+/// list it was computed at as computing it renders every message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopPosition {
+    pub width: u16,
+    pub height: u16,
+    pub position: Option<ScrollPosition>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scroll {
+    Up(usize),
+    Down(usize),
+    Top,
+    Bottom,
+    /// Shows the message at the index at the bottom of the view, or as close
+    /// to it as the oldest message allows.
+    To(usize),
 }
 
 #[derive(Debug, Default)]
 pub struct ChatBuffer {
     pub messages: Vec<TircMessage>,
-    pub scroll_position: usize,
+    /// `None` while following the newest message.
+    pub scroll_position: Option<ScrollPosition>,
+    /// `CHATHISTORY` request awaiting its batch.
+    pub history_request: Option<HistoryRequest>,
+    /// Whether the server has no history before the oldest message.
+    pub history_exhausted: bool,
+    /// Messages from others received since the buffer was last viewed.
+    pub unread: usize,
+    /// Unread messages mentioning the user.
+    pub highlights: usize,
+    /// Number of messages that had been read when the buffer was last left,
+    /// `None` if it was never viewed. A marker is drawn above the message at
+    /// that index.
+    pub last_read: Option<usize>,
+    /// Index of the message the last search step stopped at, which `n` and
+    /// `N` continue from. `None` to search from the view.
+    pub search_match: Option<usize>,
+    /// `None` until computed or after the messages it depends on changed.
+    pub top_position: Option<TopPosition>,
+    /// Text of the messages as rendered, searched by `n`, `N` and `:grep`.
+    /// Holds the messages before the first one that changed since.
+    pub rendered_texts: RefCell<Vec<Option<String>>>,
+    /// Whether the user is in the channel, which is rejoined on reconnect.
+    pub joined: bool,
+}
+
+impl ChatBuffer {
+    pub fn is_scrolled(&self) -> bool {
+        self.scroll_position.is_some()
+    }
+
+    /// Counts a message received while the buffer is not viewed.
+    pub fn mark_unread(&mut self, highlight: bool) {
+        self.unread += 1;
+
+        if highlight {
+            self.highlights += 1;
+        }
+    }
+
+    /// Replaces the messages, e.g. the results of a new search, following the
+    /// newest one.
+    pub fn set_messages(&mut self, messages: Vec<TircMessage>) {
+        self.messages = messages;
+        self.scroll_position = None;
+        self.changed_at(0);
+    }
+
+    /// Forgets the cached top position unless the message at `index`, which
+    /// was added, removed or replaced, is below the top of the view, and the
+    /// rendered texts from that message on.
+    fn changed_at(&mut self, index: usize) {
+        self.rendered_texts.get_mut().truncate(index);
+
+        let below_top = self
+            .top_position
+            .and_then(|top| top.position)
+            .is_some_and(|position| index > position.message);
+
+        if !below_top {
+            self.top_position = None;
+        }
+    }
+
+    /// Keeps `last_read` and `search_match` on the same message after
+    /// inserting `count` messages at `index`.
+    fn shift_indices(&mut self, index: usize, count: usize) {
+        self.changed_at(index);
+
+        if let Some(last_read) = &mut self.last_read {
+            if index <= *last_read {
+                *last_read += count;
+            }
+        }
+
+        if let Some(search_match) = &mut self.search_match {
+            if index <= *search_match {
+                *search_match += count;
+            }
+        }
+    }
+
+    /// Index of the message sent with `label`, awaiting its echo.
+    pub fn find_label(&self, label: &str) -> Option<usize> {
+        self.messages
+            .iter()
+            .position(|message| message.get_tag("label") == Some(label))
+    }
+
+    /// Removes the message sent with `label`, e.g. a draft that was never
+    /// sent, and keeps a scrolled view on the same message.
+    pub fn remove_label(&mut self, label: &str) -> Option<TircMessage> {
+        let index = self.find_label(label)?;
+        let message = self.messages.remove(index);
+
+        self.changed_at(index);
+
+        if let Some(last_read) = &mut self.last_read {
+            if index < *last_read {
+                *last_read -= 1;
+            }
+        }
+
+        self.search_match = match self.search_match {
+            Some(search_match) if index < search_match => Some(search_match - 1),
+            Some(search_match) if index == search_match => None,
+            search_match => search_match,
+        };
+
+        if let Some(position) = &mut self.scroll_position {
+            if index < position.message || position.message == self.messages.len() {
+                position.message = position.message.saturating_sub(1);
+            }
+        }
+
+        Some(message)
+    }
+
+    /// Merges messages fetched with `CHATHISTORY` in timestamp order, skipping
+    /// messages already in the buffer by `msgid`, and keeps a scrolled view
+    /// on the same message. Returns the number of messages added.
+    pub fn merge_history(&mut self, messages: Vec<TircMessage>) -> usize {
+        let mut msgids: HashSet<String> = self
+            .messages
+            .iter()
+            .filter_map(|message| message.get_msgid().map(str::to_owned))
+            .collect();
+        let mut added = 0;
+
+        for message in messages {
+            if let Some(msgid) = message.get_msgid() {
+                if !msgids.insert(msgid.to_owned()) {
+                    continue;
+                }
+            }
+
+            let date_time = *message.get_date_time();
+            let index = self
+                .messages
+                .partition_point(|message| *message.get_date_time() <= date_time);
+
+            self.messages.insert(index, message);
+            self.shift_indices(index, 1);
+            added += 1;
+
+            if let Some(position) = &mut self.scroll_position {
+                if index <= position.message {
+                    position.message += 1;
+                }
+            }
+        }
+
+        added
+    }
+
+    /// Computes the scroll position after applying `scroll`.
+    ///
+    /// `view_height` is the number of rows available to messages and
+    /// `height` returns the number of rows a message takes once wrapped, zero
+    /// for messages that are not rendered.
+    pub fn scrolled(
+        &self,
+        scroll: Scroll,
+        view_height: usize,
+        mut height: impl FnMut(&TircMessage) -> usize,
+    ) -> Option<ScrollPosition> {
+        match scroll {
+            Scroll::Bottom => None,
+            Scroll::Top => self.top_position(view_height, &mut height),
+            Scroll::To(message) => {
+                if message + 1 >= self.messages.len() {
+                    return None;
+                }
+
+                let position = ScrollPosition { message, offset: 0 };
+
+                if self.rows_above(position, view_height, &mut height) < view_height {
+                    return self.top_position(view_height, &mut height);
+                }
+
+                Some(position)
+            }
+            Scroll::Up(rows) => {
+                let position = self.scroll_position.unwrap_or(ScrollPosition {
+                    message: self.messages.len().checked_sub(1)?,
+                    offset: 0,
+                });
+                let mut message = position.message;
+                let mut offset = position.offset + rows;
+
+                loop {
+                    let message_height = height(&self.messages[message]);
+
+                    if offset < message_height {
+                        break;
+                    }
+
+                    if message == 0 {
+                        return self.top_position(view_height, &mut height);
+                    }
+
+                    offset -= message_height;
+                    message -= 1;
+                }
+
+                let position = ScrollPosition { message, offset };
+
+                if self.rows_above(position, view_height, &mut height) < view_height {
+                    return self.top_position(view_height, &mut height);
+                }
+
+                Some(position)
+            }
+            Scroll::Down(rows) => {
+                let position = self.scroll_position?;
+                let mut message = position.message;
+                let mut offset = position.offset;
+                let mut remaining = rows;
+
+                while offset < remaining {
+                    remaining -= offset;
+                    message += 1;
+
+                    if message >= self.messages.len() {
+                        return None;
+                    }
+
+                    // Reveal the first row of the next message.
+                    offset = height(&self.messages[message]);
+
+                    if offset > 0 {
+                        offset -= 1;
+                        remaining -= 1;
+                    }
+                }
+
+                offset -= remaining;
+
+                if message + 1 == self.messages.len() && offset == 0 {
+                    return None;
+                }
+
+                Some(ScrollPosition { message, offset })
+            }
+        }
+    }
+
+    /// Rows from the top of `position`'s message up to the oldest message,
+    /// counted until `view_height` is reached.
+    fn rows_above(
+        &self,
+        position: ScrollPosition,
+        view_height: usize,
+        height: &mut impl FnMut(&TircMessage) -> usize,
+    ) -> usize {
+        let mut rows = height(&self.messages[position.message]).saturating_sub(position.offset);
+
+        for message in self.messages[..position.message].iter().rev() {
+            if rows >= view_height {
+                break;
+            }
+
+            rows += height(message);
+        }
+
+        rows
+    }
+
+    /// The position showing the oldest message at the top of the view, or
+    /// `None` if every message fits.
+    fn top_position(
+        &self,
+        view_height: usize,
+        height: &mut impl FnMut(&TircMessage) -> usize,
+    ) -> Option<ScrollPosition> {
+        let mut rows = 0;
+
+        for (index, message) in self.messages.iter().enumerate() {
+            rows += height(message);
+
+            if rows >= view_height {
+                let position = ScrollPosition {
+                    message: index,
+                    offset: rows - view_height,
+                };
+
+                if index + 1 == self.messages.len() && position.offset == 0 {
+                    return None;
+                }
+
+                return Some(position);
+            }
+        }
+
+        None
+    }
+}
+
+/// Identifies a buffer by the server it belongs to and its name, so that
+/// `#rust` on two networks ends up in two distinct buffers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BufferId {
+    pub server: String,
+    pub name: String,
+}
+
+impl BufferId {
+    pub fn new(server: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+            name: name.into(),
+        }
+    }
+
+    /// The status buffer of `server`, receiving everything that has no more
+    /// specific target.
+    pub fn status(server: impl Into<String>) -> Self {
+        Self::new(server, State::get_default_buffer_name())
+    }
+
+    pub fn is_status(&self) -> bool {
+        self.name == State::get_default_buffer_name()
+    }
+
+    /// The buffer listing the results of `:grep`, kept with the servers'
+    /// buffers under `server`.
+    pub fn search_results(server: impl Into<String>) -> Self {
+        Self::new(server, "(grep)")
+    }
+
+    pub fn is_search_results(&self) -> bool {
+        self.name == "(grep)"
+    }
+
+    /// Whether messages cannot be sent from the buffer, as it is no channel
+    /// or query.
+    pub fn is_read_only(&self) -> bool {
+        self.is_search_results()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ServerState {
+    pub host: String,
+    pub nickname: String,
+    /// IRCv3 capabilities currently enabled on the connection.
+    pub capabilities: BTreeSet<String>,
+    /// Limits of messages of several lines, if `draft/multiline` is enabled.
+    pub multiline: Option<MultilineLimits>,
+    /// `user@host` the server relays our messages with, once known.
+    pub user_host: Option<String>,
+}
+
+/// Messages of a batch being received, dispatched once it is closed.
+#[derive(Debug)]
+struct Batch {
+    /// Lowercased batch type, e.g. `netsplit`.
+    kind: String,
+    params: Vec<String>,
+    /// Label of the command a `labeled-response` batch answers.
+    label: Option<String>,
+    /// Reference of the batch this one is nested in.
+    parent: Option<String>,
+    messages: Vec<TircMessage>,
+    /// Closed batches nested in this one, dispatched after its messages.
+    children: Vec<Batch>,
 }
 
 #[derive(Debug)]
 pub struct State {
     pub mode: Mode,
-    pub nickname: String,
-    pub server: String,
-    pub current_buffer: String,
-    pub buffers: IndexMap<String, ChatBuffer>,
+    pub servers: IndexMap<String, ServerState>,
+    pub current_buffer: BufferId,
+    pub buffers: IndexMap<BufferId, ChatBuffer>,
     pub users_in_current_buffer: Rc<[User]>,
+    /// Pattern of the last search, highlighted in every buffer.
+    pub search: Option<Regex>,
+    /// Query of the reverse search through the input history, typed after
+    /// `Ctrl-R`.
+    pub history_search: Option<String>,
+    /// Question shown in place of the input prompt, answered with `y`.
+    pub confirmation: Option<String>,
+    /// Open batches by server and reference tag.
+    batches: HashMap<(String, String), Batch>,
 }
 
 impl Default for State {
@@ -40,21 +457,16 @@ impl Default for State {
 
 impl State {
     pub fn new() -> State {
-        let default_buffer_name = State::get_default_buffer_name();
-
-        let buffers = {
-            let mut buffers = IndexMap::new();
-            buffers.insert(default_buffer_name.to_string(), ChatBuffer::default());
-            buffers
-        };
-
         State {
             mode: Mode::Normal,
-            nickname: String::new(),
-            server: String::new(),
-            current_buffer: default_buffer_name,
-            buffers,
+            servers: IndexMap::new(),
+            current_buffer: BufferId::default(),
+            buffers: IndexMap::new(),
             users_in_current_buffer: Rc::new([]),
+            search: None,
+            history_search: None,
+            confirmation: None,
+            batches: HashMap::new(),
         }
     }
 
@@ -62,88 +474,215 @@ impl State {
         String::from("(status)")
     }
 
-    fn get_buffer_name_by_index(&self, index: usize) -> String {
+    /// Registers a server and creates its status buffer. The first server
+    /// added becomes the current buffer.
+    pub fn add_server(&mut self, name: &str, host: &str) {
+        self.servers.insert(
+            name.to_string(),
+            ServerState {
+                host: host.to_string(),
+                ..Default::default()
+            },
+        );
+
+        let status = BufferId::status(name);
+        self.create_buffer_if_not_exists(&status);
+
+        if !self.buffers.contains_key(&self.current_buffer) {
+            self.current_buffer = status;
+        }
+    }
+
+    /// Forgets the cached top positions and rendered texts, e.g. once
+    /// reloaded formatters may render messages differently.
+    pub fn reset_render_caches(&mut self) {
+        for buffer in self.buffers.values_mut() {
+            buffer.top_position = None;
+            buffer.rendered_texts.get_mut().clear();
+        }
+    }
+
+    /// Forgets the open batches of `server`, which are never closed once
+    /// disconnected.
+    pub fn clear_batches(&mut self, server: &str) {
+        self.batches
+            .retain(|(batch_server, _), _| batch_server != server);
+    }
+
+    /// Forgets a server along with its buffers. If the current buffer was
+    /// one of them, the first remaining buffer becomes current.
+    pub fn remove_server(&mut self, name: &str) {
+        self.servers.shift_remove(name);
+        self.buffers.retain(|buffer_id, _| buffer_id.server != name);
+        self.clear_batches(name);
+
+        if !self.buffers.contains_key(&self.current_buffer) {
+            if let Some(buffer_id) = self.buffers.keys().next().cloned() {
+                self.switch_to(buffer_id);
+            }
+        }
+    }
+
+    /// Starts a new search, which `n` and `N` step through from the view of
+    /// each buffer.
+    pub fn set_search(&mut self, pattern: Regex) {
+        self.search = Some(pattern);
+
+        for buffer in self.buffers.values_mut() {
+            buffer.search_match = None;
+        }
+    }
+
+    pub fn current_server(&self) -> Option<&ServerState> {
+        self.servers.get(&self.current_buffer.server)
+    }
+
+    pub fn nickname(&self, server: &str) -> &str {
+        self.servers
+            .get(server)
+            .map_or("", |server| server.nickname.as_str())
+    }
+
+    /// Learns the `user@host` the server relays our messages with from the
+    /// welcome reply, which usually ends with our full prefix, or from our
+    /// own messages echoed back.
+    pub fn update_user_host(&mut self, server: &str, message: &Message) {
+        let Some(server_state) = self.servers.get_mut(server) else {
+            return;
+        };
+        let prefix = match (&message.command, &message.prefix) {
+            (Command::Response(Response::RPL_WELCOME, params), _) => params
+                .last()
+                .and_then(|text| text.split_whitespace().last())
+                .and_then(|prefix| prefix.split_once('!'))
+                .filter(|(_, user_host)| user_host.contains('@'))
+                .map(|(nickname, user_host)| (nickname.to_owned(), user_host.to_owned())),
+            (_, Some(Prefix::Nickname(nickname, user, host)))
+                if !user.is_empty() && !host.is_empty() =>
+            {
+                Some((nickname.clone(), format!("{}@{}", user, host)))
+            }
+            _ => None,
+        };
+
+        if let Some((nickname, user_host)) = prefix {
+            if nickname == server_state.nickname {
+                server_state.user_host = Some(user_host);
+            }
+        }
+    }
+
+    fn get_buffer_id_by_index(&self, index: usize) -> BufferId {
         let buffers = &self.buffers;
-        let buffer_name = buffers.keys().nth(index).unwrap();
-        buffer_name.to_string()
+        let buffer_id = buffers.keys().nth(index).unwrap();
+        buffer_id.clone()
     }
 
     fn get_current_buffer_index(&self) -> usize {
         let buffers = &self.buffers;
-        let current_buffer_name = &self.current_buffer;
+        let current_buffer_id = &self.current_buffer;
 
-        buffers
-            .keys()
-            .position(|name| name == current_buffer_name)
-            .unwrap()
+        buffers.get_index_of(current_buffer_id).unwrap()
+    }
+
+    /// Makes `buffer_id` the current buffer. The messages of the buffer left
+    /// are read, and those of the buffer shown are no longer unread.
+    fn switch_to(&mut self, buffer_id: BufferId) {
+        if let Some(buffer) = self.buffers.get_mut(&self.current_buffer) {
+            buffer.last_read = Some(buffer.messages.len());
+            // The new messages marker moved.
+            buffer.top_position = None;
+        }
+
+        if let Some(buffer) = self.buffers.get_mut(&buffer_id) {
+            buffer.unread = 0;
+            buffer.highlights = 0;
+        }
+
+        self.current_buffer = buffer_id;
     }
 
     pub fn next_buffer(&mut self) {
         let buffers = &self.buffers;
         let current_buffer_index = self.get_current_buffer_index();
         let next_buffer_index = (current_buffer_index + 1) % buffers.len();
-        self.current_buffer = self.get_buffer_name_by_index(next_buffer_index);
+        self.switch_to(self.get_buffer_id_by_index(next_buffer_index));
     }
 
     pub fn previous_buffer(&mut self) {
         let buffers = &self.buffers;
         let current_buffer_index = self.get_current_buffer_index();
         let previous_buffer_index = (current_buffer_index + buffers.len() - 1) % buffers.len();
-        self.current_buffer = self.get_buffer_name_by_index(previous_buffer_index);
+        self.switch_to(self.get_buffer_id_by_index(previous_buffer_index));
     }
 
     pub fn set_current_buffer_index(&mut self, index: usize) {
-        self.current_buffer = self.get_buffer_name_by_index(index);
+        self.switch_to(self.get_buffer_id_by_index(index));
     }
 
-    pub fn set_current_buffer(&mut self, buffer_name: &str) {
-        self.current_buffer = buffer_name.to_string();
+    pub fn set_current_buffer(&mut self, buffer_id: &BufferId) {
+        self.switch_to(buffer_id.clone());
     }
 
-    pub fn create_buffer_if_not_exists(&mut self, buffer_name: &str) {
+    /// Creates the buffer unless it exists. New buffers are inserted after the
+    /// last buffer of the same server, which keeps each server's buffers
+    /// grouped together in the buffer bar.
+    pub fn create_buffer_if_not_exists(&mut self, buffer_id: &BufferId) {
         let buffers = &mut self.buffers;
 
-        if buffers.get(buffer_name).is_none() {
-            buffers.insert(buffer_name.to_string(), ChatBuffer::default());
+        if buffers.contains_key(buffer_id) {
+            return;
         }
-    }
 
-    fn push_message_to_buffer(&mut self, buffer_name: &str, message: TircMessage) {
-        let buffer = self.buffers.get_mut(buffer_name).unwrap();
+        let index = buffers
+            .keys()
+            .rposition(|id| id.server == buffer_id.server)
+            .map_or(buffers.len(), |index| index + 1);
 
-        if let TircMessage::Irc(_, m, _) = &message {
-            if let Some(tags) = &m.tags {
-                if let Some(label) = tags.iter().find(|tag| tag.0 == "label") {
-                    // Find index of message with same tag label
-                    let index = buffer.messages.iter().position(|m| match m {
-                        TircMessage::Irc(_, m, _) => m.tags.as_ref().map_or(false, |tags| {
-                            tags.iter().any(|tag| tag.0 == "label" && tag.1 == label.1)
-                        }),
-                        _ => false,
-                    });
+        buffers.shift_insert(index, buffer_id.clone(), ChatBuffer::default());
+    }
 
-                    if let Some(index) = index {
-                        // Remove old message
-                        buffer.messages[index] = message;
+    /// Appends `message` to the buffer, or replaces the message sent with the
+    /// same label. Returns whether the message was appended.
+    fn push_message_to_buffer(&mut self, buffer_id: &BufferId, message: TircMessage) -> bool {
+        let buffer = self.buffers.get_mut(buffer_id).unwrap();
+        let index = message
+            .get_tag("label")
+            .and_then(|label| buffer.find_label(label));
 
-                        return;
-                    }
-                }
-            }
+        if let Some(index) = index {
+            buffer.messages[index] = message;
+            buffer.changed_at(index);
+
+            return false;
         }
 
+        buffer.changed_at(buffer.messages.len());
         buffer.messages.push(message);
+
+        true
     }
 
-    fn get_target_buffer_name(&mut self, message: &Message) -> String {
+    /// The buffer `message` from `server` is filed into.
+    pub fn target_buffer(&self, server: &str, message: &TircMessage) -> BufferId {
+        let buffer_name = match message {
+            TircMessage::Irc(_, m, _) => self.get_target_buffer_name(server, m),
+            _ => State::get_default_buffer_name(),
+        };
+
+        BufferId::new(server, buffer_name)
+    }
+
+    fn get_target_buffer_name(&self, server: &str, message: &Message) -> String {
         let default_buffer_name = State::get_default_buffer_name();
+        let nickname = self.nickname(server);
 
         match &message.command {
             Command::PRIVMSG(target, _) | Command::NOTICE(target, _) => {
                 let buffer = match message.source_nickname() {
                     // Incoming message from someone else: a channel message goes
                     // to the channel, a direct message goes to the sender's nick.
-                    Some(source) if source != self.nickname => {
+                    Some(source) if source != nickname => {
                         message.response_target().unwrap_or(source).to_owned()
                     }
                     // An echo of one of our own messages (server replied with our
@@ -172,26 +711,362 @@ impl State {
         }
     }
 
-    pub fn push_message(&mut self, message: TircMessage) {
-        let buffer_name = match &message {
-            TircMessage::Irc(_, m, _) => self.get_target_buffer_name(m),
-            _ => State::get_default_buffer_name(),
+    /// Pushes `message` into the given buffer, creating it if needed. Used for
+    /// lines that are not routed by their content, e.g. command errors.
+    pub fn push_message_to(&mut self, buffer_id: &BufferId, message: TircMessage) {
+        self.create_buffer_if_not_exists(buffer_id);
+        self.push_message_to_buffer(buffer_id, message);
+    }
+
+    fn batch_of(&self, server: &str, message: &TircMessage) -> Option<&Batch> {
+        let reference = message.get_tag("batch")?;
+
+        self.batches.get(&(server.to_owned(), reference.to_owned()))
+    }
+
+    /// Whether `message` belongs to a `chathistory` batch being collected,
+    /// i.e. was not received live.
+    pub fn is_history(&self, server: &str, message: &TircMessage) -> bool {
+        self.batch_of(server, message)
+            .is_some_and(|batch| batch.kind == "chathistory")
+    }
+
+    /// Collects `message` if it opens, belongs to or closes a batch, otherwise
+    /// hands it back. Closing a batch dispatches its messages, returning the
+    /// buffers they were appended to.
+    fn collect_batch(
+        &mut self,
+        lua: &Lua,
+        server: &str,
+        message: TircMessage,
+    ) -> mlua::Result<Result<Vec<BufferId>, TircMessage>> {
+        // Batches may be nested, so `BATCH` lines are handled before the
+        // batch they are tagged with.
+        let command = match &message {
+            TircMessage::Irc(_, irc_message, _) => match &irc_message.command {
+                Command::BATCH(reference, kind, params) => {
+                    Some((reference.clone(), kind.clone(), params.clone()))
+                }
+                _ => None,
+            },
+            TircMessage::Lua(_, _) => None,
         };
 
-        self.create_buffer_if_not_exists(&buffer_name);
-        self.push_message_to_buffer(&buffer_name, message)
+        match command {
+            Some((reference, Some(kind), params)) if reference.starts_with('+') => {
+                self.batches.insert(
+                    (server.to_owned(), reference[1..].to_owned()),
+                    Batch {
+                        kind: kind.to_str().to_lowercase(),
+                        params: params.unwrap_or_default(),
+                        label: message.get_tag("label").map(str::to_owned),
+                        parent: message.get_tag("batch").map(str::to_owned),
+                        messages: Vec::new(),
+                        children: Vec::new(),
+                    },
+                );
+
+                Ok(Ok(Vec::new()))
+            }
+            Some((reference, _, _)) if reference.starts_with('-') => {
+                let Some(batch) = self
+                    .batches
+                    .remove(&(server.to_owned(), reference[1..].to_owned()))
+                else {
+                    // The end of a batch we never saw open has nothing to show.
+                    return Ok(Ok(Vec::new()));
+                };
+
+                let parent = batch
+                    .parent
+                    .as_ref()
+                    .and_then(|parent| self.batches.get_mut(&(server.to_owned(), parent.clone())));
+
+                match parent {
+                    // Dispatched along with its parent.
+                    Some(parent) => {
+                        parent.children.push(batch);
+
+                        Ok(Ok(Vec::new()))
+                    }
+                    None => self.dispatch_batch(lua, server, batch, None).map(Ok),
+                }
+            }
+            _ => {
+                let key = message
+                    .get_tag("batch")
+                    .map(|reference| (server.to_owned(), reference.to_owned()));
+
+                match key.and_then(|key| self.batches.get_mut(&key)) {
+                    Some(batch) => {
+                        batch.messages.push(message);
+
+                        Ok(Ok(Vec::new()))
+                    }
+                    None => Ok(Err(message)),
+                }
+            }
+        }
+    }
+
+    /// Files the messages of a closed batch as a unit, then those of the
+    /// batches nested in it. The batch is exposed to Lua formatters as the
+    /// `batch` field of its messages, linked to the `parent` it is nested in.
+    fn dispatch_batch(
+        &mut self,
+        lua: &Lua,
+        server: &str,
+        mut batch: Batch,
+        parent: Option<&mlua::Table>,
+    ) -> mlua::Result<Vec<BufferId>> {
+        let lua_batch = to_lua_batch(lua, &batch.kind, &batch.params)?;
+        let children = std::mem::take(&mut batch.children);
+
+        lua_batch.set("parent", parent)?;
+
+        for message in &batch.messages {
+            message.get_lua_message().set("batch", &lua_batch)?;
+        }
+
+        let mut appended = self.dispatch_messages(lua, server, batch, &lua_batch)?;
+
+        for child in children {
+            appended.extend(self.dispatch_batch(lua, server, child, Some(&lua_batch))?);
+        }
+
+        Ok(appended)
+    }
+
+    /// Files the messages of a closed batch according to its type.
+    fn dispatch_messages(
+        &mut self,
+        lua: &Lua,
+        server: &str,
+        batch: Batch,
+        lua_batch: &mlua::Table,
+    ) -> mlua::Result<Vec<BufferId>> {
+        match batch.kind.as_str() {
+            "chathistory" => {
+                if let Some(target) = batch.params.first() {
+                    self.merge_history(server, target, batch.messages);
+                }
+
+                Ok(Vec::new())
+            }
+            "netsplit" | "netjoin" => self.summarize_batch(lua, server, batch, lua_batch),
+            "labeled-response" => {
+                let mut label = batch.label;
+                let mut appended = Vec::new();
+
+                // The first reply filed into the buffer of the labeled message
+                // takes its place, as an unbatched echo would.
+                for mut message in batch.messages {
+                    if let (Some(tag), TircMessage::Irc(_, irc_message, _)) = (&label, &mut message)
+                    {
+                        let buffer_id =
+                            BufferId::new(server, self.get_target_buffer_name(server, irc_message));
+
+                        if self
+                            .buffers
+                            .get(&buffer_id)
+                            .is_some_and(|buffer| buffer.find_label(tag).is_some())
+                        {
+                            irc_message
+                                .tags
+                                .get_or_insert_with(Vec::new)
+                                .push(Tag("label".to_owned(), label.take()));
+                        }
+                    }
+
+                    appended.extend(self.route_message(server, message));
+                }
+
+                Ok(appended)
+            }
+            _ => Ok(batch
+                .messages
+                .into_iter()
+                .filter_map(|message| self.route_message(server, message))
+                .collect()),
+        }
+    }
+
+    fn merge_history(&mut self, server: &str, target: &str, messages: Vec<TircMessage>) {
+        let buffer_id = BufferId::new(server, target);
+
+        self.create_buffer_if_not_exists(&buffer_id);
+
+        let buffer = self.buffers.get_mut(&buffer_id).unwrap();
+        let request = buffer.history_request.take();
+        let added = buffer.merge_history(messages);
+
+        if request == Some(HistoryRequest::Before) && added == 0 {
+            buffer.history_exhausted = true;
+        }
+    }
+
+    /// Collapses the quits of a netsplit, or the joins of a netjoin, into one
+    /// line per buffer naming the nicknames involved.
+    fn summarize_batch(
+        &mut self,
+        lua: &Lua,
+        server: &str,
+        batch: Batch,
+        lua_batch: &mlua::Table,
+    ) -> mlua::Result<Vec<BufferId>> {
+        let mut nicknames: IndexMap<BufferId, Vec<String>> = IndexMap::new();
+
+        for message in &batch.messages {
+            if let TircMessage::Irc(_, irc_message, _) = message {
+                let buffer_id =
+                    BufferId::new(server, self.get_target_buffer_name(server, irc_message));
+
+                nicknames
+                    .entry(buffer_id)
+                    .or_default()
+                    .extend(irc_message.source_nickname().map(str::to_owned));
+            }
+        }
+
+        let (title, verb) = match batch.kind.as_str() {
+            "netsplit" => ("Netsplit", "quit"),
+            _ => ("Netjoin", "joined"),
+        };
+        let servers = batch.params.join(" <-> ");
+        let mut appended = Vec::new();
+
+        for (buffer_id, nicknames) in nicknames {
+            let text = format!("{} {}, {}: {}", title, servers, verb, nicknames.join(", "));
+            let message = TircMessage::client_notice(NoticeLevel::Info, &text, lua)?;
+            let lua_message = message.get_lua_message();
+
+            lua_message.set("batch", lua_batch)?;
+            lua_message.set("nicks", nicknames)?;
+
+            self.create_buffer_if_not_exists(&buffer_id);
+            self.push_message_to_buffer(&buffer_id, message);
+            appended.push(buffer_id);
+        }
+
+        Ok(appended)
+    }
+
+    /// Inserts messages restored from the logs before the messages of a
+    /// buffer, keeping a scrolled view on the same message.
+    pub fn restore_messages(&mut self, buffer_id: &BufferId, messages: Vec<TircMessage>) {
+        self.create_buffer_if_not_exists(buffer_id);
+
+        let buffer = self.buffers.get_mut(buffer_id).unwrap();
+        let count = messages.len();
+
+        buffer.messages.splice(0..0, messages);
+        buffer.shift_indices(0, count);
+
+        if let Some(position) = &mut buffer.scroll_position {
+            position.message += count;
+        }
+    }
+
+    /// Files `message` received from (or sent to) `server` into its buffer,
+    /// or collects it into a batch until the batch is closed.
+    ///
+    /// Returns the buffers messages were appended to, once per message. A
+    /// message that replaced the pending message sent with the same label is
+    /// not included.
+    pub fn push_message(
+        &mut self,
+        lua: &Lua,
+        server: &str,
+        message: TircMessage,
+    ) -> mlua::Result<Vec<BufferId>> {
+        match self.collect_batch(lua, server, message)? {
+            Ok(appended) => Ok(appended),
+            Err(message) => Ok(self.route_message(server, message).into_iter().collect()),
+        }
+    }
+
+    /// Appends `message` to the buffer it is addressed to.
+    fn route_message(&mut self, server: &str, message: TircMessage) -> Option<BufferId> {
+        let buffer_id = self.target_buffer(server, &message);
+
+        self.create_buffer_if_not_exists(&buffer_id);
+
+        if let TircMessage::Irc(_, message, _) = &message {
+            self.update_joined(server, message);
+        }
+
+        self.push_message_to_buffer(&buffer_id, message)
+            .then_some(buffer_id)
+    }
+
+    /// Notes whether the user joined or left a channel of `server`.
+    fn update_joined(&mut self, server: &str, message: &Message) {
+        let nickname = self.nickname(server);
+        let is_own = |name: &str| name.eq_ignore_ascii_case(nickname);
+        let from_self = message.source_nickname().is_some_and(is_own);
+
+        let (channel, joined) = match &message.command {
+            Command::JOIN(channel, _, _) if from_self => (channel, true),
+            Command::PART(channel, _) if from_self => (channel, false),
+            Command::KICK(channel, kicked, _) if is_own(kicked) => (channel, false),
+            _ => return,
+        };
+
+        let buffer_id = BufferId::new(server, channel);
+        self.create_buffer_if_not_exists(&buffer_id);
+        self.buffers.get_mut(&buffer_id).unwrap().joined = joined;
+    }
+
+    /// Channels of `server` the user is in, to rejoin after reconnecting.
+    pub fn joined_channels(&self, server: &str) -> Vec<String> {
+        self.buffers
+            .iter()
+            .filter(|(buffer_id, buffer)| buffer_id.server == server && buffer.joined)
+            .map(|(buffer_id, _)| buffer_id.name.clone())
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ui::state::ChatBuffer;
+    use super::{BufferId, ChatBuffer, Scroll, ScrollPosition, State, TircMessage, TopPosition};
+    use crate::ui::{history::HistoryRequest, NoticeLevel};
+
+    fn state() -> State {
+        let mut state = State::default();
+        state.add_server("net", "irc.example.com");
+        state
+    }
 
     fn target_buffer(nickname: &str, raw: &str) -> String {
-        let mut state = super::State::default();
-        state.nickname = nickname.to_string();
+        let mut state = state();
+        state.servers["net"].nickname = nickname.to_string();
         let message: irc::proto::Message = raw.parse().expect("valid irc message");
-        state.get_target_buffer_name(&message)
+        state.get_target_buffer_name("net", &message)
+    }
+
+    #[test]
+    fn user_host_is_learned_from_own_prefixes() {
+        let mut state = state();
+        state.servers["net"].nickname = "me".to_string();
+        let mut update = |raw: &str| {
+            state.update_user_host("net", &raw.parse().expect("valid irc message"));
+            state.servers["net"].user_host.clone()
+        };
+
+        assert_eq!(update(":alice!a@elsewhere JOIN #tirc"), None);
+        assert_eq!(
+            update(":irc.example.com 001 me :Welcome to the network me!~me@host"),
+            Some("~me@host".to_owned())
+        );
+        assert_eq!(
+            update(":me!~me@cloaked JOIN #tirc"),
+            Some("~me@cloaked".to_owned())
+        );
+        assert_eq!(
+            update(":irc.example.com 001 me :Welcome to the network"),
+            Some("~me@cloaked".to_owned())
+        );
     }
 
     #[test]
@@ -204,7 +1079,10 @@ mod tests {
 
     #[test]
     fn test_target_buffer_incoming_direct_message() {
-        assert_eq!(target_buffer("me", ":alice!u@h PRIVMSG me :hi\r\n"), "alice");
+        assert_eq!(
+            target_buffer("me", ":alice!u@h PRIVMSG me :hi\r\n"),
+            "alice"
+        );
     }
 
     #[test]
@@ -236,23 +1114,21 @@ mod tests {
     }
 
     #[test]
-    fn test_get_buffer_name_by_index() {
-        let state = super::State::default();
-        assert_eq!(state.get_buffer_name_by_index(0), "(status)");
+    fn test_get_buffer_id_by_index() {
+        let state = state();
+        assert_eq!(state.get_buffer_id_by_index(0), BufferId::status("net"));
     }
 
     #[test]
     fn test_get_current_buffer_index() {
-        let state = super::State::default();
+        let state = state();
         assert_eq!(state.get_current_buffer_index(), 0);
     }
 
     #[test]
     fn test_set_current_buffer_index() {
-        let mut state = super::State::default();
-        state
-            .buffers
-            .insert("foo".to_string(), ChatBuffer::default());
+        let mut state = state();
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
         assert_eq!(state.get_current_buffer_index(), 0);
         state.set_current_buffer_index(1);
         assert_eq!(state.get_current_buffer_index(), 1);
@@ -260,24 +1136,18 @@ mod tests {
 
     #[test]
     fn test_set_current_buffer() {
-        let mut state = super::State::default();
-        state
-            .buffers
-            .insert("foo".to_string(), ChatBuffer::default());
+        let mut state = state();
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
         assert_eq!(state.get_current_buffer_index(), 0);
-        state.set_current_buffer("foo");
+        state.set_current_buffer(&BufferId::new("net", "foo"));
         assert_eq!(state.get_current_buffer_index(), 1);
     }
 
     #[test]
     fn test_next_buffer() {
-        let mut state = super::State::default();
-        state
-            .buffers
-            .insert("foo".to_string(), ChatBuffer::default());
-        state
-            .buffers
-            .insert("bar".to_string(), ChatBuffer::default());
+        let mut state = state();
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
+        state.create_buffer_if_not_exists(&BufferId::new("net", "bar"));
         assert_eq!(state.get_current_buffer_index(), 0);
         state.next_buffer();
         assert_eq!(state.get_current_buffer_index(), 1);
@@ -289,13 +1159,9 @@ mod tests {
 
     #[test]
     fn test_previous_buffer() {
-        let mut state = super::State::default();
-        state
-            .buffers
-            .insert("foo".to_string(), ChatBuffer::default());
-        state
-            .buffers
-            .insert("bar".to_string(), ChatBuffer::default());
+        let mut state = state();
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
+        state.create_buffer_if_not_exists(&BufferId::new("net", "bar"));
         assert_eq!(state.get_current_buffer_index(), 0);
         state.previous_buffer();
         assert_eq!(state.get_current_buffer_index(), 2);
@@ -307,10 +1173,566 @@ mod tests {
 
     #[test]
     fn test_create_buffer_if_not_exists() {
-        let mut state = super::State::default();
-        state.create_buffer_if_not_exists("foo");
+        let mut state = state();
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
         assert_eq!(state.buffers.len(), 2);
-        state.create_buffer_if_not_exists("foo");
+        state.create_buffer_if_not_exists(&BufferId::new("net", "foo"));
         assert_eq!(state.buffers.len(), 2);
     }
+
+    #[test]
+    fn test_buffers_are_namespaced_per_server() {
+        let mut state = state();
+        state.add_server("other", "irc.other.org");
+        state.create_buffer_if_not_exists(&BufferId::new("other", "#rust"));
+        state.create_buffer_if_not_exists(&BufferId::new("net", "#rust"));
+
+        let ids: Vec<_> = state.buffers.keys().cloned().collect();
+        assert_eq!(
+            ids,
+            [
+                BufferId::status("net"),
+                BufferId::new("net", "#rust"),
+                BufferId::status("other"),
+                BufferId::new("other", "#rust"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_server_removes_its_buffers() {
+        let mut state = state();
+        state.add_server("other", "irc.other.org");
+        state.create_buffer_if_not_exists(&BufferId::new("other", "#rust"));
+        state.set_current_buffer(&BufferId::new("other", "#rust"));
+
+        state.remove_server("other");
+
+        assert!(!state.servers.contains_key("other"));
+        assert_eq!(
+            state.buffers.keys().cloned().collect::<Vec<_>>(),
+            [BufferId::status("net")]
+        );
+        assert_eq!(state.current_buffer, BufferId::status("net"));
+    }
+
+    /// A buffer of client notices whose text is the number of rows they wrap
+    /// to.
+    fn buffer(lua: &mlua::Lua, heights: &[usize]) -> ChatBuffer {
+        let messages = heights
+            .iter()
+            .map(|height| {
+                TircMessage::client_notice(NoticeLevel::Info, &height.to_string(), lua).unwrap()
+            })
+            .collect();
+
+        ChatBuffer {
+            messages,
+            ..Default::default()
+        }
+    }
+
+    fn height(message: &TircMessage) -> usize {
+        message
+            .get_lua_message()
+            .get::<String>("raw")
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn scroll(buffer: &mut ChatBuffer, scroll: Scroll) -> Option<ScrollPosition> {
+        buffer.scroll_position = buffer.scrolled(scroll, 4, height);
+        buffer.scroll_position
+    }
+
+    fn position(message: usize, offset: usize) -> Option<ScrollPosition> {
+        Some(ScrollPosition { message, offset })
+    }
+
+    #[test]
+    fn test_scroll_accounts_for_wrapped_heights() {
+        let lua = mlua::Lua::new();
+        let mut buffer = buffer(&lua, &[1, 1, 1, 3, 0, 2]);
+
+        assert_eq!(scroll(&mut buffer, Scroll::Up(1)), position(5, 1));
+        assert_eq!(scroll(&mut buffer, Scroll::Up(2)), position(3, 1));
+        assert_eq!(scroll(&mut buffer, Scroll::Down(2)), position(5, 1));
+        assert_eq!(scroll(&mut buffer, Scroll::Down(1)), None);
+        assert_eq!(scroll(&mut buffer, Scroll::Down(1)), None);
+    }
+
+    #[test]
+    fn test_scroll_stops_at_oldest_message() {
+        let lua = mlua::Lua::new();
+        let mut buffer = buffer(&lua, &[1, 1, 1, 3, 0, 2]);
+
+        assert_eq!(scroll(&mut buffer, Scroll::Up(100)), position(3, 2));
+        assert_eq!(scroll(&mut buffer, Scroll::Bottom), None);
+        assert_eq!(scroll(&mut buffer, Scroll::Top), position(3, 2));
+
+        let mut short = self::buffer(&lua, &[1, 2]);
+        assert_eq!(scroll(&mut short, Scroll::Up(1)), None);
+        assert_eq!(scroll(&mut short, Scroll::Top), None);
+    }
+
+    #[test]
+    fn test_top_position_is_kept_for_messages_below_it() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        let top = |position| {
+            Some(TopPosition {
+                width: 80,
+                height: 5,
+                position,
+            })
+        };
+
+        state.create_buffer_if_not_exists(&buffer_id);
+        let notice = || TircMessage::client_notice(NoticeLevel::Info, "hi", &lua).unwrap();
+
+        // Appending may make messages that all fit scrollable.
+        state.buffers[&buffer_id].top_position = top(None);
+        state.push_message_to(&buffer_id, notice());
+        assert_eq!(state.buffers[&buffer_id].top_position, None);
+
+        state.push_message_to(&buffer_id, notice());
+        state.buffers[&buffer_id].top_position = top(position(0, 1));
+        state.push_message_to(&buffer_id, notice());
+        assert_eq!(state.buffers[&buffer_id].top_position, top(position(0, 1)));
+
+        state.restore_messages(&buffer_id, vec![notice()]);
+        assert_eq!(state.buffers[&buffer_id].top_position, None);
+    }
+
+    #[test]
+    fn test_rendered_texts_are_kept_before_changes() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        let notice = || TircMessage::client_notice(NoticeLevel::Info, "hi", &lua).unwrap();
+        let rendered = |state: &State| state.buffers[&buffer_id].rendered_texts.borrow().len();
+
+        for _ in 0..3 {
+            state.push_message_to(&buffer_id, notice());
+        }
+
+        *state.buffers[&buffer_id].rendered_texts.borrow_mut() = vec![None; 3];
+        state.push_message_to(&buffer_id, notice());
+        assert_eq!(rendered(&state), 3);
+
+        state.restore_messages(&buffer_id, vec![notice()]);
+        assert_eq!(rendered(&state), 0);
+
+        *state.buffers[&buffer_id].rendered_texts.borrow_mut() = vec![None; 5];
+        state.reset_render_caches();
+        assert_eq!(rendered(&state), 0);
+    }
+
+    #[test]
+    fn test_scroll_to_message_stays_within_messages() {
+        let lua = mlua::Lua::new();
+        let mut buffer = buffer(&lua, &[1, 1, 1, 3, 0, 2]);
+
+        assert_eq!(scroll(&mut buffer, Scroll::To(3)), position(3, 0));
+        assert_eq!(scroll(&mut buffer, Scroll::To(1)), position(3, 2));
+        assert_eq!(scroll(&mut buffer, Scroll::To(5)), None);
+    }
+
+    #[test]
+    fn test_new_messages_do_not_move_scrolled_view() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let status = BufferId::status("net");
+
+        for _ in 0..10 {
+            let message = TircMessage::client_notice(NoticeLevel::Info, "1", &lua).unwrap();
+            state.push_message_to(&status, message);
+        }
+
+        let buffer = state.buffers.get_mut(&status).unwrap();
+        assert_eq!(scroll(buffer, Scroll::Up(3)), position(6, 0));
+
+        let message = TircMessage::client_notice(NoticeLevel::Info, "1", &lua).unwrap();
+        state.push_message_to(&status, message);
+
+        assert_eq!(state.buffers[&status].scroll_position, position(6, 0));
+    }
+
+    #[test]
+    fn test_restored_messages_go_before_live_messages() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        let notice =
+            |text: &str| TircMessage::client_notice(NoticeLevel::Info, text, &lua).unwrap();
+
+        state.push_message_to(&buffer_id, notice("live"));
+        state.buffers[&buffer_id].scroll_position = position(0, 0);
+        state.restore_messages(&buffer_id, vec![notice("old"), notice("marker")]);
+
+        let buffer = &state.buffers[&buffer_id];
+        let raw: Vec<String> = buffer
+            .messages
+            .iter()
+            .map(|message| message.get_lua_message().get("raw").unwrap())
+            .collect();
+
+        assert_eq!(raw, ["old", "marker", "live"]);
+        assert_eq!(buffer.scroll_position, position(2, 0));
+    }
+
+    #[test]
+    fn test_search_match_stays_on_its_message() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        let notice =
+            |text: &str| TircMessage::client_notice(NoticeLevel::Info, text, &lua).unwrap();
+
+        state.push_message_to(&buffer_id, notice("match"));
+        state.buffers[&buffer_id].search_match = Some(0);
+        state.restore_messages(&buffer_id, vec![notice("old")]);
+
+        assert_eq!(state.buffers[&buffer_id].search_match, Some(1));
+
+        state.set_search(regex::Regex::new("match").unwrap());
+
+        assert_eq!(state.buffers[&buffer_id].search_match, None);
+        assert!(BufferId::search_results("net").is_read_only());
+        assert!(!buffer_id.is_read_only());
+    }
+
+    fn push_raw(state: &mut State, lua: &mlua::Lua, raw: &str) {
+        let message: irc::proto::Message = raw.parse().expect("valid irc message");
+        let message = TircMessage::from_message(message.into(), lua).unwrap();
+
+        state.push_message(lua, "net", message).unwrap();
+    }
+
+    fn msgids(state: &State, buffer_id: &BufferId) -> Vec<String> {
+        state.buffers[buffer_id]
+            .messages
+            .iter()
+            .map(|message| message.get_msgid().unwrap_or_default().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_chathistory_batch_is_merged_in_order() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+
+        push_raw(
+            &mut state,
+            &lua,
+            "@msgid=b;time=2024-05-01T12:02:00.000Z :a!u@h PRIVMSG #tirc :live",
+        );
+        state.buffers[&buffer_id].history_request = Some(HistoryRequest::Latest);
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +1 chathistory #tirc",
+        );
+        for raw in [
+            "@batch=1;msgid=a;time=2024-05-01T12:01:00.000Z :a!u@h PRIVMSG #tirc :one",
+            "@batch=1;msgid=b;time=2024-05-01T12:02:00.000Z :a!u@h PRIVMSG #tirc :live",
+            "@batch=1;msgid=c;time=2024-05-01T12:03:00.000Z :a!u@h PRIVMSG #tirc :three",
+        ] {
+            push_raw(&mut state, &lua, raw);
+        }
+
+        assert_eq!(msgids(&state, &buffer_id), ["b"]);
+
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -1");
+
+        assert_eq!(msgids(&state, &buffer_id), ["a", "b", "c"]);
+        assert_eq!(state.buffers[&buffer_id].history_request, None);
+        assert!(!state.buffers[&buffer_id].history_exhausted);
+    }
+
+    #[test]
+    fn test_nested_batch_is_dispatched_with_its_parent() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+
+        push_raw(
+            &mut state,
+            &lua,
+            "@label=l :irc.example.com BATCH +outer labeled-response",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=outer :irc.example.com BATCH +inner chathistory #tirc",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=inner;msgid=a;time=2024-05-01T12:01:00.000Z :a!u@h PRIVMSG #tirc :one",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=outer :irc.example.com BATCH -inner",
+        );
+
+        assert!(!state.buffers.contains_key(&buffer_id));
+
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -outer");
+
+        assert_eq!(msgids(&state, &buffer_id), ["a"]);
+
+        let batch: mlua::Table = state.buffers[&buffer_id].messages[0]
+            .get_lua_message()
+            .get("batch")
+            .unwrap();
+        let parent: mlua::Table = batch.get("parent").unwrap();
+        assert_eq!(batch.get::<String>("type").unwrap(), "chathistory");
+        assert_eq!(parent.get::<String>("type").unwrap(), "labeled-response");
+    }
+
+    #[test]
+    fn test_batches_are_cleared_on_disconnect() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let status = BufferId::status("net");
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +s netsplit a.net b.net",
+        );
+        state.clear_batches("net");
+        push_raw(&mut state, &lua, "@batch=s :alice!u@h QUIT :a.net b.net");
+
+        assert_eq!(state.buffers[&status].messages.len(), 1);
+    }
+
+    #[test]
+    fn test_empty_chathistory_before_batch_exhausts_history() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+
+        state.create_buffer_if_not_exists(&buffer_id);
+        state.buffers[&buffer_id].history_request = Some(HistoryRequest::Before);
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +1 chathistory #tirc",
+        );
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -1");
+
+        assert!(state.buffers[&buffer_id].history_exhausted);
+    }
+
+    fn raw_texts(state: &State, buffer_id: &BufferId) -> Vec<String> {
+        state.buffers[buffer_id]
+            .messages
+            .iter()
+            .map(|message| message.get_lua_message().get("raw").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_netsplit_batch_is_summarized() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let status = BufferId::status("net");
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +s netsplit a.net b.net",
+        );
+        push_raw(&mut state, &lua, "@batch=s :alice!u@h QUIT :a.net b.net");
+        push_raw(&mut state, &lua, "@batch=s :bob!u@h QUIT :a.net b.net");
+
+        assert!(state.buffers[&status].messages.is_empty());
+
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -s");
+
+        assert_eq!(
+            raw_texts(&state, &status),
+            ["Netsplit a.net <-> b.net, quit: alice, bob"]
+        );
+
+        let summary = state.buffers[&status].messages[0].get_lua_message();
+        let batch: mlua::Table = summary.get("batch").unwrap();
+        assert_eq!(batch.get::<String>("type").unwrap(), "netsplit");
+        assert_eq!(
+            summary.get::<Vec<String>>("nicks").unwrap(),
+            ["alice", "bob"]
+        );
+    }
+
+    #[test]
+    fn test_netjoin_batch_is_summarized_per_channel() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +j netjoin a.net b.net",
+        );
+        push_raw(&mut state, &lua, "@batch=j :alice!u@h JOIN #tirc");
+        push_raw(&mut state, &lua, "@batch=j :alice!u@h JOIN #rust");
+        push_raw(&mut state, &lua, "@batch=j :bob!u@h JOIN #tirc");
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -j");
+
+        assert_eq!(
+            raw_texts(&state, &BufferId::new("net", "#tirc")),
+            ["Netjoin a.net <-> b.net, joined: alice, bob"]
+        );
+        assert_eq!(
+            raw_texts(&state, &BufferId::new("net", "#rust")),
+            ["Netjoin a.net <-> b.net, joined: alice"]
+        );
+    }
+
+    #[test]
+    fn test_labeled_response_batch_replaces_labeled_message() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        state.servers["net"].nickname = "me".to_string();
+
+        push_raw(&mut state, &lua, "@label=1 PRIVMSG #tirc :hi");
+        push_raw(
+            &mut state,
+            &lua,
+            "@label=1 :irc.example.com BATCH +r labeled-response",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=r;time=2024-05-01T12:00:00.000Z :me!u@h PRIVMSG #tirc :hi",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=r :irc.example.com NOTICE me :relayed",
+        );
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -r");
+
+        let messages = &state.buffers[&buffer_id].messages;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].get_tag("time").is_some());
+        assert_eq!(state.buffers[&BufferId::status("net")].messages.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_label_keeps_read_and_scroll_positions() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let buffer_id = BufferId::new("net", "#tirc");
+        state.servers["net"].nickname = "me".to_string();
+
+        push_raw(&mut state, &lua, ":alice!u@h PRIVMSG #tirc :one");
+        push_raw(&mut state, &lua, "@label=1 PRIVMSG #tirc :queued");
+        push_raw(&mut state, &lua, ":alice!u@h PRIVMSG #tirc :two");
+
+        let buffer = state.buffers.get_mut(&buffer_id).unwrap();
+        buffer.last_read = Some(3);
+        buffer.scroll_position = Some(ScrollPosition {
+            message: 2,
+            offset: 0,
+        });
+
+        assert!(buffer.remove_label("2").is_none());
+        assert!(buffer.remove_label("1").is_some());
+        assert_eq!(buffer.messages.len(), 2);
+        assert_eq!(buffer.last_read, Some(2));
+        assert_eq!(
+            buffer.scroll_position.map(|position| position.message),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_batch_type_is_exposed_and_markers_hidden() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let status = BufferId::status("net");
+
+        push_raw(
+            &mut state,
+            &lua,
+            ":irc.example.com BATCH +o example.com/other x",
+        );
+        push_raw(
+            &mut state,
+            &lua,
+            "@batch=o :irc.example.com NOTICE * :inside",
+        );
+        push_raw(&mut state, &lua, ":irc.example.com BATCH -o");
+
+        let messages = &state.buffers[&status].messages;
+        assert_eq!(messages.len(), 1);
+
+        let batch: mlua::Table = messages[0].get_lua_message().get("batch").unwrap();
+        assert_eq!(batch.get::<String>("type").unwrap(), "example.com/other");
+        assert_eq!(batch.get::<Vec<String>>("params").unwrap(), ["x"]);
+    }
+
+    #[test]
+    fn test_switching_buffers_marks_them_read() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        let status = BufferId::status("net");
+        let buffer_id = BufferId::new("net", "#tirc");
+
+        state.create_buffer_if_not_exists(&buffer_id);
+        state.push_message_to(
+            &status,
+            TircMessage::client_notice(NoticeLevel::Info, "1", &lua).unwrap(),
+        );
+        state.buffers[&buffer_id].mark_unread(false);
+        state.buffers[&buffer_id].mark_unread(true);
+
+        assert_eq!(state.buffers[&buffer_id].unread, 2);
+        assert_eq!(state.buffers[&buffer_id].highlights, 1);
+
+        state.set_current_buffer(&buffer_id);
+
+        assert_eq!(state.buffers[&buffer_id].unread, 0);
+        assert_eq!(state.buffers[&buffer_id].highlights, 0);
+        assert_eq!(state.buffers[&status].last_read, Some(1));
+
+        state.restore_messages(
+            &status,
+            vec![TircMessage::client_notice(NoticeLevel::Info, "0", &lua).unwrap()],
+        );
+
+        assert_eq!(state.buffers[&status].last_read, Some(2));
+    }
+
+    #[test]
+    fn test_joined_channels_follow_own_joins_and_parts() {
+        let lua = mlua::Lua::new();
+        let mut state = state();
+        state.servers["net"].nickname = "me".to_string();
+
+        for raw in [
+            ":Me!u@h JOIN #tirc",
+            ":me!u@h JOIN #rust",
+            ":me!u@h JOIN #gone",
+            ":alice!u@h PART #tirc",
+            ":me!u@h PART #gone",
+            ":op!u@h KICK #rust me :bye",
+            ":alice!u@h PRIVMSG #chat :hi",
+        ] {
+            push_raw(&mut state, &lua, raw);
+        }
+
+        assert_eq!(state.joined_channels("net"), ["#tirc"]);
+        assert!(state.joined_channels("other").is_empty());
+    }
 }